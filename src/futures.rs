@@ -0,0 +1,130 @@
+//! Futures driven by the event loop
+//!
+//! These futures rely on the timeouts of the event loop rather than on OS timers or
+//! a timer thread, so they must be driven by the event loop they were created from,
+//! typically with `EventLoop::block_on()`.
+//!
+//! `Sleep` completes at a deadline, and `Timeout` bounds the time an other future can
+//! take to complete.
+
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+use sources::timer::TimeoutAction;
+use {LoopHandle, RegistrationToken};
+
+#[derive(Default)]
+struct SleepState {
+    fired: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+/// A future completing at a deadline
+///
+/// It is created with `LoopHandle::sleep()` or `LoopHandle::sleep_until()`. Dropping
+/// it removes its timeout from the event loop.
+pub struct Sleep<'l, Data: 'l> {
+    handle: LoopHandle<'l, Data>,
+    token: RegistrationToken,
+    state: Rc<SleepState>,
+}
+
+impl<'l, Data: 'l> Sleep<'l, Data> {
+    pub(crate) fn new(handle: &LoopHandle<'l, Data>, deadline: Instant) -> io::Result<Self> {
+        let state = Rc::new(SleepState::default());
+        let timeout_state = state.clone();
+        let token = handle.insert_timeout(deadline, move |_, _| {
+            timeout_state.fired.set(true);
+            if let Some(waker) = timeout_state.waker.borrow_mut().take() {
+                waker.wake();
+            }
+            TimeoutAction::Drop
+        })?;
+        Ok(Sleep {
+            handle: handle.clone(),
+            token,
+            state,
+        })
+    }
+}
+
+impl<'l, Data: 'l> Future for Sleep<'l, Data> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.state.fired.get() {
+            Poll::Ready(())
+        } else {
+            *self.state.waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<'l, Data: 'l> Drop for Sleep<'l, Data> {
+    fn drop(&mut self) {
+        // a fired timeout has already been removed
+        if !self.state.fired.get() {
+            self.handle.remove(self.token);
+        }
+    }
+}
+
+/// A future that did not complete before its deadline
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the future did not complete before its deadline")
+    }
+}
+
+impl Error for TimedOut {}
+
+/// A future wrapping an other one, which fails if it does not complete in time
+///
+/// It is created with `LoopHandle::timeout()`. Once the deadline is reached, the inner
+/// future is dropped, cancelling it, and `Err(TimedOut)` is returned.
+pub struct Timeout<'l, Data: 'l, F> {
+    future: Option<Pin<Box<F>>>,
+    sleep: Sleep<'l, Data>,
+}
+
+impl<'l, Data: 'l, F: Future> Timeout<'l, Data, F> {
+    pub(crate) fn new(sleep: Sleep<'l, Data>, future: F) -> Self {
+        Timeout {
+            future: Some(Box::pin(future)),
+            sleep,
+        }
+    }
+}
+
+impl<'l, Data: 'l, F: Future> Future for Timeout<'l, Data, F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let future = match this.future {
+            Some(ref mut future) => future,
+            None => return Poll::Ready(Err(TimedOut)),
+        };
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                this.future = None;
+                Poll::Ready(Err(TimedOut))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -35,7 +35,7 @@
 //!     // Actual run of your loop
 //!     loop {
 //!         // Dispatch received events to their callbacks, waiting at most 20 ms for
-//!         // new events
+//!         // new events. Passing `None` instead waits until an event is received.
 //!         //
 //!         // The `&mut shared_data` is a mutable reference that will be forwarded to all
 //!         // your callbacks, allowing them to easily share some state
@@ -58,8 +58,43 @@
 //! This crate also provide some adapters for common event sources such as:
 //!
 //! - MPSC channels
+//! - bounded channels, blocking, failing or dropping messages when full
+//! - multi-consumer channels, distributing messages among several event loops
+//! - broadcast channels, delivering each message to several event loops
+//! - request channels, whose requests are answered by the event loop
+//! - watch channels, delivering only the latest of their values
+//! - signal-safe channels, whose messages can be sent from POSIX signal handlers
 //! - Timers
+//! - pings, waking up the event loop from anywhere
+//! - eventfds, delivering their counter, possibly shared with foreign code
 //! - unix signals
+//! - terminal resizes
+//! - self-pipes, notified by signal handlers like the ones of `signal-hook`
+//! - exits of child processes
+//! - exits of any process, through pidfds or kqueue
+//! - subprocesses, delivering their output and exit status
+//! - pseudo-terminals, running a process and delivering its output
+//! - serial ports, with helpers to configure their mode and speed
+//! - the standard input, with a guard keeping its terminal in raw mode
+//! - changes to files, through inotify (with the `inotify` cargo feature)
+//! - accesses to whole mounts or filesystems, and permission requests, through fanotify
+//! - hotplug of devices, through udev (with the `udev` cargo feature)
+//! - input devices, through evdev (with the `evdev` cargo feature)
+//! - vertical blanks and page flips of DRM devices
+//! - the signaling of GPU fences, through sync_files
+//! - the edges of GPIO lines
+//! - the watchdog and the journal of systemd, with helpers to notify it (with the `systemd` cargo feature)
+//! - netlink sockets, notably watching the network interfaces and addresses
+//! - the changing sets of poll descriptors of libraries like ALSA or libusb
+//! - TCP and unix listeners, delivering the accepted connections
+//! - buffered unix streams, with the credentials of their peer
+//! - vsock listeners and streams, connecting virtual machines to their host
+//! - UDP sockets, delivering their datagrams
+//! - nested event loops
+//! - inactivity timeouts wrapping any other source
+//! - debouncing of the bursts of events of any other source
+//! - rate limiting of the events of any other source
+//! - calendar schedules, firing at cron-like local times
 //!
 //! As well as generic `mio::Evented` objects.
 //!
@@ -68,6 +103,21 @@
 //! are stored and then executed during `EventLoop::dispatch(..)`, once all events from the sources
 //! have been processed.
 //!
+//! ## Platform support
+//!
+//! The event loop relies on `mio`, using epoll on Linux and Android, and kqueue on macOS,
+//! iOS and the BSDs. All event sources are available on these platforms, except for unix
+//! signals and terminal resizes, eventfds, the exits of child processes, subprocesses,
+//! pseudo-terminals, serial ports, inotify, fanotify, udev, evdev, DRM, sync_files, GPIO,
+//! netlink, vsock and systemd, the timerfd-based timers and calendar schedules, which are
+//! currently only supported on Linux.
+//!
+//! On illumos and Solaris, `mio` relies on the epoll emulation of the system rather than
+//! on event ports, the event loop thus behaves as on Linux, without unix signals.
+//!
+//! On Windows, the event loop relies on the IOCP backend of `mio`. The file descriptor
+//! based adapters of the `generic` module and unix signals are not available there.
+//!
 //! ## Custom event sources
 //!
 //! You can create custom event sources can will be inserted in the event loop by
@@ -84,9 +134,28 @@ extern crate mio_extras;
 #[cfg(unix)]
 extern crate nix;
 
-pub use self::loop_logic::{EventLoop, InsertError, LoopHandle, LoopSignal};
+pub use self::loop_logic::{
+    DispatchError, EventLoop, GroupId, InsertError, LoopHandle, LoopSignal, PanicAction, Priority,
+    SignalSafe, Wakeup,
+};
+pub use self::metrics::{Metrics, SourceMetrics};
 pub use self::sources::*;
 
+pub mod futures;
 mod list;
 mod loop_logic;
+mod loop_waker;
+mod metrics;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod precise_timeout;
 mod sources;
+
+// convert an error of nix, which is the errno of the failed system call when it
+// comes from one
+#[cfg(unix)]
+pub(crate) fn nix_err(err: ::nix::Error) -> ::std::io::Error {
+    match err {
+        ::nix::Error::Sys(errno) => ::std::io::Error::from(errno),
+        err => ::std::io::Error::new(::std::io::ErrorKind::Other, err),
+    }
+}
@@ -1,61 +1,520 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::mem;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use mio::Token;
+use mio::{Poll, PollOpt, Token};
 
-use sources::EventDispatcher;
+use loop_logic::{GroupId, Priority};
+use sources::{ErasedSource, EventDispatcher, PostAction};
 
-pub(crate) struct SourceList<Data> {
-    sources: Vec<Option<Rc<RefCell<EventDispatcher<Data>>>>>,
+type ErasedDispatcher<'l, Data> = Rc<RefCell<dyn EventDispatcher<Data> + 'l>>;
+
+// a token is made of the index of its slot in the list and of the generation of
+// this slot, incremented every time it is reused, so that stale tokens are detected:
+// a slot whose generation reached the maximum is retired rather than wrapping around
+// and reviving its first tokens
+//
+// in between, the sub-id distinguishes the tokens derived from the one of a source
+// by a `TokenFactory`, it is 0 for the token of the source itself
+//
+// on 32-bit targets, the bits left by the sub-id are shared evenly between the index
+// and the generation, allowing 4096 sources and as many reuses of each slot
+const INDEX_BITS: u32 = if usize::BITS >= 64 {
+    usize::BITS / 2
+} else {
+    12
+};
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const SUB_BITS: u32 = 8;
+pub(crate) const MAX_SUB_ID: usize = (1 << SUB_BITS) - 1;
+const SUB_MASK: usize = MAX_SUB_ID << INDEX_BITS;
+const GENERATION_SHIFT: u32 = INDEX_BITS + SUB_BITS;
+// the all-ones token is reserved by mio
+const MAX_GENERATION: usize = (1 << (usize::BITS - GENERATION_SHIFT)) - 2;
+
+// reserved for the timer of sub-millisecond timeouts, its generation is never reached
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const PRECISE_TIMEOUT_TOKEN: Token = Token(usize::MAX - 1);
+
+fn make_token(index: usize, generation: usize) -> Token {
+    Token(index | (generation << GENERATION_SHIFT))
+}
+
+fn split_token(token: Token) -> (usize, usize) {
+    (token.0 & INDEX_MASK, token.0 >> GENERATION_SHIFT)
+}
+
+pub(crate) fn make_sub_token(token: Token, sub_id: usize) -> Token {
+    Token((token.0 & !SUB_MASK) | (sub_id << INDEX_BITS))
 }
 
-impl<Data> SourceList<Data> {
-    pub(crate) fn new() -> SourceList<Data> {
+// the token of the source a token was derived from, along with its sub-id
+pub(crate) fn split_sub_token(token: Token) -> (Token, usize) {
+    (
+        Token(token.0 & !SUB_MASK),
+        (token.0 & SUB_MASK) >> INDEX_BITS,
+    )
+}
+
+pub(crate) struct SourceEntry<'l, Data> {
+    pub(crate) dispatcher: ErasedDispatcher<'l, Data>,
+    pub(crate) source: Rc<dyn ErasedSource + 'l>,
+    pub(crate) action: Rc<Cell<PostAction>>,
+    pub(crate) priority: Priority,
+    pub(crate) group: Option<GroupId>,
+    // whether the source should be registered, disabling or enabling it again is a
+    // no-op
+    enabled: bool,
+    // whether the source is registered with `PollOpt::oneshot()` in addition to its
+    // own poll options
+    oneshot: bool,
+    // whether the source is actually registered, which lags behind `enabled` while
+    // the change is queued
+    registered: bool,
+    // whether the registration of the source needs to be refreshed
+    rearm: bool,
+    // whether the token of the source is in the change list
+    queued: bool,
+}
+
+impl<'l, Data> SourceEntry<'l, Data> {
+    // the entry of a disabled source
+    pub(crate) fn new(
+        dispatcher: ErasedDispatcher<'l, Data>,
+        source: Rc<dyn ErasedSource + 'l>,
+        action: Rc<Cell<PostAction>>,
+        priority: Priority,
+    ) -> SourceEntry<'l, Data> {
+        SourceEntry {
+            dispatcher,
+            source,
+            action,
+            priority,
+            group: None,
+            enabled: false,
+            oneshot: false,
+            registered: false,
+            rearm: false,
+            queued: false,
+        }
+    }
+
+    fn extra_opts(&self) -> PollOpt {
+        if self.oneshot {
+            PollOpt::oneshot()
+        } else {
+            PollOpt::empty()
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no source for this token")
+}
+
+struct Slot<'l, Data> {
+    generation: usize,
+    entry: Option<SourceEntry<'l, Data>>,
+}
+
+// While the event loop is dispatching, the registration changes of the sources are
+// queued in a change list, and applied right before it polls again: each source is
+// then registered at most once, whatever the number of changes, and a source
+// inserted and removed by the same callbacks is never registered. Outside of the
+// dispatching, the changes are applied right away, and their errors returned.
+pub(crate) struct SourceList<'l, Data> {
+    sources: Vec<Slot<'l, Data>>,
+    next_group: u64,
+    dispatching: bool,
+    changes: Vec<Token>,
+}
+
+impl<'l, Data> SourceList<'l, Data> {
+    pub(crate) fn new() -> SourceList<'l, Data> {
         SourceList {
             sources: Vec::new(),
+            next_group: 0,
+            dispatching: false,
+            changes: Vec::new(),
         }
     }
 
+    fn get(&self, token: Token) -> Option<&SourceEntry<'l, Data>> {
+        let (index, generation) = split_token(token);
+        self.sources
+            .get(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.entry.as_ref())
+    }
+
+    fn get_mut(&mut self, token: Token) -> Option<&mut SourceEntry<'l, Data>> {
+        let (index, generation) = split_token(token);
+        self.sources
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.entry.as_mut())
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (Token, &SourceEntry<'l, Data>)> {
+        self.sources.iter().enumerate().filter_map(|(index, slot)| {
+            slot.entry
+                .as_ref()
+                .map(|entry| (make_token(index, slot.generation), entry))
+        })
+    }
+
+    // the action cell is returned along with the dispatcher, to retrieve the
+    // action requested by the callback once it has run
     pub(crate) fn get_dispatcher(
         &self,
         token: Token,
-    ) -> Option<Rc<RefCell<EventDispatcher<Data>>>> {
-        match self.sources.get(token.0) {
-            Some(&Some(ref dispatcher)) => Some(dispatcher.clone()),
-            _ => None,
+    ) -> Option<(ErasedDispatcher<'l, Data>, Rc<Cell<PostAction>>)> {
+        // a source disabled by a previous callback is not dispatched
+        self.get(token)
+            .filter(|entry| entry.enabled)
+            .map(|entry| (entry.dispatcher.clone(), entry.action.clone()))
+    }
+
+    // only returns the source if it is still associated with this dispatcher,
+    // as the token may have been reused since it was retrieved
+    pub(crate) fn get_source(
+        &self,
+        token: Token,
+        dispatcher: &ErasedDispatcher<'l, Data>,
+    ) -> Option<Rc<dyn ErasedSource + 'l>> {
+        self.get(token)
+            .filter(|entry| Rc::ptr_eq(&entry.dispatcher, dispatcher))
+            .map(|entry| entry.source.clone())
+    }
+
+    pub(crate) fn get_source_by_token(&self, token: Token) -> Option<Rc<dyn ErasedSource + 'l>> {
+        self.get(token).map(|entry| entry.source.clone())
+    }
+
+    pub(crate) fn get_priority(&self, token: Token) -> Option<Priority> {
+        self.get(token).map(|entry| entry.priority)
+    }
+
+    pub(crate) fn set_dispatching(&mut self, dispatching: bool) {
+        self.dispatching = dispatching;
+    }
+
+    pub(crate) fn enable(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        self.get_mut(token).ok_or_else(not_found)?.enabled = true;
+        self.change(poll, token)
+    }
+
+    pub(crate) fn disable(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        self.get_mut(token).ok_or_else(not_found)?.enabled = false;
+        self.change(poll, token)
+    }
+
+    pub(crate) fn set_oneshot(
+        &mut self,
+        poll: &Poll,
+        token: Token,
+        oneshot: bool,
+    ) -> io::Result<()> {
+        let entry = self.get_mut(token).ok_or_else(not_found)?;
+        entry.oneshot = oneshot;
+        entry.rearm = true;
+        self.change(poll, token)
+    }
+
+    // a disabled source is registered anew when enabled
+    pub(crate) fn reregister(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        self.get_mut(token).ok_or_else(not_found)?.rearm = true;
+        self.change(poll, token)
+    }
+
+    // deregister a source about to be removed, which cannot be deferred as the
+    // evented object may be closed once removed
+    pub(crate) fn deregister_removed(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        match self.get_mut(token) {
+            Some(entry) if entry.registered => {
+                entry.enabled = false;
+                entry.registered = false;
+                entry.source.deregister(poll)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn change(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        if !self.dispatching {
+            return self.apply(poll, token);
+        }
+        if let Some(entry) = self.get_mut(token) {
+            if !mem::replace(&mut entry.queued, true) {
+                self.changes.push(token);
+            }
         }
+        Ok(())
     }
 
-    pub(crate) fn add_source(&mut self, dispatcher: Rc<RefCell<EventDispatcher<Data>>>) -> Token {
-        let free_id = self.sources.iter().position(Option::is_none);
+    // synchronize the registration of a source with its state
+    fn apply(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        // the source may have been removed since its change was queued
+        let entry = match self.get_mut(token) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        entry.queued = false;
+        let rearm = mem::replace(&mut entry.rearm, false);
+        match (entry.enabled, entry.registered) {
+            (true, false) => {
+                if let Err(error) = entry.source.register(poll, token, entry.extra_opts()) {
+                    // the source stays disabled, it can be enabled again
+                    entry.enabled = false;
+                    return Err(error);
+                }
+                entry.registered = true;
+            }
+            (false, true) => {
+                // the source is not registered anymore, even if this fails
+                entry.registered = false;
+                entry.source.deregister(poll)?;
+            }
+            (true, true) if rearm => {
+                entry.source.reregister(poll, token, entry.extra_opts())?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // apply the queued changes, all of them even if some fail, returning the first
+    // error along with the token of its source
+    pub(crate) fn apply_changes(&mut self, poll: &Poll) -> Result<(), (Token, io::Error)> {
+        let mut ret = Ok(());
+        for token in mem::take(&mut self.changes) {
+            if let Err(error) = self.apply(poll, token) {
+                ret = ret.and(Err((token, error)));
+            }
+        }
+        ret
+    }
+
+    pub(crate) fn new_group(&mut self) -> GroupId {
+        self.next_group += 1;
+        GroupId(self.next_group)
+    }
+
+    // returns whether a source is associated with this token
+    pub(crate) fn set_group(&mut self, token: Token, group: Option<GroupId>) -> bool {
+        match self.get_mut(token) {
+            Some(entry) => {
+                entry.group = group;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn group_tokens(&self, group: GroupId) -> Vec<Token> {
+        self.entries()
+            .filter(|&(_, entry)| entry.group == Some(group))
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    pub(crate) fn before_sleep(&self, timeout: Option<Duration>) {
+        for (_, entry) in self.entries() {
+            entry.source.before_sleep(timeout);
+        }
+    }
+
+    // the earliest deadline of the enabled sources
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.entries()
+            .filter(|&(_, entry)| entry.enabled)
+            .filter_map(|(_, entry)| entry.source.next_deadline())
+            .min()
+    }
+
+    // the enabled sources whose deadline has been reached
+    pub(crate) fn expired_sources(&self, now: Instant) -> Vec<(Priority, Token)> {
+        self.entries()
+            .filter(|&(_, entry)| {
+                entry.enabled
+                    && entry
+                        .source
+                        .next_deadline()
+                        .map_or(false, |deadline| deadline <= now)
+            })
+            .map(|(token, entry)| (entry.priority, token))
+            .collect()
+    }
+
+    pub(crate) fn add_source(&mut self, entry: SourceEntry<'l, Data>) -> Token {
+        let free_id = self
+            .sources
+            .iter()
+            .position(|slot| slot.entry.is_none() && slot.generation < MAX_GENERATION);
         if let Some(id) = free_id {
-            self.sources[id] = Some(dispatcher);
-            Token(id)
+            let slot = &mut self.sources[id];
+            slot.generation += 1;
+            slot.entry = Some(entry);
+            make_token(id, slot.generation)
         } else {
-            self.sources.push(Some(dispatcher));
-            Token(self.sources.len() - 1)
+            assert!(
+                self.sources.len() <= INDEX_MASK,
+                "[calloop] Too many sources in the event loop."
+            );
+            self.sources.push(Slot {
+                generation: 0,
+                entry: Some(entry),
+            });
+            make_token(self.sources.len() - 1, 0)
         }
     }
 
-    // this method returns the removed dispatcher to ensure it is not dropped
+    // this method returns the removed entry to ensure it is not dropped
     // while the refcell containing the list is borrowed, as dropping a dispatcher
     // can trigger the removal of an other source
-    pub(crate) fn del_source(
-        &mut self,
-        token: Token,
-    ) -> Option<Rc<RefCell<EventDispatcher<Data>>>> {
-        ::std::mem::replace(&mut self.sources[token.0], None)
+    pub(crate) fn del_source(&mut self, token: Token) -> Option<SourceEntry<'l, Data>> {
+        let (index, generation) = split_token(token);
+        self.sources
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.entry.take())
+    }
+
+    // the tokens of all the sources, in order of priority
+    pub(crate) fn tokens_by_priority(&self) -> Vec<Token> {
+        let mut entries: Vec<_> = self
+            .entries()
+            .map(|(token, entry)| (entry.priority, token))
+            .collect();
+        entries.sort_by_key(|&(priority, _)| priority);
+        entries.into_iter().map(|(_, token)| token).collect()
+    }
+
+    // same as above, for all the sources
+    pub(crate) fn take_all(&mut self) -> Vec<SourceEntry<'l, Data>> {
+        self.sources
+            .iter_mut()
+            .filter_map(|slot| slot.entry.take())
+            .collect()
     }
 }
 
-pub(crate) trait ErasedList {
+// a type-erased value, only meant to be dropped
+pub(crate) trait Erased {}
+
+impl<T> Erased for T {}
+
+pub(crate) trait ErasedList<'l> {
     // this returs a value for the same reason as above, but we must erase its type
-    // due to the `Data` parameter, hence Box<Any>
-    fn del_source(&mut self, token: Token) -> Box<::std::any::Any>;
+    // due to the `Data` parameter, hence Box<dyn Erased>
+    //
+    // the source is only removed if the token is still associated with it, as it
+    // may have removed itself and the token been reused since
+    //
+    // returns `None` if the source was not removed
+    fn del_source(
+        &mut self,
+        token: Token,
+        source: &dyn ErasedSource,
+    ) -> Option<Box<dyn Erased + 'l>>;
+
+    fn deregister_removed(&mut self, poll: &Poll, token: Token) -> io::Result<()>;
+    fn enable(&mut self, poll: &Poll, token: Token) -> io::Result<()>;
+    fn disable(&mut self, poll: &Poll, token: Token) -> io::Result<()>;
+    fn reregister(&mut self, poll: &Poll, token: Token) -> io::Result<()>;
+}
+
+impl<'l, Data: 'l> ErasedList<'l> for SourceList<'l, Data> {
+    fn del_source(
+        &mut self,
+        token: Token,
+        source: &dyn ErasedSource,
+    ) -> Option<Box<dyn Erased + 'l>> {
+        let is_same = self.get(token).map_or(false, |entry| {
+            ::std::ptr::eq(
+                &*entry.source as *const dyn ErasedSource as *const u8,
+                source as *const dyn ErasedSource as *const u8,
+            )
+        });
+        if is_same {
+            self.del_source(token)
+                .map(|entry| Box::new(entry) as Box<dyn Erased + 'l>)
+        } else {
+            None
+        }
+    }
+
+    fn deregister_removed(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        SourceList::deregister_removed(self, poll, token)
+    }
+
+    fn enable(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        SourceList::enable(self, poll, token)
+    }
+
+    fn disable(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        SourceList::disable(self, poll, token)
+    }
+
+    fn reregister(&mut self, poll: &Poll, token: Token) -> io::Result<()> {
+        SourceList::reregister(self, poll, token)
+    }
 }
 
-impl<Data: 'static> ErasedList for SourceList<Data> {
-    fn del_source(&mut self, token: Token) -> Box<::std::any::Any> {
-        Box::new(self.del_source(token))
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use mio::Registration;
+
+    use sources::generic::Generic;
+    use EventSource;
+
+    use super::*;
+
+    fn entry<'l>() -> SourceEntry<'l, ()> {
+        let source = Generic::new(Registration::new2().0);
+        let dispatcher = source.make_dispatcher(|_, _: &mut ()| {});
+        SourceEntry::new(
+            dispatcher,
+            Rc::new(RefCell::new(source)),
+            Rc::new(Cell::new(PostAction::Continue)),
+            Priority::Normal,
+        )
+    }
+
+    #[test]
+    fn slot_reuse() {
+        let mut list = SourceList::new();
+        let mut tokens = HashSet::new();
+        for _ in 0..1000 {
+            let token = list.add_source(entry());
+            assert!(tokens.insert(token));
+            assert!(list.del_source(token).is_some());
+        }
+        // all the tokens came from the same slot, none of them is valid anymore
+        assert_eq!(list.sources.len(), 1);
+        for &token in &tokens {
+            assert!(list.del_source(token).is_none());
+        }
+    }
+
+    #[test]
+    fn slot_retirement() {
+        let mut list = SourceList::new();
+        let first = list.add_source(entry());
+        list.del_source(first);
+        list.sources[0].generation = MAX_GENERATION - 1;
+        let last = list.add_source(entry());
+        list.del_source(last);
+
+        // the slot is not reused once its generation is exhausted
+        let token = list.add_source(entry());
+        assert_eq!(list.sources.len(), 2);
+        assert_ne!(token, first);
+        assert!(list.del_source(first).is_none());
+        assert!(list.del_source(token).is_some());
     }
 }
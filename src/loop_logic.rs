@@ -1,33 +1,94 @@
+use std::any::Any;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
 use std::io;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::task::{Context, Poll as TaskPoll, Wake, Waker};
+use std::time::{Duration, Instant};
 
-use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness};
+use mio::{Events, Poll, Ready, Token};
 
-use list::SourceList;
-use sources::{EventSource, Idle, Source};
+use futures::{Sleep, Timeout};
+use list::{split_sub_token, SourceEntry, SourceList};
+use loop_waker::LoopWaker;
+use metrics::Metrics;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use precise_timeout::PreciseTimeout;
+use sources::ping::PingSource;
+use sources::timer::{Scheduled, TimeoutAction};
+use sources::{
+    Dispatcher, ErasedSource, EventDispatcher, EventSource, Hook, Idle, PostAction,
+    RegistrationToken, Source,
+};
+
+type IdleCallback<'l, Data> = Rc<RefCell<Option<Box<dyn FnMut(&mut Data) + 'l>>>>;
+// an idle callback along with its optional deadline
+type IdleEntry<'l, Data> = (Option<Instant>, IdleCallback<'l, Data>);
+type SleepCallback<'l, Data> =
+    Rc<RefCell<Option<Box<dyn FnMut(Option<Duration>, &mut Data) + 'l>>>>;
+
+/// The priority of an event source
+///
+/// During a dispatching cycle, the events of all sources that are ready are
+/// processed in order of priority: the callbacks of `High` sources are called
+/// before the ones of `Normal` sources, which are called before the ones of
+/// `Low` sources. Sources with the same priority are processed in no particular
+/// order.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sources processed first, such as input devices
+    High,
+    /// The default priority
+    #[default]
+    Normal,
+    /// Sources processed last, such as logging channels
+    Low,
+}
+
+/// A group of event sources
+///
+/// It is created with `LoopHandle::create_group()`, and allows enabling, disabling
+/// or removing several sources at once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GroupId(pub(crate) u64);
 
 /// An handle to an event loop
 ///
-/// This handle allows you to insert new sources and idles in this event loop,
+/// This handle allows you to insert new sources, idles and hooks in this event loop,
 /// it can be cloned, and it is possible to insert new sources from within a source
 /// callback.
-pub struct LoopHandle<Data> {
+///
+/// The sources inserted, enabled, disabled or updated from within the callbacks of
+/// the sources are registered in a batch once the callbacks are done, before the event
+/// loop polls again, to reduce the number of system calls. A failure to register them
+/// is then returned by `EventLoop::dispatch()` as a `DispatchError::Source`, the
+/// failing source being left disabled. Outside of the callbacks, the changes are
+/// applied right away, and their errors returned by the methods making them.
+pub struct LoopHandle<'l, Data> {
     poll: Rc<Poll>,
-    list: Rc<RefCell<SourceList<Data>>>,
-    idles: Rc<RefCell<Vec<Rc<RefCell<Option<Box<FnMut(&mut Data)>>>>>>>,
+    list: Rc<RefCell<SourceList<'l, Data>>>,
+    idles: Rc<RefCell<Vec<IdleEntry<'l, Data>>>>,
+    pre_poll_hooks: Rc<RefCell<Vec<IdleCallback<'l, Data>>>>,
+    post_dispatch_hooks: Rc<RefCell<Vec<IdleCallback<'l, Data>>>>,
+    pre_sleep_hooks: Rc<RefCell<Vec<SleepCallback<'l, Data>>>>,
 }
 
-impl<Data> Clone for LoopHandle<Data> {
-    fn clone(&self) -> LoopHandle<Data> {
+impl<'l, Data> Clone for LoopHandle<'l, Data> {
+    fn clone(&self) -> LoopHandle<'l, Data> {
         LoopHandle {
             poll: self.poll.clone(),
             list: self.list.clone(),
             idles: self.idles.clone(),
+            pre_poll_hooks: self.pre_poll_hooks.clone(),
+            post_dispatch_hooks: self.post_dispatch_hooks.clone(),
+            pre_sleep_hooks: self.pre_sleep_hooks.clone(),
         }
     }
 }
@@ -52,125 +113,948 @@ impl<E> From<InsertError<E>> for io::Error {
     }
 }
 
-impl<Data: 'static> LoopHandle<Data> {
+/// An error generated when dispatching events
+#[derive(Debug)]
+pub enum DispatchError {
+    /// Polling for new events failed
+    Poll(io::Error),
+    /// Waiting for events was interrupted by a signal
+    ///
+    /// This is only returned if the event loop was made interruptible, see
+    /// `EventLoop::set_interruptible()`.
+    Interrupted,
+    /// An event source failed processing its events
+    Source {
+        /// The token of the source
+        token: RegistrationToken,
+        /// The generated error
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DispatchError::Poll(ref error) => write!(formatter, "polling failed: {}", error),
+            DispatchError::Interrupted => write!(formatter, "polling was interrupted"),
+            DispatchError::Source { token, ref error } => {
+                write!(formatter, "source {:?} failed: {}", token, error)
+            }
+        }
+    }
+}
+
+impl ::std::error::Error for DispatchError {
+    fn source(&self) -> Option<&(dyn (::std::error::Error) + 'static)> {
+        match *self {
+            DispatchError::Poll(ref error) | DispatchError::Source { ref error, .. } => Some(error),
+            DispatchError::Interrupted => None,
+        }
+    }
+}
+
+impl From<DispatchError> for io::Error {
+    fn from(e: DispatchError) -> io::Error {
+        match e {
+            DispatchError::Poll(error) | DispatchError::Source { error, .. } => error,
+            DispatchError::Interrupted => {
+                io::Error::new(io::ErrorKind::Interrupted, "polling was interrupted")
+            }
+        }
+    }
+}
+
+impl<'l, Data: 'l> LoopHandle<'l, Data> {
     /// Insert an new event source in the loop
     ///
     /// The provided callback will be called during the dispatching cycles whenever the
     /// associated source generates events, see `EventLoop::dispatch(..)` for details.
-    pub fn insert_source<E: EventSource, F: FnMut(E::Event, &mut Data) + 'static>(
+    /// It can return a `PostAction` to act on its own source.
+    ///
+    /// The source is inserted with the `Normal` priority.
+    pub fn insert_source<E, F, R>(
         &self,
         source: E,
         callback: F,
-    ) -> Result<Source<E>, InsertError<E>> {
-        let dispatcher = source.make_dispatcher(callback);
-
-        let token = self.list.borrow_mut().add_source(dispatcher);
+    ) -> Result<Source<'l, E>, InsertError<E>>
+    where
+        E: EventSource + 'l,
+        F: FnMut(E::Event, &mut Data) -> R + 'l,
+        R: Into<PostAction>,
+    {
+        self.insert_source_with_priority(source, Priority::Normal, callback)
+    }
 
-        let interest = source.interest();
-        let opt = source.pollopts();
+    /// Insert an new event source in the loop with a given priority
+    ///
+    /// Same as `insert_source`, but the events of this source will be processed
+    /// according to the provided priority, see `Priority` for details.
+    pub fn insert_source_with_priority<E, F, R>(
+        &self,
+        source: E,
+        priority: Priority,
+        callback: F,
+    ) -> Result<Source<'l, E>, InsertError<E>>
+    where
+        E: EventSource + 'l,
+        F: FnMut(E::Event, &mut Data) -> R + 'l,
+        R: Into<PostAction>,
+    {
+        let dispatcher = Dispatcher::new(source, callback);
 
-        if let Err(e) = self.poll.register(&source, token, interest, opt) {
-            return Err(InsertError { source, error: e });
+        match self.register_dispatcher_with_priority(dispatcher.clone(), priority) {
+            Ok(RegistrationToken { token }) => Ok(Source {
+                source: dispatcher.source,
+                poll: self.poll.clone(),
+                list: self.list.clone(),
+                token,
+            }),
+            Err(error) => Err(InsertError {
+                source: dispatcher.into_source_inner(),
+                error,
+            }),
         }
+    }
 
+    /// Insert an new event source in the loop, in the disabled state
+    ///
+    /// Same as `insert_source`, but the source does not generate any events until it
+    /// is enabled using `Source::enable()`. This allows you to set up several sources
+    /// referring to each other before any of their callbacks can be called.
+    pub fn insert_source_disabled<E, F, R>(
+        &self,
+        source: E,
+        callback: F,
+    ) -> Result<Source<'l, E>, InsertError<E>>
+    where
+        E: EventSource + 'l,
+        F: FnMut(E::Event, &mut Data) -> R + 'l,
+        R: Into<PostAction>,
+    {
+        let dispatcher = Dispatcher::new(source, callback);
+        // inserting a disabled source cannot fail
+        let RegistrationToken { token } = self
+            .register_dispatcher_disabled(dispatcher.clone())
+            .unwrap();
         Ok(Source {
-            source,
+            source: dispatcher.source,
             poll: self.poll.clone(),
             list: self.list.clone(),
             token,
         })
     }
 
+    /// Insert a one-shot event source in the loop
+    ///
+    /// The provided callback is called at most once, with the first event generated
+    /// by the source, which is then removed from the event loop. Other events the
+    /// source may generate during the same dispatch are discarded.
+    ///
+    /// The returned `Source` handle can be dropped if you don't need to access or
+    /// cancel the source in the meantime.
+    pub fn insert_source_oneshot<E, F>(
+        &self,
+        source: E,
+        callback: F,
+    ) -> Result<Source<'l, E>, InsertError<E>>
+    where
+        E: EventSource + 'l,
+        F: FnOnce(E::Event, &mut Data) + 'l,
+    {
+        let mut callback = Some(callback);
+        self.insert_source(source, move |event, data: &mut Data| {
+            if let Some(callback) = callback.take() {
+                callback(event, data);
+            }
+            PostAction::Remove
+        })
+    }
+
+    /// Insert a timeout in the loop
+    ///
+    /// The callback is called once `deadline` is reached, with the deadline that fired
+    /// as argument. It then returns when it should be called again, or
+    /// `TimeoutAction::Drop` to remove the timeout from the event loop. This allows
+    /// periodic work to reschedule itself without needing a handle to a timer.
+    ///
+    /// The returned token can be used to disable or remove the timeout.
+    pub fn insert_timeout<F>(
+        &self,
+        deadline: Instant,
+        mut callback: F,
+    ) -> io::Result<RegistrationToken>
+    where
+        F: FnMut(Instant, &mut Data) -> TimeoutAction + 'l,
+    {
+        let source = Scheduled::new(deadline);
+        let next_deadline = source.deadline();
+        let source = self.insert_source(source, move |deadline, data: &mut Data| {
+            match callback(deadline, data) {
+                TimeoutAction::Drop => return PostAction::Remove,
+                TimeoutAction::ToDuration(duration) => {
+                    next_deadline.set(Some(Instant::now() + duration))
+                }
+                TimeoutAction::ToInstant(instant) => next_deadline.set(Some(instant)),
+            }
+            PostAction::Continue
+        })?;
+        Ok(source.token())
+    }
+
+    /// Create a future completing once `duration` has elapsed
+    ///
+    /// It relies on a timeout of this event loop, and thus completes only while the
+    /// event loop is dispatched, for example with `EventLoop::block_on()`.
+    pub fn sleep(&self, duration: Duration) -> io::Result<Sleep<'l, Data>> {
+        Sleep::new(self, Instant::now() + duration)
+    }
+
+    /// Create a future completing once `deadline` is reached
+    ///
+    /// Same as `sleep()`, with an absolute deadline.
+    pub fn sleep_until(&self, deadline: Instant) -> io::Result<Sleep<'l, Data>> {
+        Sleep::new(self, deadline)
+    }
+
+    /// Wrap a future so that it fails if it does not complete within `duration`
+    ///
+    /// The returned future outputs `Err(TimedOut)` once `duration` has elapsed, the
+    /// inner future being then dropped. As `sleep()`, it relies on a timeout of this
+    /// event loop.
+    pub fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> io::Result<Timeout<'l, Data, F>> {
+        Ok(Timeout::new(self.sleep(duration)?, future))
+    }
+
+    /// Insert a dispatcher in the loop
+    ///
+    /// The event source and callback bundled in the dispatcher are inserted with
+    /// the `Normal` priority, and you are given back a token identifying them in
+    /// the event loop. You can keep clones of the dispatcher to access its event
+    /// source while it is inserted.
+    pub fn register_dispatcher<S: EventSource + 'l>(
+        &self,
+        dispatcher: Dispatcher<'l, S, Data>,
+    ) -> io::Result<RegistrationToken> {
+        self.register_dispatcher_with_priority(dispatcher, Priority::Normal)
+    }
+
+    /// Insert a dispatcher in the loop with a given priority
+    ///
+    /// Same as `register_dispatcher`, see `Priority` for details.
+    pub fn register_dispatcher_with_priority<S: EventSource + 'l>(
+        &self,
+        dispatcher: Dispatcher<'l, S, Data>,
+        priority: Priority,
+    ) -> io::Result<RegistrationToken> {
+        self.register_dispatcher_inner(dispatcher, priority, true)
+    }
+
+    /// Insert a dispatcher in the loop, in the disabled state
+    ///
+    /// Same as `register_dispatcher`, but its source does not generate any events
+    /// until it is enabled using `LoopHandle::enable()`.
+    pub fn register_dispatcher_disabled<S: EventSource + 'l>(
+        &self,
+        dispatcher: Dispatcher<'l, S, Data>,
+    ) -> io::Result<RegistrationToken> {
+        self.register_dispatcher_inner(dispatcher, Priority::Normal, false)
+    }
+
+    fn register_dispatcher_inner<S: EventSource + 'l>(
+        &self,
+        dispatcher: Dispatcher<'l, S, Data>,
+        priority: Priority,
+        enabled: bool,
+    ) -> io::Result<RegistrationToken> {
+        let token = self.list.borrow_mut().add_source(SourceEntry::new(
+            dispatcher.dispatcher,
+            dispatcher.source.clone(),
+            dispatcher.action,
+            priority,
+        ));
+
+        if !enabled {
+            return Ok(RegistrationToken { token });
+        }
+
+        let ret = self.list.borrow_mut().enable(&self.poll, token);
+        if let Err(error) = ret {
+            let _entry = self.list.borrow_mut().del_source(token);
+            return Err(error);
+        }
+
+        Ok(RegistrationToken { token })
+    }
+
+    /// Refresh the registration of the source associated with this token
+    ///
+    /// See `Source::reregister()`.
+    pub fn update(&self, token: &RegistrationToken) -> io::Result<()> {
+        self.list.borrow_mut().reregister(&self.poll, token.token)
+    }
+
+    /// Register the source associated with this token in oneshot mode, or not
+    ///
+    /// In oneshot mode, the source is registered with `PollOpt::oneshot()` whatever
+    /// its own poll options, without needing to change them. It is then disabled by
+    /// the poll layer after it generated an event, and won't be woken up again until
+    /// it is reactivated, even if it is still ready. This is useful for
+    /// request/response protocols, to avoid spurious wakeups until the next response
+    /// is expected.
+    pub fn set_oneshot(&self, token: &RegistrationToken, oneshot: bool) -> io::Result<()> {
+        self.list
+            .borrow_mut()
+            .set_oneshot(&self.poll, token.token, oneshot)
+    }
+
+    /// Re-arm the source associated with this token
+    ///
+    /// This reactivates a source in oneshot mode, see `set_oneshot()`, or whose own
+    /// poll options include `PollOpt::oneshot()`, once it generated an event. It is
+    /// the same as `update()`.
+    pub fn reactivate(&self, token: &RegistrationToken) -> io::Result<()> {
+        self.update(token)
+    }
+
+    /// Disable the source associated with this token
+    ///
+    /// See `Source::disable()`.
+    pub fn disable(&self, token: &RegistrationToken) -> io::Result<()> {
+        self.list.borrow_mut().disable(&self.poll, token.token)
+    }
+
+    /// Re-enable the source associated with this token
+    ///
+    /// See `Source::enable()`.
+    pub fn enable(&self, token: &RegistrationToken) -> io::Result<()> {
+        self.list.borrow_mut().enable(&self.poll, token.token)
+    }
+
+    /// Remove the source associated with this token from the event loop
+    ///
+    /// If it was inserted using a `Dispatcher`, the event source can then be
+    /// retrieved using `Dispatcher::into_source_inner()`.
+    pub fn remove(&self, token: RegistrationToken) {
+        let source = self.list.borrow().get_source_by_token(token.token);
+        if let Some(source) = source {
+            let _ = self.remove_source(token.token, &*source);
+        }
+    }
+
+    // deregister and remove a source that is still in the list
+    fn remove_source(&self, token: Token, source: &dyn ErasedSource) -> io::Result<()> {
+        let ret = self.list.borrow_mut().deregister_removed(&self.poll, token);
+        let entry = self.list.borrow_mut().del_source(token);
+        source.before_removal();
+        drop(entry);
+        ret
+    }
+
+    /// Create a new group of sources
+    pub fn create_group(&self) -> GroupId {
+        self.list.borrow_mut().new_group()
+    }
+
+    /// Insert an new event source in the loop, as part of a group
+    ///
+    /// Same as `insert_source`, the source can then be acted upon along with the
+    /// rest of its group.
+    pub fn insert_source_in_group<E, F, R>(
+        &self,
+        group: GroupId,
+        source: E,
+        callback: F,
+    ) -> Result<Source<'l, E>, InsertError<E>>
+    where
+        E: EventSource + 'l,
+        F: FnMut(E::Event, &mut Data) -> R + 'l,
+        R: Into<PostAction>,
+    {
+        let source = self.insert_source(source, callback)?;
+        self.list.borrow_mut().set_group(source.token, Some(group));
+        Ok(source)
+    }
+
+    /// Set the group of the source associated with this token
+    ///
+    /// A source is part of at most one group, `None` removes it from its group.
+    pub fn set_group(&self, token: &RegistrationToken, group: Option<GroupId>) -> io::Result<()> {
+        if self.list.borrow_mut().set_group(token.token, group) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no source for this token",
+            ))
+        }
+    }
+
+    /// Disable all the sources of a group
+    ///
+    /// All of them are disabled even if some fail, the first error is returned.
+    pub fn disable_group(&self, group: GroupId) -> io::Result<()> {
+        self.for_group(group, |token| self.disable(&token))
+    }
+
+    /// Re-enable all the sources of a group
+    ///
+    /// All of them are enabled even if some fail, the first error is returned.
+    pub fn enable_group(&self, group: GroupId) -> io::Result<()> {
+        self.for_group(group, |token| self.enable(&token))
+    }
+
+    /// Remove all the sources of a group from the event loop
+    pub fn remove_group(&self, group: GroupId) {
+        let _ = self.for_group(group, |token| {
+            self.remove(token);
+            Ok(())
+        });
+    }
+
+    fn for_group<F>(&self, group: GroupId, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(RegistrationToken) -> io::Result<()>,
+    {
+        let tokens = self.list.borrow().group_tokens(group);
+        let mut ret = Ok(());
+        for token in tokens {
+            let token_ret = f(RegistrationToken { token });
+            ret = ret.and(token_ret);
+        }
+        ret
+    }
+
+    // apply the action requested by the callback of a source
+    fn apply_action(
+        &self,
+        token: Token,
+        dispatcher: &Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+        action: PostAction,
+    ) -> io::Result<()> {
+        if action == PostAction::Continue {
+            return Ok(());
+        }
+        // the source may have been removed by its callback
+        let source = match self.list.borrow().get_source(token, dispatcher) {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        match action {
+            PostAction::Continue => Ok(()),
+            PostAction::Reregister => self.list.borrow_mut().reregister(&self.poll, token),
+            PostAction::Disable => self.list.borrow_mut().disable(&self.poll, token),
+            PostAction::Remove => self.remove_source(token, &*source),
+        }
+    }
+
     /// Insert an idle callback
     ///
     /// This callback will be called during a dispatching cycle when the event loop has
     /// finished processing all pending events from the sources and becomes idle.
-    pub fn insert_idle<F: FnOnce(&mut Data) + 'static>(&self, callback: F) -> Idle {
+    pub fn insert_idle<F: FnOnce(&mut Data) + 'l>(&self, callback: F) -> Idle<'l> {
+        self.insert_idle_inner(None, callback)
+    }
+
+    /// Insert an idle callback with a deadline
+    ///
+    /// Same as `insert_idle`, but if the event loop is still busy processing events
+    /// from the sources when the deadline is reached, the callback is run anyway
+    /// between two batches of events, rather than waiting for the event loop to
+    /// become idle.
+    pub fn insert_idle_with_deadline<F: FnOnce(&mut Data) + 'l>(
+        &self,
+        deadline: Instant,
+        callback: F,
+    ) -> Idle<'l> {
+        self.insert_idle_inner(Some(deadline), callback)
+    }
+
+    fn insert_idle_inner<F: FnOnce(&mut Data) + 'l>(
+        &self,
+        deadline: Option<Instant>,
+        callback: F,
+    ) -> Idle<'l> {
         let mut opt_cb = Some(callback);
         let callback = Rc::new(RefCell::new(Some(Box::new(move |data: &mut Data| {
             if let Some(cb) = opt_cb.take() {
                 cb(data);
             }
-        }) as Box<FnMut(&mut Data)>)));
-        self.idles.borrow_mut().push(callback.clone());
+        })
+            as Box<dyn FnMut(&mut Data) + 'l>)));
+        self.idles.borrow_mut().push((deadline, callback.clone()));
         Idle { callback }
     }
+
+    /// Insert a pre-poll hook
+    ///
+    /// This callback will be called during each dispatching cycle, right before the
+    /// event loop starts waiting for new events. Unlike idle callbacks, it is called
+    /// at every cycle until it is cancelled.
+    pub fn insert_pre_poll_hook<F: FnMut(&mut Data) + 'l>(&self, callback: F) -> Hook<'l> {
+        insert_hook(&self.pre_poll_hooks, callback)
+    }
+
+    /// Insert a post-dispatch hook
+    ///
+    /// This callback will be called during each dispatching cycle, once the callbacks
+    /// of all the sources and idles have been called. Unlike idle callbacks, it is
+    /// called at every cycle until it is cancelled.
+    pub fn insert_post_dispatch_hook<F: FnMut(&mut Data) + 'l>(&self, callback: F) -> Hook<'l> {
+        insert_hook(&self.post_dispatch_hooks, callback)
+    }
+
+    /// Insert a pre-sleep hook
+    ///
+    /// This callback will be called during each dispatching cycle, right before the
+    /// event loop goes to sleep waiting for new events, with the maximum duration of
+    /// this wait (`None` meaning it may wait indefinitely). This is the last
+    /// opportunity to flush buffered outgoing data before blocking. It is called
+    /// after `EventSource::before_sleep()` has been called on all the sources.
+    pub fn insert_pre_sleep_hook<F>(&self, callback: F) -> Hook<'l>
+    where
+        F: FnMut(Option<Duration>, &mut Data) + 'l,
+    {
+        let callback = Rc::new(RefCell::new(Some(
+            Box::new(callback) as Box<dyn FnMut(Option<Duration>, &mut Data) + 'l>
+        )));
+        self.pre_sleep_hooks.borrow_mut().push(callback.clone());
+        Hook { callback }
+    }
+}
+
+/// Why the event loop woke up during its last dispatch
+///
+/// It is retrieved with `EventLoop::last_wakeup()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Wakeup {
+    /// The timeout expired without any source being ready
+    pub timeout: bool,
+    /// The loop was woken up by a `LoopSignal`
+    pub signal: bool,
+    /// The sources that were dispatched, in dispatch order
+    ///
+    /// This includes both the sources that were ready and the sources whose
+    /// deadline was reached, such as timers.
+    pub sources: Vec<RegistrationToken>,
+}
+
+/// What to do after a source callback panicked
+///
+/// This is decided by the panic handler, see `EventLoop::set_panic_handler()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Keep the source in the event loop and continue dispatching events
+    Continue,
+    /// Remove the source from the event loop and continue dispatching events
+    Remove,
+    /// Resume unwinding, as if no panic handler was set
+    Resume,
+}
+
+type PanicHandler<'l> = Box<dyn FnMut(RegistrationToken, &(dyn Any + Send)) -> PanicAction + 'l>;
+
+fn insert_hook<'l, Data: 'l, F: FnMut(&mut Data) + 'l>(
+    hooks: &RefCell<Vec<IdleCallback<'l, Data>>>,
+    callback: F,
+) -> Hook<'l> {
+    let callback = Rc::new(RefCell::new(Some(
+        Box::new(callback) as Box<dyn FnMut(&mut Data) + 'l>
+    )));
+    hooks.borrow_mut().push(callback.clone());
+    Hook { callback }
+}
+
+// the hooks list is not borrowed while they run, so that they can insert new hooks
+fn dispatch_hooks<Data>(hooks: &RefCell<Vec<IdleCallback<'_, Data>>>, data: &mut Data) {
+    let current = hooks.borrow().clone();
+    for hook in &current {
+        if let Some(ref mut callback) = *hook.borrow_mut() {
+            callback(data);
+        }
+    }
+    // cleanup the cancelled hooks
+    hooks.borrow_mut().retain(|hook| hook.borrow().is_some());
 }
 
+const DEFAULT_EVENTS_CAPACITY: usize = 32;
+
 /// An event loop
 ///
 /// This loop can host several event sources, that can be dynamically added or removed.
-pub struct EventLoop<Data> {
-    handle: LoopHandle<Data>,
+///
+/// The callbacks of the sources, idles and hooks, as well as the shared data given to
+/// them, only need to live for `'l`: they can borrow local state, the event loop and
+/// its handles cannot outlive it. All of them are dropped along with the event loop.
+pub struct EventLoop<'l, Data> {
+    handle: LoopHandle<'l, Data>,
     events_buffer: Events,
+    ready_buffer: Vec<(Priority, Token, Ready)>,
     stop_signal: Arc<AtomicBool>,
-    wakeup: SetReadiness,
+    wakeup: Arc<LoopWaker>,
+    wakeup_token: Token,
+    last_wakeup: Wakeup,
+    dispatch_budget: Option<usize>,
+    interruptible: bool,
+    dispatch_counts: HashMap<Token, usize>,
+    deferred_buffer: Vec<(Priority, Token, Ready)>,
+    metrics: Option<Metrics>,
+    panic_handler: Option<PanicHandler<'l>>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    precise_timeout: PreciseTimeout,
 }
 
-impl<Data: 'static> EventLoop<Data> {
+impl<'l, Data: 'l> EventLoop<'l, Data> {
     /// Create a new event loop
     ///
     /// It is backed by an `mio` provided machinnery, and will fail if the `mio`
     /// initialization fails.
-    pub fn new() -> io::Result<EventLoop<Data>> {
+    pub fn new() -> io::Result<EventLoop<'l, Data>> {
         let handle = LoopHandle {
             poll: Rc::new(Poll::new()?),
             list: Rc::new(RefCell::new(SourceList::new())),
             idles: Rc::new(RefCell::new(Vec::new())),
+            pre_poll_hooks: Rc::new(RefCell::new(Vec::new())),
+            post_dispatch_hooks: Rc::new(RefCell::new(Vec::new())),
+            pre_sleep_hooks: Rc::new(RefCell::new(Vec::new())),
         };
         // create a wakeup event source
-        let (wakeup_registration, wakeup_readiness) = Registration::new2();
-        let mut wakeup_source = ::sources::generic::Generic::new(wakeup_registration);
-        wakeup_source.set_interest(Ready::readable());
-        wakeup_source.set_pollopts(PollOpt::edge());
-        let readiness2 = wakeup_readiness.clone();
-        handle.insert_source(wakeup_source, move |_, _| {
-            // unmark the readiness so that the wakeup source is not
-            // processed in a loop
-            readiness2.set_readiness(Ready::empty()).unwrap();
-        })?;
+        let waker = Arc::new(LoopWaker::new()?);
+        let wakeup_source = handle.insert_source(PingSource(waker.clone()), |_, _| {})?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let precise_timeout = PreciseTimeout::new(&handle.poll)?;
         Ok(EventLoop {
             handle,
-            events_buffer: Events::with_capacity(32),
+            events_buffer: Events::with_capacity(DEFAULT_EVENTS_CAPACITY),
+            ready_buffer: Vec::with_capacity(32),
             stop_signal: Arc::new(AtomicBool::new(false)),
-            wakeup: wakeup_readiness,
+            wakeup: waker,
+            wakeup_token: wakeup_source.token().token,
+            last_wakeup: Wakeup::default(),
+            dispatch_budget: None,
+            interruptible: false,
+            dispatch_counts: HashMap::new(),
+            deferred_buffer: Vec::new(),
+            metrics: None,
+            panic_handler: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            precise_timeout,
         })
     }
 
     /// Retrieve a loop handle
-    pub fn handle(&self) -> LoopHandle<Data> {
+    pub fn handle(&self) -> LoopHandle<'l, Data> {
         self.handle.clone()
     }
 
-    fn dispatch_events(&mut self, timeout: Option<Duration>, data: &mut Data) -> io::Result<()> {
+    // wait for events and store them in the ready buffer
+    // waits at most until the earliest of `timeout` and `deadline`
+    fn poll_events(
+        &mut self,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+        data: &mut Data,
+    ) -> Result<(), DispatchError> {
+        // don't wait past the deadline of an idle callback or a source
+        let deadline = match (deadline, self.next_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let timeout = match deadline {
+            Some(deadline) => {
+                let until_deadline = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until_deadline, |t| t.min(until_deadline)))
+            }
+            None => timeout,
+        };
+        // events deferred by the dispatch budget are still pending
+        let timeout = if self.deferred_buffer.is_empty() {
+            timeout
+        } else {
+            Some(Duration::from_millis(0))
+        };
+
+        self.handle.list.borrow().before_sleep(timeout);
+        // the hooks list is not borrowed while they run, as for the other hooks
+        let pre_sleep_hooks = self.handle.pre_sleep_hooks.borrow().clone();
+        for hook in &pre_sleep_hooks {
+            if let Some(ref mut callback) = *hook.borrow_mut() {
+                callback(timeout, data);
+            }
+        }
+        self.handle
+            .pre_sleep_hooks
+            .borrow_mut()
+            .retain(|hook| hook.borrow().is_some());
+
+        // epoll timeouts are rounded up to the millisecond
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        self.precise_timeout
+            .set(timeout)
+            .map_err(DispatchError::Poll)?;
+
+        // in case a callback panicked while dispatching
+        self.handle.list.borrow_mut().set_dispatching(false);
+        self.apply_registration_changes()?;
+
+        self.events_buffer.clear();
+        let poll_start = self.metrics.as_ref().map(|_| Instant::now());
+        let ret = if self.interruptible {
+            self.handle
+                .poll
+                .poll_interruptible(&mut self.events_buffer, timeout)
+        } else {
+            // `mio` retries on EINTR, adjusting the timeout
+            self.handle.poll.poll(&mut self.events_buffer, timeout)
+        };
+        match ret {
+            Ok(_) => {}
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {
+                return Err(DispatchError::Interrupted)
+            }
+            Err(error) => return Err(DispatchError::Poll(error)),
+        }
+        if let (Some(metrics), Some(start)) = (self.metrics.as_mut(), poll_start) {
+            metrics.wakeups += 1;
+            metrics.poll_time += start.elapsed();
+        }
+
+        self.dispatch_counts.clear();
+        self.ready_buffer.append(&mut self.deferred_buffer);
+
+        // sources whose deadline is reached are dispatched as if they were ready
+        {
+            let list = self.handle.list.borrow();
+            self.ready_buffer.extend(
+                list.expired_sources(Instant::now())
+                    .into_iter()
+                    .map(|(priority, token)| (priority, token, Ready::empty())),
+            );
+        }
+        // the events of the precise timeout are not associated with a source
+        let has_events = {
+            let list = self.handle.list.borrow();
+            self.events_buffer
+                .iter()
+                .any(|event| list.get_priority(event.token()).is_some())
+        };
+        self.last_wakeup = Wakeup {
+            timeout: !has_events && self.ready_buffer.is_empty(),
+            ..Wakeup::default()
+        };
+        self.buffer_events();
+
+        Ok(())
+    }
+
+    // move the events polled into the ready buffer, sorting them according to the
+    // priority of their source, the sort being stable the events of a given priority
+    // keep their poll order
+    fn buffer_events(&mut self) {
+        {
+            let list = self.handle.list.borrow();
+            self.ready_buffer
+                .extend(self.events_buffer.iter().filter_map(|event| {
+                    list.get_priority(event.token())
+                        .map(|priority| (priority, event.token(), event.readiness()))
+                }));
+        }
         self.events_buffer.clear();
-        self.handle.poll.poll(&mut self.events_buffer, timeout)?;
+        self.ready_buffer.sort_by_key(|&(priority, _, _)| priority);
+    }
+
+    // dispatch the events of the ready buffer, then the ones that are ready since
+    //
+    // the registration changes made meanwhile are batched, see `SourceList`
+    fn dispatch_events(&mut self, data: &mut Data) -> Result<(), DispatchError> {
+        self.handle.list.borrow_mut().set_dispatching(true);
+        let ret = self.dispatch_batches(data);
+        self.handle.list.borrow_mut().set_dispatching(false);
+        // the changes are applied even if a source failed
+        let changes = self.apply_registration_changes();
+        ret.and(changes)
+    }
+
+    fn apply_registration_changes(&self) -> Result<(), DispatchError> {
+        self.handle
+            .list
+            .borrow_mut()
+            .apply_changes(&self.handle.poll)
+            .map_err(|(token, error)| DispatchError::Source {
+                token: RegistrationToken { token },
+                error,
+            })
+    }
 
+    fn dispatch_batches(&mut self, data: &mut Data) -> Result<(), DispatchError> {
         loop {
-            if self.events_buffer.is_empty() {
+            if self.ready_buffer.is_empty() {
                 break;
             }
 
-            for event in &self.events_buffer {
-                let opt_dispatcher = self.handle.list.borrow().get_dispatcher(event.token());
-                if let Some(dispatcher) = opt_dispatcher {
-                    dispatcher.borrow_mut().ready(event.readiness(), data);
+            // the events of this batch are all dispatched even if a source fails,
+            // only the first error is reported
+            let mut first_error = None;
+            let mut dispatched = false;
+            for (priority, event_token, readiness) in self.ready_buffer.drain(..) {
+                let (token, sub_id) = split_sub_token(event_token);
+                // sources which exhausted their budget are dispatched during the next
+                // call to `dispatch()`, to give a chance to the other sources
+                if let Some(budget) = self.dispatch_budget {
+                    let count = self.dispatch_counts.entry(token).or_insert(0);
+                    if *count >= budget {
+                        match self
+                            .deferred_buffer
+                            .iter_mut()
+                            .find(|evt| evt.1 == event_token)
+                        {
+                            Some(deferred) => deferred.2 |= readiness,
+                            None => self
+                                .deferred_buffer
+                                .push((priority, event_token, readiness)),
+                        }
+                        continue;
+                    }
+                    *count += 1;
+                }
+                // the source may have been removed by a previous callback
+                let opt_dispatcher = self.handle.list.borrow().get_dispatcher(token);
+                if let Some((dispatcher, action)) = opt_dispatcher {
+                    dispatched = true;
+                    if token == self.wakeup_token {
+                        self.last_wakeup.signal = true;
+                    } else if !self
+                        .last_wakeup
+                        .sources
+                        .contains(&RegistrationToken { token })
+                    {
+                        self.last_wakeup.sources.push(RegistrationToken { token });
+                    }
+                    let dispatch_start = self.metrics.as_ref().map(|_| Instant::now());
+                    let ret = match self.panic_handler {
+                        None => dispatcher.borrow_mut().ready_sub(readiness, sub_id, data),
+                        Some(ref mut handler) => {
+                            let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+                                dispatcher.borrow_mut().ready_sub(readiness, sub_id, data)
+                            }));
+                            match ret {
+                                Ok(ret) => ret,
+                                Err(payload) => {
+                                    match handler(RegistrationToken { token }, &*payload) {
+                                        PanicAction::Continue => Ok(()),
+                                        PanicAction::Remove => {
+                                            action.set(PostAction::Remove);
+                                            Ok(())
+                                        }
+                                        PanicAction::Resume => panic::resume_unwind(payload),
+                                    }
+                                }
+                            }
+                        }
+                    };
+                    if let (Some(metrics), Some(start)) = (self.metrics.as_mut(), dispatch_start) {
+                        let source_metrics = metrics
+                            .sources
+                            .entry(RegistrationToken { token })
+                            .or_default();
+                        source_metrics.events += 1;
+                        source_metrics.dispatch_time += start.elapsed();
+                    }
+                    let action = action.replace(PostAction::Continue);
+                    let ret = ret.and(self.handle.apply_action(token, &dispatcher, action));
+                    if let Err(error) = ret {
+                        first_error = first_error.or(Some(DispatchError::Source {
+                            token: RegistrationToken { token },
+                            error,
+                        }));
+                    }
                 }
             }
+            if let Some(error) = first_error {
+                return Err(error);
+            }
+
+            self.dispatch_expired_idles(data);
+
+            // all the remaining events were deferred
+            if !dispatched {
+                break;
+            }
 
             // process remaining events if any
-            self.events_buffer.clear();
+            self.apply_registration_changes()?;
             self.handle
                 .poll
-                .poll(&mut self.events_buffer, Some(Duration::from_millis(0)))?;
+                .poll(&mut self.events_buffer, Some(Duration::from_millis(0)))
+                .map_err(DispatchError::Poll)?;
+            self.buffer_events();
         }
 
         Ok(())
     }
 
+    // the earliest deadline of the idle callbacks and the sources
+    fn next_deadline(&self) -> Option<Instant> {
+        match (
+            self.next_idle_deadline(),
+            self.handle.list.borrow().next_deadline(),
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // the instant at which this loop needs to be dispatched when it is nested in an
+    // other one, as pending idles and deferred events are not reflected by the poller
+    #[cfg(unix)]
+    pub(crate) fn nested_deadline(&self) -> Option<Instant> {
+        let has_idles = self
+            .handle
+            .idles
+            .borrow()
+            .iter()
+            .any(|(_, idle)| idle.borrow().is_some());
+        if has_idles || !self.deferred_buffer.is_empty() {
+            Some(Instant::now())
+        } else {
+            self.next_deadline()
+        }
+    }
+
+    // the earliest deadline of the pending idle callbacks
+    fn next_idle_deadline(&self) -> Option<Instant> {
+        self.handle
+            .idles
+            .borrow()
+            .iter()
+            .filter(|&(_, idle)| idle.borrow().is_some())
+            .filter_map(|&(deadline, _)| deadline)
+            .min()
+    }
+
+    fn dispatch_expired_idles(&mut self, data: &mut Data) {
+        let now = Instant::now();
+        let expired = {
+            let mut idles = self.handle.idles.borrow_mut();
+            let (expired, pending) = ::std::mem::take(&mut *idles)
+                .into_iter()
+                .partition(|&(deadline, _)| deadline.map_or(false, |deadline| deadline <= now));
+            *idles = pending;
+            expired
+        };
+        for (_, idle) in expired {
+            if let Some(ref mut callback) = *idle.borrow_mut() {
+                callback(data);
+            }
+        }
+    }
+
     fn dispatch_idles(&mut self, data: &mut Data) {
-        let idles = ::std::mem::replace(&mut *self.handle.idles.borrow_mut(), Vec::new());
-        for idle in idles {
+        let idles = ::std::mem::take(&mut *self.handle.idles.borrow_mut());
+        for (_, idle) in idles {
             if let Some(ref mut callback) = *idle.borrow_mut() {
                 callback(data);
             }
@@ -182,21 +1066,193 @@ impl<Data: 'static> EventLoop<Data> {
     /// Some source have events available, their callbacks will be immediatly called.
     /// Otherwise this will wait until an event is receive or the provided `timeout`
     /// is reached. If `timeout` is `None`, it will wait without a duration limit.
+    /// On Linux and Android, sub-millisecond timeouts are honored using a timerfd,
+    /// they are rounded up to the millisecond on the other platforms.
     ///
     /// Once pending events have been processed or the timeout is reached, all pending
     /// idle callbacks will be fired before this method returns.
-    pub fn dispatch(&mut self, timeout: Option<Duration>, data: &mut Data) -> io::Result<()> {
-        self.dispatch_events(timeout, data)?;
-
-        self.dispatch_idles(data);
-
-        Ok(())
+    ///
+    /// The pre-poll hooks are called before waiting for events, and the post-dispatch
+    /// hooks once the idle callbacks have been fired.
+    ///
+    /// If an event source fails to process its events, the events of the other sources
+    /// that were ready at the same time are still processed, and the error is then
+    /// returned along with the token of the failing source. Idle callbacks and
+    /// post-dispatch hooks are not fired in this case.
+    pub fn dispatch(
+        &mut self,
+        timeout: Option<Duration>,
+        data: &mut Data,
+    ) -> Result<(), DispatchError> {
+        dispatch_hooks(&self.handle.pre_poll_hooks, data);
+        self.poll_events(timeout, None, data)?;
+        self.dispatch_pending(data)
     }
 
-    /// Get a signal to stop this event loop from running
+    /// Dispatch pending events to their callbacks, waiting at most until a deadline
     ///
-    /// To be used in conjunction with the `run()` method.
-    pub fn get_signal(&self) -> LoopSignal {
+    /// This is the same as `dispatch()`, but the wait is bounded by an absolute
+    /// `deadline` rather than a duration. As it is converted to a timeout right
+    /// before waiting, repeated calls with the same deadline, like within a frame,
+    /// do not accumulate drift. If the deadline has already passed, this does not
+    /// wait for events.
+    pub fn dispatch_until(
+        &mut self,
+        deadline: Instant,
+        data: &mut Data,
+    ) -> Result<(), DispatchError> {
+        dispatch_hooks(&self.handle.pre_poll_hooks, data);
+        self.poll_events(None, Some(deadline), data)?;
+        self.dispatch_pending(data)
+    }
+
+    /// Wait for events, without dispatching them
+    ///
+    /// This is the first half of `dispatch()`: the pre-poll hooks are called, then
+    /// this waits until an event is received or the provided `timeout` is reached.
+    /// The tokens of the sources that are ready are returned in order of priority,
+    /// and their events are kept until `dispatch_pending()` is called. This allows
+    /// you to run your own logic between waiting for events and dispatching them.
+    pub fn poll(
+        &mut self,
+        timeout: Option<Duration>,
+        data: &mut Data,
+    ) -> Result<Vec<RegistrationToken>, DispatchError> {
+        dispatch_hooks(&self.handle.pre_poll_hooks, data);
+        self.poll_events(timeout, None, data)?;
+        let mut tokens = Vec::new();
+        for &(_, token, _) in &self.ready_buffer {
+            let token = RegistrationToken {
+                token: split_sub_token(token).0,
+            };
+            if token.token != self.wakeup_token && !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Dispatch the pending events to their callbacks, without waiting
+    ///
+    /// This is the second half of `dispatch()`: the events retrieved by `poll()`
+    /// are dispatched, along with the ones which became available since, then the
+    /// idle callbacks and post-dispatch hooks are fired.
+    pub fn dispatch_pending(&mut self, data: &mut Data) -> Result<(), DispatchError> {
+        self.dispatch_events(data)?;
+
+        self.dispatch_idles(data);
+
+        dispatch_hooks(&self.handle.post_dispatch_hooks, data);
+
+        Ok(())
+    }
+
+    /// Set a handler for the panics of source callbacks
+    ///
+    /// By default, a panic in the callback of a source unwinds through
+    /// `dispatch()`. Once a handler is set, such panics are caught and the handler is
+    /// invoked with the token of the source and the panic payload. It then decides
+    /// what to do with the source, see `PanicAction`. It can also abort the process
+    /// if that is appropriate for your program.
+    ///
+    /// Note that the shared data given to `dispatch()` may be left in an inconsistent
+    /// state by the panicking callback.
+    pub fn set_panic_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(RegistrationToken, &(dyn Any + Send)) -> PanicAction + 'l,
+    {
+        self.panic_handler = Some(Box::new(handler));
+    }
+
+    /// Remove the panic handler
+    ///
+    /// Panics of source callbacks will unwind through `dispatch()` again.
+    pub fn unset_panic_handler(&mut self) {
+        self.panic_handler = None;
+    }
+
+    /// Enable or disable the collection of metrics
+    ///
+    /// Metrics are disabled by default. Disabling them discards the metrics
+    /// collected so far.
+    pub fn set_metrics_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.metrics = None;
+        } else if self.metrics.is_none() {
+            self.metrics = Some(Metrics::default());
+        }
+    }
+
+    /// Access the metrics collected by this event loop
+    ///
+    /// Returns `None` if metrics are not enabled.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Reset the metrics collected by this event loop
+    pub fn reset_metrics(&mut self) {
+        if let Some(ref mut metrics) = self.metrics {
+            *metrics = Metrics::default();
+        }
+    }
+
+    /// Remove all the sources from the event loop
+    ///
+    /// The sources are removed in order of priority, each of them being notified
+    /// through `EventSource::before_removal()`. This allows them to shut down
+    /// gracefully, unlike when the event loop is dropped. Idles and hooks are kept,
+    /// and new sources can be inserted afterwards.
+    pub fn shutdown(&mut self) {
+        let tokens = self.handle.list.borrow().tokens_by_priority();
+        for token in tokens {
+            if token != self.wakeup_token {
+                self.handle.remove(RegistrationToken { token });
+            }
+        }
+    }
+
+    /// Set whether waiting for events can be interrupted by signals
+    ///
+    /// By default, if a signal is received while the event loop is waiting for events,
+    /// it resumes waiting for the remaining of the timeout. If the event loop is made
+    /// interruptible, `dispatch()` and `run()` instead return a
+    /// `DispatchError::Interrupted`, allowing you to react to the signal.
+    pub fn set_interruptible(&mut self, interruptible: bool) {
+        self.interruptible = interruptible;
+    }
+
+    /// Set how many events of a single source can be dispatched during a call to `dispatch()`
+    ///
+    /// Once a source exhausted its budget, its next events are kept for the next call to
+    /// `dispatch()`, so that a source that is constantly ready (such as a busy file
+    /// descriptor in level-triggered mode) cannot starve the others. `None`, the
+    /// default, disables the budget.
+    pub fn set_dispatch_budget(&mut self, budget: Option<usize>) {
+        self.dispatch_budget = budget;
+    }
+
+    /// Set the maximum number of events retrieved by a single poll of the system
+    ///
+    /// The events that did not fit are retrieved by further polls during the same
+    /// `dispatch()`. Servers with many simultaneously ready sources can increase it
+    /// to save these system calls. Defaults to 32, a capacity of 0 is rounded up to 1.
+    pub fn set_events_capacity(&mut self, capacity: usize) {
+        self.events_buffer = Events::with_capacity(capacity.max(1));
+    }
+
+    /// Why the event loop woke up during the last call to `dispatch()`
+    ///
+    /// When using `run()`, this describes the last dispatch, so it is mostly useful
+    /// from the callback given to `run()`.
+    pub fn last_wakeup(&self) -> &Wakeup {
+        &self.last_wakeup
+    }
+
+    /// Get a signal to stop this event loop from running
+    ///
+    /// To be used in conjunction with the `run()` method.
+    pub fn get_signal(&self) -> LoopSignal {
         LoopSignal {
             signal: self.stop_signal.clone(),
             wakeup: self.wakeup.clone(),
@@ -206,18 +1262,21 @@ impl<Data: 'static> EventLoop<Data> {
     /// Run this event loop
     ///
     /// This will repeatedly try to dispatch events (see the `dispatch()` method) on
-    /// this event loop, waiting at most `timeout` every time.
+    /// this event loop, waiting at most `timeout` every time. If `timeout` is `None`,
+    /// each dispatch blocks until an event is received, so that an idle program does
+    /// not needlessly wake up.
     ///
     /// Between each dispatch wait, your provided callback will be called.
     ///
     /// You can use the `get_signal()` method to retrieve a way to stop or wakeup
-    /// the event loop from anywhere.
+    /// the event loop from anywhere. The loop also stops if dispatching events
+    /// returns an error.
     pub fn run<F>(
         &mut self,
         timeout: Option<Duration>,
         data: &mut Data,
         mut cb: F,
-    ) -> io::Result<()>
+    ) -> Result<(), DispatchError>
     where
         F: FnMut(&mut Data),
     {
@@ -228,14 +1287,118 @@ impl<Data: 'static> EventLoop<Data> {
         }
         Ok(())
     }
+
+    /// Run this event loop until the given future completes
+    ///
+    /// The future is polled from the thread of the event loop, and it is polled
+    /// again each time it is woken up, which can happen from any thread. Between
+    /// these, events are dispatched as with the `dispatch()` method, blocking until
+    /// an event is received.
+    ///
+    /// Returns the output of the future, or the error of a failing dispatch.
+    pub fn block_on<F: Future>(
+        &mut self,
+        future: F,
+        data: &mut Data,
+    ) -> Result<F::Output, DispatchError> {
+        let mut future = Box::pin(future);
+        let block_on_waker = Arc::new(BlockOnWaker {
+            woken: AtomicBool::new(true),
+            wakeup: self.wakeup.clone(),
+        });
+        let waker = Waker::from(block_on_waker.clone());
+        let mut context = Context::from_waker(&waker);
+        loop {
+            if block_on_waker.woken.swap(false, Ordering::AcqRel) {
+                if let TaskPoll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return Ok(output);
+                }
+            }
+            // the future may have been woken up by a callback during this dispatch
+            if !block_on_waker.woken.load(Ordering::Acquire) {
+                self.dispatch(None, data)?;
+            }
+        }
+    }
+}
+
+// wakes up the event loop running `EventLoop::block_on()`
+struct BlockOnWaker {
+    woken: AtomicBool,
+    wakeup: Arc<LoopWaker>,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.wakeup.wake();
+    }
+}
+
+impl<'l, Data> Drop for EventLoop<'l, Data> {
+    fn drop(&mut self) {
+        // callbacks holding a handle to the loop would otherwise keep it alive
+        loop {
+            let sources = self.handle.list.borrow_mut().take_all();
+            let idles = ::std::mem::take(&mut *self.handle.idles.borrow_mut());
+            let pre_poll_hooks = ::std::mem::take(&mut *self.handle.pre_poll_hooks.borrow_mut());
+            let post_dispatch_hooks =
+                ::std::mem::take(&mut *self.handle.post_dispatch_hooks.borrow_mut());
+            let pre_sleep_hooks = ::std::mem::take(&mut *self.handle.pre_sleep_hooks.borrow_mut());
+            if sources.is_empty()
+                && idles.is_empty()
+                && pre_poll_hooks.is_empty()
+                && post_dispatch_hooks.is_empty()
+                && pre_sleep_hooks.is_empty()
+            {
+                break;
+            }
+            // the idle and hook callbacks are also owned by their handles
+            for (_, idle) in idles {
+                idle.borrow_mut().take();
+            }
+            for hook in pre_poll_hooks.into_iter().chain(post_dispatch_hooks) {
+                hook.borrow_mut().take();
+            }
+            for hook in pre_sleep_hooks {
+                hook.borrow_mut().take();
+            }
+            // dropping the callbacks can insert new ones, hence the loop
+            drop(sources);
+        }
+    }
+}
+
+/// The file descriptor of the underlying poller (epoll or kqueue)
+///
+/// This allows nesting the event loop into a foreign one, like the GLib or Qt main
+/// loops: the file descriptor becomes readable when some sources of this event
+/// loop are ready, `dispatch()` with a zero timeout should then be called to process
+/// them.
+///
+/// The deadlines of the sources (like timers) and the idle callbacks are not
+/// reflected by this file descriptor, the foreign loop must also be woken up for
+/// them. Neither are the sources based on `mio::Registration`, like channels, when
+/// this event loop is not itself waiting for events.
+#[cfg(unix)]
+impl<'l, Data> AsRawFd for EventLoop<'l, Data> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.poll.as_raw_fd()
+    }
 }
 
 /// A signal that can be shared between thread to stop or wakeup a running
 /// event loop
+///
+/// On unix, its methods are async-signal-safe, see `SignalSafe`.
 #[derive(Clone)]
 pub struct LoopSignal {
     signal: Arc<AtomicBool>,
-    wakeup: SetReadiness,
+    wakeup: Arc<LoopWaker>,
 }
 
 impl LoopSignal {
@@ -256,15 +1419,42 @@ impl LoopSignal {
     /// ensures the event loop will terminate quickly if you specified a long
     /// timeout (or no timeout at all) to the `dispatch` or `run` method.
     pub fn wakeup(&self) {
-        self.wakeup.set_readiness(Ready::readable()).unwrap();
+        self.wakeup.wake();
     }
 }
 
+/// Types whose methods can be called from a POSIX signal handler
+///
+/// The methods taking `&self` of the types implementing this trait are
+/// async-signal-safe: they do not take any lock nor allocate, and they preserve
+/// `errno`. Dropping or cloning them is not guaranteed to be.
+///
+/// # Safety
+///
+/// Implementing this trait for types which do not uphold these guarantees can cause
+/// deadlocks or memory corruption in the programs relying on it.
+pub unsafe trait SignalSafe {}
+
+// `stop()` is a lock-free atomic store, and `wakeup()` a write on an eventfd or a pipe
+#[cfg(unix)]
+unsafe impl SignalSafe for LoopSignal {}
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll as TaskPoll, Waker};
+    use std::time::{Duration, Instant};
+
+    use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
 
-    use super::EventLoop;
+    use super::{DispatchError, EventLoop, PanicAction, Priority, Wakeup};
+    use sources::generic::Generic;
+    use sources::timer::TimeoutAction;
+    use sources::{Dispatcher, EventDispatcher, EventSource, PostAction, TokenFactory};
 
     #[test]
     fn dispatch_idle() {
@@ -332,4 +1522,1439 @@ mod tests {
         // the test should return
         event_loop.run(None, &mut (), |_| {}).unwrap();
     }
+
+    #[test]
+    fn priority_order() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let mut order = Vec::new();
+
+        let mut readinesses = Vec::new();
+        let mut sources = Vec::new();
+        for &(name, priority) in &[
+            ("low", Priority::Low),
+            ("normal", Priority::Normal),
+            ("high", Priority::High),
+        ] {
+            let (registration, readiness) = Registration::new2();
+            let mut generic = Generic::new(registration);
+            generic.set_interest(Ready::readable());
+            generic.set_pollopts(PollOpt::edge());
+            sources.push(
+                handle
+                    .insert_source_with_priority(generic, priority, move |_, order: &mut Vec<_>| {
+                        order.push(name)
+                    })
+                    .unwrap(),
+            );
+            readinesses.push(readiness);
+        }
+
+        for readiness in &readinesses {
+            readiness.set_readiness(Ready::readable()).unwrap();
+        }
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut order)
+            .unwrap();
+
+        assert_eq!(order, &["high", "normal", "low"]);
+    }
+
+    #[test]
+    fn post_action_remove() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level());
+
+        let mut dispatched = 0;
+
+        let source = event_loop
+            .handle()
+            .insert_source(generic, |_, d: &mut u32| {
+                *d += 1;
+                PostAction::Remove
+            })
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+
+        // the source removed itself after its first event
+        assert_eq!(dispatched, 1);
+
+        // it can still be retrieved
+        source.remove();
+    }
+
+    #[test]
+    fn post_action_disable() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level());
+
+        let mut dispatched = 0;
+
+        let source = event_loop
+            .handle()
+            .insert_source(generic, |_, d: &mut u32| {
+                *d += 1;
+                PostAction::Disable
+            })
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+
+        assert_eq!(dispatched, 1);
+
+        // the source is still ready, and dispatched again once enabled
+        source.enable().unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+
+        assert_eq!(dispatched, 2);
+    }
+
+    struct FailingSource(Registration);
+
+    impl Evented for FailingSource {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.0.register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.0.reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            poll.deregister(&self.0)
+        }
+    }
+
+    impl EventSource for FailingSource {
+        type Event = ();
+
+        fn interest(&self) -> Ready {
+            Ready::readable()
+        }
+
+        fn pollopts(&self) -> PollOpt {
+            PollOpt::edge()
+        }
+
+        fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+            &self,
+            _: F,
+        ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+            Rc::new(RefCell::new(FailingDispatcher))
+        }
+    }
+
+    struct FailingDispatcher;
+
+    impl<Data> EventDispatcher<Data> for FailingDispatcher {
+        fn ready(&mut self, _: Ready, _: &mut Data) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "failing source"))
+        }
+    }
+
+    #[test]
+    fn source_error() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let (registration, readiness) = Registration::new2();
+        let source = event_loop
+            .handle()
+            .insert_source(FailingSource(registration), |(), _: &mut ()| {})
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        match event_loop.dispatch(Some(Duration::from_millis(0)), &mut ()) {
+            Err(DispatchError::Source { token, error }) => {
+                assert_eq!(token, source.token());
+                assert_eq!(error.to_string(), "failing source");
+            }
+            other => panic!("Unexpected dispatch result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn register_dispatcher() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_pollopts(PollOpt::edge());
+
+        let dispatcher = Dispatcher::new(generic, |_, d: &mut bool| *d = true);
+        let token = handle.register_dispatcher(dispatcher.clone()).unwrap();
+
+        let mut dispatched = false;
+
+        // the source has no interest yet
+        readiness.set_readiness(Ready::readable()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(!dispatched);
+
+        // change it through the dispatcher
+        dispatcher.as_source_mut().set_interest(Ready::readable());
+        handle.update(&token).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(dispatched);
+
+        handle.remove(token);
+        dispatcher.into_source_inner();
+    }
+
+    #[test]
+    fn metrics() {
+        let mut event_loop = EventLoop::new().unwrap();
+        assert!(event_loop.metrics().is_none());
+        event_loop.set_metrics_enabled(true);
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let source = event_loop
+            .handle()
+            .insert_source(generic, |_, _| {})
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+
+        {
+            let metrics = event_loop.metrics().unwrap();
+            assert_eq!(metrics.wakeups, 1);
+            assert_eq!(metrics.sources[&source.token()].events, 1);
+        }
+
+        event_loop.reset_metrics();
+        assert_eq!(event_loop.metrics().unwrap().wakeups, 0);
+    }
+
+    #[test]
+    fn hooks() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let mut calls = Vec::new();
+
+        let pre_poll = handle.insert_pre_poll_hook(|calls: &mut Vec<_>| calls.push("pre_poll"));
+        handle.insert_post_dispatch_hook(|calls: &mut Vec<_>| calls.push("post_dispatch"));
+        handle.insert_idle(|calls: &mut Vec<_>| calls.push("idle"));
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut calls)
+            .unwrap();
+        assert_eq!(calls, &["pre_poll", "idle", "post_dispatch"]);
+
+        // hooks are called at every cycle until cancelled
+        pre_poll.cancel();
+        calls.clear();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut calls)
+            .unwrap();
+        assert_eq!(calls, &["post_dispatch"]);
+    }
+
+    #[test]
+    fn panic_handler_remove() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let handled = Rc::new(RefCell::new(Vec::new()));
+        let handled2 = handled.clone();
+        event_loop.set_panic_handler(move |token, _| {
+            handled2.borrow_mut().push(token);
+            PanicAction::Remove
+        });
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level());
+        let source = event_loop
+            .handle()
+            .insert_source(generic, |_, _| -> PostAction { panic!("Callback panic") })
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+
+        // the source was removed after its first panic
+        assert_eq!(&*handled.borrow(), &[source.token()]);
+    }
+
+    #[test]
+    fn idle_deadline() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        // a source that is always ready, keeping the event loop busy
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level());
+        let _source = handle
+            .insert_source(generic, |_, state: &mut (bool, u32)| {
+                state.1 += 1;
+                if state.0 {
+                    // stop flooding once the idle has run
+                    PostAction::Remove
+                } else {
+                    PostAction::Continue
+                }
+            })
+            .unwrap();
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        handle.insert_idle_with_deadline(Instant::now() + Duration::from_millis(10), |state| {
+            state.0 = true
+        });
+
+        let mut state = (false, 0);
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut state)
+            .unwrap();
+
+        assert!(state.0);
+    }
+
+    #[test]
+    fn last_wakeup() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let signal = event_loop.get_signal();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let source = event_loop
+            .handle()
+            .insert_source(generic, |_, _| {})
+            .unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+        assert_eq!(
+            event_loop.last_wakeup(),
+            &Wakeup {
+                timeout: true,
+                signal: false,
+                sources: vec![],
+            }
+        );
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+        signal.wakeup();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+        assert_eq!(
+            event_loop.last_wakeup(),
+            &Wakeup {
+                timeout: false,
+                signal: true,
+                sources: vec![source.token()],
+            }
+        );
+    }
+
+    #[test]
+    fn dispatch_budget() {
+        let mut event_loop = EventLoop::new().unwrap();
+        event_loop.set_dispatch_budget(Some(2));
+
+        // level-triggered sources that are always ready
+        let (chatty_registration, chatty_readiness) = Registration::new2();
+        let mut chatty = Generic::new(chatty_registration);
+        chatty.set_interest(Ready::readable());
+        chatty.set_pollopts(PollOpt::level());
+        event_loop
+            .handle()
+            .insert_source(chatty, |_, calls: &mut Vec<_>| calls.push("chatty"))
+            .unwrap();
+        chatty_readiness.set_readiness(Ready::readable()).unwrap();
+
+        let (other_registration, other_readiness) = Registration::new2();
+        let mut other = Generic::new(other_registration);
+        other.set_interest(Ready::readable());
+        other.set_pollopts(PollOpt::edge());
+        event_loop
+            .handle()
+            .insert_source(other, |_, calls: &mut Vec<_>| calls.push("other"))
+            .unwrap();
+        other_readiness.set_readiness(Ready::readable()).unwrap();
+
+        let mut calls = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut calls)
+            .unwrap();
+
+        assert_eq!(calls.iter().filter(|&&c| c == "chatty").count(), 2);
+        assert_eq!(calls.iter().filter(|&&c| c == "other").count(), 1);
+    }
+
+    // a future resolving once a value is set by an event source
+    struct ValueFuture {
+        state: Rc<RefCell<(Option<u32>, Option<Waker>)>>,
+    }
+
+    impl Future for ValueFuture {
+        type Output = u32;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> TaskPoll<u32> {
+            let mut state = self.state.borrow_mut();
+            match state.0.take() {
+                Some(value) => TaskPoll::Ready(value),
+                None => {
+                    state.1 = Some(cx.waker().clone());
+                    TaskPoll::Pending
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn block_on() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let state = Rc::new(RefCell::new((None, None::<Waker>)));
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let state2 = state.clone();
+        event_loop
+            .handle()
+            .insert_source(generic, move |_, _| {
+                let mut state = state2.borrow_mut();
+                state.0 = Some(42);
+                if let Some(waker) = state.1.take() {
+                    waker.wake();
+                }
+            })
+            .unwrap();
+
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(50));
+            readiness.set_readiness(Ready::readable()).unwrap();
+        });
+
+        let value = event_loop.block_on(ValueFuture { state }, &mut ()).unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn sleep() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let start = Instant::now();
+        let sleep = handle.sleep(Duration::from_millis(50)).unwrap();
+        event_loop.block_on(sleep, &mut ()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // a dropped sleep does not wake up the event loop
+        let deadline = Instant::now() + Duration::from_millis(20);
+        drop(handle.sleep_until(deadline).unwrap());
+        event_loop
+            .dispatch(Some(Duration::from_millis(50)), &mut ())
+            .unwrap();
+        assert!(event_loop.last_wakeup().timeout);
+    }
+
+    #[test]
+    fn future_timeout() {
+        use futures::TimedOut;
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let sleep = handle.sleep(Duration::from_millis(10)).unwrap();
+        let future = handle.timeout(Duration::from_secs(5), sleep).unwrap();
+        assert_eq!(event_loop.block_on(future, &mut ()).unwrap(), Ok(()));
+
+        let start = Instant::now();
+        let sleep = handle.sleep(Duration::from_secs(5)).unwrap();
+        let future = handle.timeout(Duration::from_millis(10), sleep).unwrap();
+        assert_eq!(event_loop.block_on(future, &mut ()).unwrap(), Err(TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn borrowed_data() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let mut seen: Vec<&str> = Vec::new();
+
+        {
+            let mut event_loop = EventLoop::new().unwrap();
+            let handle = event_loop.handle();
+            for name in &names {
+                handle.insert_idle(move |seen: &mut Vec<&str>| seen.push(name));
+            }
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut seen)
+                .unwrap();
+        }
+
+        assert_eq!(seen, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn drop_sources_with_loop() {
+        struct DropFlag<'a>(&'a RefCell<bool>);
+
+        impl<'a> Drop for DropFlag<'a> {
+            fn drop(&mut self) {
+                *self.0.borrow_mut() = true;
+            }
+        }
+
+        let dropped = RefCell::new(false);
+
+        {
+            let event_loop = EventLoop::<()>::new().unwrap();
+            let handle = event_loop.handle();
+            let (registration, _readiness) = Registration::new2();
+            let flag = DropFlag(&dropped);
+            let loop_handle = handle.clone();
+            // the callback keeps the loop alive through its handle
+            handle
+                .insert_source(Generic::new(registration), move |_, _| {
+                    let _ = (&flag, &loop_handle);
+                })
+                .unwrap();
+        }
+
+        assert!(*dropped.borrow());
+    }
+
+    #[test]
+    fn oneshot() {
+        let mut event_loop = EventLoop::new().unwrap();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let source = event_loop
+            .handle()
+            .insert_source_oneshot(generic, |_, count: &mut u32| *count += 1)
+            .unwrap();
+
+        let mut count = 0;
+        for _ in 0..2 {
+            readiness.set_readiness(Ready::readable()).unwrap();
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut count)
+                .unwrap();
+            readiness.set_readiness(Ready::empty()).unwrap();
+        }
+
+        assert_eq!(count, 1);
+        assert_eq!(
+            event_loop
+                .handle()
+                .update(&source.token())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let group = handle.create_group();
+        let mut readinesses = Vec::new();
+        let mut tokens = Vec::new();
+        for i in 0..3 {
+            let (registration, readiness) = Registration::new2();
+            let mut generic = Generic::new(registration);
+            generic.set_interest(Ready::readable());
+            generic.set_pollopts(PollOpt::edge());
+            let callback = move |_, fired: &mut Vec<u32>| fired.push(i);
+            let source = if i < 2 {
+                handle.insert_source_in_group(group, generic, callback)
+            } else {
+                handle.insert_source(generic, callback)
+            };
+            tokens.push(source.unwrap().token());
+            readinesses.push(readiness);
+        }
+
+        let mut fired = Vec::new();
+        handle.disable_group(group).unwrap();
+        for readiness in &readinesses {
+            readiness.set_readiness(Ready::readable()).unwrap();
+        }
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(fired, vec![2]);
+
+        handle.remove_group(group);
+        assert!(handle.enable(&tokens[0]).is_err());
+        assert!(handle.enable(&tokens[1]).is_err());
+        assert!(handle.update(&tokens[2]).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repeated_enable_disable() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        // a pipe, as epoll rejects deregistering a file descriptor twice
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let mut generic = Generic::from_raw_fd(read);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let group = handle.create_group();
+        let source = handle
+            .insert_source_in_group(group, generic, |_, fired: &mut u32| *fired += 1)
+            .unwrap();
+        let token = source.token();
+
+        handle.disable(&token).unwrap();
+        handle.disable(&token).unwrap();
+        source.disable().unwrap();
+        // a disabled source is registered anew once enabled
+        handle.update(&token).unwrap();
+        // the group has an already disabled source
+        handle.disable_group(group).unwrap();
+
+        ::nix::unistd::write(write, b"x").unwrap();
+        let mut fired = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(fired, 0);
+
+        handle.enable(&token).unwrap();
+        source.enable().unwrap();
+        handle.enable_group(group).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(fired, 1);
+
+        // removing a disabled source does not fail either
+        handle.disable(&token).unwrap();
+        drop(source);
+        handle.remove(token);
+        ::nix::unistd::close(read).unwrap();
+        ::nix::unistd::close(write).unwrap();
+    }
+
+    #[test]
+    fn stale_token() {
+        let event_loop = EventLoop::<()>::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (registration, _readiness) = Registration::new2();
+        let old_token = handle
+            .insert_source(Generic::new(registration), |_, _| {})
+            .unwrap()
+            .token();
+        handle.remove(old_token);
+
+        // the new source reuses the slot of the removed one
+        let (registration, _readiness) = Registration::new2();
+        let new_token = handle
+            .insert_source(Generic::new(registration), |_, _| {})
+            .unwrap()
+            .token();
+
+        assert_ne!(old_token, new_token);
+        assert_eq!(
+            handle.update(&old_token).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        handle.remove(old_token);
+        assert!(handle.update(&new_token).is_ok());
+    }
+
+    // a source recording its removal
+    struct ShutdownSource {
+        registration: Registration,
+        name: &'static str,
+        removed: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Evented for ShutdownSource {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.registration.register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.registration.reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            poll.deregister(&self.registration)
+        }
+    }
+
+    impl EventSource for ShutdownSource {
+        type Event = ();
+
+        fn interest(&self) -> Ready {
+            Ready::readable()
+        }
+
+        fn pollopts(&self) -> PollOpt {
+            PollOpt::edge()
+        }
+
+        fn before_removal(&mut self) {
+            self.removed.borrow_mut().push(self.name);
+        }
+
+        fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+            &self,
+            _callback: F,
+        ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+            Rc::new(RefCell::new(FailingDispatcher))
+        }
+    }
+
+    #[test]
+    fn shutdown() {
+        let mut event_loop = EventLoop::<()>::new().unwrap();
+        let handle = event_loop.handle();
+        let removed = Rc::new(RefCell::new(Vec::new()));
+
+        for &(name, priority) in &[
+            ("low", Priority::Low),
+            ("normal", Priority::Normal),
+            ("high", Priority::High),
+        ] {
+            let source = ShutdownSource {
+                registration: Registration::new2().0,
+                name,
+                removed: removed.clone(),
+            };
+            handle
+                .insert_source_with_priority(source, priority, |_, _| {})
+                .unwrap();
+        }
+
+        event_loop.shutdown();
+        assert_eq!(*removed.borrow(), vec!["high", "normal", "low"]);
+
+        // the event loop can still be woken up
+        event_loop.get_signal().wakeup();
+        event_loop.dispatch(None, &mut ()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wakeup_from_signal_handler() {
+        use super::{LoopSignal, SignalSafe};
+        use nix::sys::signal::{raise, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+        use std::ptr;
+        use std::sync::atomic::{AtomicPtr, Ordering};
+
+        static LOOP_SIGNAL: AtomicPtr<LoopSignal> = AtomicPtr::new(ptr::null_mut());
+
+        extern "C" fn handler(_: ::nix::libc::c_int) {
+            fn assert_signal_safe<T: SignalSafe>(_: &T) {}
+            let signal = LOOP_SIGNAL.load(Ordering::Acquire);
+            if let Some(signal) = unsafe { signal.as_ref() } {
+                assert_signal_safe(signal);
+                signal.wakeup();
+            }
+        }
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let signal = Box::new(event_loop.get_signal());
+        LOOP_SIGNAL.store(&*signal as *const _ as *mut _, Ordering::Release);
+
+        let action = SigAction::new(
+            SigHandler::Handler(handler),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let previous = unsafe { sigaction(Signal::SIGURG, &action).unwrap() };
+        raise(Signal::SIGURG).unwrap();
+
+        // this would block forever without the wakeup
+        event_loop.dispatch(None, &mut ()).unwrap();
+        assert!(event_loop.last_wakeup().signal);
+
+        unsafe { sigaction(Signal::SIGURG, &previous).unwrap() };
+        LOOP_SIGNAL.store(ptr::null_mut(), Ordering::Release);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interruptible() {
+        use nix::libc;
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+        extern "C" fn handler(_: libc::c_int) {}
+
+        let action = SigAction::new(
+            SigHandler::Handler(handler),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let previous = unsafe { sigaction(Signal::SIGWINCH, &action).unwrap() };
+
+        let interrupt = |delay| {
+            let thread = unsafe { libc::pthread_self() } as usize;
+            ::std::thread::spawn(move || {
+                ::std::thread::sleep(delay);
+                unsafe { libc::pthread_kill(thread as libc::pthread_t, libc::SIGWINCH) };
+            })
+        };
+
+        let mut event_loop = EventLoop::new().unwrap();
+
+        // by default, the signal does not interrupt the dispatch
+        let sender = interrupt(Duration::from_millis(50));
+        let start = Instant::now();
+        event_loop
+            .dispatch(Some(Duration::from_millis(200)), &mut ())
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        sender.join().unwrap();
+
+        event_loop.set_interruptible(true);
+        let sender = interrupt(Duration::from_millis(50));
+        match event_loop.dispatch(None, &mut ()) {
+            Err(DispatchError::Interrupted) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        sender.join().unwrap();
+
+        unsafe { sigaction(Signal::SIGWINCH, &previous).unwrap() };
+    }
+
+    #[test]
+    fn pre_sleep_hook() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let hook = handle.insert_pre_sleep_hook(|timeout, timeouts: &mut Vec<_>| {
+            timeouts.push(timeout);
+        });
+
+        let mut timeouts = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(10)), &mut timeouts)
+            .unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut timeouts)
+            .unwrap();
+        hook.cancel();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut timeouts)
+            .unwrap();
+
+        assert_eq!(
+            timeouts,
+            vec![
+                Some(Duration::from_millis(10)),
+                Some(Duration::from_millis(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn poll_then_dispatch_pending() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let source = handle
+            .insert_source(generic, |_, dispatched: &mut bool| *dispatched = true)
+            .unwrap();
+
+        let mut dispatched = false;
+        readiness.set_readiness(Ready::readable()).unwrap();
+        let tokens = event_loop
+            .poll(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert_eq!(tokens, vec![source.token()]);
+        assert!(!dispatched);
+
+        event_loop.dispatch_pending(&mut dispatched).unwrap();
+        assert!(dispatched);
+    }
+
+    #[test]
+    fn insert_disabled() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        let source = handle
+            .insert_source_disabled(generic, |_, dispatched: &mut bool| *dispatched = true)
+            .unwrap();
+        readiness.set_readiness(Ready::readable()).unwrap();
+
+        let mut dispatched = false;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(!dispatched);
+
+        source.enable().unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(dispatched);
+    }
+
+    // a source made of two registrations, generating their index as event
+    struct CompositeSource {
+        registrations: [Registration; 2],
+    }
+
+    impl Evented for CompositeSource {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            let mut factory = TokenFactory::new(token);
+            for registration in &self.registrations {
+                registration.register(poll, factory.token(), interest, opts)?;
+            }
+            Ok(())
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            let mut factory = TokenFactory::new(token);
+            for registration in &self.registrations {
+                registration.reregister(poll, factory.token(), interest, opts)?;
+            }
+            Ok(())
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            for registration in &self.registrations {
+                poll.deregister(registration)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl EventSource for CompositeSource {
+        type Event = usize;
+
+        fn interest(&self) -> Ready {
+            Ready::readable()
+        }
+
+        fn pollopts(&self) -> PollOpt {
+            PollOpt::edge()
+        }
+
+        fn make_dispatcher<'l, Data: 'l, F: FnMut(usize, &mut Data) + 'l>(
+            &self,
+            callback: F,
+        ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+            Rc::new(RefCell::new(CompositeDispatcher { callback }))
+        }
+    }
+
+    struct CompositeDispatcher<F> {
+        callback: F,
+    }
+
+    impl<Data, F: FnMut(usize, &mut Data)> EventDispatcher<Data> for CompositeDispatcher<F> {
+        fn ready(&mut self, _: Ready, _: &mut Data) -> io::Result<()> {
+            unreachable!()
+        }
+
+        fn ready_sub(&mut self, _: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+            (self.callback)(sub_id, data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sub_tokens() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (registration0, readiness0) = Registration::new2();
+        let (registration1, readiness1) = Registration::new2();
+        let source = handle
+            .insert_source(
+                CompositeSource {
+                    registrations: [registration0, registration1],
+                },
+                |sub_id, ready: &mut Vec<usize>| ready.push(sub_id),
+            )
+            .unwrap();
+
+        let mut ready = Vec::new();
+        readiness1.set_readiness(Ready::readable()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ready)
+            .unwrap();
+        assert_eq!(ready, vec![1]);
+        assert_eq!(event_loop.last_wakeup().sources, vec![source.token()]);
+
+        ready.clear();
+        readiness0.set_readiness(Ready::readable()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ready)
+            .unwrap();
+        assert_eq!(ready, vec![0]);
+    }
+
+    #[test]
+    fn dispatch_until() {
+        let mut event_loop = EventLoop::<()>::new().unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        event_loop.dispatch_until(deadline, &mut ()).unwrap();
+        assert!(Instant::now() >= deadline);
+        assert!(event_loop.last_wakeup().timeout);
+
+        // a past deadline does not wait
+        let start = Instant::now();
+        event_loop.dispatch_until(deadline, &mut ()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn precise_timeout() {
+        let mut event_loop = EventLoop::<()>::new().unwrap();
+
+        // the timeout would be rounded up to 1ms without the timerfd, tolerate
+        // a few late wakeups on a loaded system
+        let precise = (0..10).any(|_| {
+            let start = Instant::now();
+            event_loop
+                .dispatch(Some(Duration::from_micros(200)), &mut ())
+                .unwrap();
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_micros(200));
+            assert!(event_loop.last_wakeup().timeout);
+            elapsed < Duration::from_micros(900)
+        });
+        assert!(precise);
+    }
+
+    #[test]
+    fn insert_timeout() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        // fires three times, rescheduling itself, then removes itself
+        let start = Instant::now();
+        let token = handle
+            .insert_timeout(start, |deadline, count: &mut u32| {
+                *count += 1;
+                match *count {
+                    1 => TimeoutAction::ToDuration(Duration::from_millis(10)),
+                    2 => TimeoutAction::ToInstant(deadline + Duration::from_millis(10)),
+                    _ => TimeoutAction::Drop,
+                }
+            })
+            .unwrap();
+
+        let mut count = 0;
+        while count < 3 {
+            event_loop.dispatch(None, &mut count).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // the timeout was removed
+        assert_eq!(
+            handle.enable(&token).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        event_loop
+            .dispatch(Some(Duration::from_millis(20)), &mut count)
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn events_capacity() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+        event_loop.set_events_capacity(1);
+
+        let mut readinesses = Vec::new();
+        for _ in 0..3 {
+            let (registration, readiness) = Registration::new2();
+            let mut generic = Generic::new(registration);
+            generic.set_interest(Ready::readable());
+            generic.set_pollopts(PollOpt::edge());
+            handle
+                .insert_source(generic, |_, count: &mut u32| *count += 1)
+                .unwrap();
+            readiness.set_readiness(Ready::readable()).unwrap();
+            readinesses.push(readiness);
+        }
+
+        // all the events are retrieved, one poll at a time
+        let mut count = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn poller_fd() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        use nix::poll::{poll, EventFlags, PollFd};
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        handle
+            .insert_source(generic, |_, dispatched: &mut bool| *dispatched = true)
+            .unwrap();
+
+        let mut fds = [PollFd::new(event_loop.as_raw_fd(), EventFlags::POLLIN)];
+        assert_eq!(poll(&mut fds, 0).unwrap(), 0);
+
+        tx.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(poll(&mut fds, 1000).unwrap(), 1);
+
+        let mut dispatched = false;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(dispatched);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn oneshot_reactivate() {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level() | PollOpt::oneshot());
+        let source = handle
+            .insert_source(generic, |_, count: &mut u32| *count += 1)
+            .unwrap();
+
+        // the data is never read, so the source remains ready
+        tx.write_all(&[1, 2, 3]).unwrap();
+
+        let mut count = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // the source was disarmed by the poll layer
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 1);
+
+        handle.reactivate(&source.token()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn oneshot_mode() {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::readable());
+        // the poll options of the source itself are level-triggered
+        generic.set_pollopts(PollOpt::level());
+        let source = handle
+            .insert_source(generic, |_, count: &mut u32| *count += 1)
+            .unwrap();
+        handle.set_oneshot(&source.token(), true).unwrap();
+
+        // the data is never read, so the source remains ready
+        tx.write_all(&[1, 2, 3]).unwrap();
+
+        let mut count = 0;
+        for _ in 0..2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut count)
+                .unwrap();
+        }
+        assert_eq!(count, 1);
+
+        handle.reactivate(&source.token()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // the mode is kept when the source is disabled and enabled again
+        source.disable().unwrap();
+        source.enable().unwrap();
+        for _ in 0..2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut count)
+                .unwrap();
+        }
+        assert_eq!(count, 3);
+    }
+
+    // a source recording its registration calls
+    struct RecordingSource {
+        registration: Registration,
+        calls: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Evented for RecordingSource {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.calls.borrow_mut().push("register");
+            self.registration.register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.calls.borrow_mut().push("reregister");
+            self.registration.reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            self.calls.borrow_mut().push("deregister");
+            poll.deregister(&self.registration)
+        }
+    }
+
+    impl EventSource for RecordingSource {
+        type Event = ();
+
+        fn interest(&self) -> Ready {
+            Ready::readable()
+        }
+
+        fn pollopts(&self) -> PollOpt {
+            PollOpt::edge()
+        }
+
+        fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+            &self,
+            _: F,
+        ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+            Rc::new(RefCell::new(IdleDispatcher))
+        }
+    }
+
+    struct IdleDispatcher;
+
+    impl<Data> EventDispatcher<Data> for IdleDispatcher {
+        fn ready(&mut self, _: Ready, _: &mut Data) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn batched_registration() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let kept = Rc::new(RefCell::new(Vec::new()));
+        let removed = Rc::new(RefCell::new(Vec::new()));
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        handle
+            .insert_source(generic, {
+                let handle = handle.clone();
+                let (kept, removed) = (kept.clone(), removed.clone());
+                move |_, _: &mut ()| {
+                    let source = handle
+                        .insert_source(
+                            RecordingSource {
+                                registration: Registration::new2().0,
+                                calls: kept.clone(),
+                            },
+                            |(), _: &mut ()| {},
+                        )
+                        .unwrap();
+                    for _ in 0..3 {
+                        source.reregister().unwrap();
+                    }
+                    source.disable().unwrap();
+                    source.enable().unwrap();
+                    let other = handle
+                        .insert_source(
+                            RecordingSource {
+                                registration: Registration::new2().0,
+                                calls: removed.clone(),
+                            },
+                            |(), _: &mut ()| {},
+                        )
+                        .unwrap();
+                    other.remove();
+                    // nothing is registered until the callbacks are done
+                    assert!(kept.borrow().is_empty());
+                }
+            })
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut ())
+            .unwrap();
+        assert_eq!(*kept.borrow(), &["register"]);
+        assert!(removed.borrow().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn batched_registration_error() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let inserted = Rc::new(RefCell::new(None));
+        let (registration, readiness) = Registration::new2();
+        let mut generic = Generic::new(registration);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+        handle
+            .insert_source(generic, {
+                let handle = handle.clone();
+                let inserted = inserted.clone();
+                move |_, _: &mut ()| {
+                    // not a file descriptor, the insertion only fails once applied
+                    let source = handle
+                        .insert_source(Generic::from_raw_fd(-1), |_, _: &mut ()| {})
+                        .unwrap();
+                    *inserted.borrow_mut() = Some(source.token());
+                }
+            })
+            .unwrap();
+
+        readiness.set_readiness(Ready::readable()).unwrap();
+        match event_loop.dispatch(Some(Duration::from_millis(0)), &mut ()) {
+            Err(DispatchError::Source { token, .. }) => {
+                assert_eq!(Some(token), *inserted.borrow());
+            }
+            other => panic!("Unexpected dispatch result: {:?}", other),
+        }
+        // the source stays in the event loop, disabled
+        let token = inserted.borrow().unwrap();
+        assert!(handle.disable(&token).is_ok());
+        handle.remove(token);
+    }
 }
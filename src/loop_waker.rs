@@ -0,0 +1,248 @@
+//! The mechanism waking up the event loop from other threads
+//!
+//! On unix, it is a non-blocking eventfd on Linux and a pipe elsewhere, making the
+//! wakeup async-signal-safe: it is a single `write(2)`, without any lock or allocation.
+//!
+//! The wakeups are coalesced: once the event loop has been woken up, the following
+//! ones are skipped until it drains the waker, so that a producer sending many
+//! messages in a tight loop does not make a system call for each of them.
+
+use std::io;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+#[cfg(all(unix, not(target_os = "linux")))]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+#[cfg(unix)]
+use nix::libc;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+#[cfg(not(unix))]
+use mio::{Registration, SetReadiness};
+
+#[cfg(all(unix, not(target_os = "linux")))]
+use nix_err;
+
+// with an eventfd, there is no separate write end, it is written through the read one
+#[cfg(unix)]
+pub(crate) struct LoopWaker {
+    read: OwnedFd,
+    write: Option<OwnedFd>,
+    // whether a wakeup was written and not drained yet
+    pending: AtomicBool,
+}
+
+#[cfg(unix)]
+impl LoopWaker {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new() -> io::Result<LoopWaker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(LoopWaker {
+            read: unsafe { OwnedFd::from_raw_fd(fd) },
+            write: None,
+            pending: AtomicBool::new(false),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn new() -> io::Result<LoopWaker> {
+        let (read, write) = ::nix::unistd::pipe().map_err(nix_err)?;
+        let waker = LoopWaker {
+            read: unsafe { OwnedFd::from_raw_fd(read) },
+            write: Some(unsafe { OwnedFd::from_raw_fd(write) }),
+            pending: AtomicBool::new(false),
+        };
+        for &fd in &[read, write] {
+            fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(nix_err)?;
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_err)?;
+        }
+        Ok(waker)
+    }
+
+    // this must remain async-signal-safe
+    pub(crate) fn wake(&self) {
+        // atomics are lock-free, so this is async-signal-safe as well
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // an eventfd requires writing a 64-bit counter increment, which a pipe accepts
+        // as well
+        let value = 1u64;
+        // a failing write modifies errno, which must be preserved in a signal handler
+        let errno = errno_location().map(|errno| unsafe { *errno });
+        // if the pipe is full or the counter saturated, the event loop has already
+        // been woken up
+        unsafe {
+            libc::write(
+                self.write.as_ref().unwrap_or(&self.read).as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                ::std::mem::size_of::<u64>(),
+            );
+        }
+        if let (Some(location), Some(errno)) = (errno_location(), errno) {
+            unsafe { *location = errno };
+        }
+    }
+
+    // reading an eventfd resets its counter, a pipe needs to be emptied
+    pub(crate) fn drain(&self) {
+        let mut buffer = [0u8; 64];
+        loop {
+            let ret = unsafe {
+                libc::read(
+                    self.read.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if ret <= 0 {
+                break;
+            }
+        }
+        // cleared once the waker is empty: clearing it before reading would let a
+        // racing wakeup be consumed while keeping it set, skipping all the following
+        // ones. A wakeup skipped between the read and this store is not lost, as the
+        // owner of the waker processes its pending work after draining it.
+        self.pending.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(unix)]
+impl Evented for LoopWaker {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.read.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.read.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.read.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[cfg(unix)]
+fn errno_location() -> Option<*mut libc::c_int> {
+    #[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "dragonfly"))]
+    return Some(unsafe { libc::__errno_location() });
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    return Some(unsafe { libc::__error() });
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    return Some(unsafe { libc::__errno() });
+    #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+    return Some(unsafe { libc::___errno() });
+    #[allow(unreachable_code)]
+    None
+}
+
+#[cfg(not(unix))]
+pub(crate) struct LoopWaker {
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+#[cfg(not(unix))]
+impl LoopWaker {
+    pub(crate) fn new() -> io::Result<LoopWaker> {
+        let (registration, readiness) = Registration::new2();
+        Ok(LoopWaker {
+            registration,
+            readiness,
+        })
+    }
+
+    pub(crate) fn wake(&self) {
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+
+    pub(crate) fn drain(&self) {
+        let _ = self.readiness.set_readiness(Ready::empty());
+    }
+}
+
+#[cfg(not(unix))]
+impl Evented for LoopWaker {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    // the eventfd counter tells how many wakeups were written
+    fn written(waker: &LoopWaker) -> u64 {
+        let mut value = 0u64;
+        let ret = unsafe {
+            libc::read(
+                waker.read.as_raw_fd(),
+                &mut value as *mut u64 as *mut libc::c_void,
+                ::std::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            0
+        } else {
+            value
+        }
+    }
+
+    #[test]
+    fn coalesced_wakeups() {
+        let waker = LoopWaker::new().unwrap();
+        for _ in 0..1000 {
+            waker.wake();
+        }
+        assert_eq!(written(&waker), 1);
+
+        // the wakeups are skipped until the waker is drained
+        waker.wake();
+        assert_eq!(written(&waker), 0);
+
+        // a drained waker is woken up again
+        waker.drain();
+        waker.wake();
+        waker.wake();
+        assert_eq!(written(&waker), 1);
+    }
+}
@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use sources::RegistrationToken;
+
+/// Metrics of an event loop
+///
+/// They are accumulated since they were enabled or last reset with
+/// `EventLoop::reset_metrics()`.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// Number of times the event loop woke up from waiting for events
+    pub wakeups: u64,
+    /// Total time spent waiting for events
+    pub poll_time: Duration,
+    /// Metrics of the individual event sources
+    ///
+    /// Entries of sources removed from the event loop are kept until the
+    /// metrics are reset.
+    pub sources: HashMap<RegistrationToken, SourceMetrics>,
+}
+
+/// Metrics of an individual event source
+#[derive(Clone, Debug, Default)]
+pub struct SourceMetrics {
+    /// Number of readiness events dispatched to this source
+    pub events: u64,
+    /// Total time spent processing these events, callbacks included
+    pub dispatch_time: Duration,
+}
@@ -0,0 +1,74 @@
+//! Sub-millisecond poll timeouts
+//!
+//! `epoll_wait` only accepts timeouts in milliseconds, `mio` rounding them up. When a
+//! more precise timeout is requested, a timerfd armed with it is registered alongside
+//! the sources, waking the poller at the right time.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::time::Duration;
+
+use mio::unix::EventedFd;
+use mio::{Poll, PollOpt, Ready};
+
+use nix::libc;
+
+use list::PRECISE_TIMEOUT_TOKEN;
+
+pub(crate) struct PreciseTimeout {
+    fd: OwnedFd,
+    armed: bool,
+}
+
+impl PreciseTimeout {
+    pub(crate) fn new(poll: &Poll) -> io::Result<PreciseTimeout> {
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timeout = PreciseTimeout {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            armed: false,
+        };
+        // arming the timer again resets its expirations, so it never needs to be read
+        poll.register(
+            &EventedFd(&fd),
+            PRECISE_TIMEOUT_TOKEN,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        Ok(timeout)
+    }
+
+    // arm the timer if the timeout is not a whole number of milliseconds, otherwise
+    // make sure it is disarmed, so that it does not wake up a later poll
+    pub(crate) fn set(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.filter(|t| t.subsec_nanos() % 1_000_000 != 0);
+        if timeout.is_none() && !self.armed {
+            return Ok(());
+        }
+        let value = timeout.unwrap_or_default();
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: value.as_secs() as libc::time_t,
+                tv_nsec: value.subsec_nanos() as libc::c_long,
+            },
+        };
+        let ret = unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), 0, &spec, ptr::null_mut()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.armed = timeout.is_some();
+        Ok(())
+    }
+}
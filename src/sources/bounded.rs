@@ -0,0 +1,411 @@
+//! A bounded channel with a configurable overflow policy
+//!
+//! Create a channel using `channel::<T>(bound, overflow)`, which returns a `Sender<T>`
+//! that can be cloned and sent accross threads if `T: Send`, and a `Receiver<T>` to be
+//! inserted into an `EventLoop`. At most `bound` messages are waiting in the channel,
+//! and the `Overflow` policy decides what sending one more does.
+//!
+//! Dropping the oldest messages gives the channel ring-buffer semantics, where the
+//! latest values win, as needed to deliver telemetry or state updates.
+//!
+//! With the `Overflow::Block` policy, futures can send with `Sender::send_async()`
+//! rather than blocking their thread: the returned future completes once the event
+//! loop made room for the message.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::TrySendError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll as TaskPoll, Waker};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::Event;
+use sources::ping::{make_ping, Ping, PingSource};
+use {EventDispatcher, EventSource};
+
+/// What sending a message on a full channel does
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Block until the event loop receives a message
+    Block,
+    /// Fail with `TrySendError::Full`, giving the message back
+    Fail,
+    /// Drop the message being sent
+    DropNewest,
+    /// Drop the oldest message waiting in the channel
+    DropOldest,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    // whether the receiver was dropped
+    disconnected: bool,
+    // the tasks waiting for room in the queue
+    send_wakers: Vec<Waker>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_full: Condvar,
+    bound: usize,
+    overflow: Overflow,
+    ping: Ping,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+/// Create a new bounded channel
+///
+/// **Panics** if `bound` is zero.
+pub fn channel<T>(bound: usize, overflow: Overflow) -> io::Result<(Sender<T>, Receiver<T>)> {
+    assert!(
+        bound > 0,
+        "[calloop] The bound of a channel cannot be zero."
+    );
+    let (ping, source) = make_ping()?;
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(bound),
+            disconnected: false,
+            send_wakers: Vec::new(),
+        }),
+        not_full: Condvar::new(),
+        bound,
+        overflow,
+        ping,
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, source },
+    ))
+}
+
+/// The sending end of a bounded channel
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message to the event loop
+    ///
+    /// If the channel is full, the `Overflow` policy it was created with applies. It
+    /// fails with `TrySendError::Disconnected` if the receiver has been dropped,
+    /// giving the message back.
+    pub fn send(&self, mut msg: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            match self.push(&mut state, msg) {
+                Ok(ret) => return ret,
+                Err(back) => msg = back,
+            }
+            state = self.shared.not_full.wait(state).unwrap();
+        }
+    }
+
+    /// Send a message to the event loop from a future
+    ///
+    /// Same as `send()`, but rather than blocking while the channel is full with the
+    /// `Overflow::Block` policy, the returned future waits for the event loop to
+    /// receive a message. The other policies never wait.
+    pub fn send_async(&self, msg: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            msg: Some(msg),
+        }
+    }
+
+    // returns the message back if it must wait for room in the queue
+    fn push(&self, state: &mut State<T>, msg: T) -> Result<Result<(), TrySendError<T>>, T> {
+        if state.disconnected {
+            return Ok(Err(TrySendError::Disconnected(msg)));
+        }
+        while state.queue.len() >= self.shared.bound {
+            match self.shared.overflow {
+                Overflow::Block => return Err(msg),
+                Overflow::Fail => return Ok(Err(TrySendError::Full(msg))),
+                Overflow::DropNewest => return Ok(Ok(())),
+                Overflow::DropOldest => {
+                    state.queue.pop_front();
+                }
+            }
+        }
+        state.queue.push_back(msg);
+        self.shared.ping.ping();
+        Ok(Ok(()))
+    }
+}
+
+/// A future sending a message on a bounded channel
+///
+/// It is returned by `Sender::send_async()`, and outputs the result of the send.
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    msg: Option<T>,
+}
+
+// the message is never pinned
+impl<'a, T> Unpin for SendFuture<'a, T> {}
+
+impl<'a, T> Future for SendFuture<'a, T> {
+    type Output = Result<(), TrySendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> TaskPoll<Self::Output> {
+        let this = self.get_mut();
+        let msg = match this.msg.take() {
+            Some(msg) => msg,
+            None => panic!("[calloop] SendFuture polled after completion."),
+        };
+        let mut state = this.sender.shared.state.lock().unwrap();
+        match this.sender.push(&mut state, msg) {
+            Ok(ret) => TaskPoll::Ready(ret),
+            Err(back) => {
+                this.msg = Some(back);
+                if !state.send_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.send_wakers.push(cx.waker().clone());
+                }
+                TaskPoll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.closed.store(true, Ordering::Release);
+            self.shared.ping.ping();
+        }
+    }
+}
+
+/// The receiving end of a bounded channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` for each message, and an `Event::Closed` once all the senders have
+/// been dropped and there are no messages left.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    source: PingSource,
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.disconnected = true;
+            state.queue.clear();
+            mem::take(&mut state.send_wakers)
+        };
+        self.shared.not_full.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Evented for Receiver<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.source.deregister(poll)
+    }
+}
+
+impl<T: 'static> EventSource for Receiver<T> {
+    type Event = Event<T>;
+
+    fn interest(&self) -> Ready {
+        self.source.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.source.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let shared = self.shared.clone();
+        let mut closed = false;
+        self.source.make_dispatcher(move |(), data: &mut Data| {
+            // read before the queue is drained, so that a message sent right before
+            // the closing is never delivered after it
+            let disconnected = shared.closed.load(Ordering::Acquire);
+            // the lock must not be held while the callback runs, as it may send
+            // new messages
+            loop {
+                let (msg, wakers) = {
+                    let mut state = shared.state.lock().unwrap();
+                    let msg = state.queue.pop_front();
+                    // all the waiting tasks are woken, as some may have been dropped
+                    let wakers = match msg {
+                        Some(_) => mem::take(&mut state.send_wakers),
+                        None => Vec::new(),
+                    };
+                    (msg, wakers)
+                };
+                match msg {
+                    Some(msg) => {
+                        shared.not_full.notify_one();
+                        for waker in wakers {
+                            waker.wake();
+                        }
+                        callback(Event::Msg(msg), data);
+                    }
+                    None => break,
+                }
+            }
+            if !closed && disconnected {
+                closed = true;
+                callback(Event::Closed, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn receive(event_loop: &mut ::EventLoop<Vec<u32>>, receiver: Receiver<u32>) -> Vec<u32> {
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg);
+                }
+            })
+            .unwrap();
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        received
+    }
+
+    #[test]
+    fn overflow_drop() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<u32>(2, Overflow::DropNewest).unwrap();
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(receive(&mut event_loop, receiver), vec![0, 1]);
+
+        let (sender, receiver) = channel::<u32>(2, Overflow::DropOldest).unwrap();
+        for i in 0..4 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(receive(&mut event_loop, receiver), vec![2, 3]);
+    }
+
+    #[test]
+    fn overflow_fail() {
+        let (sender, receiver) = channel::<u32>(1, Overflow::Fail).unwrap();
+        sender.send(1).unwrap();
+        assert_eq!(sender.send(2), Err(TrySendError::Full(2)));
+
+        ::std::mem::drop(receiver);
+        assert_eq!(sender.send(3), Err(TrySendError::Disconnected(3)));
+    }
+
+    #[test]
+    fn overflow_block() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<u32>(1, Overflow::Block).unwrap();
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg);
+                }
+            })
+            .unwrap();
+
+        // the second send blocks until the event loop receives the first message
+        let producer = thread::spawn(move || {
+            sender.send(1).unwrap();
+            sender.send(2).unwrap();
+        });
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(10)), &mut received)
+                .unwrap();
+        }
+        producer.join().unwrap();
+        assert_eq!(received, vec![1, 2]);
+    }
+
+    #[test]
+    fn send_async() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<u32>(1, Overflow::Block).unwrap();
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg);
+                }
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        sender.send(1).unwrap();
+        // the channel is full, the send waits for the event loop to receive a message
+        event_loop
+            .block_on(sender.send_async(2), &mut received)
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, vec![1]);
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![1, 2]);
+    }
+}
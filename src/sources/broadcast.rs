@@ -0,0 +1,258 @@
+//! A broadcast channel delivering each message to several event loops
+//!
+//! Create a channel using `channel::<T>()`, which returns a `Sender<T>` and a
+//! `Receiver<T>`. Additional receivers are created with `Receiver::try_clone()`, and
+//! can be inserted into other event loops, possibly on other threads. Every receiver
+//! gets a clone of each message sent after it was created.
+//!
+//! This allows fanning out configuration changes or input events to several
+//! subsystems, each running its own event loop.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::Event;
+use sources::ping::{make_ping, Ping, PingSource};
+use {EventDispatcher, EventSource};
+
+type Queue<T> = Arc<Mutex<VecDeque<T>>>;
+
+struct Subscriber<T> {
+    id: u64,
+    queue: Queue<T>,
+    ping: Ping,
+}
+
+struct Shared<T> {
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+    next_id: AtomicU64,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+/// Create a new broadcast channel
+pub fn channel<T: Clone>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let shared = Arc::new(Shared {
+        subscribers: Mutex::new(Vec::new()),
+        next_id: AtomicU64::new(0),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    let receiver = Receiver::new(shared.clone())?;
+    Ok((Sender { shared }, receiver))
+}
+
+/// The sending end of a broadcast channel
+///
+/// It can be cloned and sent accross threads if `T: Send`.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send a message to all the receivers
+    ///
+    /// It fails if all the receivers have been dropped, giving the message back.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        let subscribers = self.shared.subscribers.lock().unwrap();
+        let (last, others) = match subscribers.split_last() {
+            Some(split) => split,
+            None => return Err(SendError(msg)),
+        };
+        for subscriber in others {
+            subscriber.queue.lock().unwrap().push_back(msg.clone());
+            subscriber.ping.ping();
+        }
+        last.queue.lock().unwrap().push_back(msg);
+        last.ping.ping();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.closed.store(true, Ordering::Release);
+            for subscriber in self.shared.subscribers.lock().unwrap().iter() {
+                subscriber.ping.ping();
+            }
+        }
+    }
+}
+
+/// A receiving end of a broadcast channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` for each message, and an `Event::Closed` once all the senders have
+/// been dropped and there are no messages left.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    id: u64,
+    queue: Queue<T>,
+    source: PingSource,
+}
+
+impl<T> Receiver<T> {
+    fn new(shared: Arc<Shared<T>>) -> io::Result<Receiver<T>> {
+        let (ping, source) = make_ping()?;
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        shared.subscribers.lock().unwrap().push(Subscriber {
+            id,
+            queue: queue.clone(),
+            ping,
+        });
+        Ok(Receiver {
+            shared,
+            id,
+            queue,
+            source,
+        })
+    }
+
+    /// Create an other receiver for the same channel
+    ///
+    /// It receives the messages sent from now on.
+    pub fn try_clone(&self) -> io::Result<Receiver<T>> {
+        Receiver::new(self.shared.clone())
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.id != self.id);
+    }
+}
+
+impl<T> Evented for Receiver<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.source.deregister(poll)
+    }
+}
+
+impl<T: 'static> EventSource for Receiver<T> {
+    type Event = Event<T>;
+
+    fn interest(&self) -> Ready {
+        self.source.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.source.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let shared = self.shared.clone();
+        let queue = self.queue.clone();
+        let mut closed = false;
+        self.source.make_dispatcher(move |(), data: &mut Data| {
+            // read before the queue is drained, so that a message sent right before
+            // the closing is never delivered after it
+            let disconnected = shared.closed.load(Ordering::Acquire);
+            // the lock must not be held while the callback runs, as it may send
+            // new messages
+            loop {
+                let msg = queue.lock().unwrap().pop_front();
+                match msg {
+                    Some(msg) => callback(Event::Msg(msg), data),
+                    None => break,
+                }
+            }
+            if !closed && disconnected {
+                closed = true;
+                callback(Event::Closed, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn broadcast() {
+        let mut loop1 = ::EventLoop::new().unwrap();
+        let mut loop2 = ::EventLoop::new().unwrap();
+
+        let (sender, receiver1) = channel::<u32>().unwrap();
+        let receiver2 = receiver1.try_clone().unwrap();
+        for (event_loop, receiver) in [(&loop1, receiver1), (&loop2, receiver2)] {
+            event_loop
+                .handle()
+                .insert_source(receiver, |evt, received: &mut Vec<Option<u32>>| match evt {
+                    Event::Msg(msg) => received.push(Some(msg)),
+                    Event::Closed => received.push(None),
+                })
+                .unwrap();
+        }
+
+        let mut received1 = Vec::new();
+        let mut received2 = Vec::new();
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        ::std::mem::drop(sender);
+        loop1
+            .dispatch(Some(Duration::from_millis(0)), &mut received1)
+            .unwrap();
+        loop2
+            .dispatch(Some(Duration::from_millis(0)), &mut received2)
+            .unwrap();
+        assert_eq!(received1, &[Some(1), Some(2), None]);
+        assert_eq!(received2, &[Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn late_receiver() {
+        let (sender, receiver1) = channel::<u32>().unwrap();
+        sender.send(1).unwrap();
+        let receiver2 = receiver1.try_clone().unwrap();
+        assert_eq!(receiver1.queue.lock().unwrap().len(), 1);
+        assert!(receiver2.queue.lock().unwrap().is_empty());
+
+        ::std::mem::drop((receiver1, receiver2));
+        assert_eq!(sender.send(2), Err(SendError(2)));
+    }
+}
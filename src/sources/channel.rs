@@ -1,14 +1,31 @@
 //! An MPSC channel whose receiving end is an event source
 //!
-//! Create a channel using `Channel::<T>::new()`, which returns a
+//! Create a channel using `channel::<T>()`, which returns a
 //! `Sender<T>` that can be cloned and sent accross threads if `T: Send`,
 //! and a `Channel<T>` that can be inserted into an `EventLoop`. It will generate
 //! one event per message.
 //!
+//! The queue of a channel created with `channel()` is unbounded. To prevent a fast
+//! producer from exhausting the memory of a slow event loop, use a bounded channel
+//! created with `sync_channel()` instead: `SyncSender::send()` then blocks while the
+//! queue is full, and `SyncSender::try_send()` returns `TrySendError::Full`.
+//! The `bounded` module provides a channel dropping messages when full instead, and
+//! an async `Sender::send_async()` waiting for room in the queue.
+//!
+//! Under high message rates, calling the callback for each message can cost more than
+//! processing them. `Channel::batched()` turns the channel into a `BatchedChannel`,
+//! whose callback receives all the pending messages at once.
+//!
+//! Once the `Channel` is dropped, because its source was removed from the event loop
+//! or the event loop itself was dropped, sending fails with `SendError::Disconnected`,
+//! giving the message back. The producer threads can use this to shut themselves down.
+//! Note that the `Source` returned by `insert_source()` keeps the `Channel` alive, as
+//! `Source::remove()` gives it back.
+//!
 //! This implementation is based on
 //! [`mio_more::channel`](https://docs.rs/mio-more/*/mio_more/channel/index.html).
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io;
 use std::rc::Rc;
 use std::sync::mpsc::TryRecvError;
@@ -28,6 +45,10 @@ pub enum Event<T> {
     ///
     /// This means all the `Sender`s associated with this channel
     /// have been dropped, no more messages will ever be received.
+    ///
+    /// This event is generated once, after all the messages that were sent have been
+    /// received. The callback can return `PostAction::Remove` to remove the source
+    /// from the event loop.
     Closed,
 }
 
@@ -36,6 +57,9 @@ pub enum Event<T> {
 /// This is the event source to be inserted into your `EventLoop`.
 pub struct Channel<T> {
     receiver: Rc<Receiver<T>>,
+    // `mio_extras` receivers cannot be registered twice, so we need to track
+    // whether this one has already been, to reregister it instead
+    registered: Cell<bool>,
 }
 
 /// Create a new asynchronous channel
@@ -45,21 +69,73 @@ pub fn channel<T>() -> (Sender<T>, Channel<T>) {
         sender,
         Channel {
             receiver: Rc::new(receiver),
+            registered: Cell::new(false),
         },
     )
 }
 
 /// Create a new synchronous, bounded channel
+///
+/// At most `bound` messages can be waiting in the channel, sending more blocks until
+/// the event loop receives some of them. The `bound` must not be zero: the event loop
+/// is only woken up once a message is queued, so every send would block forever.
 pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Channel<T>) {
     let (sender, receiver) = miochan::sync_channel(bound);
     (
         sender,
         Channel {
             receiver: Rc::new(receiver),
+            registered: Cell::new(false),
         },
     )
 }
 
+/// Turn an existing receiver into a channel event source
+///
+/// This allows inserting a channel created with `std::sync::mpsc` into an event loop,
+/// without changing its producers. See `from_receiver()`.
+pub fn from_std_receiver<T: Send + 'static>(
+    receiver: ::std::sync::mpsc::Receiver<T>,
+) -> io::Result<Channel<T>> {
+    from_receiver(receiver)
+}
+
+/// Turn a blocking receiver into a channel event source
+///
+/// The receiver can be anything iterating over the messages, blocking until the next
+/// one, like the receivers of `std::sync::mpsc` or `crossbeam-channel`. As their
+/// producers do not wake the event loop up, a thread is spawned to forward the
+/// messages to the returned `Channel`. As it only holds one message at a time, the
+/// backpressure of a bounded receiver is kept.
+///
+/// The `Channel` is closed once the iteration ends, typically when all the senders of
+/// the receiver have been dropped. Conversely, the receiver is dropped when the
+/// thread notices the `Channel` was, upon forwarding a message.
+pub fn from_receiver<I>(receiver: I) -> io::Result<Channel<I::Item>>
+where
+    I: IntoIterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    let (sender, channel) = sync_channel(1);
+    ::std::thread::Builder::new()
+        .name("calloop-channel-forwarder".into())
+        .spawn(move || {
+            for msg in receiver {
+                if sender.send(msg).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(channel)
+}
+
+impl<T> Channel<T> {
+    /// Turn this channel into one delivering its messages in batches
+    pub fn batched(self) -> BatchedChannel<T> {
+        BatchedChannel { channel: self }
+    }
+}
+
 impl<T> Evented for Channel<T> {
     fn register(
         &self,
@@ -68,7 +144,13 @@ impl<T> Evented for Channel<T> {
         interest: Ready,
         opts: PollOpt,
     ) -> io::Result<()> {
-        self.receiver.register(poll, token, interest, opts)
+        if self.registered.get() {
+            self.receiver.reregister(poll, token, interest, opts)
+        } else {
+            self.receiver.register(poll, token, interest, opts)?;
+            self.registered.set(true);
+            Ok(())
+        }
     }
 
     fn reregister(
@@ -97,13 +179,14 @@ impl<T: 'static> EventSource for Channel<T> {
         PollOpt::edge()
     }
 
-    fn make_dispatcher<Data: 'static, F: FnMut(Event<T>, &mut Data) + 'static>(
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
         &self,
         callback: F,
-    ) -> Rc<RefCell<EventDispatcher<Data>>> {
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
         Rc::new(RefCell::new(Dispatcher {
             _data: ::std::marker::PhantomData,
             receiver: self.receiver.clone(),
+            closed: false,
             callback,
         }))
     }
@@ -112,21 +195,122 @@ impl<T: 'static> EventSource for Channel<T> {
 struct Dispatcher<Data, T, F: FnMut(Event<T>, &mut Data)> {
     _data: ::std::marker::PhantomData<fn(&mut Data)>,
     receiver: Rc<Receiver<T>>,
+    closed: bool,
     callback: F,
 }
 
 impl<Data, T, F: FnMut(Event<T>, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, T, F> {
-    fn ready(&mut self, _: Ready, data: &mut Data) {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
         loop {
             match self.receiver.try_recv() {
                 Ok(val) => (self.callback)(Event::Msg(val), data),
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
-                    (self.callback)(Event::Closed, data);
+                    if !self.closed {
+                        self.closed = true;
+                        (self.callback)(Event::Closed, data);
+                    }
                     break;
                 }
             }
         }
+        Ok(())
+    }
+}
+
+/// A channel delivering its messages in batches
+///
+/// Each time the event loop is woken up by the channel, its callback receives an
+/// `Event::Msg` with all the messages that are pending, in the order they were sent.
+/// Senders only wake up the event loop for the first message of each batch.
+pub struct BatchedChannel<T> {
+    channel: Channel<T>,
+}
+
+impl<T> BatchedChannel<T> {
+    /// Turn this channel back into one delivering its messages one by one
+    pub fn into_inner(self) -> Channel<T> {
+        self.channel
+    }
+}
+
+impl<T> Evented for BatchedChannel<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.channel.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.channel.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.channel.deregister(poll)
+    }
+}
+
+impl<T: 'static> EventSource for BatchedChannel<T> {
+    type Event = Event<Vec<T>>;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<Vec<T>>, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(BatchedDispatcher {
+            _data: ::std::marker::PhantomData,
+            receiver: self.channel.receiver.clone(),
+            closed: false,
+            callback,
+        }))
+    }
+}
+
+struct BatchedDispatcher<Data, T, F: FnMut(Event<Vec<T>>, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    receiver: Rc<Receiver<T>>,
+    closed: bool,
+    callback: F,
+}
+
+impl<Data, T, F: FnMut(Event<Vec<T>>, &mut Data)> EventDispatcher<Data>
+    for BatchedDispatcher<Data, T, F>
+{
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut batch = Vec::new();
+        let disconnected = loop {
+            match self.receiver.try_recv() {
+                Ok(val) => batch.push(val),
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        if !batch.is_empty() {
+            (self.callback)(Event::Msg(batch), data);
+        }
+        if disconnected && !self.closed {
+            self.closed = true;
+            (self.callback)(Event::Closed, data);
+        }
+        Ok(())
     }
 }
 
@@ -153,7 +337,8 @@ mod tests {
                 Event::Closed => {
                     got.1 = true;
                 }
-            }).map_err(Into::<io::Error>::into)
+            })
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
         // nothing is sent, nothing is received
@@ -179,4 +364,223 @@ mod tests {
 
         assert_eq!(got, (true, true));
     }
+
+    #[test]
+    fn closed_channel() {
+        use sources::PostAction;
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx, rx) = channel::<u32>();
+
+        let mut received = Vec::new();
+
+        let source = handle
+            .insert_source(rx, move |evt, received: &mut Vec<Option<u32>>| match evt {
+                Event::Msg(val) => {
+                    received.push(Some(val));
+                    PostAction::Continue
+                }
+                Event::Closed => {
+                    received.push(None);
+                    PostAction::Remove
+                }
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        // the pending messages are received before the closing
+        tx.send(1).unwrap();
+        ::std::mem::drop(tx);
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, &[Some(1), None]);
+
+        // the source removed itself
+        assert!(handle.enable(&source.token()).is_err());
+    }
+
+    #[test]
+    fn disconnected_channel() {
+        let event_loop = ::EventLoop::<()>::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx1, rx1) = channel::<u32>();
+        let (tx2, rx2) = sync_channel::<u32>(1);
+
+        let token = handle
+            .insert_source(rx1, |_, _| {})
+            .map_err(Into::<io::Error>::into)
+            .unwrap()
+            .token();
+        handle
+            .insert_source(rx2, |_, _| {})
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        // the message is given back once the source is removed
+        tx1.send(1).unwrap();
+        handle.remove(token);
+        match tx1.send(2) {
+            Err(SendError::Disconnected(2)) => {}
+            _ => panic!("the channel should be disconnected"),
+        }
+
+        // or once the event loop is dropped
+        tx2.send(1).unwrap();
+        ::std::mem::drop(event_loop);
+        match tx2.try_send(2) {
+            Err(TrySendError::Disconnected(2)) => {}
+            _ => panic!("the channel should be disconnected"),
+        }
+    }
+
+    #[test]
+    fn std_channel() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx, rx) = ::std::sync::mpsc::channel::<u32>();
+
+        let mut received = Vec::new();
+
+        let _source = handle
+            .insert_source(
+                from_std_receiver(rx).unwrap(),
+                move |evt, received: &mut Vec<Option<u32>>| match evt {
+                    Event::Msg(val) => received.push(Some(val)),
+                    Event::Closed => received.push(None),
+                },
+            )
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        ::std::mem::drop(tx);
+        while received.len() < 3 {
+            event_loop
+                .dispatch(Some(::std::time::Duration::from_millis(10)), &mut received)
+                .unwrap();
+        }
+        assert_eq!(received, &[Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn batched_channel() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx, rx) = channel::<u32>();
+
+        let mut batches = Vec::new();
+
+        let _source = handle
+            .insert_source(rx.batched(), move |evt, batches: &mut Vec<Vec<u32>>| {
+                if let Event::Msg(batch) = evt {
+                    batches.push(batch);
+                }
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut batches)
+            .unwrap();
+        assert_eq!(batches, vec![vec![0, 1, 2]]);
+
+        tx.send(3).unwrap();
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut batches)
+            .unwrap();
+        assert_eq!(batches, vec![vec![0, 1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn bounded_channel() {
+        use std::thread;
+        use std::time::Duration;
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx, rx) = sync_channel::<u32>(2);
+
+        let mut received = Vec::new();
+
+        let _source = handle
+            .insert_source(rx, move |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(val) = evt {
+                    received.push(val);
+                }
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        // the queue is full
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        match tx.try_send(3) {
+            Err(TrySendError::Full(3)) => {}
+            _ => panic!("the channel should be full"),
+        }
+
+        // a blocked sender resumes once the event loop receives the messages
+        let sender = thread::spawn(move || tx.send(3).unwrap());
+        while received.len() < 3 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(10)), &mut received)
+                .unwrap();
+        }
+        sender.join().unwrap();
+        assert_eq!(received, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn disable_enable_channel() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let handle = event_loop.handle();
+
+        let (tx, rx) = channel::<u32>();
+
+        let mut received = Vec::new();
+
+        let source = handle
+            .insert_source(rx, move |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(val) = evt {
+                    received.push(val);
+                }
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        source.disable().unwrap();
+
+        // messages sent while the source is disabled are not delivered
+        tx.send(1).unwrap();
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut received)
+            .unwrap();
+
+        assert!(received.is_empty());
+
+        // they are once it is enabled again
+        source.enable().unwrap();
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut received)
+            .unwrap();
+
+        assert_eq!(received, &[1]);
+    }
 }
@@ -0,0 +1,225 @@
+//! A wrapper source coalescing the bursts of events of an other source
+//!
+//! A `Debounce<S>` wraps an event source and holds back its events until it has been
+//! quiet for a configured duration. Only the last event of each burst is then given to
+//! the callback, the previous ones being dropped. This is typically used to avoid
+//! triggering some expensive processing, like a re-layout, for each event of a storm
+//! of file change or resize notifications.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// A source forwarding the last event of each burst of an other one
+///
+/// A burst ends when the inner source did not generate any event during the quiet
+/// period given to `Debounce::new()`.
+pub struct Debounce<S> {
+    inner: S,
+    quiet: Duration,
+    deadline: Rc<Cell<Option<Instant>>>,
+    registered: Cell<bool>,
+}
+
+impl<S: EventSource> Debounce<S> {
+    /// Wrap a source, with the quiet period ending its bursts
+    pub fn new(source: S, quiet: Duration) -> Debounce<S> {
+        Debounce {
+            inner: source,
+            quiet,
+            deadline: Rc::new(Cell::new(None)),
+            registered: Cell::new(false),
+        }
+    }
+
+    /// Access the inner source
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the inner source
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner source
+    ///
+    /// The event of a burst which did not end yet is lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: EventSource> Evented for Debounce<S> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)?;
+        self.registered.set(true);
+        Ok(())
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)?;
+        self.registered.set(true);
+        Ok(())
+    }
+
+    // the pending event of a disabled source is delivered once it is enabled again
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registered.set(false);
+        self.inner.deregister(poll)
+    }
+}
+
+impl<S: EventSource> EventSource for Debounce<S>
+where
+    S::Event: 'static,
+{
+    type Event = S::Event;
+
+    fn interest(&self) -> Ready {
+        self.inner.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.inner.pollopts()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let deadline = if self.registered.get() {
+            self.deadline.get()
+        } else {
+            None
+        };
+        match (self.inner.next_deadline(), deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn before_removal(&mut self) {
+        self.inner.before_removal();
+    }
+
+    fn before_sleep(&mut self, timeout: Option<Duration>) {
+        self.inner.before_sleep(timeout);
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(S::Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let pending = Rc::new(RefCell::new(None));
+        let inner_pending = pending.clone();
+        let deadline = self.deadline.clone();
+        let quiet = self.quiet;
+        let inner = self.inner.make_dispatcher(move |event, _: &mut Data| {
+            *inner_pending.borrow_mut() = Some(event);
+            deadline.set(Some(Instant::now() + quiet));
+        });
+        Rc::new(RefCell::new(Dispatcher {
+            inner,
+            deadline: self.deadline.clone(),
+            pending,
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data, E, F> {
+    inner: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    deadline: Rc<Cell<Option<Instant>>>,
+    // the last event of the current burst
+    pending: Rc<RefCell<Option<E>>>,
+    callback: F,
+}
+
+impl<'l, Data, E, F: FnMut(E, &mut Data)> EventDispatcher<Data> for Dispatcher<'l, Data, E, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.ready_sub(ready, 0, data)
+    }
+
+    fn ready_sub(&mut self, ready: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        let expired = || {
+            self.deadline
+                .get()
+                .map_or(false, |deadline| deadline <= Instant::now())
+        };
+        // without readiness, this source was dispatched because of a deadline, which
+        // is the one of the inner source if it was not its own
+        if !ready.is_empty() || !expired() {
+            self.inner.borrow_mut().ready_sub(ready, sub_id, data)?;
+        }
+        // new events of the inner source extend the burst
+        if expired() {
+            self.deadline.set(None);
+            let event = self.pending.borrow_mut().take();
+            if let Some(event) = event {
+                (self.callback)(event, data);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use sources::channel::{channel, Event};
+
+    #[test]
+    fn debounce() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, source) = channel();
+        let source = Debounce::new(source, Duration::from_millis(50));
+        event_loop
+            .handle()
+            .insert_source(source, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg)
+                }
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+        for i in 0..3 {
+            sender.send(i).unwrap();
+            event_loop
+                .dispatch(Some(Duration::from_millis(10)), &mut received)
+                .unwrap();
+        }
+        assert!(received.is_empty());
+
+        // only the last message of the burst is delivered, after the quiet period
+        while received.is_empty() {
+            event_loop.dispatch(None, &mut received).unwrap();
+        }
+        assert_eq!(received, vec![2]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(60)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![2]);
+    }
+}
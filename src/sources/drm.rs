@@ -0,0 +1,301 @@
+//! Event source for the events of DRM devices
+//!
+//! Only available on Linux.
+//!
+//! The kernel reports the vertical blanks and completed page flips requested on a DRM
+//! device, like `/dev/dri/card0`, as a stream of events read from its file
+//! descriptor. A `DrmEvents` source reads this stream and generates a typed `Event`
+//! for each of them, carrying the `user_data` given with the request, so that a
+//! compositor can render its next frame once the previous one is on screen.
+//!
+//! The source does not own the file descriptor: the device is kept open by the
+//! program, which uses it to perform the mode setting and page flips, and must
+//! outlive the source.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+const DRM_EVENT_VBLANK: u32 = 0x01;
+const DRM_EVENT_FLIP_COMPLETE: u32 = 0x02;
+const DRM_EVENT_CRTC_SEQUENCE: u32 = 0x03;
+
+// the size of the header of an event
+const HEADER_LEN: usize = 8;
+
+/// The data of a vertical blank or of a completed page flip
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VblankEvent {
+    /// The data given with the request
+    pub user_data: u64,
+    /// The time of the vertical blank, on the monotonic clock
+    pub time: Duration,
+    /// The sequence number of the vertical blank
+    pub sequence: u32,
+    /// The CRTC of the event, or 0 for old kernels
+    pub crtc_id: u32,
+}
+
+/// An event of a DRM device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A vertical blank requested with `DRM_IOCTL_WAIT_VBLANK` occurred
+    Vblank(VblankEvent),
+    /// A page flip requested with `DRM_MODE_PAGE_FLIP_EVENT` was completed
+    PageFlipComplete(VblankEvent),
+    /// A sequence requested with `DRM_IOCTL_CRTC_QUEUE_SEQUENCE` was reached
+    CrtcSequence {
+        /// The data given with the request
+        user_data: u64,
+        /// The time of the vertical blank, on the monotonic clock
+        time: Duration,
+        /// The sequence number of the vertical blank
+        sequence: u64,
+    },
+    /// An event of an other type, with its content after the header
+    Unknown {
+        /// The type of the event
+        kind: u32,
+        /// The content of the event
+        data: Vec<u8>,
+    },
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+// parse the events of a read, stopping at the first malformed one
+fn parse(mut data: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    while data.len() >= HEADER_LEN {
+        let kind = u32_at(data, 0);
+        let len = u32_at(data, 4) as usize;
+        if len < HEADER_LEN || len > data.len() {
+            break;
+        }
+        let event = &data[..len];
+        let vblank = || VblankEvent {
+            user_data: u64_at(event, 8),
+            time: Duration::new(
+                u64::from(u32_at(event, 16)),
+                u32_at(event, 20).min(999_999) * 1000,
+            ),
+            sequence: u32_at(event, 24),
+            crtc_id: u32_at(event, 28),
+        };
+        events.push(match kind {
+            DRM_EVENT_VBLANK if len >= 32 => Event::Vblank(vblank()),
+            DRM_EVENT_FLIP_COMPLETE if len >= 32 => Event::PageFlipComplete(vblank()),
+            DRM_EVENT_CRTC_SEQUENCE if len >= 32 => Event::CrtcSequence {
+                user_data: u64_at(event, 8),
+                time: Duration::from_nanos(u64_at(event, 16)),
+                sequence: u64_at(event, 24),
+            },
+            kind => Event::Unknown {
+                kind,
+                data: event[HEADER_LEN..].to_vec(),
+            },
+        });
+        data = &data[len..];
+    }
+    events
+}
+
+/// An event source reading the events of a DRM device
+///
+/// It generates an `Event` for each event read from the device.
+pub struct DrmEvents {
+    fd: RawFd,
+}
+
+impl DrmEvents {
+    /// Read the events of the DRM device with the given file descriptor
+    ///
+    /// It is switched to non-blocking mode, but not closed when the source is
+    /// dropped.
+    pub fn new(fd: RawFd) -> io::Result<DrmEvents> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DrmEvents { fd })
+    }
+}
+
+impl AsRawFd for DrmEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Evented for DrmEvents {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl EventSource for DrmEvents {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd,
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: RawFd,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // the kernel only returns whole events, as many as fit
+        let mut buffer = [0u8; 1024];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            if len == 0 {
+                return Ok(());
+            }
+            for event in parse(&buffer[..len as usize]) {
+                (self.callback)(event, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn vblank(kind: u32, user_data: u64, sequence: u32) -> Vec<u8> {
+        let mut event = Vec::new();
+        event.extend_from_slice(&kind.to_ne_bytes());
+        event.extend_from_slice(&32u32.to_ne_bytes());
+        event.extend_from_slice(&user_data.to_ne_bytes());
+        event.extend_from_slice(&5u32.to_ne_bytes());
+        event.extend_from_slice(&250u32.to_ne_bytes());
+        event.extend_from_slice(&sequence.to_ne_bytes());
+        event.extend_from_slice(&42u32.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn events() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // a pipe carries the events as a device would
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let source = DrmEvents::new(read).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |event, events: &mut Vec<Event>| events.push(event))
+            .unwrap();
+
+        let mut stream = vblank(DRM_EVENT_VBLANK, 7, 100);
+        stream.extend(vblank(DRM_EVENT_FLIP_COMPLETE, 8, 101));
+        stream.extend_from_slice(&0x8000_0000u32.to_ne_bytes());
+        stream.extend_from_slice(&12u32.to_ne_bytes());
+        stream.extend_from_slice(b"abcd");
+        ::nix::unistd::write(write, &stream).unwrap();
+
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        let time = Duration::new(5, 250_000);
+        assert_eq!(
+            events,
+            &[
+                Event::Vblank(VblankEvent {
+                    user_data: 7,
+                    time,
+                    sequence: 100,
+                    crtc_id: 42,
+                }),
+                Event::PageFlipComplete(VblankEvent {
+                    user_data: 8,
+                    time,
+                    sequence: 101,
+                    crtc_id: 42,
+                }),
+                Event::Unknown {
+                    kind: 0x8000_0000,
+                    data: b"abcd".to_vec(),
+                },
+            ]
+        );
+
+        ::nix::unistd::close(write).unwrap();
+        ::nix::unistd::close(read).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+//! Event source for evdev input devices
+//!
+//! Only available on Linux, with the `evdev` cargo feature.
+//!
+//! An `Evdev` source reads an input device of the kernel, one of the
+//! `/dev/input/eventX` nodes, and generates an `InputEvent` for each event the device
+//! reports. It does not link to libevdev or libinput: the events are decoded as they
+//! come, grouped into frames by the `SYN_REPORT` events of the device.
+//!
+//! If the client does not read the events fast enough, the kernel drops some of them
+//! and reports a `SYN_DROPPED`. The source then discards the rest of the incomplete
+//! frame, queries the current state of the keys, switches and absolute axes of the
+//! device, and generates the events bringing it from the last state delivered to the
+//! current one, followed by a `SYN_REPORT`. The `SYN_DROPPED` itself is not delivered.
+//! The multitouch axes are not resynchronized, as their state is per slot.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+/// Synchronization events
+pub const EV_SYN: u16 = 0x00;
+/// Keys and buttons
+pub const EV_KEY: u16 = 0x01;
+/// Relative axes
+pub const EV_REL: u16 = 0x02;
+/// Absolute axes
+pub const EV_ABS: u16 = 0x03;
+/// Miscellaneous events
+pub const EV_MSC: u16 = 0x04;
+/// Switches
+pub const EV_SW: u16 = 0x05;
+/// LEDs
+pub const EV_LED: u16 = 0x11;
+
+/// The end of a frame of events
+pub const SYN_REPORT: u16 = 0;
+/// Some events were dropped by the kernel
+pub const SYN_DROPPED: u16 = 3;
+
+// the first multitouch axis
+const ABS_MT_SLOT: u16 = 0x2f;
+
+// the number of events read at once
+const BATCH: usize = 64;
+
+// the request of a reading ioctl of evdev, with the size of its argument
+fn ioc_read(nr: u32, size: usize) -> libc::Ioctl {
+    ((2 << 30) | (size << 16) | ((b'E' as usize) << 8) | nr as usize) as libc::Ioctl
+}
+
+fn bit(bits: &[u8], index: usize) -> bool {
+    bits[index / 8] & (1 << (index % 8)) != 0
+}
+
+fn set_bit(bits: &mut [u8], index: usize, value: bool) {
+    if value {
+        bits[index / 8] |= 1 << (index % 8);
+    } else {
+        bits[index / 8] &= !(1 << (index % 8));
+    }
+}
+
+fn query_bits(fd: RawFd, nr: u32, bits: &mut [u8]) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, ioc_read(nr, bits.len()), bits.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// An event reported by an input device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    /// The time of the event, on the clock of the device, `CLOCK_REALTIME` by default
+    pub time: Duration,
+    /// The type of the event, like `EV_KEY`
+    pub kind: u16,
+    /// The code of the event, like the key or the axis
+    pub code: u16,
+    /// The value of the event, like 1 for a pressed key
+    pub value: i32,
+}
+
+impl InputEvent {
+    fn from_raw(event: &libc::input_event) -> InputEvent {
+        InputEvent {
+            time: Duration::new(event.time.tv_sec as u64, event.time.tv_usec as u32 * 1000),
+            kind: event.type_,
+            code: event.code,
+            value: event.value,
+        }
+    }
+}
+
+// the state of a device, as delivered to the callback
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    keys: Vec<u8>,
+    switches: Vec<u8>,
+    // the values of the axes the device has
+    axes: Vec<Option<i32>>,
+}
+
+impl State {
+    fn empty() -> State {
+        State {
+            keys: vec![0; (libc::KEY_CNT + 7) / 8],
+            switches: vec![0; (libc::SW_CNT + 7) / 8],
+            axes: vec![None; libc::ABS_CNT],
+        }
+    }
+
+    fn query(fd: RawFd) -> io::Result<State> {
+        let mut state = State::empty();
+        query_bits(fd, 0x18, &mut state.keys)?;
+        query_bits(fd, 0x1b, &mut state.switches)?;
+        let mut axes = vec![0u8; (libc::ABS_CNT + 7) / 8];
+        query_bits(fd, 0x20 + u32::from(EV_ABS), &mut axes)?;
+        for axis in 0..ABS_MT_SLOT as usize {
+            if !bit(&axes, axis) {
+                continue;
+            }
+            let mut info: libc::input_absinfo = unsafe { mem::zeroed() };
+            let request = ioc_read(0x40 + axis as u32, mem::size_of_val(&info));
+            if unsafe { libc::ioctl(fd, request, &mut info) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            state.axes[axis] = Some(info.value);
+        }
+        Ok(state)
+    }
+
+    fn update(&mut self, event: &InputEvent) {
+        let code = event.code as usize;
+        match event.kind {
+            EV_KEY if code < libc::KEY_CNT => set_bit(&mut self.keys, code, event.value != 0),
+            EV_SW if code < libc::SW_CNT => set_bit(&mut self.switches, code, event.value != 0),
+            EV_ABS if code < libc::ABS_CNT && self.axes[code].is_some() => {
+                self.axes[code] = Some(event.value)
+            }
+            _ => {}
+        }
+    }
+
+    // the events bringing this state to the other one
+    fn changes(&self, other: &State, time: Duration) -> Vec<InputEvent> {
+        let mut changes = Vec::new();
+        let event = |kind, code: usize, value| InputEvent {
+            time,
+            kind,
+            code: code as u16,
+            value,
+        };
+        for code in 0..libc::KEY_CNT {
+            if bit(&self.keys, code) != bit(&other.keys, code) {
+                changes.push(event(EV_KEY, code, bit(&other.keys, code) as i32));
+            }
+        }
+        for code in 0..libc::SW_CNT {
+            if bit(&self.switches, code) != bit(&other.switches, code) {
+                changes.push(event(EV_SW, code, bit(&other.switches, code) as i32));
+            }
+        }
+        for (code, (old, new)) in self.axes.iter().zip(&other.axes).enumerate() {
+            if let (&Some(old), &Some(new)) = (old, new) {
+                if old != new {
+                    changes.push(event(EV_ABS, code, new));
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// An event source reading an evdev input device
+///
+/// It generates an `InputEvent` for each event reported by the device.
+pub struct Evdev {
+    fd: Rc<OwnedFd>,
+    state: State,
+}
+
+impl Evdev {
+    /// Open the input device at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Evdev> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Evdev::new(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Wrap the file descriptor of an opened input device, taking ownership of it
+    ///
+    /// This is how the devices opened through `logind` are used. It is switched to
+    /// non-blocking mode.
+    pub fn from_fd(fd: RawFd) -> io::Result<Evdev> {
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Evdev::new(fd)
+    }
+
+    fn new(fd: OwnedFd) -> io::Result<Evdev> {
+        let state = State::query(fd.as_raw_fd())?;
+        Ok(Evdev {
+            fd: Rc::new(fd),
+            state,
+        })
+    }
+
+    /// The name of the device
+    pub fn name(&self) -> io::Result<String> {
+        let mut name = vec![0u8; 256];
+        let len = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                ioc_read(0x06, name.len()),
+                name.as_mut_ptr(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        name.truncate(len as usize);
+        while name.last() == Some(&0) {
+            name.pop();
+        }
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+
+    /// Grab the device, or release it
+    ///
+    /// While it is grabbed, its events are only delivered to this source.
+    pub fn grab(&self, grab: bool) -> io::Result<()> {
+        // EVIOCGRAB, writing an int
+        let request = ((1 << 30) | (4 << 16) | ((b'E' as usize) << 8) | 0x90) as libc::Ioctl;
+        if unsafe { libc::ioctl(self.fd.as_raw_fd(), request, grab as libc::c_int) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Evdev {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Evented for Evdev {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for Evdev {
+    type Event = InputEvent;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(InputEvent, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.clone(),
+            state: self.state.clone(),
+            dropping: false,
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(InputEvent, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: Rc<OwnedFd>,
+    state: State,
+    // whether the events are discarded until the end of the frame
+    dropping: bool,
+    callback: F,
+}
+
+impl<Data, F: FnMut(InputEvent, &mut Data)> Dispatcher<Data, F> {
+    fn process(&mut self, event: InputEvent, data: &mut Data) -> io::Result<()> {
+        if event.kind == EV_SYN && event.code == SYN_DROPPED {
+            self.dropping = true;
+        } else if self.dropping {
+            if event.kind == EV_SYN && event.code == SYN_REPORT {
+                self.dropping = false;
+                let current = State::query(self.fd.as_raw_fd())?;
+                for change in self.state.changes(&current, event.time) {
+                    (self.callback)(change, data);
+                }
+                self.state = current;
+                (self.callback)(event, data);
+            }
+        } else {
+            self.state.update(&event);
+            (self.callback)(event, data);
+        }
+        Ok(())
+    }
+}
+
+impl<Data, F: FnMut(InputEvent, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut buffer: [libc::input_event; BATCH] = unsafe { mem::zeroed() };
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    mem::size_of_val(&buffer),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            if len == 0 {
+                // the device was unplugged
+                return Ok(());
+            }
+            let count = len as usize / mem::size_of::<libc::input_event>();
+            for raw in &buffer[..count] {
+                self.process(InputEvent::from_raw(raw), data)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn raw(kind: u16, code: u16, value: i32) -> libc::input_event {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.time.tv_sec = 12;
+        event.time.tv_usec = 34;
+        event.type_ = kind;
+        event.code = code;
+        event.value = value;
+        event
+    }
+
+    #[test]
+    fn decode() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // a pipe carries the events as a device would, without its ioctls
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let mut state = State::empty();
+        state.axes[0] = Some(0);
+        let source = Evdev {
+            fd: Rc::new(unsafe { OwnedFd::from_raw_fd(read) }),
+            state,
+        };
+        ::nix::fcntl::fcntl(
+            read,
+            ::nix::fcntl::FcntlArg::F_SETFL(::nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |event, events: &mut Vec<InputEvent>| {
+                events.push(event)
+            })
+            .unwrap();
+
+        let raws = [
+            raw(EV_KEY, 30, 1),
+            raw(EV_ABS, 0, 120),
+            raw(EV_SYN, SYN_REPORT, 0),
+        ];
+        let len = unsafe {
+            libc::write(
+                write,
+                raws.as_ptr() as *const libc::c_void,
+                mem::size_of_val(&raws),
+            )
+        };
+        assert_eq!(len as usize, mem::size_of_val(&raws));
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        let time = Duration::new(12, 34_000);
+        assert_eq!(
+            events,
+            &[
+                InputEvent {
+                    time,
+                    kind: EV_KEY,
+                    code: 30,
+                    value: 1
+                },
+                InputEvent {
+                    time,
+                    kind: EV_ABS,
+                    code: 0,
+                    value: 120
+                },
+                InputEvent {
+                    time,
+                    kind: EV_SYN,
+                    code: SYN_REPORT,
+                    value: 0
+                },
+            ]
+        );
+        ::nix::unistd::close(write).unwrap();
+    }
+
+    #[test]
+    fn resync_changes() {
+        let time = Duration::from_secs(1);
+        let mut delivered = State::empty();
+        delivered.axes[1] = Some(10);
+        delivered.update(&InputEvent {
+            time,
+            kind: EV_KEY,
+            code: 30,
+            value: 1,
+        });
+
+        // the key was released and the axis moved while the events were dropped
+        let mut current = delivered.clone();
+        set_bit(&mut current.keys, 30, false);
+        set_bit(&mut current.switches, 2, true);
+        current.axes[1] = Some(20);
+        let changes = delivered.changes(&current, time);
+        let changes = changes
+            .iter()
+            .map(|e| (e.kind, e.code, e.value))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, &[(EV_KEY, 30, 0), (EV_SW, 2, 1), (EV_ABS, 1, 20)]);
+        assert!(current.changes(&current, time).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+//! Event source for eventfds
+//!
+//! Only available on Linux.
+//!
+//! An eventfd is a counter maintained by the kernel: writing to it adds to the counter,
+//! reading from it returns the counter and resets it to zero. Unlike a `ping`, it
+//! carries a count, and it is a plain file descriptor which can be shared with foreign
+//! code, either created with `EventFd::new()` then given with `as_raw_fd()`, or
+//! received from it and wrapped with `EventFd::from_fd()`.
+//!
+//! The counter is incremented by a `Notifier`, which can be cloned and sent to other
+//! threads. The source generates an event with the value read from the counter.
+//!
+//! In semaphore mode, a read returns 1 and only decrements the counter: the source
+//! then generates a separate event of value 1 for every unit of the counter.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+/// An event source reading an eventfd
+///
+/// It generates an event with the value read from the counter each time it is non-zero.
+pub struct EventFd {
+    fd: Arc<OwnedFd>,
+}
+
+impl EventFd {
+    /// Create a new eventfd with the given initial value
+    ///
+    /// It is in semaphore mode if `semaphore` is true.
+    pub fn new(initial: u32, semaphore: bool) -> io::Result<EventFd> {
+        let mut flags = libc::EFD_CLOEXEC | libc::EFD_NONBLOCK;
+        if semaphore {
+            flags |= libc::EFD_SEMAPHORE;
+        }
+        let fd = unsafe { libc::eventfd(initial, flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd {
+            fd: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Wrap an existing eventfd, taking ownership of it
+    ///
+    /// It is switched to non-blocking mode, and closed when the source and all its
+    /// notifiers are dropped. Its mode is the one it was created with.
+    pub fn from_fd(fd: RawFd) -> io::Result<EventFd> {
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd { fd: Arc::new(fd) })
+    }
+
+    /// Create a notifier, incrementing the counter of this eventfd
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            fd: self.fd.clone(),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// A handle incrementing the counter of an eventfd
+///
+/// It can be cloned and sent accross threads.
+#[derive(Clone)]
+pub struct Notifier {
+    fd: Arc<OwnedFd>,
+}
+
+impl Notifier {
+    /// Add a value to the counter
+    ///
+    /// This fails with `WouldBlock` if the counter would overflow, until it is read.
+    pub fn notify(&self, value: u64) -> io::Result<()> {
+        let ret = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Evented for EventFd {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for EventFd {
+    type Event = u64;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        // a single read is done per dispatch, the eventfd stays readable in semaphore
+        // mode until its counter reaches zero
+        PollOpt::level()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(u64, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(u64, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: Arc<OwnedFd>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(u64, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut value = 0u64;
+        let ret = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut value as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // the counter may have been read by foreign code
+            if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        (self.callback)(value, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = EventFd::new(0, false).unwrap();
+        let notifier = source.notifier();
+        event_loop
+            .handle()
+            .insert_source(source, |value, values: &mut Vec<u64>| values.push(value))
+            .unwrap();
+
+        let mut values = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut values)
+            .unwrap();
+        assert!(values.is_empty());
+
+        ::std::thread::spawn(move || {
+            notifier.notify(2).unwrap();
+            notifier.notify(3).unwrap();
+        })
+        .join()
+        .unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut values)
+            .unwrap();
+        assert_eq!(values, &[5]);
+    }
+
+    #[test]
+    fn semaphore() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // as if it was created by foreign code
+        let fd = unsafe { libc::eventfd(3, libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE) };
+        assert!(fd >= 0);
+        let source = EventFd::from_fd(fd).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |value, values: &mut Vec<u64>| values.push(value))
+            .unwrap();
+
+        let mut values = Vec::new();
+        for _ in 0..4 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut values)
+                .unwrap();
+        }
+        assert_eq!(values, &[1, 1, 1]);
+    }
+}
@@ -0,0 +1,506 @@
+//! Event source for monitoring filesystems with fanotify
+//!
+//! Only available on Linux, and requires the `CAP_SYS_ADMIN` capability.
+//!
+//! Unlike inotify, fanotify can monitor the whole mount or filesystem containing a
+//! path, rather than a single directory, and can ask the event loop for the permission
+//! to open or access a file. A `Fanotify` source is created with `Fanotify::new()`,
+//! and the objects to monitor are marked with `add_mark()`, with a mask of the `FAN_*`
+//! constants re-exported by this module. It generates an `Event` for each access the
+//! kernel reports, which holds an open file descriptor to the accessed file.
+//!
+//! ## Permission events
+//!
+//! If the source was created with the `FAN_CLASS_CONTENT` or `FAN_CLASS_PRE_CONTENT`
+//! class, the `*_PERM` events can be monitored: the process accessing the file is
+//! blocked until the event is answered with `Event::allow()` or `Event::deny()`. An
+//! event dropped without an answer allows the access, so that processes are never
+//! blocked forever. The event loop must not access the monitored files itself, as it
+//! would wait for itself.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+pub use nix::libc::{
+    FAN_ACCESS, FAN_ACCESS_PERM, FAN_ATTRIB, FAN_CLASS_CONTENT, FAN_CLASS_NOTIF,
+    FAN_CLASS_PRE_CONTENT, FAN_CLOSE, FAN_CLOSE_NOWRITE, FAN_CLOSE_WRITE, FAN_EVENT_ON_CHILD,
+    FAN_MARK_DONT_FOLLOW, FAN_MARK_FILESYSTEM, FAN_MARK_INODE, FAN_MARK_MOUNT, FAN_MARK_ONLYDIR,
+    FAN_MODIFY, FAN_ONDIR, FAN_OPEN, FAN_OPEN_EXEC, FAN_OPEN_EXEC_PERM, FAN_OPEN_PERM,
+    FAN_Q_OVERFLOW, FAN_UNLIMITED_MARKS, FAN_UNLIMITED_QUEUE,
+};
+
+use {EventDispatcher, EventSource};
+
+// the events waiting for an answer
+const PERMISSION_EVENTS: u64 = FAN_OPEN_PERM | FAN_ACCESS_PERM | FAN_OPEN_EXEC_PERM;
+
+// answer a permission event of the fanotify descriptor
+fn respond(fanotify: &OwnedFd, fd: RawFd, response: u32) -> io::Result<()> {
+    let response = libc::fanotify_response { fd, response };
+    let len = mem::size_of_val(&response);
+    let ret = unsafe {
+        libc::write(
+            fanotify.as_raw_fd(),
+            &response as *const _ as *const libc::c_void,
+            len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// An access to a monitored file
+pub struct Event {
+    mask: u64,
+    pid: i32,
+    file: Option<File>,
+    // set for the permission events which were not answered yet
+    pending: Option<Rc<OwnedFd>>,
+}
+
+impl Event {
+    /// The kind of access, as a mask of the `FAN_*` constants
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    /// The pid of the process which accessed the file
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// The accessed file, open with the flags given to `Fanotify::new()`
+    ///
+    /// It is `None` for the `FAN_Q_OVERFLOW` event, generated when some events were
+    /// lost because the queue of the kernel was full.
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    /// The path of the accessed file
+    ///
+    /// It is resolved through `/proc`, the file may have been moved since.
+    pub fn path(&self) -> io::Result<PathBuf> {
+        match self.file {
+            Some(ref file) => fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no file for this event",
+            )),
+        }
+    }
+
+    /// Whether this event waits for an answer
+    pub fn is_permission(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Allow the access, if this is a permission event
+    pub fn allow(mut self) -> io::Result<()> {
+        self.respond(libc::FAN_ALLOW)
+    }
+
+    /// Deny the access, if this is a permission event
+    ///
+    /// The process accessing the file gets an `EPERM` error.
+    pub fn deny(mut self) -> io::Result<()> {
+        self.respond(libc::FAN_DENY)
+    }
+
+    fn respond(&mut self, response: u32) -> io::Result<()> {
+        match (self.pending.take(), self.file.as_ref()) {
+            (Some(fanotify), Some(file)) => respond(&fanotify, file.as_raw_fd(), response),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _ = self.respond(libc::FAN_ALLOW);
+    }
+}
+
+impl ::std::fmt::Debug for Event {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Event")
+            .field("mask", &self.mask)
+            .field("pid", &self.pid)
+            .field("file", &self.file)
+            .field("is_permission", &self.is_permission())
+            .finish()
+    }
+}
+
+/// An event source for monitoring filesystems with fanotify
+///
+/// It generates an `Event` for each access to the marked objects.
+pub struct Fanotify {
+    fd: Rc<OwnedFd>,
+}
+
+impl Fanotify {
+    /// Create a new source, without any mark
+    ///
+    /// The `flags` contain the class of the source, `FAN_CLASS_NOTIF` if it does not
+    /// monitor permission events, and possibly `FAN_UNLIMITED_QUEUE` and
+    /// `FAN_UNLIMITED_MARKS`. The files of the events are open with the `open(2)` flags
+    /// given as `file_flags`, such as `O_RDONLY`.
+    ///
+    /// This fails with `EPERM` without the `CAP_SYS_ADMIN` capability.
+    pub fn new(flags: libc::c_uint, file_flags: libc::c_int) -> io::Result<Fanotify> {
+        let fd = unsafe {
+            libc::fanotify_init(
+                flags | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                (file_flags | libc::O_CLOEXEC | libc::O_LARGEFILE) as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Fanotify {
+            fd: Rc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Monitor the accesses of the given mask to a path
+    ///
+    /// By default only the file or directory itself is monitored, the `flags` can
+    /// extend this to the whole mount or filesystem containing it, with
+    /// `FAN_MARK_MOUNT` or `FAN_MARK_FILESYSTEM`. The mask of an already marked object
+    /// is extended.
+    pub fn add_mark<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        flags: libc::c_uint,
+        mask: u64,
+    ) -> io::Result<()> {
+        self.mark(path.as_ref(), libc::FAN_MARK_ADD | flags, mask)
+    }
+
+    /// Stop monitoring the accesses of the given mask to a path
+    ///
+    /// The `flags` must designate the same object as when it was marked.
+    pub fn remove_mark<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        flags: libc::c_uint,
+        mask: u64,
+    ) -> io::Result<()> {
+        self.mark(path.as_ref(), libc::FAN_MARK_REMOVE | flags, mask)
+    }
+
+    fn mark(&mut self, path: &Path, flags: libc::c_uint, mask: u64) -> io::Result<()> {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let ret = unsafe {
+            libc::fanotify_mark(
+                self.fd.as_raw_fd(),
+                flags,
+                mask,
+                libc::AT_FDCWD,
+                cpath.as_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Evented for Fanotify {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for Fanotify {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: Rc<OwnedFd>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // aligned for `fanotify_event_metadata`
+        let mut buffer = [0u64; 512];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    mem::size_of_val(&buffer),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            let bytes =
+                unsafe { ::std::slice::from_raw_parts(buffer.as_ptr() as *const u8, len as usize) };
+            self.dispatch(bytes, data)?;
+        }
+    }
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> Dispatcher<Data, F> {
+    fn dispatch(&mut self, bytes: &[u8], data: &mut Data) -> io::Result<()> {
+        let header = mem::size_of::<libc::fanotify_event_metadata>();
+        let mut offset = 0;
+        // wrap all the events first, for their file descriptors to be closed and their
+        // permission requests allowed if the callback panics or an event has an
+        // unknown version
+        let mut events = Vec::new();
+        while offset + header <= bytes.len() {
+            let raw = unsafe {
+                ::std::ptr::read_unaligned(
+                    bytes[offset..].as_ptr() as *const libc::fanotify_event_metadata
+                )
+            };
+            if raw.event_len < header as u32 {
+                break;
+            }
+            offset += raw.event_len as usize;
+            let file = if raw.fd >= 0 {
+                Some(unsafe { File::from_raw_fd(raw.fd) })
+            } else {
+                None
+            };
+            // the mask of an event with an unknown version cannot be trusted, it
+            // is answered in case it is a permission event
+            let pending = if file.is_some()
+                && (raw.mask & PERMISSION_EVENTS != 0
+                    || raw.vers != libc::FANOTIFY_METADATA_VERSION)
+            {
+                Some(self.fd.clone())
+            } else {
+                None
+            };
+            let event = Event {
+                mask: raw.mask,
+                pid: raw.pid,
+                file,
+                pending,
+            };
+            events.push((raw.vers, event));
+        }
+        for (vers, event) in events {
+            if vers != libc::FANOTIFY_METADATA_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unsupported fanotify metadata version",
+                ));
+            }
+            (self.callback)(event, data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::IntoRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "calloop-fanotify-{}-{}",
+            name,
+            ::std::process::id()
+        ));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fanotify() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut source = match Fanotify::new(FAN_CLASS_NOTIF, libc::O_RDONLY) {
+            Ok(source) => source,
+            // this process is not allowed to use fanotify
+            Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let dir = temp_dir("notif");
+        source
+            .add_mark(&dir, FAN_MARK_INODE, FAN_CLOSE_WRITE | FAN_EVENT_ON_CHILD)
+            .unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |evt, events: &mut Vec<(u64, PathBuf)>| {
+                events.push((evt.mask(), evt.path().unwrap()))
+            })
+            .unwrap();
+
+        fs::write(dir.join("file"), b"").unwrap();
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        assert_eq!(events, &[(FAN_CLOSE_WRITE, dir.join("file"))]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn permission() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut source = match Fanotify::new(FAN_CLASS_CONTENT, libc::O_RDONLY) {
+            Ok(source) => source,
+            Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let dir = temp_dir("perm");
+        let file = dir.join("file");
+        fs::write(&file, b"").unwrap();
+        source
+            .add_mark(&file, FAN_MARK_INODE, FAN_OPEN_PERM)
+            .unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |evt, answered: &mut bool| {
+                assert!(evt.is_permission());
+                evt.deny().unwrap();
+                *answered = true;
+            })
+            .unwrap();
+
+        // the opening blocks until it is answered by the event loop
+        let opening = {
+            let file = file.clone();
+            thread::spawn(move || File::open(file).map(|_| ()))
+        };
+        let mut answered = false;
+        while !answered {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut answered)
+                .unwrap();
+        }
+        let err = opening.join().unwrap().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_version() {
+        // the responses are written to a pipe rather than to a fanotify descriptor
+        let (reader, writer) = ::nix::unistd::pipe().unwrap();
+        let mut dispatcher = Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: Rc::new(unsafe { OwnedFd::from_raw_fd(writer) }),
+            callback: |_, count: &mut u32| *count += 1,
+        };
+
+        let dir = temp_dir("version");
+        let mut bytes = Vec::new();
+        let mut fds = Vec::new();
+        for &vers in &[
+            libc::FANOTIFY_METADATA_VERSION,
+            0,
+            libc::FANOTIFY_METADATA_VERSION,
+        ] {
+            let fd = File::open(&dir).unwrap().into_raw_fd();
+            fds.push(fd);
+            let mut raw: libc::fanotify_event_metadata = unsafe { mem::zeroed() };
+            raw.event_len = mem::size_of_val(&raw) as u32;
+            raw.vers = vers;
+            raw.metadata_len = raw.event_len as u16;
+            raw.mask = FAN_OPEN_PERM;
+            raw.fd = fd;
+            bytes.extend_from_slice(unsafe {
+                ::std::slice::from_raw_parts(&raw as *const _ as *const u8, mem::size_of_val(&raw))
+            });
+        }
+
+        let mut count = 0;
+        let err = dispatcher.dispatch(&bytes, &mut count).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(count, 1);
+
+        // all the permission events were allowed, including the unread ones
+        let mut responses = [0u8; 3 * mem::size_of::<libc::fanotify_response>()];
+        let len = ::nix::unistd::read(reader, &mut responses).unwrap();
+        assert_eq!(len, responses.len());
+        for (i, &fd) in fds.iter().enumerate() {
+            let response = unsafe {
+                ::std::ptr::read_unaligned(
+                    responses[i * mem::size_of::<libc::fanotify_response>()..].as_ptr()
+                        as *const libc::fanotify_response,
+                )
+            };
+            assert_eq!(response.fd, fd);
+            assert_eq!(response.response, libc::FAN_ALLOW);
+        }
+
+        let _ = ::nix::unistd::close(reader);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -55,6 +55,12 @@ impl<E: Evented + 'static> Generic<E> {
     /// If the source was already inserted in an event loop,
     /// it needs to be re-registered for the change to take
     /// effect.
+    ///
+    /// On unix, the interest can include the flags of
+    /// `mio::unix::UnixReady`. Notably `UnixReady::priority()`
+    /// wakes the source on exceptional conditions (`EPOLLPRI`),
+    /// such as TCP urgent data or changes of GPIO sysfs
+    /// attributes. This is only supported by epoll.
     pub fn set_interest(&mut self, interest: Ready) {
         self.interest = interest;
     }
@@ -64,6 +70,10 @@ impl<E: Evented + 'static> Generic<E> {
     /// If the source was already inserted in an event loop,
     /// it needs to be re-registered for the change to take
     /// effect.
+    ///
+    /// With `PollOpt::oneshot()`, the source is disarmed after
+    /// each event, until it is reactivated using
+    /// `LoopHandle::reactivate()` or `PostAction::Reregister`.
     pub fn set_pollopts(&mut self, pollopts: PollOpt) {
         self.pollopts = pollopts;
     }
@@ -82,17 +92,18 @@ impl<E: Evented + 'static> Generic<E> {
     }
 }
 
+#[cfg(unix)]
 impl<Fd: AsRawFd> Generic<EventedFd<Fd>> {
     /// Wrap a file descriptor based source into a `Generic` event source.
     ///
     /// This will only work with poll-compatible file descriptors, which typically
     /// not include basic files.
-    #[cfg(unix)]
     pub fn from_fd_source(source: Fd) -> Generic<EventedFd<Fd>> {
         Generic::new(EventedFd(source))
     }
 }
 
+#[cfg(unix)]
 impl Generic<EventedRawFd> {
     /// Wrap a raw file descriptor into a `Generic` event source.
     ///
@@ -101,7 +112,6 @@ impl Generic<EventedRawFd> {
     ///
     /// This does _not_ take ownership of the file descriptor, hence you are responsible
     /// of its correct lifetime.
-    #[cfg(unix)]
     pub fn from_raw_fd(fd: RawFd) -> Generic<EventedRawFd> {
         Generic::new(EventedRawFd(fd))
     }
@@ -112,6 +122,12 @@ pub struct Event<E: Evented + 'static> {
     /// An access to the source that generated this event
     pub source: Rc<RefCell<E>>,
     /// The associated rediness
+    ///
+    /// On unix, hangups and errors are reported by the `hup` and `error`
+    /// flags of `mio::unix::UnixReady`, separately from readability. This
+    /// allows telling a closed peer apart from available data, even though
+    /// the source is usually still reported readable in this case, as a
+    /// read will return the end of the stream.
     pub readiness: Ready,
 }
 
@@ -119,6 +135,7 @@ pub struct Event<E: Evented + 'static> {
 #[cfg(unix)]
 pub struct EventedFd<F: AsRawFd>(pub F);
 
+#[cfg(unix)]
 impl<F: AsRawFd> Evented for EventedFd<F> {
     fn register(
         &self,
@@ -152,6 +169,7 @@ impl<F: AsRawFd> Evented for EventedFd<F> {
 #[cfg(unix)]
 pub struct EventedRawFd(pub RawFd);
 
+#[cfg(unix)]
 impl Evented for EventedRawFd {
     fn register(
         &self,
@@ -215,10 +233,10 @@ impl<E: Evented + 'static> EventSource for Generic<E> {
         self.pollopts
     }
 
-    fn make_dispatcher<Data: 'static, F: FnMut(Event<E>, &mut Data) + 'static>(
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<E>, &mut Data) + 'l>(
         &self,
         callback: F,
-    ) -> Rc<RefCell<EventDispatcher<Data>>> {
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
         Rc::new(RefCell::new(Dispatcher {
             _data: ::std::marker::PhantomData,
             inner: self.inner.clone(),
@@ -236,27 +254,28 @@ struct Dispatcher<Data, E: Evented + 'static, F: FnMut(Event<E>, &mut Data)> {
 impl<Data, E: Evented + 'static, F: FnMut(Event<E>, &mut Data)> EventDispatcher<Data>
     for Dispatcher<Data, E, F>
 {
-    fn ready(&mut self, ready: Ready, data: &mut Data) {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
         (self.callback)(
             Event {
                 source: self.inner.clone(),
                 readiness: ready,
             },
             data,
-        )
+        );
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::{self, Read, Write};
-
-    use super::{Event, Generic};
     #[cfg(unix)]
     #[test]
     fn dispatch_unix() {
+        use std::io::{self, Read, Write};
         use std::os::unix::net::UnixStream;
 
+        use super::{Event, Generic};
+
         let mut event_loop = ::EventLoop::new().unwrap();
 
         let handle = event_loop.handle();
@@ -279,7 +298,8 @@ mod test {
                 assert_eq!(&buffer[..6], &[1, 2, 3, 4, 5, 6]);
 
                 *d = true;
-            }).map_err(Into::<io::Error>::into)
+            })
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
         event_loop
@@ -288,7 +308,7 @@ mod test {
 
         assert!(!dispached);
 
-        tx.write(&[1, 2, 3, 4, 5, 6]).unwrap();
+        tx.write_all(&[1, 2, 3, 4, 5, 6]).unwrap();
         tx.flush().unwrap();
 
         event_loop
@@ -297,4 +317,97 @@ mod test {
 
         assert!(dispached);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn dispatch_hup() {
+        use std::os::unix::net::UnixStream;
+
+        use mio::unix::UnixReady;
+        use mio::{PollOpt, Ready};
+
+        use super::{Event, Generic};
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::edge());
+
+        handle
+            .insert_source(generic, |Event { readiness, .. }, d: &mut bool| {
+                assert!(UnixReady::from(readiness).is_hup());
+                assert!(!UnixReady::from(readiness).is_error());
+                *d = true;
+            })
+            .unwrap();
+
+        // close the peer
+        drop(tx);
+
+        let mut dispatched = false;
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(dispatched);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn dispatch_priority() {
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::AsRawFd;
+
+        use mio::unix::UnixReady;
+        use mio::{PollOpt, Ready};
+        use nix::libc;
+
+        use super::{Event, Generic};
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tx = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (rx, _) = listener.accept().unwrap();
+
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::from(UnixReady::priority()));
+        generic.set_pollopts(PollOpt::edge());
+
+        handle
+            .insert_source(generic, |Event { readiness, .. }, d: &mut bool| {
+                assert!(UnixReady::from(readiness).is_priority());
+                *d = true;
+            })
+            .unwrap();
+
+        let mut dispatched = false;
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut dispatched)
+            .unwrap();
+        assert!(!dispatched);
+
+        // send a byte of urgent data
+        let byte = 1u8;
+        let ret = unsafe {
+            libc::send(
+                tx.as_raw_fd(),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+                libc::MSG_OOB,
+            )
+        };
+        assert_eq!(ret, 1);
+
+        event_loop
+            .dispatch(
+                Some(::std::time::Duration::from_millis(100)),
+                &mut dispatched,
+            )
+            .unwrap();
+        assert!(dispatched);
+    }
 }
@@ -0,0 +1,372 @@
+//! Event source for the edges of GPIO lines
+//!
+//! Only available on Linux, since 5.10.
+//!
+//! A `LineEvents` source requests some lines of a GPIO chip, one of the
+//! `/dev/gpiochipN` character devices, as inputs with edge detection, and generates a
+//! `LineEvent` for each rising or falling edge detected on them, timestamped by the
+//! kernel. Lines requested by other means, like libgpiod, can be wrapped with
+//! `LineEvents::from_fd()`.
+//!
+//! This uses the version 2 of the GPIO character device interface.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+// the maximum number of lines of a request
+const GPIO_V2_LINES_MAX: usize = 64;
+
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+
+const GPIO_V2_LINE_EVENT_RISING_EDGE: u32 = 1;
+
+#[repr(C)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+#[repr(C)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; 10],
+}
+
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; 32],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+struct RawLineEvent {
+    timestamp_ns: u64,
+    id: u32,
+    offset: u32,
+    seqno: u32,
+    line_seqno: u32,
+    padding: [u32; 6],
+}
+
+/// The edges to detect on the lines
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edges {
+    /// Only the rising edges, from inactive to active
+    Rising,
+    /// Only the falling edges, from active to inactive
+    Falling,
+    /// Both the rising and falling edges
+    Both,
+}
+
+impl Edges {
+    fn flags(self) -> u64 {
+        match self {
+            Edges::Rising => GPIO_V2_LINE_FLAG_EDGE_RISING,
+            Edges::Falling => GPIO_V2_LINE_FLAG_EDGE_FALLING,
+            Edges::Both => GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING,
+        }
+    }
+}
+
+/// The kind of an edge
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// The line became active
+    Rising,
+    /// The line became inactive
+    Falling,
+}
+
+/// An edge detected on a line
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LineEvent {
+    /// The time of the edge, on the monotonic clock by default
+    pub timestamp: Duration,
+    /// The kind of the edge
+    pub edge: Edge,
+    /// The offset of the line on its chip
+    pub offset: u32,
+    /// The sequence number of the event among all the lines of the request
+    pub seqno: u32,
+    /// The sequence number of the event on this line
+    pub line_seqno: u32,
+}
+
+impl LineEvent {
+    fn from_raw(event: &RawLineEvent) -> LineEvent {
+        LineEvent {
+            timestamp: Duration::from_nanos(event.timestamp_ns),
+            edge: if event.id == GPIO_V2_LINE_EVENT_RISING_EDGE {
+                Edge::Rising
+            } else {
+                Edge::Falling
+            },
+            offset: event.offset,
+            seqno: event.seqno,
+            line_seqno: event.line_seqno,
+        }
+    }
+}
+
+/// An event source receiving the edges of some GPIO lines
+///
+/// It generates a `LineEvent` for each edge detected on the lines.
+pub struct LineEvents {
+    fd: OwnedFd,
+}
+
+impl LineEvents {
+    /// Request some lines of a GPIO chip as inputs, detecting the given edges
+    ///
+    /// The lines are given by their offsets on the chip, at most 64 of them, and the
+    /// `consumer` label identifies the program to the other users of the chip. This
+    /// fails with `EBUSY` if some lines are already requested.
+    pub fn request<P: AsRef<Path>>(
+        chip: P,
+        offsets: &[u32],
+        edges: Edges,
+        consumer: &str,
+    ) -> io::Result<LineEvents> {
+        if offsets.is_empty() || offsets.len() > GPIO_V2_LINES_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "between 1 and 64 lines can be requested",
+            ));
+        }
+        let path = CString::new(chip.as_ref().as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+        let chip = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+        if chip < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut request: LineRequest = unsafe { mem::zeroed() };
+        request.offsets[..offsets.len()].copy_from_slice(offsets);
+        // the label is truncated, keeping its nul terminator
+        let label = consumer.as_bytes();
+        let len = label.len().min(request.consumer.len() - 1);
+        request.consumer[..len].copy_from_slice(&label[..len]);
+        request.config.flags = GPIO_V2_LINE_FLAG_INPUT | edges.flags();
+        request.num_lines = offsets.len() as u32;
+        // GPIO_V2_GET_LINE_IOCTL
+        let code =
+            (3 << 30) | ((mem::size_of::<LineRequest>() as libc::Ioctl) << 16) | (0xb4 << 8) | 0x07;
+        let ret = unsafe { libc::ioctl(chip, code, &mut request) };
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(chip) };
+        if ret < 0 {
+            return Err(err);
+        }
+        LineEvents::from_fd(request.fd)
+    }
+
+    /// Wrap the file descriptor of requested lines, taking ownership of it
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_fd(fd: RawFd) -> io::Result<LineEvents> {
+        let source = LineEvents {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(source)
+    }
+}
+
+impl AsRawFd for LineEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Evented for LineEvents {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for LineEvents {
+    type Event = LineEvent;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(LineEvent, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.as_raw_fd(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(LineEvent, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: RawFd,
+    callback: F,
+}
+
+impl<Data, F: FnMut(LineEvent, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut buffer: [RawLineEvent; 16] = unsafe { mem::zeroed() };
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    mem::size_of_val(&buffer),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            if len == 0 {
+                return Ok(());
+            }
+            let count = len as usize / mem::size_of::<RawLineEvent>();
+            for event in &buffer[..count] {
+                (self.callback)(LineEvent::from_raw(event), data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // the sizes of the structures of the kernel
+        assert_eq!(mem::size_of::<LineRequest>(), 592);
+        assert_eq!(mem::size_of::<RawLineEvent>(), 48);
+    }
+
+    #[test]
+    fn edges() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // a pipe carries the events as requested lines would
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let source = LineEvents::from_fd(read).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |event, events: &mut Vec<LineEvent>| {
+                events.push(event)
+            })
+            .unwrap();
+
+        let mut raw: [RawLineEvent; 2] = unsafe { mem::zeroed() };
+        raw[0].timestamp_ns = 1_500_000_000;
+        raw[0].id = 1;
+        raw[0].offset = 17;
+        raw[0].seqno = 1;
+        raw[0].line_seqno = 1;
+        raw[1].timestamp_ns = 2_000_000_000;
+        raw[1].id = 2;
+        raw[1].offset = 17;
+        raw[1].seqno = 2;
+        raw[1].line_seqno = 2;
+        let len = unsafe {
+            libc::write(
+                write,
+                raw.as_ptr() as *const libc::c_void,
+                mem::size_of_val(&raw),
+            )
+        };
+        assert_eq!(len as usize, mem::size_of_val(&raw));
+
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        assert_eq!(
+            events,
+            &[
+                LineEvent {
+                    timestamp: Duration::from_millis(1500),
+                    edge: Edge::Rising,
+                    offset: 17,
+                    seqno: 1,
+                    line_seqno: 1,
+                },
+                LineEvent {
+                    timestamp: Duration::from_secs(2),
+                    edge: Edge::Falling,
+                    offset: 17,
+                    seqno: 2,
+                    line_seqno: 2,
+                },
+            ]
+        );
+        ::nix::unistd::close(write).unwrap();
+    }
+}
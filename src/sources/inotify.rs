@@ -0,0 +1,287 @@
+//! Event source for watching files with inotify
+//!
+//! Only available on Linux, with the `inotify` cargo feature.
+//!
+//! An `Inotify` source watches files and directories, added with `add_watch()` and
+//! removed with `remove_watch()`, and generates an `Event` for each change the kernel
+//! reports. The kinds of changes to watch are given as a mask of the `IN_*` constants
+//! re-exported by this module.
+//!
+//! The path of an event is the watched path, joined with the name of the file the
+//! change happened to when the watch is a directory. Once a watch is removed, either
+//! explicitly or because its file was deleted, an event with the `IN_IGNORED` mask is
+//! generated for it, then the watch descriptor can be reused by the kernel.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+pub use nix::libc::{
+    IN_ACCESS, IN_ALL_EVENTS, IN_ATTRIB, IN_CLOSE, IN_CLOSE_NOWRITE, IN_CLOSE_WRITE, IN_CREATE,
+    IN_DELETE, IN_DELETE_SELF, IN_DONT_FOLLOW, IN_EXCL_UNLINK, IN_IGNORED, IN_ISDIR, IN_MASK_ADD,
+    IN_MODIFY, IN_MOVE, IN_MOVED_FROM, IN_MOVED_TO, IN_MOVE_SELF, IN_ONESHOT, IN_ONLYDIR, IN_OPEN,
+    IN_Q_OVERFLOW, IN_UNMOUNT,
+};
+
+use {EventDispatcher, EventSource};
+
+/// A watch of an `Inotify` source
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WatchDescriptor(libc::c_int);
+
+/// A change to a watched file
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// The watch which generated this event
+    ///
+    /// It is `None` for the `IN_Q_OVERFLOW` event, generated when some events were
+    /// lost because the queue of the kernel was full.
+    pub wd: Option<WatchDescriptor>,
+    /// The kind of change, as a mask of the `IN_*` constants
+    pub mask: u32,
+    /// A cookie relating the `IN_MOVED_FROM` and `IN_MOVED_TO` events of a same rename
+    pub cookie: u32,
+    /// The path of the changed file
+    pub path: PathBuf,
+}
+
+type Watches = Rc<RefCell<HashMap<WatchDescriptor, PathBuf>>>;
+
+/// An event source for watching files with inotify
+///
+/// It generates an `Event` for each change to the watched files.
+pub struct Inotify {
+    fd: OwnedFd,
+    watches: Watches,
+}
+
+impl Inotify {
+    /// Create a new source, without any watch
+    pub fn new() -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            watches: Rc::new(RefCell::new(HashMap::new())),
+        })
+    }
+
+    /// Watch a file or directory for the changes of the given mask
+    ///
+    /// If the file is already watched, its watch descriptor is returned with its mask
+    /// replaced, or extended if it contains `IN_MASK_ADD`.
+    pub fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: u32) -> io::Result<WatchDescriptor> {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), cpath.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let wd = WatchDescriptor(wd);
+        self.watches.borrow_mut().insert(wd, path.to_owned());
+        Ok(wd)
+    }
+
+    /// Stop watching a file
+    ///
+    /// A last event with the `IN_IGNORED` mask is generated for this watch.
+    pub fn remove_watch(&mut self, wd: WatchDescriptor) -> io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd.0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The path watched by a watch descriptor, if it is still active
+    pub fn path(&self, wd: WatchDescriptor) -> Option<PathBuf> {
+        self.watches.borrow().get(&wd).cloned()
+    }
+}
+
+impl Evented for Inotify {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for Inotify {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.as_raw_fd(),
+            watches: self.watches.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: RawFd,
+    watches: Watches,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // aligned for `inotify_event`, and large enough for at least one event with
+        // the longest name
+        let mut buffer = [0u64; 1024];
+        let header = mem::size_of::<libc::inotify_event>();
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    mem::size_of_val(&buffer),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            let bytes =
+                unsafe { ::std::slice::from_raw_parts(buffer.as_ptr() as *const u8, len as usize) };
+            let mut offset = 0;
+            while offset + header <= bytes.len() {
+                let raw = unsafe {
+                    ::std::ptr::read_unaligned(
+                        bytes[offset..].as_ptr() as *const libc::inotify_event
+                    )
+                };
+                let name = &bytes[offset + header..offset + header + raw.len as usize];
+                offset += header + raw.len as usize;
+                // the name is padded with nul bytes
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or(&[]));
+
+                let wd = if raw.wd >= 0 {
+                    Some(WatchDescriptor(raw.wd))
+                } else {
+                    None
+                };
+                let path = match wd {
+                    Some(wd) => {
+                        let mut watches = self.watches.borrow_mut();
+                        let path = watches.get(&wd).cloned().unwrap_or_default();
+                        if raw.mask & IN_IGNORED != 0 {
+                            watches.remove(&wd);
+                        }
+                        if name.is_empty() {
+                            path
+                        } else {
+                            path.join(name)
+                        }
+                    }
+                    None => PathBuf::new(),
+                };
+                (self.callback)(
+                    Event {
+                        wd,
+                        mask: raw.mask,
+                        cookie: raw.cookie,
+                        path,
+                    },
+                    data,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn inotify() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let dir = ::std::env::temp_dir().join(format!("calloop-inotify-{}", ::std::process::id()));
+        fs::create_dir(&dir).unwrap();
+
+        let mut source = Inotify::new().unwrap();
+        let wd = source.add_watch(&dir, IN_CREATE | IN_DELETE).unwrap();
+        assert_eq!(source.path(wd), Some(dir.clone()));
+        let source = event_loop
+            .handle()
+            .insert_source(source, |evt, events: &mut Vec<Event>| events.push(evt))
+            .unwrap();
+
+        fs::write(dir.join("file"), b"").unwrap();
+        fs::remove_file(dir.join("file")).unwrap();
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|evt| evt.wd == Some(wd)));
+        assert!(events.iter().all(|evt| evt.path == dir.join("file")));
+        assert_eq!(events[0].mask, IN_CREATE);
+        assert_eq!(events[1].mask, IN_DELETE);
+
+        events.clear();
+        source.as_source_mut().remove_watch(wd).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut events)
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].mask, IN_IGNORED);
+        assert_eq!(source.as_source_ref().path(wd), None);
+
+        fs::remove_dir(&dir).unwrap();
+    }
+}
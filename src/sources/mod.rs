@@ -1,16 +1,80 @@
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::io;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 
-use list::ErasedList;
+use list::{make_sub_token, ErasedList, MAX_SUB_ID};
 
+pub mod bounded;
+pub mod broadcast;
 pub mod channel;
+pub mod debounce;
+#[cfg(target_os = "linux")]
+pub mod drm;
+#[cfg(all(target_os = "linux", feature = "evdev"))]
+pub mod evdev;
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+#[cfg(target_os = "linux")]
+pub mod fanotify;
 pub mod generic;
 #[cfg(target_os = "linux")]
+pub mod gpio;
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+pub mod inotify;
+pub mod mpmc;
+#[cfg(unix)]
+pub mod nested;
+pub mod net;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub mod pidfd;
+pub mod ping;
+#[cfg(unix)]
+pub mod poll_descriptors;
+#[cfg(target_os = "linux")]
+pub mod process;
+#[cfg(target_os = "linux")]
+pub mod pty;
+pub mod request;
+#[cfg(target_os = "linux")]
+pub mod resize;
+#[cfg(target_os = "linux")]
+pub mod schedule;
+#[cfg(unix)]
+pub mod self_pipe;
+#[cfg(target_os = "linux")]
+pub mod serial;
+pub mod signal_safe;
+#[cfg(target_os = "linux")]
 pub mod signals;
+#[cfg(unix)]
+pub mod stdin;
+#[cfg(target_os = "linux")]
+pub mod subprocess;
+#[cfg(target_os = "linux")]
+pub mod sync_file;
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub mod systemd;
+pub mod throttle;
+pub mod timeout;
 pub mod timer;
+#[cfg(target_os = "linux")]
+pub mod timerfd;
+#[cfg(all(target_os = "linux", feature = "udev"))]
+pub mod udev;
+pub mod watch;
 
 /// Trait representing a source that can be inserted into an EventLoop
 ///
@@ -26,12 +90,37 @@ pub trait EventSource: Evented {
     /// The pollopt value that will be given to `mio` when registering your source
     fn pollopts(&self) -> PollOpt;
 
+    /// The next instant at which your source needs to process events
+    ///
+    /// Once this deadline is reached, the event loop dispatches your source as if it
+    /// was ready, even if `mio` did not report any readiness. This is used for sources
+    /// that track time, such as timers. It is called at every dispatching cycle, and
+    /// returns `None` by default.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called when your source is removed from the event loop
+    ///
+    /// This happens once it has been deregistered, and before it is dropped or
+    /// given back to the user, allowing it to flush its buffers or notify its peers.
+    /// It is notably called on all sources by `EventLoop::shutdown()`. Does nothing
+    /// by default.
+    fn before_removal(&mut self) {}
+
+    /// Called when the event loop is about to go to sleep waiting for events
+    ///
+    /// The maximum duration of this wait is given, `None` meaning it may wait
+    /// indefinitely. This allows your source to flush its buffered outgoing data
+    /// before the event loop blocks. Does nothing by default.
+    fn before_sleep(&mut self, _timeout: Option<Duration>) {}
+
     /// Wrap an user callback into a dispatcher, that will convert an `mio` readiness
     /// into an event
-    fn make_dispatcher<Data: 'static, F: FnMut(Self::Event, &mut Data) + 'static>(
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Self::Event, &mut Data) + 'l>(
         &self,
         callback: F,
-    ) -> Rc<RefCell<EventDispatcher<Data>>>;
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>>;
 }
 
 /// An event dispatcher
@@ -41,59 +130,333 @@ pub trait EventSource: Evented {
 /// and calling their inner user callback.
 pub trait EventDispatcher<Data> {
     /// The source has a readiness event
-    fn ready(&mut self, ready: Ready, data: &mut Data);
+    ///
+    /// An error returned by this method is forwarded to the caller of
+    /// `EventLoop::dispatch(..)`, along with the token of the source.
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()>;
+
+    /// The source has a readiness event on one of its sub-tokens
+    ///
+    /// Sources registering several `Evented` objects using a `TokenFactory` can
+    /// implement this method to know which of them is ready, `sub_id` being the
+    /// index of its sub-token. By default, this forwards to `ready()`.
+    fn ready_sub(&mut self, ready: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        let _ = sub_id;
+        self.ready(ready, data)
+    }
+}
+
+/// An action requested by a source callback to the event loop
+///
+/// Callbacks can return one of these values to act on their own source
+/// without needing to access its `Source` handle. Callbacks returning `()`
+/// are equivalent to callbacks returning `PostAction::Continue`.
+///
+/// The action is applied once the source has finished processing its current
+/// readiness. If the callback is invoked several times during this processing,
+/// the last action other than `Continue` is applied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PostAction {
+    /// Keep the source as it is
+    Continue,
+    /// Refresh the registration of the source, see `Source::reregister()`
+    Reregister,
+    /// Disable the source, see `Source::disable()`
+    Disable,
+    /// Remove the source from the event loop
+    ///
+    /// The evented object can still be retrieved using `Source::remove()`.
+    Remove,
+}
+
+impl From<()> for PostAction {
+    fn from(_: ()) -> PostAction {
+        PostAction::Continue
+    }
+}
+
+/// A token identifying an event source inserted in an event loop
+///
+/// It is returned by `LoopHandle::register_dispatcher()` and `Source::token()`,
+/// and can be used to act on the source through the `LoopHandle`.
+///
+/// Once its source is removed, a token is stale: acting on it through the
+/// `LoopHandle` fails with an `io::ErrorKind::NotFound` error, even if an other
+/// source was inserted in its place since.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RegistrationToken {
+    pub(crate) token: Token,
+}
+
+/// A factory of sub-tokens, for sources registering several `Evented` objects
+///
+/// In its `Evented` implementation, a composite source can create a `TokenFactory`
+/// from the token it is given and register each of its objects with a token produced
+/// by it. The readiness of these objects is then given to its dispatcher by
+/// `EventDispatcher::ready_sub()`, along with the sub-id of their token.
+///
+/// The sub-tokens are produced in order, the objects must be registered in the same
+/// order when the source is re-registered, so that they keep their sub-id.
+#[derive(Debug)]
+pub struct TokenFactory {
+    token: Token,
+    next_sub_id: usize,
+}
+
+impl TokenFactory {
+    /// Create a factory for the token given to `Evented::register()`
+    pub fn new(token: Token) -> TokenFactory {
+        TokenFactory {
+            token,
+            next_sub_id: 0,
+        }
+    }
+
+    /// Produce the next sub-token
+    ///
+    /// The first sub-token has the sub-id 0 and is the token given to the factory,
+    /// the following ones have increasing sub-ids.
+    ///
+    /// **Panics** if more than 256 sub-tokens are produced.
+    pub fn token(&mut self) -> Token {
+        assert!(
+            self.next_sub_id <= MAX_SUB_ID,
+            "[calloop] Too many sub-tokens for a source."
+        );
+        let token = make_sub_token(self.token, self.next_sub_id);
+        self.next_sub_id += 1;
+        token
+    }
+}
+
+/// An event source bundled with its callback
+///
+/// Unlike `Source`, this type is created before being inserted in the event loop
+/// using `LoopHandle::register_dispatcher()`. It can be cloned and stored wherever
+/// you need access to the event source while it is inserted, for example in the
+/// shared data of your callbacks.
+pub struct Dispatcher<'l, S: EventSource, Data> {
+    pub(crate) source: Rc<RefCell<S>>,
+    pub(crate) dispatcher: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    pub(crate) action: Rc<Cell<PostAction>>,
+}
+
+// Manual impl of `Clone` as #[derive(Clone)] adds `S: Clone` and `Data: Clone` bounds
+impl<'l, S: EventSource, Data> Clone for Dispatcher<'l, S, Data> {
+    fn clone(&self) -> Dispatcher<'l, S, Data> {
+        Dispatcher {
+            source: self.source.clone(),
+            dispatcher: self.dispatcher.clone(),
+            action: self.action.clone(),
+        }
+    }
+}
+
+impl<'l, S: EventSource + 'l, Data: 'l> Dispatcher<'l, S, Data> {
+    /// Bundle an event source with its callback
+    ///
+    /// The callback can return a `PostAction`, in the same way as the ones given
+    /// to `LoopHandle::insert_source()`.
+    pub fn new<F, R>(source: S, mut callback: F) -> Dispatcher<'l, S, Data>
+    where
+        F: FnMut(S::Event, &mut Data) -> R + 'l,
+        R: Into<PostAction>,
+    {
+        let action = Rc::new(Cell::new(PostAction::Continue));
+        let callback_action = action.clone();
+        let dispatcher = source.make_dispatcher(move |event, data: &mut Data| {
+            let ret = callback(event, data).into();
+            if ret != PostAction::Continue {
+                callback_action.set(ret);
+            }
+        });
+        Dispatcher {
+            source: Rc::new(RefCell::new(source)),
+            dispatcher,
+            action,
+        }
+    }
+}
+
+impl<'l, S: EventSource, Data> Dispatcher<'l, S, Data> {
+    /// Access the event source
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event source is currently mutably borrowed.
+    pub fn as_source_ref(&self) -> Ref<'_, S> {
+        self.source.borrow()
+    }
+
+    /// Mutably access the event source
+    ///
+    /// If you change the interest or poll options of the source, you need to call
+    /// `LoopHandle::update()` for the changes to take effect.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the event source is currently borrowed.
+    pub fn as_source_mut(&self) -> RefMut<'_, S> {
+        self.source.borrow_mut()
+    }
+
+    /// Consume the dispatcher to retrieve the event source
+    ///
+    /// # Panics
+    ///
+    /// Panics if the dispatcher is still inserted in an event loop, or if
+    /// other clones of it exist.
+    pub fn into_source_inner(self) -> S {
+        let Dispatcher { source, .. } = self;
+        match Rc::try_unwrap(source) {
+            Ok(source) => source.into_inner(),
+            Err(_) => panic!("[calloop] Dispatcher is still in use."),
+        }
+    }
 }
 
 /// An event source that has been inserted into the event loop
 ///
 /// This handle allows you to remove it, and possibly more interactions
-/// depending on the source kind that will be provided by the `Deref`
-/// implementation of this struct to the evented object.
+/// depending on the source kind, as it gives you access to the evented
+/// object using the `as_source_ref()` and `as_source_mut()` methods.
 ///
-/// Dropping this handle does not deregister this source from the event loop,
-/// but will drop the wrapped `EventSource`, maybe rendering it inert depending on
-/// its implementation.
-pub struct Source<E: EventSource> {
-    pub(crate) source: E,
+/// The evented object is shared with the event loop: dropping this handle
+/// does not remove the source from the event loop, which will keep
+/// processing its events.
+pub struct Source<'l, E: EventSource> {
+    pub(crate) source: Rc<RefCell<E>>,
     pub(crate) poll: Rc<Poll>,
-    pub(crate) list: Rc<RefCell<ErasedList>>,
+    pub(crate) list: Rc<RefCell<dyn ErasedList<'l> + 'l>>,
     pub(crate) token: Token,
 }
 
-impl<E: EventSource> Source<E> {
+impl<'l, E: EventSource> Source<'l, E> {
+    /// Access the evented object
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evented object is currently mutably borrowed.
+    pub fn as_source_ref(&self) -> Ref<'_, E> {
+        self.source.borrow()
+    }
+
+    /// Mutably access the evented object
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evented object is currently borrowed.
+    pub fn as_source_mut(&self) -> RefMut<'_, E> {
+        self.source.borrow_mut()
+    }
+
+    /// The token identifying this source in the event loop
+    ///
+    /// It is notably provided by `DispatchError::Source` when this source fails
+    /// processing its events.
+    pub fn token(&self) -> RegistrationToken {
+        RegistrationToken { token: self.token }
+    }
+
     /// Refresh the registration of this event source to the loop
     ///
     /// This can be necessary if the evented object provides methods to change
     /// its behavior. Its documentation should inform you of the need for re-registration.
     pub fn reregister(&self) -> io::Result<()> {
-        self.poll.reregister(
-            &self.source,
-            self.token,
-            self.source.interest(),
-            self.source.pollopts(),
-        )
+        self.list.borrow_mut().reregister(&self.poll, self.token)
+    }
+
+    /// Disable this event source
+    ///
+    /// The evented object is deregistered from the event loop, and will not generate
+    /// any events until it is re-enabled using `enable()`. Its callback is kept in the
+    /// event loop in the meantime. Disabling an already disabled source does nothing.
+    pub fn disable(&self) -> io::Result<()> {
+        self.list.borrow_mut().disable(&self.poll, self.token)
+    }
+
+    /// Re-enable a previously disabled event source
+    ///
+    /// Enabling an already enabled source does nothing.
+    pub fn enable(&self) -> io::Result<()> {
+        self.list.borrow_mut().enable(&self.poll, self.token)
     }
 
     /// Remove this source from the event loop
     ///
-    /// You are given the evented object back.
+    /// You are given the evented object back. This method can also be used
+    /// to retrieve it after the source removed itself using `PostAction::Remove`.
     pub fn remove(self) -> E {
-        let _ = self.poll.deregister(&self.source);
-        let _dispatcher = self.list.borrow_mut().del_source(self.token);
-        self.source
+        let _ = self
+            .list
+            .borrow_mut()
+            .deregister_removed(&self.poll, self.token);
+        let entry = self.list.borrow_mut().del_source(self.token, &*self.source);
+        // the source may have already been removed by the event loop
+        if entry.is_some() {
+            self.source.borrow_mut().before_removal();
+        }
+        drop(entry);
+        match Rc::try_unwrap(self.source) {
+            Ok(source) => source.into_inner(),
+            Err(_) => panic!("[calloop] A removed source is still referenced by the event loop."),
+        }
     }
 }
 
-impl<E: EventSource> ::std::ops::Deref for Source<E> {
-    type Target = E;
-    fn deref(&self) -> &E {
-        &self.source
-    }
+// The type-erased interface used by the event loop to manage its sources
+pub(crate) trait ErasedSource {
+    fn next_deadline(&self) -> Option<Instant>;
+    // the extra options are added to the ones of the source
+    fn register(&self, poll: &Poll, token: Token, extra: PollOpt) -> io::Result<()>;
+    fn reregister(&self, poll: &Poll, token: Token, extra: PollOpt) -> io::Result<()>;
+    fn deregister(&self, poll: &Poll) -> io::Result<()>;
+    fn before_removal(&self);
+    fn before_sleep(&self, timeout: Option<Duration>);
 }
 
-impl<E: EventSource> ::std::ops::DerefMut for Source<E> {
-    fn deref_mut(&mut self) -> &mut E {
-        &mut self.source
+impl<E: EventSource> ErasedSource for RefCell<E> {
+    fn next_deadline(&self) -> Option<Instant> {
+        // the source may be borrowed by the user, in which case its deadline will be
+        // taken into account at the next cycle
+        self.try_borrow()
+            .ok()
+            .and_then(|source| source.next_deadline())
+    }
+
+    fn register(&self, poll: &Poll, token: Token, extra: PollOpt) -> io::Result<()> {
+        let source = self.borrow();
+        poll.register(
+            &*source,
+            token,
+            source.interest(),
+            source.pollopts() | extra,
+        )
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, extra: PollOpt) -> io::Result<()> {
+        let source = self.borrow();
+        poll.reregister(
+            &*source,
+            token,
+            source.interest(),
+            source.pollopts() | extra,
+        )
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&*self.borrow())
+    }
+
+    fn before_removal(&self) {
+        self.borrow_mut().before_removal()
+    }
+
+    fn before_sleep(&self, timeout: Option<Duration>) {
+        // as for deadlines, skip the source if it is borrowed by the user
+        if let Ok(mut source) = self.try_borrow_mut() {
+            source.before_sleep(timeout);
+        }
     }
 }
 
@@ -101,22 +464,37 @@ impl<E: EventSource> ::std::ops::DerefMut for Source<E> {
 ///
 /// This handle allows you to cancel the callback. Dropping
 /// it will *not* cancel it.
-pub struct Idle {
-    pub(crate) callback: Rc<RefCell<ErasedIdle>>,
+pub struct Idle<'l> {
+    pub(crate) callback: Rc<RefCell<dyn ErasedIdle + 'l>>,
 }
 
-impl Idle {
+impl<'l> Idle<'l> {
     /// Cancel the idle callback if it was not already run
     pub fn cancel(self) {
         self.callback.borrow_mut().cancel();
     }
 }
 
+/// A hook that was inserted in this loop
+///
+/// This handle allows you to cancel the hook. Dropping
+/// it will *not* cancel it.
+pub struct Hook<'l> {
+    pub(crate) callback: Rc<RefCell<dyn ErasedIdle + 'l>>,
+}
+
+impl<'l> Hook<'l> {
+    /// Cancel the hook, it will not be called anymore
+    pub fn cancel(self) {
+        self.callback.borrow_mut().cancel();
+    }
+}
+
 pub(crate) trait ErasedIdle {
     fn cancel(&mut self);
 }
 
-impl<Data> ErasedIdle for Option<Box<FnMut(&mut Data)>> {
+impl<T> ErasedIdle for Option<T> {
     fn cancel(&mut self) {
         self.take();
     }
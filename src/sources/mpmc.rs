@@ -0,0 +1,303 @@
+//! A multi-consumer channel distributing its messages among several event loops
+//!
+//! Create a channel using `channel::<T>()`, which returns a `Sender<T>` and a
+//! `Receiver<T>`. Additional receivers are created with `Receiver::try_clone()`, and
+//! are typically sent to other threads, each inserting its own into its event loop.
+//! Every message is received by a single receiver, whichever processes it first.
+//!
+//! A sender wakes up the receivers in turn, so that the messages are spread among the
+//! event loops, and a woken receiver processes the pending messages until there are
+//! none left. This makes it the building block of a server running an event loop on
+//! each thread, like a pool of workers.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::Event;
+use sources::ping::{make_ping, Ping, PingSource};
+use {EventDispatcher, EventSource};
+
+struct Receivers {
+    pings: Vec<(u64, Ping)>,
+    // the receiver to wake up for the next message
+    next: usize,
+    next_id: u64,
+}
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    receivers: Mutex<Receivers>,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn add_receiver(&self, ping: Ping) -> u64 {
+        let mut receivers = self.receivers.lock().unwrap();
+        let id = receivers.next_id;
+        receivers.next_id += 1;
+        receivers.pings.push((id, ping));
+        id
+    }
+}
+
+/// Create a new multi-consumer channel
+pub fn channel<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        receivers: Mutex::new(Receivers {
+            pings: Vec::new(),
+            next: 0,
+            next_id: 0,
+        }),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    let receiver = Receiver::new(shared.clone())?;
+    Ok((Sender { shared }, receiver))
+}
+
+/// The sending end of a multi-consumer channel
+///
+/// It can be cloned and sent accross threads if `T: Send`.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message to one of the receivers
+    ///
+    /// It fails if all the receivers have been dropped, giving the message back.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        let mut receivers = self.shared.receivers.lock().unwrap();
+        if receivers.pings.is_empty() {
+            return Err(SendError(msg));
+        }
+        self.shared.queue.lock().unwrap().push_back(msg);
+        let index = receivers.next % receivers.pings.len();
+        receivers.next = index + 1;
+        receivers.pings[index].1.ping();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // all the receivers need to know about the closing
+            self.shared.closed.store(true, Ordering::Release);
+            for (_, ping) in &self.shared.receivers.lock().unwrap().pings {
+                ping.ping();
+            }
+        }
+    }
+}
+
+/// A receiving end of a multi-consumer channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` for each message it receives, and an `Event::Closed` once all the
+/// senders have been dropped and there are no messages left.
+///
+/// A message is only processed once a receiver is woken up for it, or for a later
+/// message, so all the receivers should be inserted into an event loop.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    id: u64,
+    source: PingSource,
+}
+
+impl<T> Receiver<T> {
+    fn new(shared: Arc<Shared<T>>) -> io::Result<Receiver<T>> {
+        let (ping, source) = make_ping()?;
+        let id = shared.add_receiver(ping);
+        Ok(Receiver { shared, id, source })
+    }
+
+    /// Create an other receiver for the same channel
+    pub fn try_clone(&self) -> io::Result<Receiver<T>> {
+        Receiver::new(self.shared.clone())
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut receivers = self.shared.receivers.lock().unwrap();
+        receivers.pings.retain(|&(id, _)| id != self.id);
+    }
+}
+
+impl<T> Evented for Receiver<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.source.deregister(poll)
+    }
+}
+
+impl<T: 'static> EventSource for Receiver<T> {
+    type Event = Event<T>;
+
+    fn interest(&self) -> Ready {
+        self.source.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.source.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let shared = self.shared.clone();
+        let mut closed = false;
+        self.source.make_dispatcher(move |(), data: &mut Data| {
+            // read before the queue is drained, so that a message sent right before
+            // the closing is never delivered after it
+            let disconnected = shared.closed.load(Ordering::Acquire);
+            // the lock must not be held while the callback runs, as it may send
+            // new messages
+            loop {
+                let msg = shared.queue.lock().unwrap().pop_front();
+                match msg {
+                    Some(msg) => callback(Event::Msg(msg), data),
+                    None => break,
+                }
+            }
+            if !closed && disconnected {
+                closed = true;
+                callback(Event::Closed, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn distribute() {
+        let mut loop1 = ::EventLoop::new().unwrap();
+        let mut loop2 = ::EventLoop::new().unwrap();
+
+        let (sender, receiver1) = channel::<u32>().unwrap();
+        let receiver2 = receiver1.try_clone().unwrap();
+        for (event_loop, receiver) in [(&loop1, receiver1), (&loop2, receiver2)] {
+            event_loop
+                .handle()
+                .insert_source(receiver, |evt, received: &mut Vec<Option<u32>>| match evt {
+                    Event::Msg(msg) => received.push(Some(msg)),
+                    Event::Closed => received.push(None),
+                })
+                .unwrap();
+        }
+
+        // each receiver is woken up in turn
+        let mut received1 = Vec::new();
+        let mut received2 = Vec::new();
+        sender.send(1).unwrap();
+        loop1
+            .dispatch(Some(Duration::from_millis(0)), &mut received1)
+            .unwrap();
+        sender.send(2).unwrap();
+        loop2
+            .dispatch(Some(Duration::from_millis(0)), &mut received2)
+            .unwrap();
+        assert_eq!(received1, &[Some(1)]);
+        assert_eq!(received2, &[Some(2)]);
+
+        // every receiver is notified of the closing
+        ::std::mem::drop(sender);
+        loop1
+            .dispatch(Some(Duration::from_millis(0)), &mut received1)
+            .unwrap();
+        loop2
+            .dispatch(Some(Duration::from_millis(0)), &mut received2)
+            .unwrap();
+        assert_eq!(received1, &[Some(1), None]);
+        assert_eq!(received2, &[Some(2), None]);
+    }
+
+    #[test]
+    fn no_receiver() {
+        let (sender, receiver) = channel::<u32>().unwrap();
+        ::std::mem::drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn closed_is_last() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        for i in 0..1000 {
+            let (sender, receiver) = channel::<u32>().unwrap();
+            let source = event_loop
+                .handle()
+                .insert_source(receiver, |evt, received: &mut Vec<Option<u32>>| match evt {
+                    Event::Msg(msg) => received.push(Some(msg)),
+                    Event::Closed => received.push(None),
+                })
+                .unwrap();
+            // the closing happens at varying times while the receiver drains the queue
+            let thread = ::std::thread::spawn(move || {
+                sender.send(0).unwrap();
+                for _ in 0..(i % 100) * 20 {
+                    ::std::hint::spin_loop();
+                }
+                sender.send(1).unwrap();
+                drop(sender);
+            });
+
+            let mut received = Vec::new();
+            while !received.contains(&None) {
+                event_loop
+                    .dispatch(Some(Duration::from_millis(100)), &mut received)
+                    .unwrap();
+            }
+            thread.join().unwrap();
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut received)
+                .unwrap();
+            assert_eq!(received, vec![Some(0), Some(1), None]);
+            source.remove();
+        }
+    }
+}
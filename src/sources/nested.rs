@@ -0,0 +1,227 @@
+//! An event source driving a nested event loop
+//!
+//! A `NestedLoop` wraps an `EventLoop`, so that it can be inserted as a single
+//! source into a parent event loop. Whenever some sources of the nested loop are
+//! ready, the callback of this source is given an `Event`, allowing it to dispatch
+//! the nested loop with its own shared data.
+//!
+//! This allows modular subsystems to own their own event loop, while being driven by
+//! the event loop of the program.
+//!
+//! The parent loop is woken up through the poller file descriptor of the nested loop,
+//! see the `AsRawFd` implementation of `EventLoop`, as well as by the deadlines of its
+//! sources and its pending idle callbacks. Sources based on `mio::Registration`, like
+//! channels, do not wake up the poller of a loop that is not itself waiting: their
+//! events are only processed the next time the nested loop is dispatched.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {DispatchError, EventDispatcher, EventLoop, EventSource, LoopHandle};
+
+/// An event loop nested into an other one
+///
+/// This is the event source to be inserted into the parent `EventLoop`.
+pub struct NestedLoop<Data: 'static> {
+    inner: Rc<RefCell<EventLoop<'static, Data>>>,
+    fd: RawFd,
+}
+
+impl<Data: 'static> NestedLoop<Data> {
+    /// Wrap an event loop to nest it into an other one
+    pub fn new(event_loop: EventLoop<'static, Data>) -> NestedLoop<Data> {
+        let fd = event_loop.as_raw_fd();
+        NestedLoop {
+            inner: Rc::new(RefCell::new(event_loop)),
+            fd,
+        }
+    }
+
+    /// Get an handle to the nested event loop
+    ///
+    /// It can be used to insert new sources into it.
+    pub fn handle(&self) -> LoopHandle<'static, Data> {
+        self.inner.borrow().handle()
+    }
+}
+
+/// An event generated by the `NestedLoop` source
+///
+/// It signals that the nested event loop needs to be dispatched.
+pub struct Event<Data: 'static> {
+    inner: Rc<RefCell<EventLoop<'static, Data>>>,
+}
+
+impl<Data: 'static> Event<Data> {
+    /// Dispatch the pending events of the nested event loop
+    ///
+    /// This is `EventLoop::dispatch()` with a zero timeout, it does not wait for
+    /// new events.
+    ///
+    /// **Panics** if called from within a callback of the nested loop.
+    pub fn dispatch(&self, data: &mut Data) -> Result<(), DispatchError> {
+        self.inner
+            .borrow_mut()
+            .dispatch(Some(Duration::from_millis(0)), data)
+    }
+}
+
+impl<Data: 'static> Evented for NestedLoop<Data> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl<D: 'static> EventSource for NestedLoop<D> {
+    type Event = Event<D>;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    // the poller remains readable as long as the nested loop has pending events,
+    // which may not all be processed by a single dispatch
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::level()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        // the nested loop is borrowed while it is dispatched
+        self.inner
+            .try_borrow()
+            .ok()
+            .and_then(|event_loop| event_loop.nested_deadline())
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<D>, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, D: 'static, F: FnMut(Event<D>, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<EventLoop<'static, D>>>,
+    callback: F,
+}
+
+impl<Data, D: 'static, F: FnMut(Event<D>, &mut Data)> EventDispatcher<Data>
+    for Dispatcher<Data, D, F>
+{
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        (self.callback)(
+            Event {
+                inner: self.inner.clone(),
+            },
+            data,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use sources::timer::Timer;
+
+    #[test]
+    fn nested_dispatch() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        use sources::generic::Generic;
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let nested = NestedLoop::new(::EventLoop::new().unwrap());
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut generic = Generic::from_fd_source(rx);
+        generic.set_interest(Ready::readable());
+        generic.set_pollopts(PollOpt::level());
+        nested
+            .handle()
+            .insert_source(generic, |evt, sum: &mut u32| {
+                let mut buffer = [0u8; 8];
+                let n = evt.source.borrow_mut().0.read(&mut buffer).unwrap();
+                *sum += buffer[..n].iter().map(|&b| u32::from(b)).sum::<u32>();
+            })
+            .unwrap();
+
+        event_loop
+            .handle()
+            .insert_source(nested, |evt, sum: &mut u32| evt.dispatch(sum).unwrap())
+            .unwrap();
+
+        let mut sum = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut sum)
+            .unwrap();
+        assert_eq!(sum, 0);
+
+        tx.write_all(&[1, 2]).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_secs(1)), &mut sum)
+            .unwrap();
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn nested_timer_deadline() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let nested = NestedLoop::new(::EventLoop::new().unwrap());
+        let timer = Timer::<()>::new();
+        timer.handle().add_timeout(Duration::from_millis(50), ());
+        nested
+            .handle()
+            .insert_source(timer, |_, fired: &mut bool| *fired = true)
+            .unwrap();
+
+        event_loop
+            .handle()
+            .insert_source(nested, |evt, fired: &mut bool| evt.dispatch(fired).unwrap())
+            .unwrap();
+
+        // the parent loop does not wait past the deadline of the nested timer
+        let mut fired = false;
+        let start = Instant::now();
+        event_loop
+            .dispatch(Some(Duration::from_secs(5)), &mut fired)
+            .unwrap();
+        assert!(fired);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
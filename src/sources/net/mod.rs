@@ -0,0 +1,33 @@
+//! Event sources for network sockets
+//!
+//! These sources wrap the non-blocking sockets of `mio`, and handle their readiness
+//! internally: they deliver the accepted connections of a listener, rather than the
+//! readiness of its socket. The accepted streams are the ones of `mio`, and can
+//! themselves be inserted into the event loop, for example by wrapping them in a
+//! `Generic` source. A `UdpSource` delivers the datagrams received by a UDP socket,
+//! and queues the ones it sends while the socket is not writable.
+//!
+//! On unix, the connections of a unix listener are accepted the same way, and a
+//! `UnixStreamSource` reads from a unix stream and buffers the data written to it. The
+//! credentials of the peer of a stream are given by `peer_credentials()`, allowing
+//! control sockets to check who they talk to.
+//!
+//! On Linux, the `AF_VSOCK` sockets connecting virtual machines to their host are
+//! handled the same way, by a `VsockListenerSource` and a `VsockStreamSource`, for
+//! guest agents and the control planes of virtual machine monitors.
+
+mod tcp;
+mod udp;
+#[cfg(unix)]
+mod unix;
+#[cfg(target_os = "linux")]
+mod vsock;
+
+pub use self::tcp::TcpListenerSource;
+pub use self::udp::UdpSource;
+#[cfg(unix)]
+pub use self::unix::{
+    peer_credentials, Credentials, StreamEvent, UnixListenerSource, UnixStreamSource,
+};
+#[cfg(target_os = "linux")]
+pub use self::vsock::{VsockAddr, VsockListenerSource, VsockStream, VsockStreamSource};
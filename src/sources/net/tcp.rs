@@ -0,0 +1,268 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// The default delay before accepting connections again, once out of file descriptors
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// An event source accepting the connections of a TCP listener
+///
+/// It generates an event with each accepted stream and the address of its peer. The
+/// streams are non-blocking.
+///
+/// When no more connections can be accepted because the process or the system ran out
+/// of file descriptors or memory, the listener stops accepting them for a backoff
+/// delay, rather than spinning on the pending connections. They are accepted once it
+/// expires, hopefully after some connections were closed.
+pub struct TcpListenerSource {
+    listener: Rc<TcpListener>,
+    backoff: Rc<Backoff>,
+}
+
+// shared with the dispatcher, so that the backoff can be changed after insertion
+struct Backoff {
+    delay: Cell<Duration>,
+    retry: Cell<Option<Instant>>,
+}
+
+impl Backoff {
+    // stop accepting connections for the backoff delay
+    fn start(&self) {
+        self.retry.set(Some(Instant::now() + self.delay.get()));
+    }
+}
+
+impl TcpListenerSource {
+    /// Create a listener bound to the given address
+    pub fn bind(addr: &SocketAddr) -> io::Result<TcpListenerSource> {
+        Ok(TcpListenerSource::from_mio(TcpListener::bind(addr)?))
+    }
+
+    /// Wrap a listener of the standard library
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_std(listener: net::TcpListener) -> io::Result<TcpListenerSource> {
+        Ok(TcpListenerSource::from_mio(TcpListener::from_std(
+            listener,
+        )?))
+    }
+
+    fn from_mio(listener: TcpListener) -> TcpListenerSource {
+        TcpListenerSource {
+            listener: Rc::new(listener),
+            backoff: Rc::new(Backoff {
+                delay: Cell::new(DEFAULT_BACKOFF),
+                retry: Cell::new(None),
+            }),
+        }
+    }
+
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Change the delay before accepting connections again, once out of resources
+    ///
+    /// It is 100 milliseconds by default. It can be changed after the source is
+    /// inserted into an event loop, and applies from the next exhaustion of resources.
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff.delay.set(backoff);
+    }
+
+    /// Access the underlying listener
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+// whether no connection can be accepted until some resources are released
+fn is_exhausted(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        use nix::libc;
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+        )
+    }
+    #[cfg(windows)]
+    {
+        // WSAEMFILE and WSAENOBUFS
+        matches!(err.raw_os_error(), Some(10024) | Some(10055))
+    }
+}
+
+impl Evented for TcpListenerSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.listener.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.listener.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.listener.deregister(poll)
+    }
+}
+
+impl EventSource for TcpListenerSource {
+    type Event = (TcpStream, SocketAddr);
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.backoff.retry.get()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((TcpStream, SocketAddr), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            listener: self.listener.clone(),
+            backoff: self.backoff.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut((TcpStream, SocketAddr), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    listener: Rc<TcpListener>,
+    backoff: Rc<Backoff>,
+    callback: F,
+}
+
+impl<Data, F: FnMut((TcpStream, SocketAddr), &mut Data)> EventDispatcher<Data>
+    for Dispatcher<Data, F>
+{
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        if let Some(retry) = self.backoff.retry.get() {
+            // the pending connections are accepted once the backoff expires
+            if Instant::now() < retry {
+                return Ok(());
+            }
+            self.backoff.retry.set(None);
+        }
+        loop {
+            match self.listener.accept() {
+                Ok(connection) => (self.callback)(connection, data),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if is_exhausted(err) => {
+                    self.backoff.start();
+                    return Ok(());
+                }
+                // these errors only concern the connection being accepted
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::Interrupted
+                        || err.kind() == io::ErrorKind::ConnectionAborted
+                        || err.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn accept() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = TcpListenerSource::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = source.local_addr().unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(stream, peer), accepted: &mut Vec<_>| {
+                assert_eq!(stream.peer_addr().unwrap(), peer);
+                accepted.push(peer)
+            })
+            .unwrap();
+
+        let first = net::TcpStream::connect(addr).unwrap();
+        let second = net::TcpStream::connect(addr).unwrap();
+        let mut accepted = Vec::new();
+        while accepted.len() < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut accepted)
+                .unwrap();
+        }
+        assert_eq!(
+            accepted,
+            &[first.local_addr().unwrap(), second.local_addr().unwrap()]
+        );
+    }
+
+    #[test]
+    fn backoff_after_insertion() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = TcpListenerSource::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = source.local_addr().unwrap();
+        let source = event_loop
+            .handle()
+            .insert_source(source, |(_, peer), accepted: &mut Vec<_>| {
+                accepted.push(peer)
+            })
+            .unwrap();
+
+        source
+            .as_source_mut()
+            .set_backoff(Duration::from_secs(3600));
+        // act as if the dispatcher ran out of file descriptors
+        source.as_source_ref().backoff.start();
+        let deadline = source.as_source_ref().next_deadline().unwrap();
+        assert!(deadline > Instant::now() + Duration::from_secs(3000));
+
+        let _stream = net::TcpStream::connect(addr).unwrap();
+        let mut accepted = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(50)), &mut accepted)
+            .unwrap();
+        assert!(accepted.is_empty());
+
+        // the pending connection is accepted once the backoff expires
+        source
+            .as_source_ref()
+            .backoff
+            .retry
+            .set(Some(Instant::now()));
+        while accepted.is_empty() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut accepted)
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::time::Instant;
+
+use mio::net::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// The default number of datagrams received per dispatch
+const DEFAULT_BUDGET: usize = 64;
+
+// the largest possible UDP payload
+const MAX_DATAGRAM: usize = 65_535;
+
+struct Inner {
+    socket: UdpSocket,
+    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+impl Inner {
+    // send the queued datagrams, until the socket would block
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some((datagram, addr)) = self.outgoing.pop_front() {
+            match self.socket.send_to(&datagram, &addr) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.outgoing.push_front((datagram, addr));
+                    return Ok(());
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {
+                    self.outgoing.push_front((datagram, addr));
+                }
+                // the datagram is dropped, as UDP would have
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An event source receiving and sending the datagrams of a UDP socket
+///
+/// It generates an event with each received datagram and the address of its sender.
+///
+/// At most a budget of datagrams are received each time the source is dispatched, 64
+/// by default, so that a flood of datagrams does not starve the other sources. The
+/// remaining ones are received during the next dispatches.
+///
+/// The datagrams sent with `send_to()` are queued if the socket is not writable, and
+/// sent by the event loop once it becomes writable.
+pub struct UdpSource {
+    inner: Rc<RefCell<Inner>>,
+    budget: usize,
+    // when the budget was exhausted, if datagrams may be left in the socket
+    pending: Rc<Cell<Option<Instant>>>,
+}
+
+impl UdpSource {
+    /// Create a socket bound to the given address
+    pub fn bind(addr: &SocketAddr) -> io::Result<UdpSource> {
+        Ok(UdpSource::from_mio(UdpSocket::bind(addr)?))
+    }
+
+    /// Wrap a socket of the standard library
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_std(socket: net::UdpSocket) -> io::Result<UdpSource> {
+        Ok(UdpSource::from_mio(UdpSocket::from_socket(socket)?))
+    }
+
+    fn from_mio(socket: UdpSocket) -> UdpSource {
+        UdpSource {
+            inner: Rc::new(RefCell::new(Inner {
+                socket,
+                outgoing: VecDeque::new(),
+            })),
+            budget: DEFAULT_BUDGET,
+            pending: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// The address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.borrow().socket.local_addr()
+    }
+
+    /// Change the maximum number of datagrams received per dispatch
+    ///
+    /// It must not be zero. It only applies if it is changed before the source is
+    /// inserted into an event loop.
+    pub fn set_budget(&mut self, budget: usize) {
+        assert!(budget > 0, "the budget of a UdpSource must not be zero");
+        self.budget = budget;
+    }
+
+    /// Send a datagram to the given address
+    ///
+    /// If the socket is not writable, or if some datagrams are already queued, the
+    /// datagram is queued and sent by the event loop. Otherwise it is sent immediately,
+    /// and its errors are returned.
+    pub fn send_to(&mut self, datagram: &[u8], addr: &SocketAddr) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        inner.outgoing.push_back((datagram.to_vec(), *addr));
+        if inner.outgoing.len() == 1 {
+            inner.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The number of datagrams waiting to be sent
+    pub fn queued(&self) -> usize {
+        self.inner.borrow().outgoing.len()
+    }
+
+    /// Run a closure with the underlying socket
+    ///
+    /// This allows configuring it, for example joining multicast groups.
+    pub fn with_socket<T, F: FnOnce(&UdpSocket) -> T>(&self, f: F) -> T {
+        f(&self.inner.borrow().socket)
+    }
+}
+
+impl Evented for UdpSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner
+            .borrow()
+            .socket
+            .register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner
+            .borrow()
+            .socket
+            .reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.borrow().socket.deregister(poll)
+    }
+}
+
+impl EventSource for UdpSource {
+    type Event = (Vec<u8>, SocketAddr);
+
+    fn interest(&self) -> Ready {
+        Ready::readable() | Ready::writable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        // the socket is edge-triggered, the remaining datagrams would not wake it up
+        self.pending.get()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((Vec<u8>, SocketAddr), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            budget: self.budget,
+            pending: self.pending.clone(),
+            buffer: vec![0; MAX_DATAGRAM],
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut((Vec<u8>, SocketAddr), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner>>,
+    budget: usize,
+    pending: Rc<Cell<Option<Instant>>>,
+    buffer: Vec<u8>,
+    callback: F,
+}
+
+impl<Data, F: FnMut((Vec<u8>, SocketAddr), &mut Data)> EventDispatcher<Data>
+    for Dispatcher<Data, F>
+{
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        if ready.is_writable() {
+            self.inner.borrow_mut().flush()?;
+        }
+
+        self.pending.set(None);
+        let mut received = 0;
+        while received < self.budget {
+            let result = self.inner.borrow().socket.recv_from(&mut self.buffer);
+            match result {
+                Ok((len, addr)) => {
+                    received += 1;
+                    (self.callback)((self.buffer[..len].to_vec(), addr), data);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.pending.set(Some(Instant::now()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn datagrams() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let local = "127.0.0.1:0".parse().unwrap();
+        let mut source = UdpSource::bind(&local).unwrap();
+        source.set_budget(2);
+        let addr = source.local_addr().unwrap();
+        let peer = net::UdpSocket::bind(local).unwrap();
+        for i in 0..5u8 {
+            peer.send_to(&[i], addr).unwrap();
+        }
+
+        let source = event_loop
+            .handle()
+            .insert_source(source, |(datagram, from), received: &mut Vec<_>| {
+                received.push((datagram, from))
+            })
+            .unwrap();
+
+        // the budget limits each dispatch to two datagrams
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 2);
+        while received.len() < 5 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut received)
+                .unwrap();
+        }
+        let peer_addr = peer.local_addr().unwrap();
+        for (i, &(ref datagram, from)) in received.iter().enumerate() {
+            assert_eq!(datagram, &[i as u8]);
+            assert_eq!(from, peer_addr);
+        }
+
+        source
+            .as_source_mut()
+            .send_to(b"reply", &peer_addr)
+            .unwrap();
+        let mut buffer = [0u8; 16];
+        let (len, from) = peer.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"reply");
+        assert_eq!(from, addr);
+    }
+}
@@ -0,0 +1,521 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::path::Path;
+use std::rc::Rc;
+
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+/// The credentials of the process at the other end of a unix socket
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    /// The pid of the process
+    ///
+    /// It is only known on Linux and Android.
+    pub pid: Option<i32>,
+    /// The effective user id of the process
+    pub uid: u32,
+    /// The effective group id of the process
+    pub gid: u32,
+}
+
+/// Get the credentials of the peer of a connected unix socket
+///
+/// They are the ones the peer had when it connected, or created the socket pair.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<Credentials> {
+    let fd = stream.as_raw_fd();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Credentials {
+            pid: Some(cred.pid),
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let mut uid = 0;
+        let mut gid = 0;
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Credentials {
+            pid: None,
+            uid,
+            gid,
+        })
+    }
+}
+
+/// An event source accepting the connections of a unix listener
+///
+/// It generates an event with each accepted stream and the address of its peer. The
+/// streams are non-blocking, and can be wrapped in a `UnixStreamSource`.
+pub struct UnixListenerSource {
+    listener: Rc<UnixListener>,
+}
+
+impl UnixListenerSource {
+    /// Create a listener bound to the given path
+    ///
+    /// This fails with `AddrInUse` if the path exists, it is not removed when the
+    /// listener is dropped.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListenerSource> {
+        UnixListenerSource::from_std(UnixListener::bind(path)?)
+    }
+
+    /// Wrap a listener of the standard library
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_std(listener: UnixListener) -> io::Result<UnixListenerSource> {
+        listener.set_nonblocking(true)?;
+        Ok(UnixListenerSource {
+            listener: Rc::new(listener),
+        })
+    }
+
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Access the underlying listener
+    pub fn get_ref(&self) -> &UnixListener {
+        &self.listener
+    }
+}
+
+impl Evented for UnixListenerSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.listener.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.listener.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.listener.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for UnixListenerSource {
+    type Event = (UnixStream, SocketAddr);
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((UnixStream, SocketAddr), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(ListenerDispatcher {
+            _data: ::std::marker::PhantomData,
+            listener: self.listener.clone(),
+            callback,
+        }))
+    }
+}
+
+struct ListenerDispatcher<Data, F: FnMut((UnixStream, SocketAddr), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    listener: Rc<UnixListener>,
+    callback: F,
+}
+
+impl<Data, F: FnMut((UnixStream, SocketAddr), &mut Data)> EventDispatcher<Data>
+    for ListenerDispatcher<Data, F>
+{
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    stream.set_nonblocking(true)?;
+                    (self.callback)((stream, addr), data);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::Interrupted
+                        || err.kind() == io::ErrorKind::ConnectionAborted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// An event generated by a `UnixStreamSource` or a `VsockStreamSource`
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// Data was read from the stream
+    Readable(Vec<u8>),
+    /// All the data buffered by the `write()` method of the source was written
+    ///
+    /// It is only generated if some data could not be written immediately.
+    Writable,
+    /// The peer closed the stream, or its connection failed
+    ///
+    /// This event is generated once, nothing can be written to the stream afterwards.
+    Hup,
+}
+
+pub(super) struct Inner<S> {
+    pub(super) stream: S,
+    outgoing: Vec<u8>,
+    hup: bool,
+}
+
+impl<S: Read + Write> Inner<S> {
+    pub(super) fn new(stream: S) -> Inner<S> {
+        Inner {
+            stream,
+            outgoing: Vec::new(),
+            hup: false,
+        }
+    }
+
+    // buffer data and write as much of it as possible
+    pub(super) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.hup {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.outgoing.extend_from_slice(data);
+        self.flush().map(|_| ())
+    }
+
+    pub(super) fn buffered(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    // write as much of the buffered data as possible, returning whether it is all written
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+// whether an error means the peer is gone
+fn is_hup(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::BrokenPipe || err.kind() == io::ErrorKind::ConnectionReset
+}
+
+/// An event source reading from and writing to a unix stream
+///
+/// It generates a `StreamEvent` with the data read from the stream. The data written
+/// with `write()` is buffered, and written as the stream becomes writable.
+pub struct UnixStreamSource {
+    inner: Rc<RefCell<Inner<UnixStream>>>,
+    fd: RawFd,
+}
+
+impl UnixStreamSource {
+    /// Wrap a connected stream
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn new(stream: UnixStream) -> io::Result<UnixStreamSource> {
+        stream.set_nonblocking(true)?;
+        Ok(UnixStreamSource {
+            fd: stream.as_raw_fd(),
+            inner: Rc::new(RefCell::new(Inner::new(stream))),
+        })
+    }
+
+    /// Connect to the unix socket at the given path
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStreamSource> {
+        UnixStreamSource::new(UnixStream::connect(path)?)
+    }
+
+    /// Write data to the stream
+    ///
+    /// What cannot be written immediately is buffered, and written by the event loop
+    /// once the stream becomes writable, generating a `StreamEvent::Writable` event
+    /// when it is all written.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.borrow_mut().write(data)
+    }
+
+    /// The size of the data waiting to be written
+    pub fn buffered(&self) -> usize {
+        self.inner.borrow().buffered()
+    }
+
+    /// The credentials of the peer of the stream
+    pub fn peer_credentials(&self) -> io::Result<Credentials> {
+        peer_credentials(&self.inner.borrow().stream)
+    }
+}
+
+impl Evented for UnixStreamSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl EventSource for UnixStreamSource {
+    type Event = StreamEvent;
+
+    fn interest(&self) -> Ready {
+        Ready::readable() | Ready::writable() | UnixReady::hup()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(StreamEvent, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(StreamDispatcher::new(
+            self.inner.clone(),
+            callback,
+        )))
+    }
+}
+
+pub(super) struct StreamDispatcher<Data, S, F: FnMut(StreamEvent, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner<S>>>,
+    callback: F,
+}
+
+impl<Data, S, F: FnMut(StreamEvent, &mut Data)> StreamDispatcher<Data, S, F> {
+    pub(super) fn new(inner: Rc<RefCell<Inner<S>>>, callback: F) -> StreamDispatcher<Data, S, F> {
+        StreamDispatcher {
+            _data: ::std::marker::PhantomData,
+            inner,
+            callback,
+        }
+    }
+
+    fn hup(&mut self, data: &mut Data) {
+        let was_hup = {
+            let mut inner = self.inner.borrow_mut();
+            inner.outgoing.clear();
+            mem::replace(&mut inner.hup, true)
+        };
+        if !was_hup {
+            (self.callback)(StreamEvent::Hup, data);
+        }
+    }
+}
+
+impl<Data, S: Read + Write, F: FnMut(StreamEvent, &mut Data)> EventDispatcher<Data>
+    for StreamDispatcher<Data, S, F>
+{
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        if self.inner.borrow().hup {
+            return Ok(());
+        }
+
+        if ready.is_writable() {
+            let result = {
+                let mut inner = self.inner.borrow_mut();
+                let pending = !inner.outgoing.is_empty();
+                inner.flush().map(|flushed| pending && flushed)
+            };
+            match result {
+                Ok(true) => (self.callback)(StreamEvent::Writable, data),
+                Ok(false) => {}
+                Err(ref err) if is_hup(err) => {
+                    self.hup(data);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        // the data still in the stream is read before reporting a hangup
+        let mut buffer = [0u8; 4096];
+        let mut read = Vec::new();
+        let mut eof = false;
+        let result = {
+            let mut inner = self.inner.borrow_mut();
+            loop {
+                match inner.stream.read(&mut buffer) {
+                    Ok(0) => {
+                        eof = true;
+                        break Ok(());
+                    }
+                    Ok(n) => read.extend_from_slice(&buffer[..n]),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(ref err) if is_hup(err) => {
+                        eof = true;
+                        break Ok(());
+                    }
+                    Err(err) => break Err(err),
+                }
+            }
+        };
+        if !read.is_empty() {
+            (self.callback)(StreamEvent::Readable(read), data);
+        }
+        result?;
+        if eof || UnixReady::from(ready).is_hup() || UnixReady::from(ready).is_error() {
+            self.hup(data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn listener_and_stream() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        let path = ::std::env::temp_dir().join(format!("calloop-unix-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let listener = UnixListenerSource::bind(&path).unwrap();
+
+        #[derive(Default)]
+        struct State {
+            received: Vec<u8>,
+            hup: bool,
+        }
+        handle
+            .insert_source(listener, {
+                let handle = handle.clone();
+                move |(stream, _), _: &mut State| {
+                    let mut server = UnixStreamSource::new(stream).unwrap();
+                    server.write(b"hello").unwrap();
+                    handle.insert_source(server, |_, _: &mut State| {}).unwrap();
+                }
+            })
+            .unwrap();
+
+        let client = UnixStreamSource::connect(&path).unwrap();
+        // the server is this process
+        let credentials = client.peer_credentials().unwrap();
+        assert_eq!(credentials.pid, Some(::std::process::id() as i32));
+        assert_eq!(credentials.uid, unsafe { libc::geteuid() });
+        handle
+            .insert_source(client, |evt, state: &mut State| match evt {
+                StreamEvent::Readable(data) => state.received.extend(data),
+                StreamEvent::Writable => {}
+                StreamEvent::Hup => state.hup = true,
+            })
+            .unwrap();
+
+        let mut state = State::default();
+        while state.received.len() < 5 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut state)
+                .unwrap();
+        }
+        assert_eq!(state.received, b"hello");
+        assert!(!state.hup);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn buffered_writes_and_hup() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (local, remote) = UnixStream::pair().unwrap();
+        let mut source = UnixStreamSource::new(local).unwrap();
+        // more than the socket buffer can hold
+        let data = vec![0u8; 1 << 22];
+        source.write(&data).unwrap();
+        assert!(source.buffered() > 0);
+        let source = event_loop
+            .handle()
+            .insert_source(source, |evt, events: &mut Vec<StreamEvent>| {
+                events.push(evt)
+            })
+            .unwrap();
+
+        // the remote end reads everything, then hangs up
+        let reader = ::std::thread::spawn(move || {
+            let mut remote = remote;
+            let mut received = vec![0u8; 1 << 22];
+            remote.read_exact(&mut received).unwrap();
+        });
+        let mut events = Vec::new();
+        while !events.iter().any(|evt| matches!(evt, StreamEvent::Hup)) {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut events)
+                .unwrap();
+        }
+        reader.join().unwrap();
+        assert!(matches!(events[0], StreamEvent::Writable));
+        assert_eq!(source.as_source_ref().buffered(), 0);
+        assert_eq!(
+            source.as_source_mut().write(b"late").err().unwrap().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
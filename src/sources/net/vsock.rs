@@ -0,0 +1,549 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use super::unix::{Inner, StreamDispatcher, StreamEvent};
+use {EventDispatcher, EventSource};
+
+/// The address of a vsock socket
+///
+/// A vsock address is made of the context id (CID) of a virtual machine, or of the host,
+/// and of a port number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VsockAddr {
+    /// The context id
+    pub cid: u32,
+    /// The port
+    pub port: u32,
+}
+
+impl VsockAddr {
+    /// Any context id, to bind a listener to all of them
+    pub const CID_ANY: u32 = libc::VMADDR_CID_ANY;
+    /// The context id of the hypervisor
+    pub const CID_HYPERVISOR: u32 = libc::VMADDR_CID_HYPERVISOR;
+    /// The context id of the local host, for the loopback transport
+    pub const CID_LOCAL: u32 = libc::VMADDR_CID_LOCAL;
+    /// The context id of the host, as seen from a virtual machine
+    pub const CID_HOST: u32 = libc::VMADDR_CID_HOST;
+    /// Any port, to bind a listener to a free one
+    pub const PORT_ANY: u32 = libc::VMADDR_PORT_ANY;
+
+    /// Create an address
+    pub fn new(cid: u32, port: u32) -> VsockAddr {
+        VsockAddr { cid, port }
+    }
+
+    fn to_raw(self) -> libc::sockaddr_vm {
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = self.cid;
+        addr.svm_port = self.port;
+        addr
+    }
+
+    fn from_raw(addr: &libc::sockaddr_vm) -> VsockAddr {
+        VsockAddr {
+            cid: addr.svm_cid,
+            port: addr.svm_port,
+        }
+    }
+}
+
+const ADDR_LEN: libc::socklen_t = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+// an owned socket
+struct Socket {
+    fd: OwnedFd,
+}
+
+impl Socket {
+    fn new(flags: libc::c_int) -> io::Result<Socket> {
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | flags, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn from_fd(fd: RawFd) -> io::Result<Socket> {
+        let socket = Socket {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        socket.set_nonblocking()?;
+        Ok(socket)
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) }
+                < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn addr(
+        &self,
+        getter: unsafe extern "C" fn(
+            libc::c_int,
+            *mut libc::sockaddr,
+            *mut libc::socklen_t,
+        ) -> libc::c_int,
+    ) -> io::Result<VsockAddr> {
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = ADDR_LEN;
+        if unsafe {
+            getter(
+                self.fd.as_raw_fd(),
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(VsockAddr::from_raw(&addr))
+    }
+}
+
+/// A connected vsock stream
+///
+/// `VsockStreamSource` wraps it to read and write it from the event loop.
+pub struct VsockStream {
+    socket: Socket,
+}
+
+impl VsockStream {
+    /// Connect to the given address
+    ///
+    /// This blocks until the connection is established, then the stream is switched to
+    /// non-blocking mode.
+    pub fn connect(addr: VsockAddr) -> io::Result<VsockStream> {
+        let socket = Socket::new(libc::SOCK_CLOEXEC)?;
+        let raw = addr.to_raw();
+        loop {
+            let ret = unsafe {
+                libc::connect(
+                    socket.fd.as_raw_fd(),
+                    &raw as *const _ as *const libc::sockaddr,
+                    ADDR_LEN,
+                )
+            };
+            if ret == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        socket.set_nonblocking()?;
+        Ok(VsockStream { socket })
+    }
+
+    /// Wrap the file descriptor of a connected vsock stream, taking ownership of it
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_fd(fd: RawFd) -> io::Result<VsockStream> {
+        Ok(VsockStream {
+            socket: Socket::from_fd(fd)?,
+        })
+    }
+
+    /// The address of the local end of the stream
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        self.socket.addr(libc::getsockname)
+    }
+
+    /// The address of the peer of the stream
+    pub fn peer_addr(&self) -> io::Result<VsockAddr> {
+        self.socket.addr(libc::getpeername)
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.fd.as_raw_fd()
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe {
+            libc::read(
+                self.socket.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a write to a closed stream fails with EPIPE, rather than raising SIGPIPE
+        let len = unsafe {
+            libc::send(
+                self.socket.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An event source accepting the connections of a vsock listener
+///
+/// It generates an event with each accepted stream and the address of its peer. The
+/// streams are non-blocking, and can be wrapped in a `VsockStreamSource`.
+pub struct VsockListenerSource {
+    socket: Rc<Socket>,
+}
+
+impl VsockListenerSource {
+    /// Create a listener bound to the given address
+    ///
+    /// The address is usually made of `VsockAddr::CID_ANY` and of the port to listen
+    /// on. This fails with `EADDRNOTAVAIL` if no vsock transport can reach the given
+    /// context id, like the loopback one for `VsockAddr::CID_LOCAL`.
+    pub fn bind(addr: VsockAddr) -> io::Result<VsockListenerSource> {
+        let socket = Socket::new(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC)?;
+        let raw = addr.to_raw();
+        let ret = unsafe {
+            libc::bind(
+                socket.fd.as_raw_fd(),
+                &raw as *const _ as *const libc::sockaddr,
+                ADDR_LEN,
+            )
+        };
+        if ret < 0 || unsafe { libc::listen(socket.fd.as_raw_fd(), 128) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(VsockListenerSource {
+            socket: Rc::new(socket),
+        })
+    }
+
+    /// Wrap the file descriptor of a listening vsock socket, taking ownership of it
+    ///
+    /// This is typically one passed by the service manager, see `systemd::listen_fds()`.
+    /// It is switched to non-blocking mode.
+    pub fn from_fd(fd: RawFd) -> io::Result<VsockListenerSource> {
+        Ok(VsockListenerSource {
+            socket: Rc::new(Socket::from_fd(fd)?),
+        })
+    }
+
+    /// The address the listener is bound to
+    ///
+    /// It gives the port chosen by the kernel when binding to `VsockAddr::PORT_ANY`.
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        self.socket.addr(libc::getsockname)
+    }
+}
+
+impl AsRawFd for VsockListenerSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.fd.as_raw_fd()
+    }
+}
+
+impl Evented for VsockListenerSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.socket.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.socket.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.socket.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for VsockListenerSource {
+    type Event = (VsockStream, VsockAddr);
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((VsockStream, VsockAddr), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(ListenerDispatcher {
+            _data: ::std::marker::PhantomData,
+            socket: self.socket.clone(),
+            callback,
+        }))
+    }
+}
+
+struct ListenerDispatcher<Data, F: FnMut((VsockStream, VsockAddr), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    socket: Rc<Socket>,
+    callback: F,
+}
+
+impl<Data, F: FnMut((VsockStream, VsockAddr), &mut Data)> EventDispatcher<Data>
+    for ListenerDispatcher<Data, F>
+{
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        loop {
+            let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+            let mut len = ADDR_LEN;
+            let fd = unsafe {
+                libc::accept4(
+                    self.socket.fd.as_raw_fd(),
+                    &mut addr as *mut _ as *mut libc::sockaddr,
+                    &mut len,
+                    libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd >= 0 {
+                let stream = VsockStream {
+                    socket: Socket {
+                        fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                    },
+                };
+                (self.callback)((stream, VsockAddr::from_raw(&addr)), data);
+                continue;
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => return Ok(()),
+                io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted => {}
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+/// An event source reading from and writing to a vsock stream
+///
+/// It generates a `StreamEvent` with the data read from the stream. The data written
+/// with `write()` is buffered, and written as the stream becomes writable.
+pub struct VsockStreamSource {
+    inner: Rc<RefCell<Inner<VsockStream>>>,
+    fd: RawFd,
+}
+
+impl VsockStreamSource {
+    /// Wrap a connected stream
+    pub fn new(stream: VsockStream) -> VsockStreamSource {
+        VsockStreamSource {
+            fd: stream.as_raw_fd(),
+            inner: Rc::new(RefCell::new(Inner::new(stream))),
+        }
+    }
+
+    /// Connect to the given address
+    ///
+    /// This blocks until the connection is established.
+    pub fn connect(addr: VsockAddr) -> io::Result<VsockStreamSource> {
+        Ok(VsockStreamSource::new(VsockStream::connect(addr)?))
+    }
+
+    /// Write data to the stream
+    ///
+    /// What cannot be written immediately is buffered, and written by the event loop
+    /// once the stream becomes writable, generating a `StreamEvent::Writable` event
+    /// when it is all written.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.borrow_mut().write(data)
+    }
+
+    /// The size of the data waiting to be written
+    pub fn buffered(&self) -> usize {
+        self.inner.borrow().buffered()
+    }
+
+    /// The address of the peer of the stream
+    pub fn peer_addr(&self) -> io::Result<VsockAddr> {
+        self.inner.borrow().stream.peer_addr()
+    }
+}
+
+impl Evented for VsockStreamSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl EventSource for VsockStreamSource {
+    type Event = StreamEvent;
+
+    fn interest(&self) -> Ready {
+        Ready::readable() | Ready::writable() | UnixReady::hup()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(StreamEvent, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(StreamDispatcher::new(
+            self.inner.clone(),
+            callback,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn unsupported(err: &io::Error) -> bool {
+        // no vsock support, or no loopback transport
+        err.raw_os_error() == Some(libc::EAFNOSUPPORT)
+            || err.raw_os_error() == Some(libc::ENODEV)
+            || err.raw_os_error() == Some(libc::EADDRNOTAVAIL)
+    }
+
+    #[test]
+    fn bind_any_port() {
+        let listener = match VsockListenerSource::bind(VsockAddr::new(
+            VsockAddr::CID_ANY,
+            VsockAddr::PORT_ANY,
+        )) {
+            Ok(listener) => listener,
+            Err(ref err) if unsupported(err) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(addr.cid, VsockAddr::CID_ANY);
+        assert_ne!(addr.port, VsockAddr::PORT_ANY);
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+        event_loop
+            .handle()
+            .insert_source(listener, |_, accepted: &mut u32| *accepted += 1)
+            .unwrap();
+        let mut accepted = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut accepted)
+            .unwrap();
+        assert_eq!(accepted, 0);
+    }
+
+    #[test]
+    fn loopback() {
+        // the loopback transport is needed to connect to the listener
+        let listener = match VsockListenerSource::bind(VsockAddr::new(
+            VsockAddr::CID_LOCAL,
+            VsockAddr::PORT_ANY,
+        )) {
+            Ok(listener) => listener,
+            Err(ref err) if unsupported(err) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let addr = listener.local_addr().unwrap();
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let handle = event_loop.handle();
+
+        #[derive(Default)]
+        struct State {
+            received: Vec<u8>,
+            hup: bool,
+        }
+        handle
+            .insert_source(listener, {
+                let handle = handle.clone();
+                move |(stream, peer), _: &mut State| {
+                    assert_eq!(peer.cid, VsockAddr::CID_LOCAL);
+                    let mut server = VsockStreamSource::new(stream);
+                    server.write(b"hello").unwrap();
+                    handle.insert_source(server, |_, _: &mut State| {}).unwrap();
+                }
+            })
+            .unwrap();
+
+        let client = VsockStreamSource::connect(addr).unwrap();
+        assert_eq!(client.peer_addr().unwrap(), addr);
+        handle
+            .insert_source(client, |evt, state: &mut State| match evt {
+                StreamEvent::Readable(data) => state.received.extend(data),
+                StreamEvent::Writable => {}
+                StreamEvent::Hup => state.hup = true,
+            })
+            .unwrap();
+
+        let mut state = State::default();
+        while state.received.len() < 5 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut state)
+                .unwrap();
+        }
+        assert_eq!(state.received, b"hello");
+        assert!(!state.hup);
+    }
+}
@@ -0,0 +1,471 @@
+//! Event source for netlink sockets
+//!
+//! Only available on Linux.
+//!
+//! A `Netlink` source opens a netlink socket of a given protocol, like `NETLINK_ROUTE`
+//! for the changes of the network interfaces, addresses and routes,
+//! `NETLINK_KOBJECT_UEVENT` for the device events of the kernel, or `NETLINK_GENERIC`.
+//! It joins the given multicast groups, and generates an `Event::Datagram` for each
+//! datagram it receives.
+//!
+//! If the receive buffer of the socket fills up, the kernel drops the next datagrams,
+//! and the source generates an `Event::Overflow`: the state built from the multicast
+//! messages, like the list of the interfaces, is then stale, and should be dumped
+//! again.
+//!
+//! The datagrams are delivered raw, as the framing depends on the protocol, but most
+//! protocols send a sequence of netlink messages, which `Datagram::messages()` splits
+//! into their headers and payloads. Requests, like a dump of the interfaces, are sent
+//! to the kernel with `Netlink::send()`, and their replies are generated as events.
+//!
+//! The protocols, groups, message kinds and flags are the `NETLINK_*`, `RTMGRP_*`,
+//! `RTM_*` and `NLM_F_*` constants of `libc`.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+// the size of the header of a netlink message
+const HEADER_LEN: usize = 16;
+
+// netlink messages are aligned on 4 bytes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A datagram received from a netlink socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    /// The port of the sender, 0 for the kernel
+    pub sender: u32,
+    /// The content of the datagram
+    pub data: Vec<u8>,
+}
+
+impl Datagram {
+    /// Split this datagram into netlink messages
+    ///
+    /// The iteration stops at the first malformed message, or at the end of the data.
+    pub fn messages(&self) -> Messages<'_> {
+        Messages { data: &self.data }
+    }
+}
+
+/// An event generated by a `Netlink` source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A datagram was received
+    Datagram(Datagram),
+    /// Some datagrams were dropped, as the receive buffer of the socket was full
+    Overflow,
+}
+
+/// A netlink message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+    /// The type of the message, like `RTM_NEWLINK` or `NLMSG_DONE`
+    pub kind: u16,
+    /// The flags of the message, like `NLM_F_MULTI`
+    pub flags: u16,
+    /// The sequence number of the message, the one of its request for replies
+    pub seq: u32,
+    /// The port of the sender of the message
+    pub pid: u32,
+    /// The payload of the message
+    pub payload: &'a [u8],
+}
+
+/// An iterator over the netlink messages of a datagram
+#[derive(Clone, Debug)]
+pub struct Messages<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Message<'a>;
+
+    fn next(&mut self) -> Option<Message<'a>> {
+        if self.data.len() < HEADER_LEN {
+            return None;
+        }
+        let u32_at = |offset: usize| {
+            let bytes = &self.data[offset..offset + 4];
+            u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        };
+        let u16_at = |offset: usize| {
+            let bytes = &self.data[offset..offset + 2];
+            u16::from_ne_bytes([bytes[0], bytes[1]])
+        };
+        let len = u32_at(0) as usize;
+        if len < HEADER_LEN || len > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let message = Message {
+            kind: u16_at(4),
+            flags: u16_at(6),
+            seq: u32_at(8),
+            pid: u32_at(12),
+            payload: &self.data[HEADER_LEN..len],
+        };
+        self.data = &self.data[align(len).min(self.data.len())..];
+        Some(message)
+    }
+}
+
+/// An event source receiving the datagrams of a netlink socket
+///
+/// It generates an `Event::Datagram` for each datagram it receives, and an
+/// `Event::Overflow` when some of them were dropped.
+pub struct Netlink {
+    fd: Rc<OwnedFd>,
+    seq: Cell<u32>,
+}
+
+impl Netlink {
+    /// Open a netlink socket of the given protocol, joining the given groups
+    ///
+    /// The groups are a bitmask, as the `RTMGRP_*` constants, which only covers the
+    /// first 32 groups: the other ones are joined with `add_membership()`.
+    pub fn new(protocol: libc::c_int, groups: u32) -> io::Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = groups;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink {
+            fd: Rc::new(fd),
+            seq: Cell::new(0),
+        })
+    }
+
+    /// Join a multicast group, by its number
+    pub fn add_membership(&mut self, group: u32) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_ADD_MEMBERSHIP,
+                &group as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The port of this socket, assigned by the kernel
+    pub fn port(&self) -> io::Result<u32> {
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockname(
+                self.fd.as_raw_fd(),
+                &mut addr as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(addr.nl_pid)
+    }
+
+    /// Send a netlink message to the kernel, returning its sequence number
+    ///
+    /// The header of the message is built from its `kind` and `flags`, with a new
+    /// sequence number, which the replies of the kernel carry.
+    pub fn send(&self, kind: u16, flags: u16, payload: &[u8]) -> io::Result<u32> {
+        let seq = self.seq.get().wrapping_add(1);
+        self.seq.set(seq);
+        let len = HEADER_LEN + payload.len();
+        let mut message = Vec::with_capacity(align(len));
+        message.extend_from_slice(&(len as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&flags.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        // the kernel fills in the port of the socket
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(align(len), 0);
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(seq)
+    }
+}
+
+impl AsRawFd for Netlink {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Evented for Netlink {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for Netlink {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: Rc<OwnedFd>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // large enough for the dumps of the kernel
+        let mut buffer = vec![0u8; 32 * 1024];
+        loop {
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of_val(&addr) as libc::socklen_t;
+            let len = unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                    &mut addr as *mut _ as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) => return Ok(()),
+                    Some(libc::EINTR) => continue,
+                    // some datagrams were lost, the next ones can still be received
+                    Some(libc::ENOBUFS) => {
+                        (self.callback)(Event::Overflow, data);
+                        continue;
+                    }
+                    _ => return Err(err),
+                }
+            }
+            let datagram = Datagram {
+                sender: addr.nl_pid,
+                data: buffer[..len as usize].to_vec(),
+            };
+            (self.callback)(Event::Datagram(datagram), data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn split_messages() {
+        let mut data = Vec::new();
+        for &(kind, payload) in &[(16u16, &b"abcde"[..]), (3, &b""[..])] {
+            let len = HEADER_LEN + payload.len();
+            data.extend_from_slice(&(len as u32).to_ne_bytes());
+            data.extend_from_slice(&kind.to_ne_bytes());
+            data.extend_from_slice(&2u16.to_ne_bytes());
+            data.extend_from_slice(&7u32.to_ne_bytes());
+            data.extend_from_slice(&0u32.to_ne_bytes());
+            data.extend_from_slice(payload);
+            data.resize(align(data.len()), 0);
+        }
+        let datagram = Datagram { sender: 0, data };
+        let messages = datagram.messages().collect::<Vec<_>>();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].kind, 16);
+        assert_eq!(messages[0].flags, 2);
+        assert_eq!(messages[0].seq, 7);
+        assert_eq!(messages[0].payload, b"abcde");
+        assert_eq!(messages[1].kind, 3);
+        assert!(messages[1].payload.is_empty());
+    }
+
+    #[test]
+    fn dump_links() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = match Netlink::new(libc::NETLINK_ROUTE, libc::RTMGRP_LINK as u32) {
+            Ok(source) => source,
+            // netlink may not be available in a sandbox
+            Err(ref err) if err.raw_os_error() == Some(libc::EPROTONOSUPPORT) => return,
+            Err(err) => panic!("{}", err),
+        };
+        // an `ifinfomsg` for all the interfaces
+        let request = [0u8; 16];
+        let seq = source
+            .send(
+                libc::RTM_GETLINK,
+                (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16,
+                &request,
+            )
+            .unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, move |evt, state: &mut (usize, bool)| {
+                let datagram = match evt {
+                    Event::Datagram(datagram) => datagram,
+                    Event::Overflow => panic!("the dump overflowed"),
+                };
+                assert_eq!(datagram.sender, 0);
+                for message in datagram.messages().filter(|m| m.seq == seq) {
+                    match message.kind {
+                        libc::RTM_NEWLINK => state.0 += 1,
+                        kind if kind == libc::NLMSG_DONE as u16 => state.1 = true,
+                        kind => panic!("unexpected message {}", kind),
+                    }
+                }
+            })
+            .unwrap();
+
+        // there is at least the loopback interface
+        let mut state = (0, false);
+        while !state.1 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut state)
+                .unwrap();
+        }
+        assert!(state.0 >= 1);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // the kernel only reports the overflows of multicast or kernel datagrams
+        let (receiver, sender) = match (
+            Netlink::new(libc::NETLINK_USERSOCK, 1),
+            Netlink::new(libc::NETLINK_USERSOCK, 0),
+        ) {
+            (Ok(receiver), Ok(sender)) => (receiver, sender),
+            _ => return,
+        };
+        let size: libc::c_int = 0;
+        unsafe {
+            libc::setsockopt(
+                receiver.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &size as *const _ as *const libc::c_void,
+                mem::size_of_val(&size) as libc::socklen_t,
+            )
+        };
+        // fill the receive buffer of the receiver until datagrams are dropped
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = 1;
+        let datagram = [0u8; 1024];
+        for _ in 0..100 {
+            // the datagrams are also sent to the kernel, which refuses them
+            unsafe {
+                libc::sendto(
+                    sender.as_raw_fd(),
+                    datagram.as_ptr() as *const libc::c_void,
+                    datagram.len(),
+                    0,
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&addr) as libc::socklen_t,
+                )
+            };
+        }
+
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, events: &mut Vec<bool>| {
+                events.push(evt == Event::Overflow)
+            })
+            .unwrap();
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut events)
+            .unwrap();
+        assert!(events.contains(&true));
+    }
+}
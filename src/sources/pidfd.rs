@@ -0,0 +1,231 @@
+//! Event source for tracking the exit of any process
+//!
+//! Available on Linux since 5.3, macOS, iOS and the BSDs.
+//!
+//! A `ProcessExit` source opens a pidfd for a process, which becomes readable once the
+//! process exits, and generates a single event then. Unlike the `process` module, the
+//! process does not need to be a child of this one, and no signal is involved. The
+//! process is not reaped: a child still needs to be waited for by its owner.
+//!
+//! On macOS, iOS and the BSDs, the source watches the exit of the process with the
+//! `EVFILT_PROC` filter of a dedicated kqueue instead, which the event loop polls.
+
+use std::cell::RefCell;
+use std::io;
+#[cfg(not(target_os = "linux"))]
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(not(target_os = "linux"))]
+use std::ptr;
+use std::rc::Rc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+/// An event source for receiving the exit of a process
+///
+/// It generates a single `()` event once the process exits.
+pub struct ProcessExit {
+    fd: OwnedFd,
+    pid: u32,
+}
+
+impl ProcessExit {
+    /// Create a new source, watching the process with the given pid
+    ///
+    /// This fails with `ESRCH` if there is no such process, and with `ENOSYS` on the
+    /// Linux kernels older than 5.3.
+    pub fn new(pid: u32) -> io::Result<ProcessExit> {
+        Ok(ProcessExit {
+            fd: open(pid)?,
+            pid,
+        })
+    }
+
+    /// The pid of the watched process
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    // pidfds are always created with `O_CLOEXEC`, only the non-blocking mode is missing
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::kqueue() };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // the kqueue becomes readable once the process exits, the types of the fields of
+    // `kevent` differ between the BSDs
+    let mut change: libc::kevent = unsafe { mem::zeroed() };
+    change.ident = pid as _;
+    change.filter = libc::EVFILT_PROC as _;
+    change.flags = (libc::EV_ADD | libc::EV_ONESHOT) as _;
+    change.fflags = libc::NOTE_EXIT as _;
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0
+        || unsafe { libc::kevent(fd.as_raw_fd(), &change, 1, ptr::null_mut(), 0, ptr::null()) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+// retrieve the exit of the process, for the kqueue not to remain readable
+#[cfg(not(target_os = "linux"))]
+fn drain(fd: RawFd) {
+    let mut event: libc::kevent = unsafe { mem::zeroed() };
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::kevent(fd, ptr::null(), 0, &mut event, 1, &timeout) };
+}
+
+impl Evented for ProcessExit {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for ProcessExit {
+    type Event = ();
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    // the pidfd of an exited process remains readable
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd.as_raw_fd(),
+            callback,
+            exited: false,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut((), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    fd: RawFd,
+    callback: F,
+    // reregistering the source makes it readable again
+    exited: bool,
+}
+
+impl<Data, F: FnMut((), &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        if !self.exited {
+            self.exited = true;
+            #[cfg(not(target_os = "linux"))]
+            drain(self.fd);
+            (self.callback)((), data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn process_exit() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut child = Command::new("sleep").arg("0.1").spawn().unwrap();
+        let source = match ProcessExit::new(child.id()) {
+            Ok(source) => source,
+            // pidfds are not supported by this kernel
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+                child.wait().unwrap();
+                return;
+            }
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(source.pid(), child.id());
+        event_loop
+            .handle()
+            .insert_source(source, |(), exits: &mut u32| *exits += 1)
+            .unwrap();
+
+        let mut exits = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut exits)
+            .unwrap();
+        assert_eq!(exits, 0);
+
+        while exits == 0 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut exits)
+                .unwrap();
+        }
+        // the child is not reaped by the source
+        assert!(child.wait().unwrap().success());
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut exits)
+            .unwrap();
+        assert_eq!(exits, 1);
+    }
+
+    #[test]
+    fn no_process() {
+        // pids are at most 2^22
+        let err = ProcessExit::new(1 << 30).err().unwrap();
+        assert!(
+            err.raw_os_error() == Some(libc::ESRCH) || err.raw_os_error() == Some(libc::ENOSYS)
+        );
+    }
+}
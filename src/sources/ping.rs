@@ -0,0 +1,217 @@
+//! A ping source, waking up the event loop from anywhere
+//!
+//! Create a ping using `make_ping()`, which returns a `Ping` that can be cloned and
+//! sent accross threads, and a `PingSource` that can be inserted into an `EventLoop`.
+//! Every call to `Ping::ping()` wakes up the event loop, the source then generates a
+//! single event for all the pings received since it was last dispatched.
+//!
+//! On Linux, it is backed by an eventfd, and by a pipe on the other unix platforms.
+//! Pinging is async-signal-safe on unix, `Ping` implementing `SignalSafe`.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use loop_waker::LoopWaker;
+use {EventDispatcher, EventSource};
+
+/// Create a new ping
+pub fn make_ping() -> io::Result<(Ping, PingSource)> {
+    let waker = Arc::new(LoopWaker::new()?);
+    Ok((Ping(waker.clone()), PingSource(waker)))
+}
+
+/// The sending end of a ping
+///
+/// It can be cloned and sent accross threads.
+#[derive(Clone)]
+pub struct Ping(Arc<LoopWaker>);
+
+impl Ping {
+    /// Wake up the associated `PingSource`
+    pub fn ping(&self) {
+        self.0.wake();
+    }
+}
+
+// `ping()` is a single write on an eventfd or a pipe
+#[cfg(unix)]
+unsafe impl ::SignalSafe for Ping {}
+
+/// The receiving end of a ping
+///
+/// This is the event source to be inserted into your `EventLoop`.
+pub struct PingSource(pub(crate) Arc<LoopWaker>);
+
+impl Evented for PingSource {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.0.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.0.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.0.deregister(poll)
+    }
+}
+
+impl EventSource for PingSource {
+    type Event = ();
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            waker: self.0.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut((), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    waker: Arc<LoopWaker>,
+    callback: F,
+}
+
+impl<Data, F: FnMut((), &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // reset the ping so that it is not processed in a loop
+        self.waker.drain();
+        (self.callback)((), data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn ping() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (ping, source) = make_ping().unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), count: &mut u32| *count += 1)
+            .unwrap();
+
+        let mut count = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // several pings generate a single event
+        ping.ping();
+        ping.clone().ping();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 1);
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn ping_from_thread() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (ping, source) = make_ping().unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), pinged: &mut bool| *pinged = true)
+            .unwrap();
+
+        ::std::thread::spawn(move || ping.ping()).join().unwrap();
+
+        let mut pinged = false;
+        event_loop
+            .dispatch(Some(Duration::from_secs(1)), &mut pinged)
+            .unwrap();
+        assert!(pinged);
+    }
+
+    #[test]
+    fn concurrent_pings() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (ping, source) = make_ping().unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), count: &mut u32| *count += 1)
+            .unwrap();
+
+        // pings racing with the draining of the source must not stall it
+        let done = Arc::new(AtomicBool::new(false));
+        let threads = (0..4)
+            .map(|_| {
+                let ping = ping.clone();
+                let done = done.clone();
+                ::std::thread::spawn(move || {
+                    while !done.load(Ordering::Acquire) {
+                        ping.ping();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        while count < 20_000 {
+            let before = count;
+            event_loop
+                .dispatch(Some(Duration::from_secs(1)), &mut count)
+                .unwrap();
+            assert!(count > before, "the ping stalled");
+        }
+        done.store(true, Ordering::Release);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // flush a last wakeup written by the threads, if any
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut count)
+            .unwrap();
+        let before = count;
+        ping.ping();
+        event_loop
+            .dispatch(Some(Duration::from_secs(1)), &mut count)
+            .unwrap();
+        assert_eq!(count, before + 1);
+    }
+}
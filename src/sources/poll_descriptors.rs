@@ -0,0 +1,410 @@
+//! Event source for libraries exposing a set of poll descriptors
+//!
+//! Many C libraries, like ALSA, libusb or c-ares, do not expose a single file
+//! descriptor, but a changing set of `pollfd`s to wait on, and expect the `revents`
+//! of a `poll()` call to be handed back to them.
+//!
+//! A `PollDescriptors` source holds such a set, and generates an event with a copy of
+//! the whole set, its `revents` filled in, each time some of its descriptors are
+//! ready. The set is replaced with `set()`, at any time, through the source or one of
+//! its `Descriptors` handles, usually from the callback after the library processed
+//! the events. The source registers and deregisters the descriptors accordingly.
+//!
+//! As with `poll()`, the readiness is level-triggered: the source is dispatched again
+//! as long as a descriptor stays ready.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Evented, Events, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+fn interest(events: libc::c_short) -> Ready {
+    let mut ready = Ready::empty();
+    if events & libc::POLLIN != 0 {
+        ready |= Ready::readable();
+    }
+    if events & libc::POLLOUT != 0 {
+        ready |= Ready::writable();
+    }
+    if events & libc::POLLPRI != 0 {
+        ready |= UnixReady::priority();
+    }
+    ready
+}
+
+fn revents(ready: Ready, events: libc::c_short) -> libc::c_short {
+    let ready = UnixReady::from(ready);
+    let mut revents = 0;
+    if ready.is_readable() {
+        revents |= libc::POLLIN;
+    }
+    if ready.is_writable() {
+        revents |= libc::POLLOUT;
+    }
+    if ready.is_priority() {
+        revents |= libc::POLLPRI;
+    }
+    // only the requested events are reported, but the errors always are
+    revents &= events;
+    if ready.is_error() {
+        revents |= libc::POLLERR;
+    }
+    if ready.is_hup() {
+        revents |= libc::POLLHUP;
+    }
+    revents
+}
+
+struct Inner {
+    // the poller the descriptors are registered into
+    poll: Poll,
+    fds: Vec<libc::pollfd>,
+    // the interest registered for each descriptor
+    registered: HashMap<RawFd, Ready>,
+}
+
+impl Inner {
+    fn set(&mut self, fds: &[libc::pollfd]) -> io::Result<()> {
+        let result = self.update(fds);
+        if result.is_err() {
+            // start over from an empty set, rather than from a partial update
+            let registered = self
+                .registered
+                .drain()
+                .map(|(fd, _)| fd)
+                .collect::<Vec<_>>();
+            for fd in registered {
+                let _ = EventedFd(&fd).deregister(&self.poll);
+            }
+            self.fds.clear();
+        }
+        result
+    }
+
+    fn update(&mut self, fds: &[libc::pollfd]) -> io::Result<()> {
+        // a descriptor may appear several times in the set
+        let mut wanted = HashMap::new();
+        for fd in fds {
+            *wanted.entry(fd.fd).or_insert_with(Ready::empty) |= interest(fd.events);
+        }
+        let removed = self
+            .registered
+            .keys()
+            .filter(|fd| !wanted.contains_key(fd))
+            .cloned()
+            .collect::<Vec<_>>();
+        for fd in removed {
+            self.registered.remove(&fd);
+            // the library may already have closed it
+            let _ = EventedFd(&fd).deregister(&self.poll);
+        }
+        for (&fd, &ready) in &wanted {
+            let token = Token(fd as usize);
+            // the library may have closed the descriptor and opened an other one with
+            // the same number, which the poller does not know about
+            if self.registered.contains_key(&fd) {
+                match EventedFd(&fd).reregister(&self.poll, token, ready, PollOpt::level()) {
+                    Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                        EventedFd(&fd).register(&self.poll, token, ready, PollOpt::level())?
+                    }
+                    result => result?,
+                }
+            } else {
+                EventedFd(&fd).register(&self.poll, token, ready, PollOpt::level())?;
+            }
+            self.registered.insert(fd, ready);
+        }
+        self.fds = fds.to_vec();
+        Ok(())
+    }
+}
+
+/// An event source polling a changing set of descriptors
+///
+/// It generates an event with the whole set, and the `revents` of its ready
+/// descriptors, each time some of them are ready.
+pub struct PollDescriptors {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl PollDescriptors {
+    /// Create a source with an empty set of descriptors
+    pub fn new() -> io::Result<PollDescriptors> {
+        Ok(PollDescriptors {
+            inner: Rc::new(RefCell::new(Inner {
+                poll: Poll::new()?,
+                fds: Vec::new(),
+                registered: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Replace the set of descriptors
+    ///
+    /// Only their `fd` and `events` are used, as with `poll()`. If this fails, the set
+    /// is left empty.
+    pub fn set(&self, fds: &[libc::pollfd]) -> io::Result<()> {
+        self.inner.borrow_mut().set(fds)
+    }
+
+    /// Get a handle to the set of descriptors
+    ///
+    /// It allows replacing it once the source is inserted into an event loop.
+    pub fn handle(&self) -> Descriptors {
+        Descriptors {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A handle to the set of descriptors of a `PollDescriptors` source
+#[derive(Clone)]
+pub struct Descriptors {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Descriptors {
+    /// Replace the set of descriptors
+    ///
+    /// Only their `fd` and `events` are used, as with `poll()`. If this fails, the set
+    /// is left empty.
+    pub fn set(&self, fds: &[libc::pollfd]) -> io::Result<()> {
+        self.inner.borrow_mut().set(fds)
+    }
+
+    /// The current set of descriptors
+    pub fn get(&self) -> Vec<libc::pollfd> {
+        self.inner.borrow().fds.clone()
+    }
+}
+
+impl Evented for PollDescriptors {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.inner.borrow().poll.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.inner.borrow().poll.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.inner.borrow().poll.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for PollDescriptors {
+    type Event = Vec<libc::pollfd>;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    // the poller remains readable as long as a descriptor is ready
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::level()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Vec<libc::pollfd>, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            events: Events::with_capacity(32),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Vec<libc::pollfd>, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner>>,
+    events: Events,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Vec<libc::pollfd>, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let fds = {
+            let inner = self.inner.borrow();
+            match inner
+                .poll
+                .poll(&mut self.events, Some(Duration::from_millis(0)))
+            {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            let ready = self
+                .events
+                .iter()
+                .map(|event| (event.token().0 as RawFd, event.readiness()))
+                .collect::<HashMap<_, _>>();
+            let mut fds = inner.fds.clone();
+            let mut any = false;
+            for fd in &mut fds {
+                fd.revents = ready
+                    .get(&fd.fd)
+                    .map(|&ready| revents(ready, fd.events))
+                    .unwrap_or(0);
+                any |= fd.revents != 0;
+            }
+            if !any {
+                return Ok(());
+            }
+            fds
+        };
+        // the callback may replace the set through a handle
+        (self.callback)(fds, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+        libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        }
+    }
+
+    #[test]
+    fn changing_set() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (mut tx1, rx1) = UnixStream::pair().unwrap();
+        let (mut tx2, rx2) = UnixStream::pair().unwrap();
+        let (fd1, fd2) = (rx1.as_raw_fd(), rx2.as_raw_fd());
+
+        let source = PollDescriptors::new().unwrap();
+        source
+            .set(&[pollfd(fd1, libc::POLLIN), pollfd(fd2, libc::POLLIN)])
+            .unwrap();
+        let handle = source.handle();
+        event_loop
+            .handle()
+            .insert_source(source, move |fds, received: &mut Vec<_>| {
+                // as a library would, handling the ready descriptors
+                for fd in fds.iter().filter(|fd| fd.revents & libc::POLLIN != 0) {
+                    let mut buffer = [0u8; 1];
+                    let len = unsafe { libc::read(fd.fd, buffer.as_mut_ptr() as *mut _, 1) };
+                    assert_eq!(len, 1);
+                }
+                received.push(fds);
+                // then only waiting on the first descriptor
+                handle.set(&[pollfd(fd1, libc::POLLIN)]).unwrap();
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert!(received.is_empty());
+
+        tx2.write_all(b"a").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][0].revents, 0);
+        assert_eq!(received[0][1].fd, fd2);
+        assert_eq!(received[0][1].revents, libc::POLLIN);
+
+        // the second descriptor is no longer in the set
+        tx2.write_all(b"c").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 1);
+
+        tx1.write_all(b"b").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].len(), 1);
+        assert_eq!(received[1][0].revents, libc::POLLIN);
+    }
+
+    #[test]
+    fn reused_descriptor() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (_tx1, rx1) = UnixStream::pair().unwrap();
+        let fd = rx1.as_raw_fd();
+        let source = PollDescriptors::new().unwrap();
+        source.set(&[pollfd(fd, libc::POLLIN)]).unwrap();
+        let handle = source.handle();
+        event_loop
+            .handle()
+            .insert_source(source, |fds, received: &mut Vec<_>| {
+                let mut buffer = [0u8; 1];
+                let len = unsafe { libc::read(fds[0].fd, buffer.as_mut_ptr() as *mut _, 1) };
+                assert_eq!(len, 1);
+                received.push(fds);
+            })
+            .unwrap();
+
+        // the descriptor is closed, and its number given to an other socket
+        let (mut tx2, rx2) = UnixStream::pair().unwrap();
+        assert!(unsafe { libc::dup2(rx2.as_raw_fd(), fd) } == fd);
+        drop(rx2);
+        handle.set(&[pollfd(fd, libc::POLLIN)]).unwrap();
+
+        tx2.write_all(b"a").unwrap();
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut received)
+            .unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][0].revents, libc::POLLIN);
+    }
+
+    #[test]
+    fn failed_set() {
+        let (_tx, rx) = UnixStream::pair().unwrap();
+        let source = PollDescriptors::new().unwrap();
+        source.set(&[pollfd(rx.as_raw_fd(), libc::POLLIN)]).unwrap();
+        // an invalid descriptor fails the whole set
+        assert!(source
+            .set(&[
+                pollfd(rx.as_raw_fd(), libc::POLLIN),
+                pollfd(-1, libc::POLLIN)
+            ])
+            .is_err());
+        assert!(source.handle().get().is_empty());
+        assert!(source.inner.borrow().registered.is_empty());
+        source.set(&[pollfd(rx.as_raw_fd(), libc::POLLIN)]).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! Event source for tracking the exit of child processes
+//!
+//! Only available on Linux.
+//!
+//! A `ChildExits` source watches the `std::process::Child`ren it is given, and
+//! generates an event with the pid and `ExitStatus` of each of them once it exits. It
+//! is built on the `Signals` source, getting notified by `SIGCHLD`, so the same
+//! caveat applies: the signal must be masked on the other threads of the program.
+//!
+//! As several `SIGCHLD` are merged into one if the children exit between two wakeups,
+//! every watched child is checked upon each of them. Only the watched children are
+//! reaped, so that the other ones can still be waited for by their owners.
+//!
+//! If waiting for a child fails, its event carries the error instead of its exit
+//! status, and the child is no longer watched.
+
+use std::cell::RefCell;
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::rc::Rc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::sys::signal::{raise, Signal};
+
+use sources::signals::Signals;
+use {nix_err, EventDispatcher, EventSource};
+
+/// An event source for receiving the exit of child processes
+///
+/// It generates an event with the pid and the exit status of each child, or the error
+/// that occurred while waiting for it.
+pub struct ChildExits {
+    signals: Signals,
+    children: Rc<RefCell<Vec<Child>>>,
+}
+
+impl ChildExits {
+    /// Create a new source, watching no child yet
+    ///
+    /// This masks `SIGCHLD` for the current thread.
+    pub fn new() -> io::Result<ChildExits> {
+        Ok(ChildExits {
+            signals: Signals::new(&[Signal::SIGCHLD])?,
+            children: Rc::new(RefCell::new(Vec::new())),
+        })
+    }
+
+    /// Watch a child, generating an event once it exits
+    ///
+    /// The child may have already exited, it is checked upon the next dispatch.
+    pub fn watch(&self, child: Child) -> io::Result<()> {
+        self.children.borrow_mut().push(child);
+        // the signal of a child that already exited may have been discarded
+        raise(Signal::SIGCHLD).map_err(nix_err)
+    }
+
+    /// The pids of the watched children which have not exited yet
+    pub fn pids(&self) -> Vec<u32> {
+        self.children.borrow().iter().map(Child::id).collect()
+    }
+}
+
+impl Evented for ChildExits {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.signals.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.signals.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.signals.deregister(poll)
+    }
+}
+
+impl EventSource for ChildExits {
+    type Event = (u32, io::Result<ExitStatus>);
+
+    fn interest(&self) -> Ready {
+        self.signals.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.signals.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((u32, io::Result<ExitStatus>), &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let children = self.children.clone();
+        self.signals.make_dispatcher(move |_, data: &mut Data| {
+            let mut exited = Vec::new();
+            // the children are not borrowed while the callback runs, as it may watch
+            // new ones
+            children
+                .borrow_mut()
+                .retain_mut(|child| match child.try_wait() {
+                    Ok(Some(status)) => {
+                        exited.push((child.id(), Ok(status)));
+                        false
+                    }
+                    Ok(None) => true,
+                    Err(err) => {
+                        exited.push((child.id(), Err(err)));
+                        false
+                    }
+                });
+            for event in exited {
+                callback(event, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn child_exits() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = ChildExits::new().unwrap();
+        let success = Command::new("true").spawn().unwrap();
+        let failure = Command::new("false").spawn().unwrap();
+        let mut expected = vec![(success.id(), true), (failure.id(), false)];
+        // both children exit before the event loop is woken up
+        ::std::thread::sleep(Duration::from_millis(100));
+        source.watch(success).unwrap();
+        source.watch(failure).unwrap();
+
+        let source = event_loop
+            .handle()
+            .insert_source(source, |(pid, status), exits: &mut Vec<(u32, bool)>| {
+                exits.push((pid, status.unwrap().success()));
+            })
+            .unwrap();
+
+        let mut exits = Vec::new();
+        while exits.len() < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut exits)
+                .unwrap();
+        }
+        exits.sort();
+        expected.sort();
+        assert_eq!(exits, expected);
+        assert!(source.as_source_ref().pids().is_empty());
+    }
+
+    #[test]
+    fn wait_error() {
+        use nix::sys::wait::waitpid;
+        use nix::unistd::Pid;
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let source = ChildExits::new().unwrap();
+        let child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        // reaped behind the back of the source, waiting for it fails
+        waitpid(Pid::from_raw(pid as i32), None).unwrap();
+        source.watch(child).unwrap();
+
+        let source = event_loop
+            .handle()
+            .insert_source(source, |(pid, status), errors: &mut Vec<u32>| {
+                assert!(status.is_err());
+                errors.push(pid);
+            })
+            .unwrap();
+
+        let mut errors = Vec::new();
+        while errors.is_empty() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut errors)
+                .unwrap();
+        }
+        assert_eq!(errors, vec![pid]);
+        assert!(source.as_source_ref().pids().is_empty());
+    }
+}
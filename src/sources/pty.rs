@@ -0,0 +1,333 @@
+//! Event source for running a process in a pseudo-terminal
+//!
+//! Only available on Linux, since 5.3.
+//!
+//! A `Pty` opens a pseudo-terminal pair and spawns a `std::process::Command` on its
+//! slave side, as the session leader controlling it. It generates an event with the
+//! data read from the master side, then an other one with the exit status of the
+//! process once it exits, all through the same callback. The input of the process is
+//! written with `Pty::write()`, and the terminal is resized with `Pty::resize()`.
+//!
+//! Like a `Subprocess`, its exit is tracked by a pidfd, and what remains of its output
+//! is delivered before its exit status.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+use nix::pty::{openpty, Winsize};
+
+use sources::pidfd::ProcessExit;
+use sources::subprocess::set_nonblocking;
+use sources::TokenFactory;
+use {nix_err, EventDispatcher, EventSource};
+
+/// An event generated by a pseudo-terminal
+#[derive(Debug)]
+pub enum Event {
+    /// Data was written to the terminal by the process
+    Output(Vec<u8>),
+    /// The process exited
+    ///
+    /// This event is generated once, no output event follows it unless the terminal
+    /// is still open in other processes.
+    Exited(ExitStatus),
+}
+
+struct Inner {
+    child: Child,
+    master: File,
+    eof: bool,
+    status: Option<ExitStatus>,
+}
+
+impl Inner {
+    // read all the available output
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while !self.eof {
+            match self.master.read(&mut buffer) {
+                Ok(0) => self.eof = true,
+                Ok(n) => data.extend_from_slice(&buffer[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // the master side fails with `EIO` once the slave side is closed
+                Err(ref err) if err.raw_os_error() == Some(libc::EIO) => self.eof = true,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// An event source running a process in a pseudo-terminal
+pub struct Pty {
+    inner: Rc<RefCell<Inner>>,
+    exit: ProcessExit,
+    master: RawFd,
+}
+
+fn winsize(size: (u16, u16)) -> Winsize {
+    Winsize {
+        ws_col: size.0,
+        ws_row: size.1,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+impl Pty {
+    /// Spawn a command in a new pseudo-terminal of the given `(columns, rows)`
+    ///
+    /// The stdin, stdout and stderr of the command are replaced by the terminal.
+    pub fn spawn(command: &mut Command, size: (u16, u16)) -> io::Result<Pty> {
+        let pty = openpty(&winsize(size), None).map_err(nix_err)?;
+        let master = unsafe { File::from_raw_fd(pty.master) };
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+        for &fd in &[pty.master, pty.slave] {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        set_nonblocking(pty.master)?;
+
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        unsafe {
+            command.pre_exec(|| {
+                // become the session leader, controlling the terminal which is now stdin
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        // the slave side is closed in this process once the command is spawned
+        let mut child = command.spawn()?;
+        let exit = match ProcessExit::new(child.id()) {
+            Ok(exit) => exit,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+        Ok(Pty {
+            master: master.as_raw_fd(),
+            inner: Rc::new(RefCell::new(Inner {
+                child,
+                master,
+                eof: false,
+                status: None,
+            })),
+            exit,
+        })
+    }
+
+    /// The pid of the process
+    pub fn id(&self) -> u32 {
+        self.exit.pid()
+    }
+
+    /// Write input to the process
+    ///
+    /// The terminal is non-blocking: this fails with `WouldBlock` if its buffer is
+    /// full, and may write only part of the data.
+    pub fn write(&self, data: &[u8]) -> io::Result<usize> {
+        self.inner.borrow_mut().master.write(data)
+    }
+
+    /// Resize the terminal to the given `(columns, rows)`
+    ///
+    /// The process is notified with `SIGWINCH`.
+    pub fn resize(&self, size: (u16, u16)) -> io::Result<()> {
+        let size = winsize(size);
+        if unsafe { libc::ioctl(self.master, libc::TIOCSWINSZ, &size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The current `(columns, rows)` of the terminal
+    pub fn size(&self) -> io::Result<(u16, u16)> {
+        let mut size: Winsize = unsafe { mem::zeroed() };
+        if unsafe { libc::ioctl(self.master, libc::TIOCGWINSZ, &mut size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((size.ws_col, size.ws_row))
+    }
+
+    /// Kill the process
+    ///
+    /// Its exit is then reported as for any other exit.
+    pub fn kill(&mut self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.status.is_some() {
+            return Ok(());
+        }
+        inner.child.kill()
+    }
+}
+
+// the sub-ids of the master side and of the pidfd
+const OUTPUT: usize = 0;
+const EXIT: usize = 1;
+
+impl Evented for Pty {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        let mut factory = TokenFactory::new(token);
+        EventedFd(&self.master).register(poll, factory.token(), interest, opts)?;
+        self.exit.register(poll, factory.token(), interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        let mut factory = TokenFactory::new(token);
+        EventedFd(&self.master).reregister(poll, factory.token(), interest, opts)?;
+        self.exit.reregister(poll, factory.token(), interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.master).deregister(poll)?;
+        self.exit.deregister(poll)
+    }
+}
+
+impl EventSource for Pty {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner>>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> Dispatcher<Data, F> {
+    fn read(&mut self, data: &mut Data) -> io::Result<()> {
+        let read = self.inner.borrow_mut().read()?;
+        if !read.is_empty() {
+            (self.callback)(Event::Output(read), data);
+        }
+        Ok(())
+    }
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.ready_sub(ready, OUTPUT, data)
+    }
+
+    fn ready_sub(&mut self, _: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        match sub_id {
+            OUTPUT => self.read(data),
+            EXIT => {
+                if self.inner.borrow().status.is_some() {
+                    return Ok(());
+                }
+                // the remaining output comes before the exit
+                self.read(data)?;
+                let status = {
+                    let mut inner = self.inner.borrow_mut();
+                    let status = inner.child.wait()?;
+                    inner.status = Some(status);
+                    status
+                };
+                (self.callback)(Event::Exited(status), data);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn pty() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut command = Command::new("sh");
+        // wait for a line of input, then report the size of the terminal
+        command.args(["-c", "read line; echo \"got $line\"; stty size"]);
+        let source = match Pty::spawn(&mut command, (80, 24)) {
+            Ok(source) => source,
+            // pidfds are not supported by this kernel
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) => return,
+            Err(err) => panic!("{}", err),
+        };
+        source.resize((100, 30)).unwrap();
+        assert_eq!(source.size().unwrap(), (100, 30));
+        assert_eq!(source.write(b"hello\n").unwrap(), 6);
+
+        #[derive(Default)]
+        struct Output {
+            output: Vec<u8>,
+            status: Option<ExitStatus>,
+        }
+        event_loop
+            .handle()
+            .insert_source(source, |evt, output: &mut Output| match evt {
+                Event::Output(data) => output.output.extend(data),
+                Event::Exited(status) => output.status = Some(status),
+            })
+            .unwrap();
+
+        let mut output = Output::default();
+        while output.status.is_none() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut output)
+                .unwrap();
+        }
+        let output_str = String::from_utf8_lossy(&output.output);
+        assert!(output_str.contains("got hello"), "{:?}", output_str);
+        assert!(output_str.contains("30 100"), "{:?}", output_str);
+        assert!(output.status.unwrap().success());
+    }
+}
@@ -0,0 +1,289 @@
+//! A request/response channel, whose requests are answered by the event loop
+//!
+//! Create a channel using `channel::<Req, Resp>()`, which returns a `Requester` that
+//! can be cloned and sent accross threads, and a `RequestSource` to be inserted into
+//! an `EventLoop`. Each request is given to the callback along with a `Replier`,
+//! which sends the response back to the `Reply` of the requester. The requester can
+//! block on it with `Reply::wait()`, or await it as a future.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll as TaskPoll, Waker};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::{self as chan, Channel, Event, SendError, Sender};
+use {EventDispatcher, EventSource};
+
+/// The request was dropped by the event loop without being answered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the request was dropped without being answered")
+    }
+}
+
+impl Error for Canceled {}
+
+struct Slot<Resp> {
+    response: Option<Resp>,
+    // whether the replier is gone, with or without answering
+    done: bool,
+    waker: Option<Waker>,
+}
+
+struct Shared<Resp> {
+    slot: Mutex<Slot<Resp>>,
+    condvar: Condvar,
+}
+
+impl<Resp> Shared<Resp> {
+    fn complete(&self, response: Option<Resp>) {
+        let mut slot = self.slot.lock().unwrap();
+        if slot.done {
+            return;
+        }
+        slot.response = response;
+        slot.done = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        self.condvar.notify_all();
+    }
+}
+
+/// Create a new request/response channel
+pub fn channel<Req, Resp>() -> (Requester<Req, Resp>, RequestSource<Req, Resp>) {
+    let (sender, channel) = chan::channel();
+    (Requester { sender }, RequestSource { channel })
+}
+
+/// The requesting end of the channel
+///
+/// It can be cloned and sent accross threads if `Req: Send` and `Resp: Send`.
+pub struct Requester<Req, Resp> {
+    sender: Sender<(Req, Replier<Resp>)>,
+}
+
+impl<Req, Resp> Requester<Req, Resp> {
+    /// Send a request to the event loop
+    ///
+    /// The returned `Reply` gives access to the response. This fails if the
+    /// `RequestSource` was dropped, giving the request back.
+    pub fn request(&self, request: Req) -> Result<Reply<Resp>, SendError<Req>> {
+        let shared = Arc::new(Shared {
+            slot: Mutex::new(Slot {
+                response: None,
+                done: false,
+                waker: None,
+            }),
+            condvar: Condvar::new(),
+        });
+        let replier = Replier {
+            shared: shared.clone(),
+        };
+        match self.sender.send((request, replier)) {
+            Ok(()) => Ok(Reply { shared }),
+            Err(SendError::Io(err)) => Err(SendError::Io(err)),
+            Err(SendError::Disconnected((request, _))) => Err(SendError::Disconnected(request)),
+        }
+    }
+}
+
+impl<Req, Resp> Clone for Requester<Req, Resp> {
+    fn clone(&self) -> Requester<Req, Resp> {
+        Requester {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// The slot for the response to a request
+///
+/// Dropping it without calling `reply()` cancels the request.
+pub struct Replier<Resp> {
+    shared: Arc<Shared<Resp>>,
+}
+
+impl<Resp> Replier<Resp> {
+    /// Send the response to the requester
+    pub fn reply(self, response: Resp) {
+        self.shared.complete(Some(response));
+    }
+}
+
+impl<Resp> Drop for Replier<Resp> {
+    fn drop(&mut self) {
+        // no-op if a response was sent
+        self.shared.complete(None);
+    }
+}
+
+/// The response to a request, once the event loop answers it
+///
+/// It is also a future, completing with the response.
+pub struct Reply<Resp> {
+    shared: Arc<Shared<Resp>>,
+}
+
+impl<Resp> Reply<Resp> {
+    /// Block until the response is received
+    pub fn wait(self) -> Result<Resp, Canceled> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        while !slot.done {
+            slot = self.shared.condvar.wait(slot).unwrap();
+        }
+        slot.response.take().ok_or(Canceled)
+    }
+
+    /// Get the response if it was received, without blocking
+    ///
+    /// Returns the `Reply` back if the request was not answered yet.
+    pub fn try_get(self) -> Result<Result<Resp, Canceled>, Reply<Resp>> {
+        let response = {
+            let mut slot = self.shared.slot.lock().unwrap();
+            if !slot.done {
+                None
+            } else {
+                Some(slot.response.take().ok_or(Canceled))
+            }
+        };
+        response.ok_or(self)
+    }
+}
+
+impl<Resp> Future for Reply<Resp> {
+    type Output = Result<Resp, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> TaskPoll<Self::Output> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        if slot.done {
+            TaskPoll::Ready(slot.response.take().ok_or(Canceled))
+        } else {
+            slot.waker = Some(cx.waker().clone());
+            TaskPoll::Pending
+        }
+    }
+}
+
+/// The receiving end of the channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` with each request and its `Replier`, and an `Event::Closed` once all
+/// the requesters have been dropped.
+pub struct RequestSource<Req, Resp> {
+    channel: Channel<(Req, Replier<Resp>)>,
+}
+
+impl<Req, Resp> Evented for RequestSource<Req, Resp> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.channel.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.channel.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.channel.deregister(poll)
+    }
+}
+
+impl<Req: 'static, Resp: 'static> EventSource for RequestSource<Req, Resp> {
+    type Event = Event<(Req, Replier<Resp>)>;
+
+    fn interest(&self) -> Ready {
+        self.channel.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.channel.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Self::Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        self.channel.make_dispatcher(callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn request_reply() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (requester, source) = channel::<u32, u32>();
+        event_loop
+            .handle()
+            .insert_source(source, |evt, _: &mut ()| {
+                if let Event::Msg((request, replier)) = evt {
+                    // odd requests are dropped
+                    if request % 2 == 0 {
+                        replier.reply(request * 10);
+                    }
+                }
+            })
+            .unwrap();
+
+        let worker = thread::spawn(move || {
+            let answered = requester.request(4).unwrap().wait();
+            let canceled = requester.request(5).unwrap().wait();
+            (answered, canceled)
+        });
+        while !worker.is_finished() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(10)), &mut ())
+                .unwrap();
+        }
+        assert_eq!(worker.join().unwrap(), (Ok(40), Err(Canceled)));
+    }
+
+    #[test]
+    fn reply_future() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (requester, source) = channel::<u32, u32>();
+        event_loop
+            .handle()
+            .insert_source(source, |evt, _: &mut ()| {
+                if let Event::Msg((request, replier)) = evt {
+                    replier.reply(request + 1);
+                }
+            })
+            .unwrap();
+
+        let reply = requester.request(1).unwrap();
+        let reply = match reply.try_get() {
+            Err(reply) => reply,
+            Ok(_) => panic!("the request should not be answered yet"),
+        };
+        assert_eq!(event_loop.block_on(reply, &mut ()).unwrap(), Ok(2));
+    }
+}
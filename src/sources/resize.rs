@@ -0,0 +1,184 @@
+//! Event source for tracking the size of a terminal
+//!
+//! Only available on Linux.
+//!
+//! A `Resize` source listens to `SIGWINCH`, and generates an event with the new
+//! `(columns, rows)` of its terminal, obtained with the `TIOCGWINSZ` ioctl, each time
+//! it is resized. It is built on the `Signals` source, so the same caveat applies: the
+//! signal must be masked on the other threads of the program.
+
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+use nix::sys::signal::Signal;
+
+use sources::signals::Signals;
+use {EventDispatcher, EventSource};
+
+/// Get the `(columns, rows)` of the terminal open as `fd`
+pub fn terminal_size(fd: RawFd) -> io::Result<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((size.ws_col, size.ws_row))
+}
+
+/// An event source for receiving the resizing of a terminal
+///
+/// It generates an event with the `(columns, rows)` of the terminal.
+pub struct Resize {
+    signals: Signals,
+    fd: RawFd,
+}
+
+impl Resize {
+    /// Create a new source, tracking the terminal of the standard output
+    ///
+    /// This masks `SIGWINCH` for the current thread.
+    pub fn new() -> io::Result<Resize> {
+        Resize::with_fd(libc::STDOUT_FILENO)
+    }
+
+    /// Create a new source, tracking the terminal open as `fd`
+    ///
+    /// The file descriptor must stay open as long as the source exists. This fails with
+    /// `ENOTTY` if it is not a terminal.
+    pub fn with_fd(fd: RawFd) -> io::Result<Resize> {
+        terminal_size(fd)?;
+        Ok(Resize {
+            signals: Signals::new(&[Signal::SIGWINCH])?,
+            fd,
+        })
+    }
+
+    /// The current `(columns, rows)` of the terminal
+    pub fn size(&self) -> io::Result<(u16, u16)> {
+        terminal_size(self.fd)
+    }
+}
+
+impl Evented for Resize {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.signals.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.signals.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.signals.deregister(poll)
+    }
+}
+
+impl EventSource for Resize {
+    type Event = (u16, u16);
+
+    fn interest(&self) -> Ready {
+        self.signals.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.signals.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((u16, u16), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let inner = self.signals.make_dispatcher(|_, _: &mut Data| {});
+        Rc::new(RefCell::new(Dispatcher {
+            inner,
+            fd: self.fd,
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data, F> {
+    inner: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    fd: RawFd,
+    callback: F,
+}
+
+impl<'l, Data, F: FnMut((u16, u16), &mut Data)> EventDispatcher<Data> for Dispatcher<'l, Data, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        // several resizings since the last dispatch only generate one event, as only
+        // the latest size matters
+        self.inner.borrow_mut().ready(ready, data)?;
+        let size = terminal_size(self.fd)?;
+        (self.callback)(size, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nix::pty::{openpty, Winsize};
+
+    use super::*;
+
+    #[test]
+    fn resize() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut size = Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = openpty(&size, None).unwrap();
+        let source = Resize::with_fd(pty.slave).unwrap();
+        assert_eq!(source.size().unwrap(), (80, 24));
+        event_loop
+            .handle()
+            .insert_source(source, |size, sizes: &mut Vec<(u16, u16)>| sizes.push(size))
+            .unwrap();
+
+        // the pty is not the controlling terminal of this process, so the signal is
+        // sent by hand, to this thread
+        size.ws_col = 100;
+        assert!(unsafe { libc::ioctl(pty.master, libc::TIOCSWINSZ, &size) } >= 0);
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGWINCH) };
+
+        let mut sizes = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut sizes)
+            .unwrap();
+        assert_eq!(sizes, &[(100, 24)]);
+
+        let _ = ::nix::unistd::close(pty.master);
+        let _ = ::nix::unistd::close(pty.slave);
+    }
+
+    #[test]
+    fn not_a_terminal() {
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let err = Resize::with_fd(read).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+        let _ = ::nix::unistd::close(read);
+        let _ = ::nix::unistd::close(write);
+    }
+}
@@ -0,0 +1,419 @@
+//! Calendar-based event sources
+//!
+//! A `Schedule` fires at the local times matching a cron-like `Calendar`
+//! specification, like every day at 03:00 or every Monday at 09:30. This module is
+//! only available on Linux.
+//!
+//! The occurrences are computed in the local time zone, so they follow daylight
+//! saving time changes: a daily schedule keeps firing at the same local time. A local
+//! time skipped by such a change does not fire, while a local time repeated by it can
+//! fire twice. The schedule relies on a realtime `TimerFd`, so it fires at the right
+//! time even if the system clock is changed, the next occurrence being then computed
+//! again from the new time.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use sources::timerfd::{self, Clock, TimerFd};
+use {EventDispatcher, EventSource};
+
+// how far in the future occurrences are looked for
+const SEARCH_LIMIT: Duration = Duration::from_secs(5 * 366 * 24 * 3600);
+
+/// A day of the week
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Weekday {
+    /// Sunday
+    Sunday,
+    /// Monday
+    Monday,
+    /// Tuesday
+    Tuesday,
+    /// Wednesday
+    Wednesday,
+    /// Thursday
+    Thursday,
+    /// Friday
+    Friday,
+    /// Saturday
+    Saturday,
+}
+
+/// A cron-like specification of local times
+///
+/// It matches the minutes whose fields are all accepted. A new `Calendar` matches
+/// every minute, each field being then restricted using the builder methods. Unlike
+/// cron, a time must match both the days of the month and the days of the week when
+/// both are restricted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Calendar {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+}
+
+fn mask(values: &[u8], min: u8, max: u8, field: &str) -> u64 {
+    values.iter().fold(0, |mask, &value| {
+        assert!(
+            value >= min && value <= max,
+            "[calloop] {} is not a valid value for the {} of a calendar.",
+            value,
+            field
+        );
+        mask | 1 << value
+    })
+}
+
+impl Calendar {
+    /// A calendar matching every minute
+    pub fn new() -> Calendar {
+        // each field is a bitmask of the accepted values, days and months start at 1
+        Calendar {
+            minutes: (1 << 60) - 1,
+            hours: (1 << 24) - 1,
+            days: !1,
+            months: ((1 << 13) - 1) & !1,
+            weekdays: (1 << 7) - 1,
+        }
+    }
+
+    /// A calendar matching every day at the given time
+    pub fn daily(hour: u8, minute: u8) -> Calendar {
+        Calendar::new().hours(&[hour]).minutes(&[minute])
+    }
+
+    /// A calendar matching every week at the given day and time
+    pub fn weekly(weekday: Weekday, hour: u8, minute: u8) -> Calendar {
+        Calendar::daily(hour, minute).weekdays(&[weekday])
+    }
+
+    /// Restrict the minutes, from 0 to 59
+    ///
+    /// **Panics** if a value is out of range.
+    pub fn minutes(mut self, minutes: &[u8]) -> Calendar {
+        self.minutes = mask(minutes, 0, 59, "minutes");
+        self
+    }
+
+    /// Restrict the hours, from 0 to 23
+    ///
+    /// **Panics** if a value is out of range.
+    pub fn hours(mut self, hours: &[u8]) -> Calendar {
+        self.hours = mask(hours, 0, 23, "hours") as u32;
+        self
+    }
+
+    /// Restrict the days of the month, from 1 to 31
+    ///
+    /// **Panics** if a value is out of range.
+    pub fn days(mut self, days: &[u8]) -> Calendar {
+        self.days = mask(days, 1, 31, "days") as u32;
+        self
+    }
+
+    /// Restrict the months, from 1 to 12
+    ///
+    /// **Panics** if a value is out of range.
+    pub fn months(mut self, months: &[u8]) -> Calendar {
+        self.months = mask(months, 1, 12, "months") as u16;
+        self
+    }
+
+    /// Restrict the days of the week
+    pub fn weekdays(mut self, weekdays: &[Weekday]) -> Calendar {
+        self.weekdays = weekdays
+            .iter()
+            .fold(0, |mask, &weekday| mask | 1 << weekday as u8);
+        self
+    }
+
+    /// The first time matching this calendar strictly after `time`
+    ///
+    /// Returns `None` if no time matches in the next few years, for example with a
+    /// calendar only accepting the 31st of February.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let start = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+        let limit = start + SEARCH_LIMIT.as_secs() as libc::time_t;
+        // the beginning of the next minute
+        let mut t = start - start.rem_euclid(60) + 60;
+        while t <= limit {
+            let mut tm = localtime(t)?;
+            if self.months & 1 << (tm.tm_mon + 1) == 0 {
+                // the first day of the next month
+                tm.tm_mon += 1;
+                tm.tm_mday = 1;
+                t = midnight(tm)?;
+            } else if self.days & 1 << tm.tm_mday == 0 || self.weekdays & 1 << tm.tm_wday == 0 {
+                tm.tm_mday += 1;
+                t = midnight(tm)?;
+            } else if self.hours & 1 << tm.tm_hour == 0 {
+                t += libc::time_t::from(60 - tm.tm_min) * 60 - libc::time_t::from(tm.tm_sec);
+            } else if self.minutes & 1 << tm.tm_min == 0 {
+                t += 60 - libc::time_t::from(tm.tm_sec);
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(t as u64));
+            }
+        }
+        None
+    }
+}
+
+impl Default for Calendar {
+    fn default() -> Calendar {
+        Calendar::new()
+    }
+}
+
+fn localtime(t: libc::time_t) -> Option<libc::tm> {
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::localtime_r(&t, &mut tm) };
+    if ret.is_null() {
+        None
+    } else {
+        Some(tm)
+    }
+}
+
+// the beginning of the day of `tm`, normalizing its fields
+fn midnight(mut tm: libc::tm) -> Option<libc::time_t> {
+    tm.tm_hour = 0;
+    tm.tm_min = 0;
+    tm.tm_sec = 0;
+    // let the system find out whether daylight saving time applies
+    tm.tm_isdst = -1;
+    let t = unsafe { libc::mktime(&mut tm) };
+    if t == -1 {
+        None
+    } else {
+        Some(t)
+    }
+}
+
+/// An event source firing at the times matching a `Calendar`
+///
+/// It generates an event for each occurrence, carrying the time it was scheduled for.
+pub struct Schedule {
+    timer: TimerFd,
+    calendar: Calendar,
+    next: Rc<Cell<Option<SystemTime>>>,
+}
+
+impl Schedule {
+    /// Create a new schedule firing at the times matching `calendar`
+    pub fn new(calendar: Calendar) -> io::Result<Schedule> {
+        let schedule = Schedule {
+            timer: TimerFd::new(Clock::Realtime)?,
+            calendar,
+            next: Rc::new(Cell::new(None)),
+        };
+        arm(&schedule.timer, &schedule.calendar, &schedule.next)?;
+        Ok(schedule)
+    }
+
+    /// The calendar followed by this schedule
+    pub fn calendar(&self) -> Calendar {
+        self.calendar
+    }
+
+    /// The time of the next occurrence
+    ///
+    /// Returns `None` if the calendar does not match any time in the next few years.
+    pub fn next_occurrence(&self) -> Option<SystemTime> {
+        self.next.get()
+    }
+}
+
+// set the timer to the next occurrence after the current time
+fn arm(timer: &TimerFd, calendar: &Calendar, next: &Cell<Option<SystemTime>>) -> io::Result<()> {
+    next.set(calendar.next_after(SystemTime::now()));
+    match next.get() {
+        Some(time) => timer.set_wall_deadline(time),
+        None => timer.cancel(),
+    }
+}
+
+impl Evented for Schedule {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.timer.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.timer.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.timer.deregister(poll)
+    }
+}
+
+impl EventSource for Schedule {
+    type Event = SystemTime;
+
+    fn interest(&self) -> Ready {
+        self.timer.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.timer.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(SystemTime, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let timer = self.timer.share();
+        let calendar = self.calendar;
+        let next = self.next.clone();
+        let error = Rc::new(Cell::new(None));
+        let inner_error = error.clone();
+        let dispatcher = self.timer.make_dispatcher(move |event, data: &mut Data| {
+            let occurrence = next.get();
+            if let Err(err) = arm(&timer, &calendar, &next) {
+                inner_error.set(Some(err));
+            }
+            // after a clock change, the occurrences are computed again from
+            // the new time
+            if let (timerfd::Event::Expired(_), Some(time)) = (event, occurrence) {
+                callback(time, data);
+            }
+        });
+        Rc::new(RefCell::new(Dispatcher {
+            inner: dispatcher,
+            error,
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data> {
+    inner: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    // an error re-arming the timer from the callback of the inner dispatcher
+    error: Rc<Cell<Option<io::Error>>>,
+}
+
+impl<'l, Data> EventDispatcher<Data> for Dispatcher<'l, Data> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.inner.borrow_mut().ready(ready, data)?;
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    // a local time, in the timezone of the system
+    fn local(year: i32, month: i32, day: i32, hour: i32, minute: i32) -> SystemTime {
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        tm.tm_year = year - 1900;
+        tm.tm_mon = month - 1;
+        tm.tm_mday = day;
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_isdst = -1;
+        let t = unsafe { libc::mktime(&mut tm) };
+        UNIX_EPOCH + Duration::from_secs(t as u64)
+    }
+
+    #[test]
+    fn next_after() {
+        let now = SystemTime::now();
+
+        let next = Calendar::new().next_after(now).unwrap();
+        let wait = next.duration_since(now).unwrap();
+        assert!(wait > Duration::from_secs(0) && wait <= Duration::from_secs(60));
+        let since_epoch = next.duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(since_epoch.as_secs() % 60, 0);
+        assert_eq!(since_epoch.subsec_nanos(), 0);
+
+        let next = Calendar::weekly(Weekday::Monday, 3, 30)
+            .next_after(now)
+            .unwrap();
+        let wait = next.duration_since(now).unwrap();
+        assert!(wait <= Duration::from_secs(8 * 24 * 3600));
+        let tm = localtime(next.duration_since(UNIX_EPOCH).unwrap().as_secs() as _).unwrap();
+        assert_eq!((tm.tm_wday, tm.tm_hour, tm.tm_min), (1, 3, 30));
+
+        // the next day matching is in the next month, and the next year
+        let evening = local(2021, 1, 31, 18, 0);
+        let next = Calendar::daily(12, 0).days(&[1]).next_after(evening);
+        assert_eq!(next, Some(local(2021, 2, 1, 12, 0)));
+        let evening = local(2021, 12, 31, 18, 0);
+        let next = Calendar::daily(12, 0).months(&[1]).next_after(evening);
+        assert_eq!(next, Some(local(2022, 1, 1, 12, 0)));
+
+        // the 31st of February never happens
+        assert_eq!(
+            Calendar::daily(12, 0)
+                .months(&[2])
+                .days(&[31])
+                .next_after(now),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_calendar() {
+        Calendar::new().hours(&[24]);
+    }
+
+    #[test]
+    fn schedule() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let before = SystemTime::now();
+        let schedule = Schedule::new(Calendar::daily(3, 0)).unwrap();
+        let after = SystemTime::now();
+        let next = schedule.next_occurrence().unwrap();
+        assert!([before, after]
+            .iter()
+            .any(|&now| Calendar::daily(3, 0).next_after(now) == Some(next)));
+
+        // move the occurrence closer, for the test not to wait until 03:00
+        let near = SystemTime::now() + Duration::from_millis(20);
+        schedule.next.set(Some(near));
+        schedule.timer.set_wall_deadline(near).unwrap();
+        event_loop
+            .handle()
+            .insert_source(schedule, |time, fired: &mut Vec<SystemTime>| {
+                fired.push(time)
+            })
+            .unwrap();
+
+        let mut fired = Vec::new();
+        while fired.is_empty() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut fired)
+                .unwrap();
+        }
+        assert!(SystemTime::now() >= near);
+        // the occurrence it was scheduled for, rather than the time it fired
+        assert_eq!(fired, &[near]);
+    }
+}
@@ -0,0 +1,160 @@
+//! A self-pipe, waking the event loop up from signal handlers
+//!
+//! Only available on unix.
+//!
+//! Create a self-pipe using `self_pipe()`, which returns its writing end as a
+//! `UnixStream`, and a `SelfPipe` to be inserted into an `EventLoop`. Every byte written
+//! to the stream is a notification, the source generating a single event with the
+//! number of notifications received since it was last dispatched.
+//!
+//! This is the notification mechanism of the signal handlers of `signal-hook`: its
+//! `low_level::pipe::register()` takes the writing end, and writes a byte to it each
+//! time the signal is received. This allows integrating the handlers of this crate
+//! into the event loop, rather than using the `signals` source, without calloop
+//! depending on it. Any handler writing to the stream with `write(2)` works as well.
+
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// Create a new self-pipe
+///
+/// Both ends are non-blocking, so that a handler never blocks on a full pipe: the
+/// notifications are merged in this case.
+pub fn self_pipe() -> io::Result<(UnixStream, SelfPipe)> {
+    let (write, read) = UnixStream::pair()?;
+    write.set_nonblocking(true)?;
+    read.set_nonblocking(true)?;
+    Ok((
+        write,
+        SelfPipe {
+            read: Rc::new(RefCell::new(read)),
+        },
+    ))
+}
+
+/// The reading end of a self-pipe
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an event
+/// with the number of notifications received.
+pub struct SelfPipe {
+    read: Rc<RefCell<UnixStream>>,
+}
+
+impl Evented for SelfPipe {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.read.borrow().as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.read.borrow().as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.read.borrow().as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for SelfPipe {
+    type Event = usize;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(usize, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            read: self.read.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(usize, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    read: Rc<RefCell<UnixStream>>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(usize, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut count = 0;
+        let mut buffer = [0u8; 64];
+        loop {
+            match self.read.borrow_mut().read(&mut buffer) {
+                // the writing end was closed, it cannot notify anymore
+                Ok(0) => break,
+                Ok(n) => count += n,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if count > 0 {
+            (self.callback)(count, data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn self_pipe_notifications() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (mut write, source) = self_pipe().unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |count, received: &mut Vec<usize>| {
+                received.push(count)
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert!(received.is_empty());
+
+        // as `signal-hook` does in its handlers, one byte per signal
+        write.write_all(&[0]).unwrap();
+        write.write_all(&[0]).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, &[2]);
+    }
+}
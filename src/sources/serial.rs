@@ -0,0 +1,226 @@
+//! Event source for serial ports
+//!
+//! Only available on Linux.
+//!
+//! A `Serial` source opens a tty device, like `/dev/ttyUSB0` or `/dev/ttyACM0`, and
+//! generates an event with the bytes received each time it is readable. The device
+//! can be put into raw mode with `set_raw()`, so that the bytes are delivered as they
+//! come, without any line editing or translation, and its speed is set with
+//! `set_baud_rate()`.
+//!
+//! Writing is non-blocking, as for the other sources of this crate.
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+use nix::sys::termios::{self, SetArg};
+
+pub use nix::sys::termios::BaudRate;
+
+use {nix_err, EventDispatcher, EventSource};
+
+/// An event source reading a serial port
+///
+/// It generates an event with the received bytes each time the port is readable.
+pub struct Serial {
+    file: Rc<File>,
+}
+
+impl Serial {
+    /// Open the serial port at the given path
+    ///
+    /// It does not become the controlling terminal of the process.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Serial> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(path)?;
+        Ok(Serial {
+            file: Rc::new(file),
+        })
+    }
+
+    /// Wrap the file descriptor of an opened tty device, taking ownership of it
+    ///
+    /// It is switched to non-blocking mode.
+    pub fn from_fd(fd: RawFd) -> io::Result<Serial> {
+        let file = unsafe { File::from_raw_fd(fd) };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Serial {
+            file: Rc::new(file),
+        })
+    }
+
+    /// Put the port into raw mode
+    ///
+    /// The bytes are received and sent as they are, 8 bits per character, without
+    /// echo, line editing, special characters or translation of newlines.
+    pub fn set_raw(&self) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let mut attrs = termios::tcgetattr(fd).map_err(nix_err)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &attrs).map_err(nix_err)
+    }
+
+    /// Set the input and output speed of the port
+    pub fn set_baud_rate(&self, baud: BaudRate) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let mut attrs = termios::tcgetattr(fd).map_err(nix_err)?;
+        termios::cfsetspeed(&mut attrs, baud).map_err(nix_err)?;
+        termios::tcsetattr(fd, SetArg::TCSANOW, &attrs).map_err(nix_err)
+    }
+
+    /// Write bytes to the port
+    ///
+    /// The port is non-blocking: this fails with `WouldBlock` if its buffer is full,
+    /// and may write only part of the data.
+    pub fn write(&self, data: &[u8]) -> io::Result<usize> {
+        (&*self.file).write(data)
+    }
+}
+
+impl AsRawFd for Serial {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Evented for Serial {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for Serial {
+    type Event = Vec<u8>;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Vec<u8>, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            file: self.file.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Vec<u8>, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    file: Rc<File>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Vec<u8>, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match (&*self.file).read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buffer[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // the device was unplugged, or the other side of a pty closed
+                Err(ref err) if err.raw_os_error() == Some(libc::EIO) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        if !received.is_empty() {
+            (self.callback)(received, data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nix::pty::openpty;
+
+    use super::*;
+
+    #[test]
+    fn raw_port() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // the slave side of a pty stands for the device
+        let pty = openpty(None, None).unwrap();
+        let mut master = unsafe { File::from_raw_fd(pty.master) };
+        let source = Serial::from_fd(pty.slave).unwrap();
+        source.set_raw().unwrap();
+        source.set_baud_rate(BaudRate::B115200).unwrap();
+        let attrs = termios::tcgetattr(source.as_raw_fd()).unwrap();
+        assert_eq!(termios::cfgetospeed(&attrs), BaudRate::B115200);
+
+        source.write(b"ping\n").unwrap();
+        let mut buffer = [0u8; 5];
+        master.read_exact(&mut buffer).unwrap();
+        // no translation of the newline in raw mode
+        assert_eq!(&buffer, b"ping\n");
+
+        event_loop
+            .handle()
+            .insert_source(source, |bytes, received: &mut Vec<u8>| {
+                received.extend(bytes)
+            })
+            .unwrap();
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert!(received.is_empty());
+
+        master.write_all(b"\x03pong\r").unwrap();
+        while received.len() < 6 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut received)
+                .unwrap();
+        }
+        // nor interpretation of the special characters
+        assert_eq!(received, b"\x03pong\r");
+    }
+}
@@ -0,0 +1,351 @@
+//! A channel whose sender can be used from a POSIX signal handler
+//!
+//! Create a channel using `channel::<T>(capacity)`, which returns a `Sender<T>` and a
+//! `Receiver<T>` to be inserted into an `EventLoop`. The messages are stored in a
+//! lock-free queue allocated once with the channel, sending being a few atomic
+//! operations followed by a wakeup of the event loop, so `Sender::send()` is
+//! async-signal-safe and the `Sender` implements `SignalSafe` on unix.
+//!
+//! This allows forwarding information out of signal handlers that cannot be replaced
+//! by a signalfd, like the ones installed by other libraries. As the messages cannot
+//! be dropped from a signal handler, they must implement `Copy`.
+
+use std::cell::{RefCell, UnsafeCell};
+use std::io;
+use std::mem::MaybeUninit;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::Event;
+use sources::ping::{make_ping, Ping, PingSource};
+use {EventDispatcher, EventSource};
+
+struct Slot<T> {
+    // the position this slot is ready for: to be written at `pos`, or read at `pos + 1`
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// a bounded multi-producer queue, whose producers never wait for each other, so that
+// a signal handler interrupting a send cannot deadlock
+struct Queue<T> {
+    slots: Box<[Slot<T>]>,
+    write: AtomicUsize,
+    read: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T: Copy> Queue<T> {
+    fn new(capacity: usize) -> Queue<T> {
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                sequence: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Queue {
+            slots,
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.write.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == pos {
+                match self.write.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (sequence.wrapping_sub(pos) as isize) < 0 {
+                // the slot still holds the message of the previous round
+                return Err(value);
+            } else {
+                pos = self.write.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.read.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let next = pos.wrapping_add(1);
+            if sequence == next {
+                match self.read.compare_exchange_weak(
+                    pos,
+                    next,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+                        slot.sequence
+                            .store(pos.wrapping_add(self.slots.len()), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (sequence.wrapping_sub(next) as isize) < 0 {
+                // empty, or the message is still being written
+                return None;
+            } else {
+                pos = self.read.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct Shared<T> {
+    queue: Queue<T>,
+    ping: Ping,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+    disconnected: AtomicBool,
+}
+
+/// Create a new channel holding at most `capacity` messages
+///
+/// **Panics** if `capacity` is zero.
+pub fn channel<T: Copy>(capacity: usize) -> io::Result<(Sender<T>, Receiver<T>)> {
+    assert!(
+        capacity > 0,
+        "[calloop] The capacity of a channel cannot be zero."
+    );
+    let (ping, source) = make_ping()?;
+    let shared = Arc::new(Shared {
+        queue: Queue::new(capacity),
+        ping,
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        disconnected: AtomicBool::new(false),
+    });
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, source },
+    ))
+}
+
+/// The sending end of the channel
+///
+/// It can be cloned and sent accross threads if `T: Send`. Only `send()` is
+/// async-signal-safe, the sender must be cloned or dropped outside of signal handlers.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Sender<T> {
+    /// Send a message to the event loop
+    ///
+    /// It fails with `TrySendError::Full` if the channel holds `capacity` messages
+    /// already, and with `TrySendError::Disconnected` if the receiver has been dropped,
+    /// giving the message back.
+    pub fn send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        if self.shared.disconnected.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(msg));
+        }
+        self.shared.queue.push(msg).map_err(TrySendError::Full)?;
+        self.shared.ping.ping();
+        Ok(())
+    }
+}
+
+// `send()` is made of atomic operations and a ping
+#[cfg(unix)]
+unsafe impl<T: Copy> ::SignalSafe for Sender<T> {}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.closed.store(true, Ordering::Release);
+            self.shared.ping.ping();
+        }
+    }
+}
+
+/// The receiving end of the channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` for each message, and an `Event::Closed` once all the senders have
+/// been dropped and there are no messages left.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    source: PingSource,
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.disconnected.store(true, Ordering::Release);
+    }
+}
+
+impl<T> Evented for Receiver<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.source.deregister(poll)
+    }
+}
+
+impl<T: Copy + 'static> EventSource for Receiver<T> {
+    type Event = Event<T>;
+
+    fn interest(&self) -> Ready {
+        self.source.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.source.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let shared = self.shared.clone();
+        let mut closed = false;
+        self.source.make_dispatcher(move |(), data: &mut Data| {
+            // read before the queue is drained, so that a message sent right before
+            // the closing is never delivered after it
+            let disconnected = shared.closed.load(Ordering::Acquire);
+            while let Some(msg) = shared.queue.pop() {
+                callback(Event::Msg(msg), data);
+            }
+            if !closed && disconnected {
+                closed = true;
+                callback(Event::Closed, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn full_channel() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<u32>(2).unwrap();
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg);
+                }
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        // the queue is reused once the event loop received its messages
+        for round in 0..3 {
+            sender.send(2 * round).unwrap();
+            sender.send(2 * round + 1).unwrap();
+            assert_eq!(sender.send(7), Err(TrySendError::Full(7)));
+            event_loop
+                .dispatch(Some(Duration::from_millis(0)), &mut received)
+                .unwrap();
+        }
+        assert_eq!(received, &[0, 1, 2, 3, 4, 5]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_from_signal_handler() {
+        use nix::sys::signal::{raise, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+        use std::ptr;
+        use std::sync::atomic::AtomicPtr;
+        use SignalSafe;
+
+        static SENDER: AtomicPtr<Sender<i32>> = AtomicPtr::new(ptr::null_mut());
+
+        extern "C" fn handler(signal: ::nix::libc::c_int) {
+            fn assert_signal_safe<T: SignalSafe>(_: &T) {}
+            let sender = SENDER.load(Ordering::Acquire);
+            if let Some(sender) = unsafe { sender.as_ref() } {
+                assert_signal_safe(sender);
+                let _ = sender.send(signal);
+            }
+        }
+
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<i32>(4).unwrap();
+        let sender = Box::new(sender);
+        SENDER.store(&*sender as *const _ as *mut _, Ordering::Release);
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<i32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg);
+                }
+            })
+            .unwrap();
+
+        let action = SigAction::new(
+            SigHandler::Handler(handler),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let previous = unsafe { sigaction(Signal::SIGPROF, &action).unwrap() };
+        raise(Signal::SIGPROF).unwrap();
+
+        let mut received = Vec::new();
+        event_loop.dispatch(None, &mut received).unwrap();
+        assert_eq!(received, &[Signal::SIGPROF as i32]);
+
+        unsafe { sigaction(Signal::SIGPROF, &previous).unwrap() };
+        SENDER.store(ptr::null_mut(), Ordering::Release);
+    }
+}
@@ -9,21 +9,28 @@
 //! but you are responsible for masking them on other threads if you run them. The simplest
 //! way to ensure that is to setup the signal event source before spawning any thread, as
 //! they'll inherit their parent signal mask.
+//!
+//! Realtime signals, from `SIGRTMIN` to `SIGRTMAX`, are tracked using their offset from
+//! `SIGRTMIN`. Unlike the standard ones, they are queued: each of them generates its own
+//! event, whose `siginfo_t` carries the value given to `sigqueue(3)` or set up for a
+//! POSIX timer notification.
 
 use std::cell::RefCell;
 use std::io;
+use std::mem;
 use std::os::raw::c_int;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
 use std::rc::Rc;
 
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 
+use nix::libc;
 use nix::sys::signal::SigSet;
 pub use nix::sys::signal::Signal;
 pub use nix::sys::signalfd::siginfo;
-use nix::sys::signalfd::{SfdFlags, SignalFd};
 
-use {EventDispatcher, EventSource};
+use {nix_err, EventDispatcher, EventSource};
 
 /// An event generated by the signal event source
 #[derive(Copy, Clone)]
@@ -33,8 +40,42 @@ pub struct Event {
 
 impl Event {
     /// Retrieve the signal number that was receive
-    pub fn signal(&self) -> Signal {
-        Signal::from_c_int(self.info.ssi_signo as c_int).unwrap()
+    ///
+    /// It is `None` for the realtime signals, see `realtime()`.
+    pub fn signal(&self) -> Option<Signal> {
+        Signal::from_c_int(self.info.ssi_signo as c_int).ok()
+    }
+
+    /// The offset from `SIGRTMIN` of the signal, if it is a realtime one
+    pub fn realtime(&self) -> Option<c_int> {
+        let signo = self.info.ssi_signo as c_int;
+        if signo >= libc::SIGRTMIN() && signo <= libc::SIGRTMAX() {
+            Some(signo - libc::SIGRTMIN())
+        } else {
+            None
+        }
+    }
+
+    /// The pid of the process which sent the signal
+    pub fn sender_pid(&self) -> u32 {
+        self.info.ssi_pid
+    }
+
+    /// The real uid of the process which sent the signal
+    pub fn sender_uid(&self) -> u32 {
+        self.info.ssi_uid
+    }
+
+    /// The `sigval` sent along with the signal, as an integer
+    ///
+    /// It is the value given to `sigqueue(3)`, or to the `sigevent` of a POSIX timer.
+    pub fn int_value(&self) -> i32 {
+        self.info.ssi_int
+    }
+
+    /// The `sigval` sent along with the signal, as a pointer
+    pub fn ptr_value(&self) -> u64 {
+        self.info.ssi_ptr
     }
 
     /// Access the full `siginfo_t` associated with this signal event
@@ -43,10 +84,13 @@ impl Event {
     }
 }
 
+// the signalfd, shared with the dispatcher
 /// An event source for receiving Unix signals
 pub struct Signals {
-    sfd: Rc<RefCell<SignalFd>>,
+    sfd: Rc<OwnedFd>,
     mask: SigSet,
+    // the numbers of the realtime signals
+    realtime: Vec<c_int>,
 }
 
 impl Signals {
@@ -58,69 +102,116 @@ impl Signals {
         }
 
         // Mask the signals for this thread
-        mask.thread_block().map_err(no_nix_err)?;
+        mask.thread_block().map_err(nix_err)?;
         // Create the SignalFd
-        let sfd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
-            .map_err(no_nix_err)?;
+        let fd =
+            unsafe { libc::signalfd(-1, mask.as_ref(), libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            let _ = mask.thread_unblock();
+            return Err(err);
+        }
 
         Ok(Signals {
-            sfd: Rc::new(RefCell::new(sfd)),
+            sfd: Rc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
             mask,
+            realtime: Vec::new(),
         })
     }
 
     /// Add a list of signals to the signals source
     ///
-    /// If this function returns an error, the signal mask of the thread may
-    /// have still been changed.
+    /// If this function returns an error, the source and the signal mask of the
+    /// thread are left unchanged.
     pub fn add_signals(&mut self, signals: &[Signal]) -> io::Result<()> {
+        let mut mask = self.mask;
         for &s in signals {
-            self.mask.add(s);
+            mask.add(s);
         }
-        self.mask.thread_block().map_err(no_nix_err)?;
-        self.sfd
-            .borrow_mut()
-            .set_mask(&self.mask)
-            .map_err(no_nix_err)?;
-        Ok(())
+        let realtime = self.realtime.clone();
+        self.apply(mask, realtime)
     }
 
     /// Remove a list of signals to the signals source
     ///
-    /// If this function returns an error, the signal mask of the thread may
-    /// have still been changed.
+    /// If this function returns an error, the source and the signal mask of the
+    /// thread are left unchanged.
     pub fn remove_signals(&mut self, signals: &[Signal]) -> io::Result<()> {
-        let mut removed = SigSet::empty();
+        let mut mask = self.mask;
         for &s in signals {
-            self.mask.remove(s);
-            removed.add(s);
+            mask.remove(s);
         }
-        removed.thread_unblock().map_err(no_nix_err)?;
-        self.sfd
-            .borrow_mut()
-            .set_mask(&self.mask)
-            .map_err(no_nix_err)?;
-        Ok(())
+        let realtime = self.realtime.clone();
+        self.apply(mask, realtime)
     }
 
     /// Replace the list of signals of the source
     ///
-    /// If this function returns an error, the signal mask of the thread may
-    /// have still been changed.
+    /// The realtime signals of the source are kept.
+    ///
+    /// If this function returns an error, the source and the signal mask of the
+    /// thread are left unchanged.
     pub fn set_signals(&mut self, signals: &[Signal]) -> io::Result<()> {
-        let mut new_mask = SigSet::empty();
+        let mut mask = SigSet::empty();
         for &s in signals {
-            new_mask.add(s);
+            mask.add(s);
+        }
+        let realtime = self.realtime.clone();
+        self.apply(mask, realtime)
+    }
+
+    /// Add a list of realtime signals to the signals source
+    ///
+    /// They are given by their offset from `SIGRTMIN`, this fails with `EINVAL` if
+    /// one of them is beyond `SIGRTMAX`.
+    ///
+    /// If this function returns an error, the source and the signal mask of the
+    /// thread are left unchanged.
+    pub fn add_realtime(&mut self, offsets: &[c_int]) -> io::Result<()> {
+        let mut realtime = self.realtime.clone();
+        for signo in realtime_signals(offsets)? {
+            if !realtime.contains(&signo) {
+                realtime.push(signo);
+            }
         }
+        self.apply(self.mask, realtime)
+    }
+
+    /// Remove a list of realtime signals from the signals source
+    ///
+    /// If this function returns an error, the source and the signal mask of the
+    /// thread are left unchanged.
+    pub fn remove_realtime(&mut self, offsets: &[c_int]) -> io::Result<()> {
+        let removed = realtime_signals(offsets)?;
+        let mut realtime = self.realtime.clone();
+        realtime.retain(|signo| !removed.contains(signo));
+        self.apply(self.mask, realtime)
+    }
 
-        self.mask.thread_unblock().map_err(no_nix_err)?;
-        new_mask.thread_block().map_err(no_nix_err)?;
-        self.sfd
-            .borrow_mut()
-            .set_mask(&new_mask)
-            .map_err(no_nix_err)?;
-        self.mask = new_mask;
+    // Switch to new signals, updating the thread mask and the signalfd together
+    //
+    // The new signals are blocked before the signalfd starts reading them, and the
+    // removed ones are unblocked after it stopped, so that none of the signals in
+    // both sets is ever unblocked. Every step is reverted if a later one fails.
+    fn apply(&mut self, mask: SigSet, realtime: Vec<c_int>) -> io::Result<()> {
+        let old = full_mask(&self.mask, &self.realtime);
+        let new = full_mask(&mask, &realtime);
+        let added = difference(&new, &old);
+        let removed = difference(&old, &new);
+
+        thread_mask(libc::SIG_BLOCK, &added)?;
+        if let Err(err) = set_fd_mask(self.sfd.as_raw_fd(), &new) {
+            let _ = thread_mask(libc::SIG_UNBLOCK, &added);
+            return Err(err);
+        }
+        if let Err(err) = thread_mask(libc::SIG_UNBLOCK, &removed) {
+            let _ = set_fd_mask(self.sfd.as_raw_fd(), &old);
+            let _ = thread_mask(libc::SIG_UNBLOCK, &added);
+            return Err(err);
+        }
 
+        self.mask = mask;
+        self.realtime = realtime;
         Ok(())
     }
 }
@@ -128,17 +219,62 @@ impl Signals {
 impl Drop for Signals {
     fn drop(&mut self) {
         // we cannot handle error here
-        if let Err(e) = self.mask.thread_unblock() {
+        if let Err(e) = thread_mask(libc::SIG_UNBLOCK, &full_mask(&self.mask, &self.realtime)) {
             eprintln!("[calloop] Failed to unmask signals: {:?}", e);
         }
     }
 }
 
-fn no_nix_err(err: ::nix::Error) -> io::Error {
-    match err {
-        ::nix::Error::Sys(errno) => errno.into(),
-        _ => unreachable!(),
+fn realtime_signals(offsets: &[c_int]) -> io::Result<Vec<c_int>> {
+    offsets
+        .iter()
+        .map(|&offset| {
+            let signo = libc::SIGRTMIN() + offset;
+            if offset < 0 || signo > libc::SIGRTMAX() {
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            } else {
+                Ok(signo)
+            }
+        })
+        .collect()
+}
+
+// the standard and realtime signals of a source
+fn full_mask(mask: &SigSet, realtime: &[c_int]) -> libc::sigset_t {
+    let mut set = *mask.as_ref();
+    for &signo in realtime {
+        unsafe { libc::sigaddset(&mut set, signo) };
     }
+    set
+}
+
+// the signals of `a` which are not in `b`
+fn difference(a: &libc::sigset_t, b: &libc::sigset_t) -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut set) };
+    for signo in 1..=libc::SIGRTMAX() {
+        unsafe {
+            if libc::sigismember(a, signo) == 1 && libc::sigismember(b, signo) != 1 {
+                libc::sigaddset(&mut set, signo);
+            }
+        }
+    }
+    set
+}
+
+fn thread_mask(how: c_int, set: &libc::sigset_t) -> io::Result<()> {
+    let ret = unsafe { libc::pthread_sigmask(how, set, ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+fn set_fd_mask(fd: RawFd, set: &libc::sigset_t) -> io::Result<()> {
+    if unsafe { libc::signalfd(fd, set, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
 impl Evented for Signals {
@@ -149,7 +285,7 @@ impl Evented for Signals {
         interest: Ready,
         opts: PollOpt,
     ) -> io::Result<()> {
-        ::mio::unix::EventedFd(&self.sfd.borrow().as_raw_fd()).register(poll, token, interest, opts)
+        ::mio::unix::EventedFd(&self.sfd.as_raw_fd()).register(poll, token, interest, opts)
     }
 
     fn reregister(
@@ -159,12 +295,11 @@ impl Evented for Signals {
         interest: Ready,
         opts: PollOpt,
     ) -> io::Result<()> {
-        ::mio::unix::EventedFd(&self.sfd.borrow().as_raw_fd())
-            .reregister(poll, token, interest, opts)
+        ::mio::unix::EventedFd(&self.sfd.as_raw_fd()).reregister(poll, token, interest, opts)
     }
 
     fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        ::mio::unix::EventedFd(&self.sfd.borrow().as_raw_fd()).deregister(poll)
+        ::mio::unix::EventedFd(&self.sfd.as_raw_fd()).deregister(poll)
     }
 }
 
@@ -179,10 +314,10 @@ impl EventSource for Signals {
         PollOpt::edge()
     }
 
-    fn make_dispatcher<Data: 'static, F: FnMut(Event, &mut Data) + 'static>(
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
         &self,
         callback: F,
-    ) -> Rc<RefCell<EventDispatcher<Data>>> {
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
         Rc::new(RefCell::new(Dispatcher {
             _data: ::std::marker::PhantomData,
             callback,
@@ -191,27 +326,78 @@ impl EventSource for Signals {
     }
 }
 
-struct Dispatcher<Data, F: FnMut(Event, &mut Data) + 'static> {
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
     _data: ::std::marker::PhantomData<fn(&mut Data)>,
     callback: F,
-    sfd: Rc<RefCell<SignalFd>>,
+    sfd: Rc<OwnedFd>,
 }
 
-impl<Data, F: FnMut(Event, &mut Data) + 'static> EventDispatcher<Data> for Dispatcher<Data, F> {
-    fn ready(&mut self, _: Ready, data: &mut Data) {
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
         loop {
-            let ret = self.sfd.borrow_mut().read_signal();
-            match ret {
-                Ok(Some(info)) => (self.callback)(Event { info }, data),
-                Ok(None) => {
+            let mut info: siginfo = unsafe { mem::zeroed() };
+            let ret = unsafe {
+                libc::read(
+                    self.sfd.as_raw_fd(),
+                    &mut info as *mut siginfo as *mut libc::c_void,
+                    mem::size_of::<siginfo>(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
                     // nothing more to read
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("[calloop] Error reading from signalfd: {:?}", e);
-                    break;
-                }
+                    io::ErrorKind::WouldBlock => Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(err),
+                };
             }
+            (self.callback)(Event { info }, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[cfg(target_env = "gnu")]
+    #[test]
+    fn realtime_signals() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut signals = Signals::new(&[]).unwrap();
+        signals.add_realtime(&[1]).unwrap();
+        assert!(signals.add_realtime(&[libc::SIGRTMAX()]).is_err());
+        event_loop
+            .handle()
+            .insert_source(signals, |evt, received: &mut Vec<(c_int, i32, u32)>| {
+                assert!(evt.signal().is_none());
+                received.push((evt.realtime().unwrap(), evt.int_value(), evt.sender_pid()));
+            })
+            .unwrap();
+
+        // realtime signals are queued, and directed to this thread
+        for value in 0..2usize {
+            let ret = unsafe {
+                libc::pthread_sigqueue(
+                    libc::pthread_self(),
+                    libc::SIGRTMIN() + 1,
+                    libc::sigval {
+                        sival_ptr: value as *mut libc::c_void,
+                    },
+                )
+            };
+            assert_eq!(ret, 0);
         }
+
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        let pid = ::std::process::id();
+        assert_eq!(received, &[(1, 0, pid), (1, 1, pid)]);
     }
 }
@@ -0,0 +1,319 @@
+//! Event source for the standard input
+//!
+//! A `Stdin` source switches the standard input to non-blocking mode, and generates an
+//! event with the bytes of each read while it is readable, then a single
+//! `Event::Closed` once it reaches its end. At most 8 reads of 4096 bytes are done each
+//! time the source is dispatched, so that a fast producer, like a large file piped
+//! into the program, does not starve the other sources: the remaining input is read
+//! during the next dispatches. The original mode is restored when the source is dropped,
+//! as it is shared with the other processes using the same terminal, like the shell.
+//!
+//! Terminal applications usually also want to receive the keys as they are pressed,
+//! without echo nor line editing: `Stdin::raw_mode()` puts the terminal into raw mode,
+//! and returns a `RawMode` guard restoring its previous configuration when dropped,
+//! including while unwinding from a panic.
+//!
+//! The standard input must be a terminal, a pipe or a socket: regular files can not be
+//! polled.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::time::Instant;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+use nix::sys::termios::{self, SetArg, Termios};
+
+use {nix_err, EventDispatcher, EventSource};
+
+// the number of reads per dispatch
+const BUDGET: usize = 8;
+
+/// An event generated by the `Stdin` source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Some bytes were read
+    Input(Vec<u8>),
+    /// The end of the input was reached
+    ///
+    /// This event is generated once, no other event follows it.
+    Closed,
+}
+
+/// An event source reading the standard input
+pub struct Stdin {
+    fd: RawFd,
+    // the original file status flags, restored on drop
+    flags: libc::c_int,
+    // when the budget was exhausted, if some input may be left
+    pending: Rc<Cell<Option<Instant>>>,
+}
+
+impl Stdin {
+    /// Read the standard input of the process
+    ///
+    /// It is switched to non-blocking mode until the source is dropped.
+    pub fn new() -> io::Result<Stdin> {
+        Stdin::with_fd(libc::STDIN_FILENO)
+    }
+
+    fn with_fd(fd: RawFd) -> io::Result<Stdin> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Stdin {
+            fd,
+            flags,
+            pending: Rc::new(Cell::new(None)),
+        })
+    }
+
+    /// Put the terminal of the standard input into raw mode
+    ///
+    /// The keys are then read as they are pressed, without echo, line editing, nor
+    /// interpretation of the special characters like `^C`, and the output is not
+    /// processed either. The terminal is restored when the guard is dropped.
+    ///
+    /// This fails with `ENOTTY` if the standard input is not a terminal.
+    pub fn raw_mode(&self) -> io::Result<RawMode> {
+        RawMode::enable(self.fd)
+    }
+}
+
+impl Drop for Stdin {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(self.fd, libc::F_SETFL, self.flags) };
+    }
+}
+
+/// A guard keeping a terminal in raw mode
+///
+/// The previous configuration of the terminal is restored when it is dropped. This does
+/// not happen if the program aborts, or is killed by a signal.
+pub struct RawMode {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> io::Result<RawMode> {
+        let original = termios::tcgetattr(fd).map_err(nix_err)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw).map_err(nix_err)?;
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+impl Evented for Stdin {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl EventSource for Stdin {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        // the input is edge-triggered, the remaining bytes would not wake it up
+        self.pending.get()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            fd: self.fd,
+            closed: false,
+            pending: self.pending.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    fd: RawFd,
+    closed: bool,
+    pending: Rc<Cell<Option<Instant>>>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.pending.set(None);
+        let mut buffer = [0u8; 4096];
+        let mut reads = 0;
+        while reads < BUDGET {
+            let len = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    // the terminal was hung up
+                    _ if err.raw_os_error() == Some(libc::EIO) => {}
+                    _ => return Err(err),
+                }
+            } else if len > 0 {
+                reads += 1;
+                (self.callback)(Event::Input(buffer[..len as usize].to_vec()), data);
+                continue;
+            }
+            self.closed = true;
+            (self.callback)(Event::Closed, data);
+            return Ok(());
+        }
+        self.pending.set(Some(Instant::now()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nix::pty::openpty;
+    use nix::sys::termios::LocalFlags;
+
+    use super::*;
+
+    #[test]
+    fn input_and_close() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // a pipe stands for the standard input
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let source = Stdin::with_fd(read).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |event, events: &mut Vec<Event>| events.push(event))
+            .unwrap();
+
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut events)
+            .unwrap();
+        assert!(events.is_empty());
+
+        ::nix::unistd::write(write, b"abc").unwrap();
+        ::nix::unistd::close(write).unwrap();
+        while events.len() < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut events)
+                .unwrap();
+        }
+        assert_eq!(events, &[Event::Input(b"abc".to_vec()), Event::Closed]);
+        ::nix::unistd::close(read).unwrap();
+    }
+
+    #[test]
+    fn restore() {
+        let pty = openpty(None, None).unwrap();
+        let flags = unsafe { libc::fcntl(pty.slave, libc::F_GETFL) };
+        let source = Stdin::with_fd(pty.slave).unwrap();
+        assert!(unsafe { libc::fcntl(pty.slave, libc::F_GETFL) } & libc::O_NONBLOCK != 0);
+
+        let canonical = |fd| {
+            termios::tcgetattr(fd)
+                .unwrap()
+                .local_flags
+                .contains(LocalFlags::ICANON)
+        };
+        assert!(canonical(pty.slave));
+        let guard = source.raw_mode().unwrap();
+        assert!(!canonical(pty.slave));
+        drop(guard);
+        assert!(canonical(pty.slave));
+
+        drop(source);
+        assert_eq!(unsafe { libc::fcntl(pty.slave, libc::F_GETFL) }, flags);
+        ::nix::unistd::close(pty.slave).unwrap();
+        ::nix::unistd::close(pty.master).unwrap();
+    }
+
+    #[test]
+    fn budget() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let source = Stdin::with_fd(read).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |event, len: &mut Vec<usize>| match event {
+                Event::Input(input) => len.push(input.len()),
+                Event::Closed => panic!("the input was closed"),
+            })
+            .unwrap();
+
+        // twice the input read by a dispatch
+        let input = vec![0u8; 2 * BUDGET * 4096];
+        let mut written = 0;
+        while written < input.len() {
+            written += ::nix::unistd::write(write, &input[written..]).unwrap();
+        }
+        let mut lens = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut lens)
+            .unwrap();
+        assert_eq!(lens, vec![4096; BUDGET]);
+        // the rest of the input is read without any new write
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut lens)
+            .unwrap();
+        assert_eq!(lens, vec![4096; 2 * BUDGET]);
+
+        ::nix::unistd::close(write).unwrap();
+        ::nix::unistd::close(read).unwrap();
+    }
+}
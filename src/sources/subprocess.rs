@@ -0,0 +1,336 @@
+//! Event source for running a subprocess
+//!
+//! Only available on Linux, since 5.3.
+//!
+//! A `Subprocess` spawns a `std::process::Command` with piped output, and generates
+//! an event with the data read from its stdout and stderr, then an other one with
+//! its exit status once it exits, all through the same callback. Its pipes are read
+//! without blocking, and its exit is tracked by a pidfd, like the `pidfd` module does.
+//!
+//! When the process exits, what remains in its pipes is delivered before its exit
+//! status. A process which shared its pipes with its own children can still generate
+//! output events afterwards, until they exit as well.
+
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use sources::pidfd::ProcessExit;
+use sources::TokenFactory;
+use {EventDispatcher, EventSource};
+
+/// An event generated by a subprocess
+#[derive(Debug)]
+pub enum Event {
+    /// Data was read from the stdout of the process
+    Stdout(Vec<u8>),
+    /// Data was read from the stderr of the process
+    Stderr(Vec<u8>),
+    /// The process exited
+    ///
+    /// This event is generated once, no output event follows it unless the pipes of
+    /// the process are still open in other processes.
+    Exited(ExitStatus),
+}
+
+struct Pipe<R> {
+    reader: R,
+    eof: bool,
+}
+
+impl<R: Read + AsRawFd> Pipe<R> {
+    fn new(reader: R) -> io::Result<Pipe<R>> {
+        set_nonblocking(reader.as_raw_fd())?;
+        Ok(Pipe { reader, eof: false })
+    }
+
+    // read all the available data
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while !self.eof {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => self.eof = true,
+                Ok(n) => data.extend_from_slice(&buffer[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(data)
+    }
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct Inner {
+    child: Child,
+    stdout: Option<Pipe<ChildStdout>>,
+    stderr: Option<Pipe<ChildStderr>>,
+    status: Option<ExitStatus>,
+}
+
+/// An event source running a subprocess
+pub struct Subprocess {
+    inner: Rc<RefCell<Inner>>,
+    exit: ProcessExit,
+    stdout: Option<RawFd>,
+    stderr: Option<RawFd>,
+}
+
+impl Subprocess {
+    /// Spawn a command, piping its stdout and stderr to the source
+    ///
+    /// The stdin of the command is left as configured, and can be retrieved with
+    /// `take_stdin()` if it was piped.
+    pub fn spawn(command: &mut Command) -> io::Result<Subprocess> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let parts = ProcessExit::new(child.id()).and_then(|exit| {
+            let stdout = child.stdout.take().map(Pipe::new).transpose()?;
+            let stderr = child.stderr.take().map(Pipe::new).transpose()?;
+            Ok((exit, stdout, stderr))
+        });
+        // the child does not outlive a failure to build the source
+        let (exit, stdout, stderr) = match parts {
+            Ok(parts) => parts,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+        Ok(Subprocess {
+            stdout: stdout.as_ref().map(|pipe| pipe.reader.as_raw_fd()),
+            stderr: stderr.as_ref().map(|pipe| pipe.reader.as_raw_fd()),
+            inner: Rc::new(RefCell::new(Inner {
+                child,
+                stdout,
+                stderr,
+                status: None,
+            })),
+            exit,
+        })
+    }
+
+    /// The pid of the process
+    pub fn id(&self) -> u32 {
+        self.exit.pid()
+    }
+
+    /// Take the stdin of the process, if it was piped
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.inner.borrow_mut().child.stdin.take()
+    }
+
+    /// Kill the process
+    ///
+    /// Its exit is then reported as for any other exit.
+    pub fn kill(&mut self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.status.is_some() {
+            return Ok(());
+        }
+        inner.child.kill()
+    }
+
+    fn fds(&self) -> [Option<RawFd>; 2] {
+        [self.stdout, self.stderr]
+    }
+}
+
+// the sub-ids of the stdout, stderr and of the pidfd
+const STDOUT: usize = 0;
+const STDERR: usize = 1;
+const EXIT: usize = 2;
+
+impl Evented for Subprocess {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        let mut factory = TokenFactory::new(token);
+        for fd in &self.fds() {
+            // a missing pipe still takes its sub-id
+            let token = factory.token();
+            if let Some(ref fd) = *fd {
+                EventedFd(fd).register(poll, token, interest, opts)?;
+            }
+        }
+        self.exit.register(poll, factory.token(), interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        let mut factory = TokenFactory::new(token);
+        for fd in &self.fds() {
+            let token = factory.token();
+            if let Some(ref fd) = *fd {
+                EventedFd(fd).reregister(poll, token, interest, opts)?;
+            }
+        }
+        self.exit.reregister(poll, factory.token(), interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        for fd in self.fds().iter().flatten() {
+            EventedFd(fd).deregister(poll)?;
+        }
+        self.exit.deregister(poll)
+    }
+}
+
+impl EventSource for Subprocess {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner>>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> Dispatcher<Data, F> {
+    fn read_stdout(&mut self, data: &mut Data) -> io::Result<()> {
+        let read = match self.inner.borrow_mut().stdout {
+            Some(ref mut pipe) => pipe.read()?,
+            None => return Ok(()),
+        };
+        if !read.is_empty() {
+            (self.callback)(Event::Stdout(read), data);
+        }
+        Ok(())
+    }
+
+    fn read_stderr(&mut self, data: &mut Data) -> io::Result<()> {
+        let read = match self.inner.borrow_mut().stderr {
+            Some(ref mut pipe) => pipe.read()?,
+            None => return Ok(()),
+        };
+        if !read.is_empty() {
+            (self.callback)(Event::Stderr(read), data);
+        }
+        Ok(())
+    }
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.ready_sub(ready, STDOUT, data)
+    }
+
+    fn ready_sub(&mut self, _: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        match sub_id {
+            STDOUT => self.read_stdout(data),
+            STDERR => self.read_stderr(data),
+            EXIT => {
+                if self.inner.borrow().status.is_some() {
+                    return Ok(());
+                }
+                // the remaining output comes before the exit
+                self.read_stdout(data)?;
+                self.read_stderr(data)?;
+                let status = {
+                    let mut inner = self.inner.borrow_mut();
+                    let status = inner.child.wait()?;
+                    inner.status = Some(status);
+                    status
+                };
+                (self.callback)(Event::Exited(status), data);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn subprocess() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2; exit 3"]);
+        let source = match Subprocess::spawn(&mut command) {
+            Ok(source) => source,
+            // pidfds are not supported by this kernel
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOSYS) => return,
+            Err(err) => panic!("{}", err),
+        };
+
+        #[derive(Default)]
+        struct Output {
+            stdout: Vec<u8>,
+            stderr: Vec<u8>,
+            status: Option<ExitStatus>,
+        }
+        event_loop
+            .handle()
+            .insert_source(source, |evt, output: &mut Output| {
+                assert!(output.status.is_none());
+                match evt {
+                    Event::Stdout(data) => output.stdout.extend(data),
+                    Event::Stderr(data) => output.stderr.extend(data),
+                    Event::Exited(status) => output.status = Some(status),
+                }
+            })
+            .unwrap();
+
+        let mut output = Output::default();
+        while output.status.is_none() {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut output)
+                .unwrap();
+        }
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert_eq!(output.status.unwrap().code(), Some(3));
+    }
+}
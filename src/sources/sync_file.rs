@@ -0,0 +1,194 @@
+//! Event source for the signaling of GPU fences
+//!
+//! Only available on Linux.
+//!
+//! A sync_file is a file descriptor representing a fence, the completion of some work
+//! of the GPU, as returned by the explicit synchronization protocols or the rendering
+//! APIs. It becomes readable once the fence signals. A `SyncFile` source generates a
+//! single event then, so that the composition can be scheduled once the buffers are
+//! ready instead of blocking on them.
+//!
+//! The fence of a dma-buf, covering the pending accesses to the buffer, can be exported
+//! as a sync_file with `SyncFile::from_dmabuf()`, since Linux 6.0.
+
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+// the flags of the accesses to wait for when exporting the fence of a dma-buf
+const DMA_BUF_SYNC_READ: u32 = 1;
+const DMA_BUF_SYNC_WRITE: u32 = 2;
+
+#[repr(C)]
+struct DmaBufExportSyncFile {
+    flags: u32,
+    fd: i32,
+}
+
+/// An event source waiting for a fence to signal
+///
+/// It generates a single `()` event once the fence signals.
+pub struct SyncFile {
+    fd: OwnedFd,
+}
+
+impl SyncFile {
+    /// Wait for the fence of the given sync_file, taking ownership of it
+    ///
+    /// It is switched to non-blocking mode, and closed when the source is dropped.
+    pub fn new(fd: RawFd) -> io::Result<SyncFile> {
+        let source = SyncFile {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(source)
+    }
+
+    /// Wait for the pending accesses to a dma-buf
+    ///
+    /// If `write` is true, the fence covers all the pending accesses, which is what is
+    /// needed before writing to the buffer. Otherwise it only covers the pending
+    /// writes, which is what is needed before reading from it. The dma-buf itself is not
+    /// owned by the source.
+    ///
+    /// This fails with `ENOTTY` on the kernels older than 6.0.
+    pub fn from_dmabuf(dmabuf: RawFd, write: bool) -> io::Result<SyncFile> {
+        let mut export = DmaBufExportSyncFile {
+            flags: if write {
+                DMA_BUF_SYNC_WRITE
+            } else {
+                DMA_BUF_SYNC_READ
+            },
+            fd: -1,
+        };
+        // DMA_BUF_IOCTL_EXPORT_SYNC_FILE
+        let request = (3 << 30) | (8 << 16) | ((b'b' as libc::Ioctl) << 8) | 2;
+        if unsafe { libc::ioctl(dmabuf, request, &mut export) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        SyncFile::new(export.fd)
+    }
+}
+
+impl AsRawFd for SyncFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Evented for SyncFile {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for SyncFile {
+    type Event = ();
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    // a signaled fence remains readable
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            callback,
+            signaled: false,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut((), &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    callback: F,
+    // reregistering the source makes it readable again
+    signaled: bool,
+}
+
+impl<Data, F: FnMut((), &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        if !self.signaled {
+            self.signaled = true;
+            (self.callback)((), data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn signaled() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // a pipe becomes readable as a fence signals
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        let source = SyncFile::new(read).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), signals: &mut u32| *signals += 1)
+            .unwrap();
+
+        let mut signals = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut signals)
+            .unwrap();
+        assert_eq!(signals, 0);
+
+        ::nix::unistd::write(write, b"x").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut signals)
+            .unwrap();
+        assert_eq!(signals, 1);
+
+        // the fence stays signaled, without generating other events
+        ::nix::unistd::write(write, b"x").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut signals)
+            .unwrap();
+        assert_eq!(signals, 1);
+        ::nix::unistd::close(write).unwrap();
+    }
+}
@@ -0,0 +1,571 @@
+//! Integration with the systemd service manager
+//!
+//! Only available on Linux, with the `systemd` cargo feature.
+//!
+//! This does not link to libsystemd: the notifications are datagrams sent to the
+//! socket given by systemd in `$NOTIFY_SOCKET`, which `notify()` does, for example
+//! with `READY=1` once the service is started or `STATUS=...` to describe its state.
+//! Nothing is sent if the service is not run by systemd.
+//!
+//! File descriptors are kept across restarts by sending them with `FDSTORE=1` through
+//! `notify_with_fds()`: they are given back to the next instance of the service, along
+//! with the sockets of socket activation, and retrieved with `listen_fds()`.
+//!
+//! A service with `WatchdogSec=` must ping systemd periodically, or it is considered
+//! hung. The `Watchdog` source does so from the event loop, at half the interval
+//! requested by systemd: as long as the loop keeps running its callbacks, the service
+//! is alive.
+//!
+//! The `Journal` source wakes the loop up when the journal changes, as
+//! `sd_journal_get_fd()` would: it watches the journal files with inotify, in
+//! `/run/log/journal` and `/var/log/journal`, and the journal is then read with
+//! `journalctl` or libsystemd.
+
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use sources::inotify::{
+    Event as InotifyEvent, Inotify, IN_ATTRIB, IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_ISDIR,
+    IN_MODIFY, IN_MOVED_FROM, IN_MOVED_TO, IN_MOVE_SELF, IN_ONLYDIR, IN_UNMOUNT,
+};
+use sources::timerfd::{Clock, Event as TimerEvent, TimerFd};
+use {EventDispatcher, EventSource};
+
+// the first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+// the directories of the volatile and persistent journals
+const JOURNAL_DIRECTORIES: [&str; 2] = ["/run/log/journal", "/var/log/journal"];
+
+// the changes watched by sd-journal, to the journal files and their directories
+const JOURNAL_MASK: u32 = IN_CREATE
+    | IN_MOVED_TO
+    | IN_MODIFY
+    | IN_ATTRIB
+    | IN_DELETE
+    | IN_DELETE_SELF
+    | IN_MOVE_SELF
+    | IN_UNMOUNT
+    | IN_MOVED_FROM
+    | IN_ONLYDIR;
+
+/// Send a notification to systemd
+///
+/// The `state` is a newline-separated list of assignments, like `READY=1`. This
+/// returns `false` if the service is not run by systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    notify_with_fds(state, &[])
+}
+
+/// Send a notification to systemd, along with some file descriptors
+///
+/// This is used with `FDSTORE=1`, and optionally `FDNAME=...`, to store them in the
+/// service manager. This returns `false` if the service is not run by systemd.
+pub fn notify_with_fds(state: &str, fds: &[RawFd]) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => send(&socket, state, fds).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+// send a notification to the given socket, whose name starts with `@` if it is abstract
+fn send(socket: &OsStr, state: &str, fds: &[RawFd]) -> io::Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = socket.as_bytes();
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid notification socket",
+        ));
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = src as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut iov = libc::iovec {
+        iov_base: state.as_ptr() as *mut libc::c_void,
+        iov_len: state.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = addr_len as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    // aligned for `cmsghdr`
+    let fds_len = mem::size_of_val(fds) as libc::c_uint;
+    let mut control = vec![0u64; unsafe { libc::CMSG_SPACE(fds_len) } as usize / 8 + 1];
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ::std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len as usize,
+            );
+        }
+    }
+    let ret = unsafe { libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// The interval of the watchdog of the service, if it is enabled
+///
+/// This is the `WatchdogSec=` of the service, within which it must ping systemd.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        // the watchdog is meant for an other process of the service
+        if pid.to_str().and_then(|pid| pid.parse::<u32>().ok()) != Some(::std::process::id()) {
+            return None;
+        }
+    }
+    env::var_os("WATCHDOG_USEC")
+        .and_then(|usec| usec.to_str().and_then(|usec| usec.parse::<u64>().ok()))
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// The file descriptors passed by systemd, with their names
+///
+/// These are the sockets of socket activation, and the file descriptors stored with
+/// `FDSTORE=1` by a previous instance of the service. They are switched to close-on-exec,
+/// and are empty if none were passed to this process.
+pub fn listen_fds() -> Vec<(RawFd, String)> {
+    let var = |name| env::var(name).ok();
+    let fds = parse_listen_fds(
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+    );
+    for &(fd, _) in &fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    fds
+}
+
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+) -> Vec<(RawFd, String)> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(::std::process::id()) {
+        return Vec::new();
+    }
+    let count = count
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let mut names = names.unwrap_or("").split(':');
+    (0..count.max(0))
+        .map(|i| {
+            let name = names.next().filter(|name| !name.is_empty());
+            (LISTEN_FDS_START + i, name.unwrap_or("unknown").to_owned())
+        })
+        .collect()
+}
+
+/// An event source pinging the watchdog of systemd
+///
+/// It sends `WATCHDOG=1` periodically, and generates a `()` event after each ping.
+pub struct Watchdog {
+    timer: TimerFd,
+    socket: OsString,
+    period: Duration,
+}
+
+impl Watchdog {
+    /// Create a source pinging the watchdog at half its interval
+    ///
+    /// This returns `None` if the service is not run by systemd, or if its watchdog is
+    /// not enabled.
+    pub fn new() -> io::Result<Option<Watchdog>> {
+        match (env::var_os("NOTIFY_SOCKET"), watchdog_interval()) {
+            (Some(socket), Some(interval)) => Watchdog::with_socket(socket, interval / 2).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn with_socket(socket: OsString, period: Duration) -> io::Result<Watchdog> {
+        let timer = TimerFd::new(Clock::Monotonic)?;
+        timer.set_time(period, period, 0)?;
+        Ok(Watchdog {
+            timer,
+            socket,
+            period,
+        })
+    }
+
+    /// The delay between two pings
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Evented for Watchdog {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.timer.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.timer.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.timer.deregister(poll)
+    }
+}
+
+impl EventSource for Watchdog {
+    type Event = ();
+
+    fn interest(&self) -> Ready {
+        self.timer.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.timer.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let expired = Rc::new(Cell::new(false));
+        let timer_expired = expired.clone();
+        let timer = self.timer.make_dispatcher(move |event, _: &mut Data| {
+            if let TimerEvent::Expired(_) = event {
+                timer_expired.set(true);
+            }
+        });
+        Rc::new(RefCell::new(Dispatcher {
+            timer,
+            expired,
+            socket: self.socket.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data, F: FnMut((), &mut Data)> {
+    timer: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    expired: Rc<Cell<bool>>,
+    socket: OsString,
+    callback: F,
+}
+
+impl<'l, Data, F: FnMut((), &mut Data)> EventDispatcher<Data> for Dispatcher<'l, Data, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.timer.borrow_mut().ready(ready, data)?;
+        if self.expired.replace(false) {
+            send(&self.socket, "WATCHDOG=1", &[])?;
+            (self.callback)((), data);
+        }
+        Ok(())
+    }
+}
+
+/// An event source waking up when the journal changes
+///
+/// It generates a `()` event when entries may have been appended to the journal, or
+/// journal files were added or removed, coalescing the changes of a same dispatch.
+///
+/// The journals of each machine, in the subdirectories of the journal directories, are
+/// watched, including the ones created later. A journal directory missing when the
+/// source is created is not watched.
+pub struct Journal {
+    inotify: Rc<RefCell<Inotify>>,
+}
+
+impl Journal {
+    /// Create a source watching the journals of the system
+    pub fn new() -> io::Result<Journal> {
+        Journal::with_directories(&JOURNAL_DIRECTORIES)
+    }
+
+    fn with_directories<P: AsRef<Path>>(directories: &[P]) -> io::Result<Journal> {
+        let mut inotify = Inotify::new()?;
+        for directory in directories {
+            match inotify.add_watch(directory, JOURNAL_MASK) {
+                Ok(_) => {}
+                Err(ref err)
+                    if err.raw_os_error() == Some(libc::ENOENT)
+                        || err.raw_os_error() == Some(libc::ENOTDIR) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err),
+            }
+            for entry in directory.as_ref().read_dir()? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    inotify.add_watch(entry.path(), JOURNAL_MASK)?;
+                }
+            }
+        }
+        Ok(Journal {
+            inotify: Rc::new(RefCell::new(inotify)),
+        })
+    }
+}
+
+impl Evented for Journal {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inotify.borrow().register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inotify
+            .borrow()
+            .reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inotify.borrow().deregister(poll)
+    }
+}
+
+impl EventSource for Journal {
+    type Event = ();
+
+    fn interest(&self) -> Ready {
+        self.inotify.borrow().interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.inotify.borrow().pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((), &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let changed = Rc::new(Cell::new(false));
+        let inotify_changed = changed.clone();
+        let watcher = self.inotify.clone();
+        let inotify =
+            self.inotify
+                .borrow()
+                .make_dispatcher(move |event: InotifyEvent, _: &mut Data| {
+                    // the journals of a new machine
+                    if event.mask & IN_ISDIR != 0 && event.mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                        // it may already be gone
+                        let _ = watcher.borrow_mut().add_watch(&event.path, JOURNAL_MASK);
+                    }
+                    inotify_changed.set(true);
+                });
+        Rc::new(RefCell::new(JournalDispatcher {
+            inotify,
+            changed,
+            callback,
+        }))
+    }
+}
+
+struct JournalDispatcher<'l, Data, F: FnMut((), &mut Data)> {
+    inotify: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    changed: Rc<Cell<bool>>,
+    callback: F,
+}
+
+impl<'l, Data, F: FnMut((), &mut Data)> EventDispatcher<Data> for JournalDispatcher<'l, Data, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.inotify.borrow_mut().ready(ready, data)?;
+        if self.changed.replace(false) {
+            (self.callback)((), data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn socket(name: &str) -> (UnixDatagram, PathBuf) {
+        let path =
+            env::temp_dir().join(format!("calloop-systemd-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn notify_with_fd() {
+        use std::os::unix::io::AsRawFd;
+
+        let (receiver, path) = socket("notify");
+        let (read, write) = ::nix::unistd::pipe().unwrap();
+        send(path.as_os_str(), "FDSTORE=1\nFDNAME=pipe", &[write]).unwrap();
+        ::nix::unistd::close(write).unwrap();
+
+        let mut buffer = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let len = unsafe { libc::recvmsg(receiver.as_raw_fd(), &mut msg, 0) };
+        assert_eq!(&buffer[..len as usize], b"FDSTORE=1\nFDNAME=pipe");
+        let received = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            assert!(!cmsg.is_null());
+            assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+            ::std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd)
+        };
+
+        // the received descriptor is the write end of the pipe
+        ::nix::unistd::write(received, b"x").unwrap();
+        let mut byte = [0u8; 1];
+        ::nix::unistd::read(read, &mut byte).unwrap();
+        assert_eq!(&byte, b"x");
+        ::nix::unistd::close(received).unwrap();
+        ::nix::unistd::close(read).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (receiver, path) = socket("watchdog");
+        let source =
+            Watchdog::with_socket(path.clone().into_os_string(), Duration::from_millis(10))
+                .unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), pings: &mut u32| *pings += 1)
+            .unwrap();
+
+        let mut pings = 0;
+        while pings < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_millis(100)), &mut pings)
+                .unwrap();
+        }
+        let mut buffer = [0u8; 16];
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..len], b"WATCHDOG=1");
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn passed_fds() {
+        let pid = ::std::process::id().to_string();
+        assert_eq!(
+            parse_listen_fds(Some(&pid), Some("3"), Some("http:store")),
+            &[
+                (3, "http".to_owned()),
+                (4, "store".to_owned()),
+                (5, "unknown".to_owned()),
+            ]
+        );
+        // meant for an other process
+        assert!(parse_listen_fds(Some("1"), Some("2"), None).is_empty());
+        assert!(parse_listen_fds(None, None, None).is_empty());
+    }
+
+    #[test]
+    fn journal() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let root = env::temp_dir().join(format!("calloop-journal-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("machine")).unwrap();
+        let source = Journal::with_directories(&[root.clone(), root.join("missing")]).unwrap();
+        event_loop
+            .handle()
+            .insert_source(source, |(), wakeups: &mut u32| *wakeups += 1)
+            .unwrap();
+
+        let mut wakeups = 0;
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut wakeups)
+            .unwrap();
+        assert_eq!(wakeups, 0);
+
+        // the changes of a same dispatch are coalesced
+        fs::write(root.join("machine").join("system.journal"), b"entry").unwrap();
+        fs::write(root.join("machine").join("user.journal"), b"entry").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut wakeups)
+            .unwrap();
+        assert_eq!(wakeups, 1);
+
+        // a new machine is watched
+        fs::create_dir(root.join("other")).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut wakeups)
+            .unwrap();
+        assert_eq!(wakeups, 2);
+        fs::write(root.join("other").join("system.journal"), b"entry").unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut wakeups)
+            .unwrap();
+        assert_eq!(wakeups, 3);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
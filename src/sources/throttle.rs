@@ -0,0 +1,303 @@
+//! A wrapper source limiting the rate of the events of an other source
+//!
+//! A `Throttle<S>` wraps an event source and delivers at most a given number of its
+//! events per period, protecting the callback from floods like a busy socket or a
+//! chatty log channel. The excess events are either dropped or delayed until the rate
+//! allows them, depending on the `Overflow` policy.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// What a `Throttle` does with the events exceeding its rate
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the excess events
+    Drop,
+    /// Buffer the excess events, and deliver them once the rate allows it
+    ///
+    /// The inner source keeps being processed, so the buffer grows without bound if
+    /// it generates events faster than the rate.
+    Delay,
+}
+
+/// A source delivering the events of an other one at a limited rate
+///
+/// No more than `limit` events are delivered during any `period`.
+pub struct Throttle<S> {
+    inner: S,
+    limit: usize,
+    period: Duration,
+    overflow: Overflow,
+    deadline: Rc<Cell<Option<Instant>>>,
+    registered: Cell<bool>,
+}
+
+impl<S: EventSource> Throttle<S> {
+    /// Wrap a source, delivering at most `limit` of its events per `period`
+    ///
+    /// **Panics** if `limit` is zero.
+    pub fn new(source: S, limit: usize, period: Duration, overflow: Overflow) -> Throttle<S> {
+        assert!(
+            limit > 0,
+            "[calloop] The limit of a throttle cannot be zero."
+        );
+        Throttle {
+            inner: source,
+            limit,
+            period,
+            overflow,
+            deadline: Rc::new(Cell::new(None)),
+            registered: Cell::new(false),
+        }
+    }
+
+    /// Access the inner source
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the inner source
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner source
+    ///
+    /// The delayed events are lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: EventSource> Evented for Throttle<S> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)?;
+        self.registered.set(true);
+        Ok(())
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)?;
+        self.registered.set(true);
+        Ok(())
+    }
+
+    // the delayed events of a disabled source are delivered once it is enabled again
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registered.set(false);
+        self.inner.deregister(poll)
+    }
+}
+
+impl<S: EventSource> EventSource for Throttle<S>
+where
+    S::Event: 'static,
+{
+    type Event = S::Event;
+
+    fn interest(&self) -> Ready {
+        self.inner.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.inner.pollopts()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let deadline = if self.registered.get() {
+            self.deadline.get()
+        } else {
+            None
+        };
+        match (self.inner.next_deadline(), deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn before_removal(&mut self) {
+        self.inner.before_removal();
+    }
+
+    fn before_sleep(&mut self, timeout: Option<Duration>) {
+        self.inner.before_sleep(timeout);
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(S::Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let buffer = Rc::new(RefCell::new(VecDeque::new()));
+        let inner_buffer = buffer.clone();
+        let inner = self.inner.make_dispatcher(move |event, _: &mut Data| {
+            inner_buffer.borrow_mut().push_back(event);
+        });
+        Rc::new(RefCell::new(Dispatcher {
+            inner,
+            limit: self.limit,
+            period: self.period,
+            overflow: self.overflow,
+            deadline: self.deadline.clone(),
+            delivered: VecDeque::new(),
+            buffer,
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data, E, F> {
+    inner: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    limit: usize,
+    period: Duration,
+    overflow: Overflow,
+    deadline: Rc<Cell<Option<Instant>>>,
+    // the instants of the deliveries of the current period
+    delivered: VecDeque<Instant>,
+    // the events generated by the inner source and not yet delivered
+    buffer: Rc<RefCell<VecDeque<E>>>,
+    callback: F,
+}
+
+impl<'l, Data, E, F: FnMut(E, &mut Data)> EventDispatcher<Data> for Dispatcher<'l, Data, E, F> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.ready_sub(ready, 0, data)
+    }
+
+    fn ready_sub(&mut self, ready: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        let now = Instant::now();
+        let expired = self
+            .deadline
+            .get()
+            .map_or(false, |deadline| deadline <= now);
+        // without readiness, this source was dispatched because of a deadline, which
+        // is the one of the inner source if it was not its own
+        if !ready.is_empty() || !expired {
+            self.inner.borrow_mut().ready_sub(ready, sub_id, data)?;
+        }
+
+        while self
+            .delivered
+            .front()
+            .map_or(false, |&delivered| delivered + self.period <= now)
+        {
+            self.delivered.pop_front();
+        }
+        while self.delivered.len() < self.limit {
+            let event = self.buffer.borrow_mut().pop_front();
+            match event {
+                Some(event) => {
+                    self.delivered.push_back(now);
+                    (self.callback)(event, data);
+                }
+                None => break,
+            }
+        }
+        if self.overflow == Overflow::Drop {
+            self.buffer.borrow_mut().clear();
+        }
+
+        // wake up once the oldest delivery leaves the period
+        let deadline = if self.buffer.borrow().is_empty() {
+            None
+        } else {
+            self.delivered
+                .front()
+                .map(|&delivered| delivered + self.period)
+        };
+        self.deadline.set(deadline);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use sources::channel::{channel, Event};
+
+    fn throttled(
+        event_loop: &mut ::EventLoop<Vec<u32>>,
+        overflow: Overflow,
+    ) -> ::sources::channel::Sender<u32> {
+        let (sender, source) = channel();
+        let source = Throttle::new(source, 2, Duration::from_millis(50), overflow);
+        event_loop
+            .handle()
+            .insert_source(source, |evt, received: &mut Vec<u32>| {
+                if let Event::Msg(msg) = evt {
+                    received.push(msg)
+                }
+            })
+            .unwrap();
+        sender
+    }
+
+    #[test]
+    fn throttle_delay() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let sender = throttled(&mut event_loop, Overflow::Delay);
+
+        let start = Instant::now();
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![0, 1]);
+
+        while received.len() < 5 {
+            event_loop.dispatch(None, &mut received).unwrap();
+        }
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn throttle_drop() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+        let sender = throttled(&mut event_loop, Overflow::Drop);
+
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        let mut received = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![0, 1]);
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(60)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![0, 1]);
+
+        // the rate allows new events once the period has elapsed
+        sender.send(5).unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, vec![0, 1, 5]);
+    }
+}
@@ -0,0 +1,221 @@
+//! A wrapper source detecting the inactivity of an other source
+//!
+//! A `Timeout<S>` wraps an event source, and forwards its events. If the inner source
+//! does not generate any event for a configured duration, it generates a
+//! `Event::TimedOut` event. This is typically used for protocol keepalives or idle
+//! detection.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use {EventDispatcher, EventSource};
+
+/// The events generated by a `Timeout` source
+#[derive(Debug)]
+pub enum Event<E> {
+    /// An event of the inner source
+    Inner(E),
+    /// The inner source did not generate any event during the configured duration
+    TimedOut,
+}
+
+/// A source generating an event when an other one is inactive for too long
+///
+/// The countdown starts when the source is inserted, and is restarted by every event
+/// of the inner source and every `Event::TimedOut`, so the latter is generated
+/// periodically as long as the inner source remains inactive.
+pub struct Timeout<S> {
+    inner: S,
+    duration: Duration,
+    deadline: Rc<Cell<Option<Instant>>>,
+}
+
+impl<S: EventSource> Timeout<S> {
+    /// Wrap a source, with the duration after which it is considered inactive
+    pub fn new(source: S, duration: Duration) -> Timeout<S> {
+        Timeout {
+            inner: source,
+            duration,
+            deadline: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// Access the inner source
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutably access the inner source
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner source
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: EventSource> Evented for Timeout<S> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)?;
+        self.deadline.set(Some(Instant::now() + self.duration));
+        Ok(())
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)?;
+        if self.deadline.get().is_none() {
+            self.deadline.set(Some(Instant::now() + self.duration));
+        }
+        Ok(())
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        // a disabled source is not inactive
+        self.deadline.set(None);
+        self.inner.deregister(poll)
+    }
+}
+
+impl<S: EventSource> EventSource for Timeout<S> {
+    type Event = Event<S::Event>;
+
+    fn interest(&self) -> Ready {
+        self.inner.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.inner.pollopts()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        match (self.inner.next_deadline(), self.deadline.get()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn before_removal(&mut self) {
+        self.inner.before_removal();
+    }
+
+    fn before_sleep(&mut self, timeout: Option<Duration>) {
+        self.inner.before_sleep(timeout);
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<S::Event>, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let callback = Rc::new(RefCell::new(callback));
+        let inner_callback = callback.clone();
+        let deadline = self.deadline.clone();
+        let duration = self.duration;
+        let inner = self.inner.make_dispatcher(move |event, data: &mut Data| {
+            deadline.set(Some(Instant::now() + duration));
+            (inner_callback.borrow_mut())(Event::Inner(event), data);
+        });
+        Rc::new(RefCell::new(Dispatcher {
+            inner,
+            deadline: self.deadline.clone(),
+            duration,
+            timed_out: Box::new(move |data: &mut Data| {
+                (callback.borrow_mut())(Event::TimedOut, data)
+            }),
+        }))
+    }
+}
+
+struct Dispatcher<'l, Data> {
+    inner: Rc<RefCell<dyn EventDispatcher<Data> + 'l>>,
+    deadline: Rc<Cell<Option<Instant>>>,
+    duration: Duration,
+    timed_out: Box<dyn FnMut(&mut Data) + 'l>,
+}
+
+impl<'l, Data> EventDispatcher<Data> for Dispatcher<'l, Data> {
+    fn ready(&mut self, ready: Ready, data: &mut Data) -> io::Result<()> {
+        self.ready_sub(ready, 0, data)
+    }
+
+    fn ready_sub(&mut self, ready: Ready, sub_id: usize, data: &mut Data) -> io::Result<()> {
+        let now = Instant::now();
+        let timed_out = self
+            .deadline
+            .get()
+            .map_or(false, |deadline| deadline <= now);
+        if timed_out {
+            self.deadline.set(Some(now + self.duration));
+            (self.timed_out)(data);
+        }
+        // without readiness, this source was dispatched because of a deadline, which
+        // is the one of the inner source if it was not its own
+        if !ready.is_empty() || !timed_out {
+            self.inner.borrow_mut().ready_sub(ready, sub_id, data)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use sources::ping::make_ping;
+
+    #[test]
+    fn timeout() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (ping, source) = make_ping().unwrap();
+        let source = Timeout::new(source, Duration::from_millis(50));
+        event_loop
+            .handle()
+            .insert_source(source, |evt, events: &mut Vec<&str>| match evt {
+                Event::Inner(()) => events.push("ping"),
+                Event::TimedOut => events.push("timeout"),
+            })
+            .unwrap();
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec!["timeout"]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // an event of the inner source restarts the countdown
+        ping.ping();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut events)
+            .unwrap();
+        assert_eq!(events, vec!["timeout", "ping"]);
+        let pinged = Instant::now();
+        event_loop
+            .dispatch(Some(Duration::from_millis(20)), &mut events)
+            .unwrap();
+        assert_eq!(events, vec!["timeout", "ping"]);
+
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec!["timeout", "ping", "timeout"]);
+        assert!(pinged.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -7,22 +7,275 @@
 //! to set or cancel timeouts. This handle is cloneable and can be send accross threads
 //! if `T: Send`, allowing you to setup timeouts from any point of your program.
 //!
-//! This implementation is based on
-//! [`mio_more::timer`](https://docs.rs/mio-more/*/mio_more/timer/index.html).
+//! The event loop takes the deadlines of the timers inserted in it into account when
+//! waiting for events, so timeouts fire as soon as they expire regardless of the
+//! timeout given to `EventLoop::dispatch(..)`.
+//!
+//! The timeouts of a timer are kept sorted by deadline, setting, cancelling or firing
+//! one of them takes a logarithmic time in the number of pending timeouts. A single
+//! timer can thus hold tens of thousands of them, like per-connection idle timeouts.
+//!
+//! A timer can be given a slack using `TimerHandle::set_slack()`: its timeouts may
+//! then fire up to this duration late, which allows the event loop to handle the
+//! timeouts expiring close to each other in a single wakeup. This trades precision
+//! for fewer wakeups, for example in battery-sensitive programs.
+//!
+//! Timers follow the system monotonic clock by default. A timer created with
+//! `Timer::with_clock()` follows an other `Clock` instead, like a `ManualClock` whose
+//! time only advances when told to, so that tests of timeout-heavy code run instantly
+//! and deterministically.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
-use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 
-use mio_extras::timer as mio_timer;
+use {EventDispatcher, EventSource};
 
-pub use self::mio_timer::Timeout;
+/// A source of time for timers
+pub trait Clock: Send + Sync {
+    /// The current time of this clock
+    fn now(&self) -> Instant;
+}
 
-use {EventDispatcher, EventSource};
+/// The system monotonic clock, followed by timers by default
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock whose time only advances when told to
+///
+/// It is meant for tests: a timer following it fires its timeouts once the clock is
+/// advanced past their deadline, at the next dispatch of the event loop. The clock can
+/// be cloned, the clones sharing the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create a new clock, starting at the current time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Advance the time of this clock
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// A timeout that was set on a timer
+///
+/// It can be used to cancel it using `TimerHandle::cancel_timeout()`, or to pause
+/// it using `TimerHandle::pause_timeout()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Timeout {
+    deadline: Instant,
+    id: u64,
+}
+
+// the period of a repeating timeout, along with a way to clone its data for each tick
+struct Interval<T> {
+    period: Duration,
+    clone: fn(&T) -> T,
+}
+
+struct Entry<T> {
+    data: T,
+    interval: Option<Interval<T>>,
+}
+
+struct TimerInner<T> {
+    // timeouts are ordered by deadline, and by insertion order for a given deadline
+    timeouts: BTreeMap<(Instant, u64), Entry<T>>,
+    // the current deadline of the timeouts which were rescheduled, as it differs
+    // from the one of their `Timeout`: repeating timeouts which fired, and resumed ones
+    rescheduled: HashMap<u64, Instant>,
+    // the paused timeouts, along with their remaining time
+    paused: HashMap<u64, (Duration, Entry<T>)>,
+    next_id: u64,
+    // the number of expirations of the last timeout that fired
+    expirations: u64,
+    // how late the timeouts may fire, so that nearby ones are handled together
+    slack: Duration,
+    // the thread of the event loop the timer is registered in, if any
+    owner: Option<ThreadId>,
+    clock: Arc<dyn Clock>,
+    // an instant in the past, given to the event loop for the expired deadlines
+    created: Instant,
+}
+
+impl<T> TimerInner<T> {
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timeouts.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    // the key of a pending timeout in the map
+    fn key(&self, timeout: &Timeout) -> (Instant, u64) {
+        let deadline = self
+            .rescheduled
+            .get(&timeout.id)
+            .cloned()
+            .unwrap_or(timeout.deadline);
+        (deadline, timeout.id)
+    }
+
+    fn pop_expired(&mut self, now: Instant) -> Option<T> {
+        let key = match self.timeouts.keys().next() {
+            Some(&key) if key.0 <= now => key,
+            _ => return None,
+        };
+        let entry = self.timeouts.remove(&key)?;
+        let (period, clone) = match entry.interval {
+            Some(ref interval) => (interval.period, interval.clone),
+            None => {
+                self.rescheduled.remove(&key.1);
+                self.expirations = 1;
+                return Some(entry.data);
+            }
+        };
+        // the next deadline follows the previous one rather than `now`, so that the
+        // ticks don't drift, the ticks missed by a late dispatch are skipped, at once
+        // as there may be a lot of them after a suspend
+        let missed = (now - key.0).as_nanos() / period.as_nanos();
+        let elapsed = period.as_nanos() * (missed + 1);
+        let deadline = key.0
+            + Duration::new(
+                (elapsed / 1_000_000_000) as u64,
+                (elapsed % 1_000_000_000) as u32,
+            );
+        self.expirations = missed as u64 + 1;
+        let data = clone(&entry.data);
+        self.rescheduled.insert(key.1, deadline);
+        self.timeouts.insert((deadline, key.1), entry);
+        Some(data)
+    }
+}
+
+/// The action to take once a timeout inserted with `LoopHandle::insert_timeout()`
+/// has fired
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Remove the timeout from the event loop
+    Drop,
+    /// Fire again once this duration has elapsed
+    ToDuration(Duration),
+    /// Fire again at this instant
+    ToInstant(Instant),
+}
+
+// the source of `LoopHandle::insert_timeout()`, dispatched by the event loop once its
+// deadline is reached, the callback then sets the next one
+pub(crate) struct Scheduled {
+    deadline: Rc<Cell<Option<Instant>>>,
+    registered: Cell<bool>,
+}
+
+impl Scheduled {
+    pub(crate) fn new(deadline: Instant) -> Scheduled {
+        Scheduled {
+            deadline: Rc::new(Cell::new(Some(deadline))),
+            registered: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn deadline(&self) -> Rc<Cell<Option<Instant>>> {
+        self.deadline.clone()
+    }
+}
+
+// there is nothing to register, but a disabled source must not expire
+impl Evented for Scheduled {
+    fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        self.registered.set(true);
+        Ok(())
+    }
+
+    fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        self.registered.set(true);
+        Ok(())
+    }
+
+    fn deregister(&self, _: &Poll) -> io::Result<()> {
+        self.registered.set(false);
+        Ok(())
+    }
+}
+
+impl EventSource for Scheduled {
+    type Event = Instant;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        if self.registered.get() {
+            self.deadline.get()
+        } else {
+            None
+        }
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Instant, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(ScheduledDispatcher {
+            _data: ::std::marker::PhantomData,
+            deadline: self.deadline.clone(),
+            callback,
+        }))
+    }
+}
+
+struct ScheduledDispatcher<Data, F: FnMut(Instant, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    deadline: Rc<Cell<Option<Instant>>>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Instant, &mut Data)> EventDispatcher<Data> for ScheduledDispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        match self.deadline.get() {
+            Some(deadline) if deadline <= Instant::now() => {
+                self.deadline.set(None);
+                (self.callback)(deadline, data);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
 /// A Timer event source
 ///
@@ -30,44 +283,74 @@ use {EventDispatcher, EventSource};
 /// an handle inside the event callback, allowing you to set new timeouts
 /// as a response to a timeout being reached (for reccuring ticks for example).
 pub struct Timer<T> {
-    inner: Arc<Mutex<mio_timer::Timer<T>>>,
+    inner: Arc<Mutex<TimerInner<T>>>,
+    registration: Registration,
+    readiness: SetReadiness,
 }
 
 impl<T> Timer<T> {
-    /// Create a new timer with default parameters
-    ///
-    /// Default time resolution is 100ms
+    /// Create a new timer
     pub fn new() -> Timer<T> {
+        Timer::with_clock(SystemClock)
+    }
+
+    /// Create a new timer following the given clock
+    ///
+    /// The event loop waits for its timeouts according to the real time: with a
+    /// `ManualClock`, a timeout thus only fires at a dispatch where the clock was
+    /// advanced past its deadline.
+    pub fn with_clock<C: Clock + 'static>(clock: C) -> Timer<T> {
+        let (registration, readiness) = Registration::new2();
         Timer {
-            inner: Arc::new(Mutex::new(mio_timer::Builder::default().build())),
+            inner: Arc::new(Mutex::new(TimerInner {
+                timeouts: BTreeMap::new(),
+                rescheduled: HashMap::new(),
+                paused: HashMap::new(),
+                next_id: 0,
+                expirations: 0,
+                slack: Duration::from_secs(0),
+                owner: None,
+                clock: Arc::new(clock),
+                created: Instant::now(),
+            })),
+            registration,
+            readiness,
         }
     }
 
     /// Create a new timer with a specific time resolution
-    pub fn with_resolution(resolution: Duration) -> Timer<T> {
-        Timer {
-            inner: Arc::new(Mutex::new(
-                mio_timer::Builder::default()
-                    .tick_duration(resolution)
-                    .build(),
-            )),
-        }
+    ///
+    /// Timers are no longer tick-based, the resolution is ignored.
+    #[deprecated(note = "timers are no longer tick-based, use `Timer::new()`")]
+    pub fn with_resolution(_resolution: Duration) -> Timer<T> {
+        Timer::new()
     }
 
     /// Get an handle for this timer
     pub fn handle(&self) -> TimerHandle<T> {
         TimerHandle {
             inner: self.inner.clone(),
+            readiness: self.readiness.clone(),
         }
     }
 }
 
+impl<T> Default for Timer<T> {
+    fn default() -> Timer<T> {
+        Timer::new()
+    }
+}
+
 /// An handle to a timer, used to set or cancel timeouts
 ///
 /// This handle can be cloned, and can be sent accross thread as long
-/// as `T: Send`.
+/// as `T: Send`. When a timeout set from an other thread than the one of the event
+/// loop becomes the earliest one, the event loop is woken up so that it does not
+/// wait past its deadline, for example an audio thread can schedule a wakeup of a
+/// GUI event loop.
 pub struct TimerHandle<T> {
-    inner: Arc<Mutex<mio_timer::Timer<T>>>,
+    inner: Arc<Mutex<TimerInner<T>>>,
+    readiness: SetReadiness,
 }
 
 // Manual impl of `Clone` as #[derive(Clone)] adds a `T: Clone` bound
@@ -75,6 +358,7 @@ impl<T> Clone for TimerHandle<T> {
     fn clone(&self) -> TimerHandle<T> {
         TimerHandle {
             inner: self.inner.clone(),
+            readiness: self.readiness.clone(),
         }
     }
 }
@@ -86,19 +370,160 @@ impl<T> TimerHandle<T> {
     ///
     /// The returned `Timeout` can be used to cancel it. You can drop it if you don't
     /// plan to cancel this timeout.
-    ///
-    /// This method can fail if the timer already has too many pending timeouts, currently
-    /// capacity is `2^16`.
     pub fn add_timeout(&self, delay_from_now: Duration, data: T) -> Timeout {
-        self.inner.lock().unwrap().set_timeout(delay_from_now, data)
+        let now = self.now();
+        self.insert(now + delay_from_now, data, None)
+    }
+
+    /// Set a new timeout expiring at an absolute deadline
+    ///
+    /// Same as `add_timeout()`, without the conversion from a relative duration. A
+    /// deadline that has already passed fires at the next dispatch.
+    pub fn add_timeout_at(&self, deadline: Instant, data: T) -> Timeout {
+        self.insert(deadline, data, None)
+    }
+
+    /// Set a new repeating timeout
+    ///
+    /// The timeout first fires after `period`, and then every `period`, a clone of
+    /// `data` being given to the callback each time. Each deadline is computed from
+    /// the previous one rather than from the time the callback ran, so that the ticks
+    /// don't drift. If the event loop is late by more than a period, the missed
+    /// ticks are skipped, and counted by `expirations()`.
+    ///
+    /// The returned `Timeout` can be used to stop it.
+    ///
+    /// **Panics** if `period` is zero.
+    pub fn add_interval(&self, period: Duration, data: T) -> Timeout
+    where
+        T: Clone,
+    {
+        assert!(
+            period > Duration::from_secs(0),
+            "[calloop] The period of an interval cannot be zero."
+        );
+        let interval = Interval {
+            period,
+            clone: T::clone,
+        };
+        let now = self.now();
+        self.insert(now + period, data, Some(interval))
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().clock.now()
+    }
+
+    fn insert(&self, deadline: Instant, data: T, interval: Option<Interval<T>>) -> Timeout {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        self.schedule(&mut inner, (deadline, id), Entry { data, interval });
+        Timeout { deadline, id }
+    }
+
+    fn schedule(&self, inner: &mut TimerInner<T>, key: (Instant, u64), entry: Entry<T>) {
+        let is_earliest = inner.next_deadline().map_or(true, |next| key.0 < next);
+        inner.timeouts.insert(key, entry);
+        // if called from an other thread, the event loop may be waiting, wake it up
+        // so that it takes the new deadline into account
+        let remote = inner
+            .owner
+            .map_or(false, |owner| owner != thread::current().id());
+        if is_earliest && remote {
+            let _ = self.readiness.set_readiness(Ready::readable());
+        }
+    }
+
+    /// The number of expirations of the timeout whose callback is running
+    ///
+    /// This is always 1 for a single timeout. For a repeating timeout, this is 1 plus
+    /// the number of ticks that were missed because the event loop was late, which
+    /// allows animation or simulation code to catch up. It is only meaningful from
+    /// within the callback of the timer.
+    pub fn expirations(&self) -> u64 {
+        self.inner.lock().unwrap().expirations
+    }
+
+    /// Set the slack of this timer
+    ///
+    /// Its timeouts may then fire up to `slack` late: the event loop waits until the
+    /// earliest deadline plus the slack, and fires all the timeouts that expired by
+    /// then at once. A timeout which is already due when the event loop goes to sleep
+    /// is not delayed. The default slack is zero.
+    pub fn set_slack(&self, slack: Duration) {
+        self.inner.lock().unwrap().slack = slack;
+    }
+
+    /// The slack of this timer
+    pub fn slack(&self) -> Duration {
+        self.inner.lock().unwrap().slack
     }
 
     /// Cancel a previsouly set timeout and retrieve the associated data
     ///
     /// This method returns `None` if the timeout does not exist (it has already fired
-    /// or has already been cancelled).
+    /// or has already been cancelled). A repeating timeout is stopped.
     pub fn cancel_timeout(&self, timeout: &Timeout) -> Option<T> {
-        self.inner.lock().unwrap().cancel_timeout(timeout)
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, entry)) = inner.paused.remove(&timeout.id) {
+            return Some(entry.data);
+        }
+        let key = inner.key(timeout);
+        inner.rescheduled.remove(&timeout.id);
+        inner.timeouts.remove(&key).map(|entry| entry.data)
+    }
+
+    /// Pause a timeout
+    ///
+    /// Its countdown is suspended until it is resumed using `resume_timeout()`.
+    /// Returns `false` if the timeout does not exist or is already paused.
+    pub fn pause_timeout(&self, timeout: &Timeout) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let key = inner.key(timeout);
+        match inner.timeouts.remove(&key) {
+            Some(entry) => {
+                let remaining = key.0.saturating_duration_since(inner.clock.now());
+                inner.rescheduled.remove(&timeout.id);
+                inner.paused.insert(timeout.id, (remaining, entry));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resume a paused timeout
+    ///
+    /// It expires once the time that remained when it was paused has elapsed.
+    /// Returns `false` if the timeout does not exist or is not paused.
+    pub fn resume_timeout(&self, timeout: &Timeout) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.paused.remove(&timeout.id) {
+            Some((remaining, entry)) => {
+                let deadline = inner.clock.now() + remaining;
+                inner.rescheduled.insert(timeout.id, deadline);
+                self.schedule(&mut inner, (deadline, timeout.id), entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The time remaining before a timeout expires
+    ///
+    /// For a paused timeout, this is the time that remained when it was paused.
+    /// Returns `None` if the timeout does not exist.
+    pub fn remaining(&self, timeout: &Timeout) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(&(remaining, _)) = inner.paused.get(&timeout.id) {
+            return Some(remaining);
+        }
+        let key = inner.key(timeout);
+        if inner.timeouts.contains_key(&key) {
+            Some(key.0.saturating_duration_since(inner.clock.now()))
+        } else {
+            None
+        }
     }
 }
 
@@ -110,10 +535,9 @@ impl<T> Evented for Timer<T> {
         interest: Ready,
         opts: PollOpt,
     ) -> io::Result<()> {
-        self.inner
-            .lock()
-            .unwrap()
-            .register(poll, token, interest, opts)
+        self.registration.register(poll, token, interest, opts)?;
+        self.inner.lock().unwrap().owner = Some(thread::current().id());
+        Ok(())
     }
 
     fn reregister(
@@ -123,14 +547,14 @@ impl<T> Evented for Timer<T> {
         interest: Ready,
         opts: PollOpt,
     ) -> io::Result<()> {
-        self.inner
-            .lock()
-            .unwrap()
-            .reregister(poll, token, interest, opts)
+        self.registration.reregister(poll, token, interest, opts)?;
+        self.inner.lock().unwrap().owner = Some(thread::current().id());
+        Ok(())
     }
 
     fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        self.inner.lock().unwrap().deregister(poll)
+        self.inner.lock().unwrap().owner = None;
+        poll.deregister(&self.registration)
     }
 }
 
@@ -145,13 +569,36 @@ impl<T: 'static> EventSource for Timer<T> {
         PollOpt::edge()
     }
 
-    fn make_dispatcher<Data: 'static, F: FnMut((T, TimerHandle<T>), &mut Data) + 'static>(
+    fn next_deadline(&self) -> Option<Instant> {
+        let inner = self.inner.lock().unwrap();
+        if inner.owner.is_some() {
+            // waiting for the slack lets the timeouts expiring meanwhile be
+            // handled by the same wakeup, the deadline is then converted from the
+            // clock of the timer to the real time, reading the latter first so that
+            // a deadline of the system clock is never delayed
+            let real_now = Instant::now();
+            let now = inner.clock.now();
+            inner.next_deadline().map(|deadline| {
+                // a timeout already due fires right away, notably once a manual
+                // clock was advanced past it, rather than after the slack in real time
+                if deadline <= now {
+                    inner.created
+                } else {
+                    real_now + (deadline + inner.slack - now)
+                }
+            })
+        } else {
+            None
+        }
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut((T, TimerHandle<T>), &mut Data) + 'l>(
         &self,
         callback: F,
-    ) -> Rc<RefCell<EventDispatcher<Data>>> {
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
         Rc::new(RefCell::new(Dispatcher {
             _data: ::std::marker::PhantomData,
-            timer: self.inner.clone(),
+            handle: self.handle(),
             callback,
         }))
     }
@@ -159,22 +606,25 @@ impl<T: 'static> EventSource for Timer<T> {
 
 struct Dispatcher<Data, T, F: FnMut((T, TimerHandle<T>), &mut Data)> {
     _data: ::std::marker::PhantomData<fn(&mut Data)>,
-    timer: Arc<Mutex<mio_timer::Timer<T>>>,
+    handle: TimerHandle<T>,
     callback: F,
 }
 
 impl<Data, T, F: FnMut((T, TimerHandle<T>), &mut Data)> EventDispatcher<Data>
     for Dispatcher<Data, T, F>
 {
-    fn ready(&mut self, _: Ready, data: &mut Data) {
-        let handle = TimerHandle {
-            inner: self.timer.clone(),
-        };
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        // this source may be dispatched because it was woken up or because its
+        // deadline was reached, in both cases the readiness is no longer needed
+        self.handle.readiness.set_readiness(Ready::empty())?;
+        let now = self.handle.now();
         loop {
-            let opt_evt = self.timer.lock().unwrap().poll();
+            // the lock must not be held while the callback runs, as it may
+            // set new timeouts
+            let opt_evt = self.handle.inner.lock().unwrap().pop_expired(now);
             match opt_evt {
-                Some(val) => (self.callback)((val, handle.clone()), data),
-                None => break,
+                Some(val) => (self.callback)((val, self.handle.clone()), data),
+                None => return Ok(()),
             }
         }
     }
@@ -183,7 +633,7 @@ impl<Data, T, F: FnMut((T, TimerHandle<T>), &mut Data)> EventDispatcher<Data>
 #[cfg(test)]
 mod tests {
     use std::io;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     use super::*;
 
@@ -198,10 +648,14 @@ mod tests {
         let timer = evl_handle
             .insert_source(Timer::<()>::new(), move |((), _), f| {
                 *f = true;
-            }).map_err(Into::<io::Error>::into)
+            })
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
-        timer.handle().add_timeout(Duration::from_millis(300), ());
+        timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(300), ());
 
         event_loop
             .dispatch(Some(::std::time::Duration::from_millis(100)), &mut fired)
@@ -229,12 +683,22 @@ mod tests {
         let timer = evl_handle
             .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
                 fired.push(val);
-            }).map_err(Into::<io::Error>::into)
+            })
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
-        timer.handle().add_timeout(Duration::from_millis(300), 1);
-        timer.handle().add_timeout(Duration::from_millis(100), 2);
-        timer.handle().add_timeout(Duration::from_millis(600), 3);
+        timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(300), 1);
+        timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(100), 2);
+        timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(600), 3);
 
         // 3 dispatches as each returns once at least one event occured
 
@@ -268,12 +732,22 @@ mod tests {
         let timer = evl_handle
             .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
                 fired.push(val)
-            }).map_err(Into::<io::Error>::into)
+            })
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
-        let timeout1 = timer.handle().add_timeout(Duration::from_millis(300), 1);
-        let timeout2 = timer.handle().add_timeout(Duration::from_millis(100), 2);
-        let timeout3 = timer.handle().add_timeout(Duration::from_millis(600), 3);
+        let timeout1 = timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(300), 1);
+        let timeout2 = timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(100), 2);
+        let timeout3 = timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(600), 3);
 
         // 3 dispatches as each returns once at least one event occured
         //
@@ -287,8 +761,14 @@ mod tests {
         assert_eq!(&fired, &[2]);
 
         // timeout2 has already fired, we cancel timeout1
-        assert_eq!(timer.handle().cancel_timeout(&timeout2), None);
-        assert_eq!(timer.handle().cancel_timeout(&timeout1), Some(1));
+        assert_eq!(
+            timer.as_source_ref().handle().cancel_timeout(&timeout2),
+            None
+        );
+        assert_eq!(
+            timer.as_source_ref().handle().cancel_timeout(&timeout1),
+            Some(1)
+        );
 
         event_loop
             .dispatch(Some(::std::time::Duration::from_millis(300)), &mut fired)
@@ -297,7 +777,10 @@ mod tests {
         assert_eq!(&fired, &[2]);
 
         // cancel timeout3
-        assert_eq!(timer.handle().cancel_timeout(&timeout3), Some(3));
+        assert_eq!(
+            timer.as_source_ref().handle().cancel_timeout(&timeout3),
+            Some(3)
+        );
 
         event_loop
             .dispatch(Some(::std::time::Duration::from_millis(600)), &mut fired)
@@ -305,4 +788,402 @@ mod tests {
 
         assert_eq!(&fired, &[2]);
     }
+
+    #[test]
+    fn timer_blocking_dispatch() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let mut fired = false;
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |((), _), f| {
+                *f = true;
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        timer
+            .as_source_ref()
+            .handle()
+            .add_timeout(Duration::from_millis(50), ());
+
+        // the event loop must wake up when the timeout expires
+        let start = Instant::now();
+        event_loop.dispatch(None, &mut fired).unwrap();
+
+        assert!(fired);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn pause_resume() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
+                fired.push(val)
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+        let timer_handle = timer.as_source_ref().handle();
+
+        let timeout = timer_handle.add_timeout(Duration::from_millis(100), 1);
+        assert!(timer_handle.pause_timeout(&timeout));
+        assert!(!timer_handle.pause_timeout(&timeout));
+        let remaining = timer_handle.remaining(&timeout).unwrap();
+        assert!(remaining <= Duration::from_millis(100));
+        assert!(remaining > Duration::from_millis(50));
+
+        // the countdown is suspended
+        let mut fired = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(150)), &mut fired)
+            .unwrap();
+        assert!(fired.is_empty());
+        assert_eq!(timer_handle.remaining(&timeout), Some(remaining));
+
+        let resumed = Instant::now();
+        assert!(timer_handle.resume_timeout(&timeout));
+        assert!(!timer_handle.resume_timeout(&timeout));
+        while fired.is_empty() {
+            event_loop.dispatch(None, &mut fired).unwrap();
+        }
+        assert_eq!(&fired, &[1]);
+        assert!(resumed.elapsed() >= remaining);
+        assert_eq!(timer_handle.remaining(&timeout), None);
+    }
+
+    #[test]
+    fn many_timeouts() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
+                fired.push(val)
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+        let timer_handle = timer.as_source_ref().handle();
+
+        // set the timeouts in reverse order, and cancel the odd ones
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let timeouts: Vec<_> = (0..20_000u32)
+            .rev()
+            .map(|i| {
+                let timeout_deadline = deadline + Duration::from_micros(u64::from(i));
+                timer_handle.add_timeout_at(timeout_deadline, i)
+            })
+            .collect();
+        for (timeout, i) in timeouts.iter().zip((0..20_000u32).rev()) {
+            if i % 2 == 1 {
+                assert_eq!(timer_handle.cancel_timeout(timeout), Some(i));
+            }
+        }
+
+        let mut fired = Vec::new();
+        while fired.len() < 10_000 {
+            event_loop.dispatch(None, &mut fired).unwrap();
+        }
+        let expected: Vec<u32> = (0..20_000).filter(|i| i % 2 == 0).collect();
+        assert_eq!(fired, expected);
+    }
+
+    #[test]
+    fn timer_deadline() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
+                fired.push(val)
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        timer.as_source_ref().handle().add_timeout_at(deadline, 1);
+
+        let mut fired = Vec::new();
+        event_loop.dispatch(None, &mut fired).unwrap();
+        assert_eq!(&fired, &[1]);
+        assert!(Instant::now() >= deadline);
+
+        // a past deadline fires right away
+        timer.as_source_ref().handle().add_timeout_at(deadline, 2);
+        event_loop.dispatch(None, &mut fired).unwrap();
+        assert_eq!(&fired, &[1, 2]);
+    }
+
+    #[test]
+    fn slack() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
+                fired.push(val)
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+        let timer_handle = timer.as_source_ref().handle();
+        timer_handle.set_slack(Duration::from_millis(30));
+        assert_eq!(timer_handle.slack(), Duration::from_millis(30));
+
+        // both timeouts are handled by a single wakeup
+        let start = Instant::now();
+        timer_handle.add_timeout(Duration::from_millis(20), 1);
+        timer_handle.add_timeout(Duration::from_millis(40), 2);
+        let mut fired = Vec::new();
+        event_loop.dispatch(None, &mut fired).unwrap();
+        assert_eq!(&fired, &[1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn interval_no_drift() {
+        let mut inner = TimerInner {
+            timeouts: BTreeMap::new(),
+            rescheduled: HashMap::new(),
+            paused: HashMap::new(),
+            next_id: 0,
+            expirations: 0,
+            slack: Duration::from_secs(0),
+            owner: None,
+            clock: Arc::new(SystemClock),
+            created: Instant::now(),
+        };
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        let interval = Interval {
+            period,
+            clone: u32::clone,
+        };
+        inner.timeouts.insert(
+            (start + period, 0),
+            Entry {
+                data: 42,
+                interval: Some(interval),
+            },
+        );
+
+        // a late tick does not delay the next one
+        assert_eq!(inner.pop_expired(start + period * 3 / 2), Some(42));
+        assert_eq!(inner.next_deadline(), Some(start + period * 2));
+        assert_eq!(inner.pop_expired(start + period * 3 / 2), None);
+
+        // missed ticks are skipped, and counted
+        assert_eq!(inner.expirations, 1);
+        assert_eq!(inner.pop_expired(start + period * 9 / 2), Some(42));
+        assert_eq!(inner.expirations, 3);
+        assert_eq!(inner.next_deadline(), Some(start + period * 5));
+
+        // after a suspend of a year
+        let year = Duration::from_secs(365 * 24 * 3600);
+        assert_eq!(inner.pop_expired(start + year), Some(42));
+        assert_eq!(inner.expirations, 3_153_600_000 - 4);
+        assert_eq!(inner.next_deadline(), Some(start + year + period));
+    }
+
+    #[test]
+    fn interval() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |(val, _), fired: &mut Vec<u32>| {
+                fired.push(val)
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        let timeout = timer
+            .as_source_ref()
+            .handle()
+            .add_interval(Duration::from_millis(20), 1);
+
+        let mut fired = Vec::new();
+        while fired.len() < 3 {
+            event_loop.dispatch(None, &mut fired).unwrap();
+        }
+        assert_eq!(&fired, &[1, 1, 1]);
+
+        // the interval is stopped by cancelling it
+        assert_eq!(
+            timer.as_source_ref().handle().cancel_timeout(&timeout),
+            Some(1)
+        );
+        event_loop
+            .dispatch(Some(Duration::from_millis(50)), &mut fired)
+            .unwrap();
+        assert_eq!(&fired, &[1, 1, 1]);
+    }
+
+    #[test]
+    fn interval_expirations() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |((), handle), fired: &mut Vec<u64>| {
+                fired.push(handle.expirations())
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        timer
+            .as_source_ref()
+            .handle()
+            .add_interval(Duration::from_millis(10), ());
+
+        // the event loop is late by more than two periods
+        ::std::thread::sleep(Duration::from_millis(35));
+        let mut fired = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(fired.len(), 1);
+        assert!(fired[0] >= 3);
+    }
+
+    #[test]
+    fn manual_clock() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let clock = ManualClock::new();
+        let timer = evl_handle
+            .insert_source(
+                Timer::with_clock(clock.clone()),
+                |(val, _), fired: &mut Vec<u32>| fired.push(val),
+            )
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+        let timer_handle = timer.as_source_ref().handle();
+
+        timer_handle.add_timeout(Duration::from_secs(3600), 1);
+        timer_handle.add_timeout(Duration::from_secs(7200), 2);
+
+        let start = Instant::now();
+        let mut fired = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert!(fired.is_empty());
+
+        clock.advance(Duration::from_secs(3600));
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(&fired, &[1]);
+
+        clock.advance(Duration::from_secs(3600));
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut fired)
+            .unwrap();
+        assert_eq!(&fired, &[1, 2]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn manual_clock_blocking_dispatch() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let clock = ManualClock::new();
+        let timer = event_loop
+            .handle()
+            .insert_source(
+                Timer::with_clock(clock.clone()),
+                |(val, _), fired: &mut Vec<u32>| fired.push(val),
+            )
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+        let timer_handle = timer.as_source_ref().handle();
+        timer_handle.set_slack(Duration::from_secs(60));
+        timer_handle.add_timeout(Duration::from_secs(3600), 1);
+        timer_handle.add_timeout(Duration::from_secs(7200), 2);
+
+        // the dispatch does not wait in real time for a timeout due on the clock
+        let start = Instant::now();
+        let mut fired = Vec::new();
+        clock.advance(Duration::from_secs(3600));
+        event_loop.dispatch(None, &mut fired).unwrap();
+        assert_eq!(&fired, &[1]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn timer_handle_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<TimerHandle<u32>>();
+    }
+
+    #[test]
+    fn timer_cancel_from_thread() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let mut fired = false;
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |((), _), f| {
+                *f = true;
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        let timer_handle = timer.as_source_ref().handle();
+        let timeout = timer_handle.add_timeout(Duration::from_millis(50), ());
+        ::std::thread::spawn(move || timer_handle.cancel_timeout(&timeout))
+            .join()
+            .unwrap()
+            .unwrap();
+
+        event_loop
+            .dispatch(Some(Duration::from_millis(100)), &mut fired)
+            .unwrap();
+        assert!(!fired);
+    }
+
+    #[test]
+    fn timer_add_from_thread() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let evl_handle = event_loop.handle();
+
+        let mut fired = false;
+
+        let timer = evl_handle
+            .insert_source(Timer::new(), |((), _), f| {
+                *f = true;
+            })
+            .map_err(Into::<io::Error>::into)
+            .unwrap();
+
+        let timer_handle = timer.as_source_ref().handle();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(50));
+            timer_handle.add_timeout(Duration::from_millis(50), ());
+        });
+
+        // the first dispatch returns once the timeout is set, the second
+        // once it expires
+        event_loop.dispatch(None, &mut fired).unwrap();
+        if !fired {
+            event_loop.dispatch(None, &mut fired).unwrap();
+        }
+
+        assert!(fired);
+    }
 }
@@ -0,0 +1,484 @@
+//! Timers backed by a timerfd, following a specific system clock
+//!
+//! Unlike the `timer` module, which follows the monotonic clock of `Instant`, a
+//! `TimerFd` follows the clock it was created with. This module is only available
+//! on Linux.
+//!
+//! With the `Clock::Realtime` clock, the timer can be set to expire at a wall-clock
+//! time using `TimerFd::set_wall_deadline()`. It then fires at the correct real-world
+//! time even if the system clock is changed in between, for example by NTP, and
+//! generates an `Event::ClockChanged` event when this happens.
+//!
+//! The timerfd can also be driven directly: `TimerFd::set_time()` is a thin wrapper
+//! around `timerfd_settime(2)`, taking its flags such as `TFD_TIMER_ABSTIME`, and a
+//! periodic interval. Each `Event::Expired` carries the number of expirations since the
+//! previous one. A timerfd created by foreign code, for example received from systemd,
+//! can be wrapped with `TimerFd::from_fd()`.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+/// The clock followed by a `TimerFd`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Clock {
+    /// The system wall clock, which can be changed by the user or NTP
+    Realtime,
+    /// A monotonic clock, which does not advance while the system is suspended
+    Monotonic,
+    /// A monotonic clock which keeps advancing while the system is suspended
+    ///
+    /// A timeout thus elapses correctly even if the system slept in between.
+    Boottime,
+    /// Same as `Boottime`, but an expiration also wakes the system up if it is
+    /// suspended
+    ///
+    /// Creating such a timer requires the `CAP_WAKE_ALARM` capability.
+    BoottimeAlarm,
+    /// Any other clock, by its `clockid_t`
+    ///
+    /// For example `CLOCK_REALTIME_ALARM`, or the clock of a foreign timerfd.
+    Id(libc::clockid_t),
+}
+
+impl Clock {
+    fn id(self) -> libc::clockid_t {
+        match self {
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+            Clock::BoottimeAlarm => libc::CLOCK_BOOTTIME_ALARM,
+            Clock::Id(id) => id,
+        }
+    }
+}
+
+/// The events generated by a `TimerFd`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The timer expired
+    ///
+    /// This is the number of expirations since the previous event, more than one if
+    /// periodic expirations were not dispatched in time.
+    Expired(u64),
+    /// The system clock was changed while the timer was waiting for a wall-clock
+    /// deadline
+    ///
+    /// The timer is still set to the same deadline, which is now interpreted
+    /// according to the new time.
+    ClockChanged,
+}
+
+struct Inner {
+    fd: OwnedFd,
+    // the deadline of the timer, if it was set with `set_wall_deadline()`
+    wall_deadline: Cell<Option<SystemTime>>,
+}
+
+impl Inner {
+    fn settime(&self, flags: libc::c_int, value: Duration, interval: Duration) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval),
+            it_value: timespec(value),
+        };
+        let ret =
+            unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), flags, &spec, ptr::null_mut()) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_wall_deadline(&self, deadline: SystemTime) -> io::Result<()> {
+        let since_epoch = deadline
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "deadline before epoch"))?;
+        // a zero value would disarm the timer
+        let since_epoch = since_epoch.max(Duration::from_nanos(1));
+        self.settime(
+            libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET,
+            since_epoch,
+            Duration::from_secs(0),
+        )?;
+        self.wall_deadline.set(Some(deadline));
+        Ok(())
+    }
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+fn duration(spec: libc::timespec) -> Duration {
+    Duration::new(spec.tv_sec as u64, spec.tv_nsec as u32)
+}
+
+/// A timer following a specific system clock
+///
+/// It holds a single timeout at a time, setting a new one replaces the previous one.
+pub struct TimerFd {
+    inner: Rc<Inner>,
+    clock: Clock,
+}
+
+impl TimerFd {
+    /// Create a new timer following the given clock
+    pub fn new(clock: Clock) -> io::Result<TimerFd> {
+        let fd =
+            unsafe { libc::timerfd_create(clock.id(), libc::TFD_NONBLOCK | libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TimerFd {
+            inner: Rc::new(Inner {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                wall_deadline: Cell::new(None),
+            }),
+            clock,
+        })
+    }
+
+    /// Wrap an existing timerfd, taking ownership of it
+    ///
+    /// The clock it was created with must be given, as it cannot be queried. It is
+    /// switched to non-blocking mode, and closed when the source is dropped.
+    pub fn from_fd(fd: RawFd, clock: Clock) -> io::Result<TimerFd> {
+        let inner = Inner {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            wall_deadline: Cell::new(None),
+        };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TimerFd {
+            inner: Rc::new(inner),
+            clock,
+        })
+    }
+
+    /// The clock followed by this timer
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Set the timer to expire once `timeout` has elapsed on its clock
+    pub fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        // a zero value would disarm the timer
+        self.inner.settime(
+            0,
+            timeout.max(Duration::from_nanos(1)),
+            Duration::from_secs(0),
+        )?;
+        self.inner.wall_deadline.set(None);
+        Ok(())
+    }
+
+    /// Set the timer to expire at a wall-clock time
+    ///
+    /// This is only possible with the `Clock::Realtime` clock, an error of kind
+    /// `InvalidInput` is returned otherwise. A deadline that has already passed
+    /// expires right away.
+    pub fn set_wall_deadline(&self, deadline: SystemTime) -> io::Result<()> {
+        if self.clock.id() != libc::CLOCK_REALTIME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "wall-clock deadlines require the realtime clock",
+            ));
+        }
+        self.inner.set_wall_deadline(deadline)
+    }
+
+    /// Disarm the timer
+    pub fn cancel(&self) -> io::Result<()> {
+        self.inner
+            .settime(0, Duration::from_secs(0), Duration::from_secs(0))?;
+        self.inner.wall_deadline.set(None);
+        Ok(())
+    }
+
+    /// Arm the timer as `timerfd_settime(2)` does
+    ///
+    /// The timer first expires after `value`, or at `value` on its clock with the
+    /// `TFD_TIMER_ABSTIME` flag, then every `interval` if it is not zero. A zero `value`
+    /// disarms the timer. With `TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET` on a realtime
+    /// clock, changes of the system clock generate an `Event::ClockChanged` event, and
+    /// the timer stays cancelled until it is set again.
+    pub fn set_time(
+        &self,
+        value: Duration,
+        interval: Duration,
+        flags: libc::c_int,
+    ) -> io::Result<()> {
+        self.inner.settime(flags, value, interval)?;
+        self.inner.wall_deadline.set(None);
+        Ok(())
+    }
+
+    /// The time until the next expiration and the interval of the timer
+    ///
+    /// Both are zero if the timer is disarmed.
+    pub fn get_time(&self) -> io::Result<(Duration, Duration)> {
+        let mut spec: libc::itimerspec = unsafe { ::std::mem::zeroed() };
+        if unsafe { libc::timerfd_gettime(self.inner.fd.as_raw_fd(), &mut spec) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((duration(spec.it_value), duration(spec.it_interval)))
+    }
+
+    // an other handle to the same timer, for sources built on top of it
+    pub(crate) fn share(&self) -> TimerFd {
+        TimerFd {
+            inner: self.inner.clone(),
+            clock: self.clock,
+        }
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.fd.as_raw_fd()
+    }
+}
+
+impl Evented for TimerFd {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.inner.fd.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.inner.fd.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        ::mio::unix::EventedFd(&self.inner.fd.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl EventSource for TimerFd {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<Inner>,
+    callback: F,
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut expirations = 0u64;
+        let ret = unsafe {
+            libc::read(
+                self.inner.fd.as_raw_fd(),
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                ::std::mem::size_of::<u64>(),
+            )
+        };
+        if ret >= 0 {
+            self.inner.wall_deadline.set(None);
+            (self.callback)(Event::Expired(expirations), data);
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            // the clock was changed, the timer remains cancelled until it is set again
+            Some(libc::ECANCELED) => {
+                if let Some(deadline) = self.inner.wall_deadline.get() {
+                    self.inner.set_wall_deadline(deadline)?;
+                }
+                (self.callback)(Event::ClockChanged, data);
+                Ok(())
+            }
+            Some(libc::EAGAIN) => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn wall_deadline() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let timer = TimerFd::new(Clock::Realtime).unwrap();
+        timer
+            .set_wall_deadline(SystemTime::now() + Duration::from_millis(50))
+            .unwrap();
+        event_loop
+            .handle()
+            .insert_source(timer, |evt, events: &mut Vec<Event>| events.push(evt))
+            .unwrap();
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec![Event::Expired(1)]);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn boottime() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let timer = TimerFd::new(Clock::Boottime).unwrap();
+        timer.set_timeout(Duration::from_millis(20)).unwrap();
+        event_loop
+            .handle()
+            .insert_source(timer, |evt, events: &mut Vec<Event>| events.push(evt))
+            .unwrap();
+
+        let mut events = Vec::new();
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec![Event::Expired(1)]);
+    }
+
+    #[test]
+    fn timeout_and_cancel() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let timer = event_loop
+            .handle()
+            .insert_source(
+                TimerFd::new(Clock::Monotonic).unwrap(),
+                |evt, events: &mut Vec<Event>| events.push(evt),
+            )
+            .unwrap();
+
+        // wall-clock deadlines require the realtime clock
+        let err = timer
+            .as_source_ref()
+            .set_wall_deadline(SystemTime::now())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        timer
+            .as_source_ref()
+            .set_timeout(Duration::from_millis(20))
+            .unwrap();
+        let mut events = Vec::new();
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec![Event::Expired(1)]);
+
+        timer
+            .as_source_ref()
+            .set_timeout(Duration::from_millis(20))
+            .unwrap();
+        timer.as_source_ref().cancel().unwrap();
+        event_loop
+            .dispatch(Some(Duration::from_millis(50)), &mut events)
+            .unwrap();
+        assert_eq!(events, vec![Event::Expired(1)]);
+    }
+
+    #[test]
+    fn periodic_expirations() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let timer = TimerFd::new(Clock::Monotonic).unwrap();
+        timer
+            .set_time(Duration::from_millis(10), Duration::from_millis(10), 0)
+            .unwrap();
+        assert_eq!(timer.get_time().unwrap().1, Duration::from_millis(10));
+        event_loop
+            .handle()
+            .insert_source(timer, |evt, events: &mut Vec<Event>| events.push(evt))
+            .unwrap();
+
+        // the expirations accumulate until the timer is dispatched
+        ::std::thread::sleep(Duration::from_millis(35));
+        let mut events = Vec::new();
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut events)
+            .unwrap();
+        match events[..] {
+            [Event::Expired(count)] => assert!(count >= 3),
+            _ => panic!("unexpected events: {:?}", events),
+        }
+    }
+
+    #[test]
+    fn foreign_absolute() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        // as if it was created by foreign code
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_BOOTTIME, libc::TFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let timer = TimerFd::from_fd(fd, Clock::Id(libc::CLOCK_BOOTTIME)).unwrap();
+        assert_eq!(
+            timer.get_time().unwrap(),
+            (Duration::from_secs(0), Duration::from_secs(0))
+        );
+
+        let mut now: libc::timespec = unsafe { ::std::mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut now) },
+            0
+        );
+        timer
+            .set_time(
+                duration(now) + Duration::from_millis(20),
+                Duration::from_secs(0),
+                libc::TFD_TIMER_ABSTIME,
+            )
+            .unwrap();
+        event_loop
+            .handle()
+            .insert_source(timer, |evt, events: &mut Vec<Event>| events.push(evt))
+            .unwrap();
+
+        let mut events = Vec::new();
+        event_loop.dispatch(None, &mut events).unwrap();
+        assert_eq!(events, vec![Event::Expired(1)]);
+    }
+}
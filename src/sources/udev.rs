@@ -0,0 +1,536 @@
+//! Event source for monitoring devices with udev
+//!
+//! Only available on Linux, with the `udev` cargo feature.
+//!
+//! A `UdevMonitor` listens to the uevent netlink socket, and generates an `Event` for
+//! each device that is added, removed or changed. It does not link to libudev: it
+//! decodes the messages broadcast by the udev daemon itself, once it has processed
+//! the device, so the device nodes and the properties set by the udev rules are
+//! available. Monitoring the raw events of the kernel, before udev processes them, is
+//! possible with `Group::Kernel`, for systems without a udev daemon.
+//!
+//! The events can be restricted to some subsystems with `match_subsystem()`. Only
+//! the messages of privileged senders are accepted: the kernel, or a udev daemon
+//! running as root.
+//!
+//! If the receive buffer of the socket fills up, the kernel drops the next messages,
+//! and the monitor generates an event with `Action::Overflow`: the devices added or
+//! removed meanwhile are then unknown, and should be rescanned from `/sys`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use nix::libc;
+
+use {EventDispatcher, EventSource};
+
+// the magic of the messages of the udev daemon, in network byte order
+const UDEV_MAGIC: u32 = 0xfeed_cafe;
+
+/// The netlink multicast group to listen to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Group {
+    /// The events broadcast by the udev daemon, once it processed the devices
+    Udev,
+    /// The raw events of the kernel
+    Kernel,
+}
+
+impl Group {
+    fn id(self) -> u32 {
+        match self {
+            Group::Kernel => 1,
+            Group::Udev => 2,
+        }
+    }
+}
+
+/// What happened to a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// The device was added
+    Add,
+    /// The device was removed
+    Remove,
+    /// The device changed
+    Change,
+    /// The device was renamed or moved
+    Move,
+    /// A driver was bound to the device
+    Bind,
+    /// A driver was unbound from the device
+    Unbind,
+    /// The device went online
+    Online,
+    /// The device went offline
+    Offline,
+    /// Some events were dropped, as the receive buffer of the socket was full
+    ///
+    /// The other fields of the event are empty, it is generated whatever the
+    /// subsystems matched by the monitor.
+    Overflow,
+    /// An other action
+    Other(String),
+}
+
+impl Action {
+    fn parse(action: &str) -> Action {
+        match action {
+            "add" => Action::Add,
+            "remove" => Action::Remove,
+            "change" => Action::Change,
+            "move" => Action::Move,
+            "bind" => Action::Bind,
+            "unbind" => Action::Unbind,
+            "online" => Action::Online,
+            "offline" => Action::Offline,
+            other => Action::Other(other.to_owned()),
+        }
+    }
+}
+
+/// An event of a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// What happened to the device
+    pub action: Action,
+    /// The path of the device in `/sys`, without the `/sys` prefix
+    pub devpath: String,
+    /// The subsystem of the device, like `input` or `drm`
+    pub subsystem: Option<String>,
+    /// The type of the device within its subsystem, like `partition`
+    pub devtype: Option<String>,
+    /// The device node of the device, like `/dev/input/event0`
+    pub devnode: Option<PathBuf>,
+    /// The sequence number of the event
+    pub seqnum: Option<u64>,
+    /// All the properties of the event, including the ones above
+    pub properties: HashMap<String, String>,
+}
+
+impl Event {
+    fn overflow() -> Event {
+        Event {
+            action: Action::Overflow,
+            devpath: String::new(),
+            subsystem: None,
+            devtype: None,
+            devnode: None,
+            seqnum: None,
+            properties: HashMap::new(),
+        }
+    }
+
+    // parse a `KEY=VALUE` list separated by nul bytes
+    fn from_properties(data: &[u8]) -> Option<Event> {
+        let mut properties = HashMap::new();
+        for property in data.split(|&b| b == 0) {
+            let property = String::from_utf8_lossy(property);
+            if let Some(eq) = property.find('=') {
+                properties.insert(property[..eq].to_owned(), property[eq + 1..].to_owned());
+            }
+        }
+        let action = Action::parse(properties.get("ACTION")?);
+        let devpath = properties.get("DEVPATH")?.clone();
+        let devnode = properties.get("DEVNAME").map(|name| {
+            // the kernel gives the name of the node relative to /dev
+            if name.starts_with('/') {
+                PathBuf::from(name)
+            } else {
+                PathBuf::from("/dev").join(name)
+            }
+        });
+        Some(Event {
+            action,
+            devpath,
+            subsystem: properties.get("SUBSYSTEM").cloned(),
+            devtype: properties.get("DEVTYPE").cloned(),
+            devnode,
+            seqnum: properties
+                .get("SEQNUM")
+                .and_then(|seqnum| seqnum.parse().ok()),
+            properties,
+        })
+    }
+
+    // parse a message of the udev daemon or of the kernel
+    fn parse(message: &[u8]) -> Option<Event> {
+        if message.starts_with(b"libudev\0") {
+            let field = |index: usize| -> Option<u32> {
+                let bytes = message.get(8 + 4 * index..12 + 4 * index)?;
+                Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            };
+            if u32::from_be(field(0)?) != UDEV_MAGIC {
+                return None;
+            }
+            let offset = field(2)? as usize;
+            let len = field(3)? as usize;
+            Event::from_properties(message.get(offset..offset.checked_add(len)?)?)
+        } else {
+            // the kernel prefixes its messages with `action@devpath`
+            let start = message.iter().position(|&b| b == 0)? + 1;
+            Event::from_properties(&message[start..])
+        }
+    }
+}
+
+struct Inner {
+    fd: OwnedFd,
+    group: Group,
+    subsystems: Vec<String>,
+}
+
+/// An event source monitoring the devices of the system
+///
+/// It generates an `Event` for each event of a device.
+pub struct UdevMonitor {
+    inner: Rc<RefCell<Inner>>,
+    fd: RawFd,
+}
+
+impl UdevMonitor {
+    /// Create a new monitor, listening to the given group
+    pub fn new(group: Group) -> io::Result<UdevMonitor> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inner = Inner {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            group,
+            subsystems: Vec::new(),
+        };
+        // receive the credentials of the senders, to check them
+        let one: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &one as *const _ as *const libc::c_void,
+                mem::size_of_val(&one) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = group.id();
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UdevMonitor {
+            inner: Rc::new(RefCell::new(inner)),
+            fd,
+        })
+    }
+
+    /// Only generate the events of the devices of a subsystem
+    ///
+    /// This can be called several times to match several subsystems. By default, the
+    /// events of all the devices are generated.
+    pub fn match_subsystem(&mut self, subsystem: &str) {
+        self.inner
+            .borrow_mut()
+            .subsystems
+            .push(subsystem.to_owned());
+    }
+}
+
+impl Evented for UdevMonitor {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+impl EventSource for UdevMonitor {
+    type Event = Event;
+
+    fn interest(&self) -> Ready {
+        Ready::readable()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        PollOpt::edge()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event, &mut Data) + 'l>(
+        &self,
+        callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        Rc::new(RefCell::new(Dispatcher {
+            _data: ::std::marker::PhantomData,
+            inner: self.inner.clone(),
+            callback,
+        }))
+    }
+}
+
+struct Dispatcher<Data, F: FnMut(Event, &mut Data)> {
+    _data: ::std::marker::PhantomData<fn(&mut Data)>,
+    inner: Rc<RefCell<Inner>>,
+    callback: F,
+}
+
+// receive a message, with the netlink port and the uid of its sender
+fn receive(fd: RawFd, buffer: &mut [u8]) -> io::Result<(usize, u32, Option<libc::uid_t>)> {
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    // aligned for `cmsghdr`
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut uid = None;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS
+        {
+            let cred = ::std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred);
+            uid = Some(cred.uid);
+        }
+    }
+    Ok((len as usize, addr.nl_pid, uid))
+}
+
+impl<Data, F: FnMut(Event, &mut Data)> EventDispatcher<Data> for Dispatcher<Data, F> {
+    fn ready(&mut self, _: Ready, data: &mut Data) -> io::Result<()> {
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            let (fd, group) = {
+                let inner = self.inner.borrow();
+                (inner.fd.as_raw_fd(), inner.group)
+            };
+            let (len, sender, uid) = match receive(fd, &mut buffer) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // some events were lost, the next ones can still be received
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    (self.callback)(Event::overflow(), data);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            // the kernel sends from the port 0, the udev daemon from its own
+            let trusted = match group {
+                Group::Kernel => sender == 0,
+                Group::Udev => sender != 0 && uid == Some(0),
+            };
+            if !trusted {
+                continue;
+            }
+            let event = match Event::parse(&buffer[..len]) {
+                Some(event) => event,
+                None => continue,
+            };
+            let matched = {
+                let inner = self.inner.borrow();
+                inner.subsystems.is_empty()
+                    || event
+                        .subsystem
+                        .as_ref()
+                        .map_or(false, |subsystem| inner.subsystems.contains(subsystem))
+            };
+            if matched {
+                (self.callback)(event, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_event() {
+        let message = b"add@/devices/virtual/input/input7\0ACTION=add\0\
+            DEVPATH=/devices/virtual/input/input7/event3\0SUBSYSTEM=input\0\
+            DEVNAME=input/event3\0SEQNUM=4242\0";
+        let event = Event::parse(message).unwrap();
+        assert_eq!(event.action, Action::Add);
+        assert_eq!(event.devpath, "/devices/virtual/input/input7/event3");
+        assert_eq!(event.subsystem.as_ref().map(|s| &s[..]), Some("input"));
+        assert_eq!(event.devnode, Some(PathBuf::from("/dev/input/event3")));
+        assert_eq!(event.seqnum, Some(4242));
+        assert_eq!(event.devtype, None);
+    }
+
+    #[test]
+    fn parse_udev_event() {
+        let properties: &[u8] =
+            b"ACTION=remove\0DEVPATH=/devices/pci0000:00/drm/card0\0SUBSYSTEM=drm\0\
+            DEVTYPE=drm_minor\0DEVNAME=/dev/dri/card0\0ID_SEAT=seat0\0";
+        let mut message = b"libudev\0".to_vec();
+        let header_size = 8 + 4 * 8;
+        for field in &[
+            UDEV_MAGIC.to_be(),
+            header_size,
+            header_size,
+            properties.len() as u32,
+            0,
+            0,
+            0,
+            0,
+        ] {
+            message.extend_from_slice(&field.to_ne_bytes());
+        }
+        message.extend_from_slice(properties);
+
+        let event = Event::parse(&message).unwrap();
+        assert_eq!(event.action, Action::Remove);
+        assert_eq!(event.devtype.as_ref().map(|s| &s[..]), Some("drm_minor"));
+        assert_eq!(event.devnode, Some(PathBuf::from("/dev/dri/card0")));
+        assert_eq!(event.properties["ID_SEAT"], "seat0");
+
+        // a wrong magic is rejected
+        message[8] ^= 1;
+        assert!(Event::parse(&message).is_none());
+    }
+
+    #[test]
+    fn monitor() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut monitor = match UdevMonitor::new(Group::Udev) {
+            Ok(monitor) => monitor,
+            // netlink may not be available in a sandbox
+            Err(ref err)
+                if err.raw_os_error() == Some(libc::EPROTONOSUPPORT)
+                    || err.raw_os_error() == Some(libc::EPERM)
+                    || err.raw_os_error() == Some(libc::EACCES) =>
+            {
+                return
+            }
+            Err(err) => panic!("{}", err),
+        };
+        monitor.match_subsystem("input");
+        event_loop
+            .handle()
+            .insert_source(monitor, |_, _| {})
+            .unwrap();
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut ())
+            .unwrap();
+    }
+
+    #[test]
+    fn overflow() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let mut monitor = match UdevMonitor::new(Group::Udev) {
+            Ok(monitor) => monitor,
+            Err(_) => return,
+        };
+        monitor.match_subsystem("input");
+        let size: libc::c_int = 0;
+        unsafe {
+            libc::setsockopt(
+                monitor.fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &size as *const _ as *const libc::c_void,
+                mem::size_of_val(&size) as libc::socklen_t,
+            )
+        };
+        // flood the group of the udev daemon, which needs to be root
+        let sender = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        assert!(sender >= 0);
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = Group::Udev.id();
+        let message = [0u8; 1024];
+        let mut sent = false;
+        for _ in 0..100 {
+            sent |= unsafe {
+                libc::sendto(
+                    sender,
+                    message.as_ptr() as *const libc::c_void,
+                    message.len(),
+                    0,
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&addr) as libc::socklen_t,
+                )
+            } >= 0;
+        }
+        let _ = ::nix::unistd::close(sender);
+        if !sent {
+            return;
+        }
+
+        event_loop
+            .handle()
+            .insert_source(monitor, |event, overflows: &mut u32| {
+                assert_eq!(event.action, Action::Overflow);
+                *overflows += 1;
+            })
+            .unwrap();
+        let mut overflows = 0;
+        event_loop
+            .dispatch(Some(::std::time::Duration::from_millis(0)), &mut overflows)
+            .unwrap();
+        assert!(overflows >= 1);
+    }
+}
@@ -0,0 +1,216 @@
+//! A watch channel, delivering only the latest of its values
+//!
+//! Create a channel using `channel::<T>()`, which returns a `Sender<T>` that can be
+//! cloned and sent accross threads if `T: Send`, and a `Receiver<T>` to be inserted
+//! into an `EventLoop`. The channel holds a single value, which every send overwrites,
+//! so that the callback only observes the most recent one, like the current position
+//! of a pointer or the latest configuration, and nothing queues up.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use sources::channel::Event;
+use sources::ping::{make_ping, Ping, PingSource};
+use {EventDispatcher, EventSource};
+
+struct Slot<T> {
+    value: Option<T>,
+    // whether the receiver was dropped
+    disconnected: bool,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    ping: Ping,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+/// Create a new watch channel
+pub fn channel<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let (ping, source) = make_ping()?;
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            value: None,
+            disconnected: false,
+        }),
+        ping,
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, source },
+    ))
+}
+
+/// The sending end of a watch channel
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a value to the event loop, replacing the one it did not receive yet
+    ///
+    /// It fails if the receiver has been dropped, giving the value back.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let old = {
+            let mut slot = self.shared.slot.lock().unwrap();
+            if slot.disconnected {
+                return Err(SendError(value));
+            }
+            slot.value.replace(value)
+        };
+        // the replaced value is dropped outside of the lock
+        drop(old);
+        self.shared.ping.ping();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.closed.store(true, Ordering::Release);
+            self.shared.ping.ping();
+        }
+    }
+}
+
+/// The receiving end of a watch channel
+///
+/// This is the event source to be inserted into your `EventLoop`. It generates an
+/// `Event::Msg` with the latest value sent since it was last dispatched, and an
+/// `Event::Closed` once all the senders have been dropped.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    source: PingSource,
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let value = {
+            let mut slot = self.shared.slot.lock().unwrap();
+            slot.disconnected = true;
+            slot.value.take()
+        };
+        drop(value);
+    }
+}
+
+impl<T> Evented for Receiver<T> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.source.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.source.deregister(poll)
+    }
+}
+
+impl<T: 'static> EventSource for Receiver<T> {
+    type Event = Event<T>;
+
+    fn interest(&self) -> Ready {
+        self.source.interest()
+    }
+
+    fn pollopts(&self) -> PollOpt {
+        self.source.pollopts()
+    }
+
+    fn make_dispatcher<'l, Data: 'l, F: FnMut(Event<T>, &mut Data) + 'l>(
+        &self,
+        mut callback: F,
+    ) -> Rc<RefCell<dyn EventDispatcher<Data> + 'l>> {
+        let shared = self.shared.clone();
+        let mut closed = false;
+        self.source.make_dispatcher(move |(), data: &mut Data| {
+            let value = shared.slot.lock().unwrap().value.take();
+            if let Some(value) = value {
+                callback(Event::Msg(value), data);
+            }
+            if !closed && shared.closed.load(Ordering::Acquire) {
+                closed = true;
+                callback(Event::Closed, data);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn latest_value() {
+        let mut event_loop = ::EventLoop::new().unwrap();
+
+        let (sender, receiver) = channel::<u32>().unwrap();
+        event_loop
+            .handle()
+            .insert_source(receiver, |evt, received: &mut Vec<Option<u32>>| match evt {
+                Event::Msg(value) => received.push(Some(value)),
+                Event::Closed => received.push(None),
+            })
+            .unwrap();
+
+        let mut received = Vec::new();
+        for i in 0..3 {
+            sender.send(i).unwrap();
+        }
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, &[Some(2)]);
+
+        sender.send(3).unwrap();
+        ::std::mem::drop(sender);
+        event_loop
+            .dispatch(Some(Duration::from_millis(0)), &mut received)
+            .unwrap();
+        assert_eq!(received, &[Some(2), Some(3), None]);
+    }
+
+    #[test]
+    fn no_receiver() {
+        let (sender, receiver) = channel::<u32>().unwrap();
+        ::std::mem::drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+}
@@ -1,7 +1,7 @@
 // These tests cannot run as a regular test because cargo would spawn a thread to run it,
 // failing the signal masking. So we make our own, non-threaded harnessing
 
-#[cfg(unix)]
+#[cfg(target_os = "linux")]
 fn main() {
     for test in self::test::TESTS {
         test();
@@ -10,10 +10,10 @@ fn main() {
     }
 }
 
-#[cfg(not(unix))]
+#[cfg(not(target_os = "linux"))]
 fn main() {}
 
-#[cfg(unix)]
+#[cfg(target_os = "linux")]
 mod test {
     extern crate calloop;
     extern crate nix;
@@ -27,7 +27,12 @@ mod test {
     use self::nix::sys::signal::{kill, SigSet};
     use self::nix::unistd::Pid;
 
-    pub const TESTS: &'static [fn()] = &[single_usr1, usr2_added_afterwards, usr2_signal_removed];
+    pub const TESTS: &[fn()] = &[
+        single_usr1,
+        usr2_added_afterwards,
+        usr2_signal_removed,
+        thread_mask_updated,
+    ];
 
     pub fn reset_mask() {
         SigSet::empty().thread_set_mask().unwrap();
@@ -43,10 +48,11 @@ mod test {
             .insert_source(
                 Signals::new(&[Signal::SIGUSR1]).unwrap(),
                 move |evt, rcv| {
-                    assert!(evt.signal() == Signal::SIGUSR1);
+                    assert!(evt.signal() == Some(Signal::SIGUSR1));
                     *rcv = true;
                 },
-            ).map_err(Into::<io::Error>::into)
+            )
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
         // send ourselves a SIGUSR1
@@ -64,17 +70,21 @@ mod test {
 
         let mut signal_received = None;
 
-        let mut signal_source = event_loop
+        let signal_source = event_loop
             .handle()
             .insert_source(
                 Signals::new(&[Signal::SIGUSR1]).unwrap(),
                 move |evt, rcv| {
-                    *rcv = Some(evt.signal());
+                    *rcv = evt.signal();
                 },
-            ).map_err(Into::<io::Error>::into)
+            )
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
-        signal_source.add_signals(&[Signal::SIGUSR2]).unwrap();
+        signal_source
+            .as_source_mut()
+            .add_signals(&[Signal::SIGUSR2])
+            .unwrap();
 
         // send ourselves a SIGUSR2
         kill(Pid::this(), Signal::SIGUSR2).unwrap();
@@ -91,17 +101,21 @@ mod test {
 
         let mut signal_received = None;
 
-        let mut signal_source = event_loop
+        let signal_source = event_loop
             .handle()
             .insert_source(
                 Signals::new(&[Signal::SIGUSR1, Signal::SIGUSR2]).unwrap(),
                 move |evt, rcv| {
-                    *rcv = Some(evt.signal());
+                    *rcv = evt.signal();
                 },
-            ).map_err(Into::<io::Error>::into)
+            )
+            .map_err(Into::<io::Error>::into)
             .unwrap();
 
-        signal_source.remove_signals(&[Signal::SIGUSR2]).unwrap();
+        signal_source
+            .as_source_mut()
+            .remove_signals(&[Signal::SIGUSR2])
+            .unwrap();
 
         // block sigusr2 anyway, to not be killed by it
         let mut set = SigSet::empty();
@@ -119,7 +133,10 @@ mod test {
         assert!(signal_received.is_none());
 
         // swap the signals from [SIGUSR1] to [SIGUSR2]
-        signal_source.set_signals(&[Signal::SIGUSR2]).unwrap();
+        signal_source
+            .as_source_mut()
+            .set_signals(&[Signal::SIGUSR2])
+            .unwrap();
 
         event_loop
             .dispatch(Some(Duration::from_millis(10)), &mut signal_received)
@@ -128,4 +145,32 @@ mod test {
         // we should get back the pending SIGUSR2 now
         assert_eq!(signal_received, Some(Signal::SIGUSR2));
     }
+
+    fn thread_mask_updated() {
+        let blocked = || {
+            let mask = SigSet::thread_get_mask().unwrap();
+            (
+                mask.contains(Signal::SIGUSR1),
+                mask.contains(Signal::SIGUSR2),
+            )
+        };
+
+        let mut signals = Signals::new(&[Signal::SIGUSR1]).unwrap();
+        assert_eq!(blocked(), (true, false));
+
+        signals.add_signals(&[Signal::SIGUSR2]).unwrap();
+        assert_eq!(blocked(), (true, true));
+
+        signals.remove_signals(&[Signal::SIGUSR1]).unwrap();
+        assert_eq!(blocked(), (false, true));
+
+        // the signals in both sets stay blocked
+        signals
+            .set_signals(&[Signal::SIGUSR1, Signal::SIGUSR2])
+            .unwrap();
+        assert_eq!(blocked(), (true, true));
+
+        ::std::mem::drop(signals);
+        assert_eq!(blocked(), (false, false));
+    }
 }